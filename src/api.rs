@@ -1,34 +1,341 @@
-use anyhow::Result;
+use std::error::Error;
+use std::fmt;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use crate::Config;
 use crate::review::Review;
 
+pub mod client;
 pub mod github;
 pub mod gitlab;
+#[cfg(test)]
+pub mod mock;
+
+/// Options for `Api::get_pr()`
+#[derive(Debug, Default, Clone)]
+pub struct GetOptions {
+    /// Overwrite a review file even if it has unsubmitted changes
+    pub force: bool,
+    /// Only fetch what changed since this commit instead of the whole pull request
+    pub since: Option<String>,
+    /// Diff against this ref instead of the pull request's base, eg. for stacked PRs
+    pub base: Option<String>,
+    /// Fetch the changes in patch format, ie. split up by commit with commit metadata
+    pub patch: bool,
+    /// Show existing comment threads in the review file
+    ///
+    /// Threads are placed by their line in the whole pull request, so this does not
+    /// combine with `since` or `patch`.
+    pub with_comments: bool,
+    /// Show a spinner on stderr while fetching
+    pub progress: bool,
+    /// Only keep the files matching one of these globs. Empty means all files
+    pub paths: Vec<String>,
+    /// Only fetch the changes of this commit of the pull request. May be abbreviated
+    pub commit: Option<String>,
+    /// Show the new lines each hunk covers below its header, ie. where comments can go
+    pub hunk_ranges: bool,
+    /// Show the log of the commits of the pull request above the diff, to comment on them
+    pub commit_log: bool,
+    /// Fetch the diff even if an earlier fetch of it is still cached
+    pub no_cache: bool,
+    /// Whether to diff against the merge base or the tip of the base
+    pub range: DiffRange,
+    /// Print the review file to stdout instead of writing it to the workdir
+    ///
+    /// No metadata is written either, so the review can't be submitted.
+    pub stdout: bool,
+}
+
+/// Options for `Api::submit_pr()`
+#[derive(Debug, Default)]
+pub struct SubmitOptions {
+    /// Print the request before sending it
+    pub debug: bool,
+    /// Only submit inline comments on these files. Empty means all files
+    pub only: Vec<String>,
+    /// Submit the review comment, action and reactions even if `only` is set
+    pub include_summary: bool,
+    /// Submit each inline comment on its own instead of bundling them in a review
+    ///
+    /// Only makes a difference on GitHub, GitLab has no bundled reviews.
+    pub individual: bool,
+    /// Warn about inline comments whose line changed since the review was fetched
+    pub verify_lines: bool,
+    /// Fail on duplicate inline comments instead of dropping them. See
+    /// `Review::deny_duplicates()`
+    pub deny_duplicates: bool,
+    /// Review comment to submit instead of the one in the review file
+    pub message: Option<String>,
+    /// Footer to add to the review comment. See `ReviewComments::append_signature()`
+    pub signature: Option<String>,
+    /// Submit even if the pull request was closed or merged when it was fetched. See
+    /// `ReviewMetadata::check_state()`
+    pub force: bool,
+}
+
+/// Error from `Api::submit_pr()` when some of the inline comments could not be submitted
+///
+/// Lets scripts tell a partially submitted review apart from one that failed outright,
+/// see the exit codes in `main`.
+#[derive(Debug, PartialEq)]
+pub struct SubmitFailure {
+    /// Number of inline comments that made it to the server
+    pub submitted: usize,
+    /// Number of inline comments that did not
+    pub failed: usize,
+}
+
+impl fmt::Display for SubmitFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Submitted {} of {} inline comments, {} failed",
+            self.submitted,
+            self.submitted + self.failed,
+            self.failed
+        )
+    }
+}
+
+impl Error for SubmitFailure {}
+
+/// An existing comment thread on a pull request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thread {
+    /// Id of the thread, ie. the GraphQL node id on GH and the discussion id on GL
+    pub id: String,
+    /// File the thread is in, after an eventual rename
+    pub file: String,
+    /// Line in the old file the thread is on. Only used if `new_line` is unset
+    pub old_line: Option<u64>,
+    /// Line in the new file the thread is on
+    pub new_line: Option<u64>,
+    pub resolved: bool,
+    /// Comments in the thread, oldest first
+    pub comments: Vec<ThreadComment>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadComment {
+    /// Id of the comment, as taken by `@prr react` and `@prr amend`
+    pub id: u64,
+    pub author: String,
+    pub body: String,
+}
+
+/// A commit of a pull request, for the commit log of `prr get --commit-log`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    pub sha: String,
+    /// Name and email of the author, eg. `Daniel Xu <dxu@dxuuu.xyz>`
+    pub author: String,
+    /// The whole commit message, subject first
+    pub message: String,
+}
+
+/// What a pull request is diffed against
+///
+/// Three-dot is what the hosts show: only the changes of the pull request, since the
+/// merge base. Two-dot compares with the tip of the base, so changes the base got since
+/// the pull request branched off show up as reverted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffRange {
+    /// `base...head`
+    #[default]
+    ThreeDot,
+    /// `base..head`
+    TwoDot,
+}
+
+impl DiffRange {
+    /// Returns what goes between the commits of a range, eg. `..`
+    pub fn separator(&self) -> &'static str {
+        match self {
+            DiffRange::ThreeDot => "...",
+            DiffRange::TwoDot => "..",
+        }
+    }
+
+    pub fn is_three_dot(&self) -> bool {
+        *self == DiffRange::ThreeDot
+    }
+}
+
+/// Which pull requests `Api::list_prs()` returns
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum PrState {
+    #[default]
+    Open,
+    /// Closed or merged
+    Closed,
+    All,
+}
+
+/// A pull request as returned by `Api::list_prs()`
+#[derive(Debug, PartialEq)]
+pub struct PrSummary {
+    pub number: u64,
+    pub title: String,
+    /// Login of the user that opened the pull request
+    pub author: String,
+    /// Name of the branch the changes are on
+    pub head_ref: String,
+    /// Name of the branch the changes are to be merged into
+    pub base_ref: String,
+    /// URL of the pull request in the web UI
+    pub url: String,
+    /// Whether the pull request is a draft, ie. not ready to be merged
+    pub draft: bool,
+}
 
+/// A host of pull requests
+///
+/// Only fetching and submitting reviews is required, the other methods fail unless the
+/// host supports them.
 pub trait Api {
-    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, force: bool) -> Result<Review>;
-    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, force: bool) -> Result<()>;
+    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &GetOptions) -> Result<Review>;
+    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &SubmitOptions) -> Result<()>;
+    /// Returns the diff between commit `since` and the current head of the pull request
+    fn diff_since(&self, _owner: &str, _repo: &str, _pr_num: u64, _since: &str) -> Result<String> {
+        bail!("Diffing against an earlier fetch is not supported")
+    }
+    /// Returns the login of the authenticated user
+    ///
+    /// Only looked up on first use and cached afterwards.
+    fn current_user(&self) -> Result<String> {
+        bail!("Looking up the authenticated user is not supported")
+    }
+    /// Returns the display name of the authenticated user, if they set one
+    ///
+    /// Shares the lookup with `current_user()`.
+    fn current_user_name(&self) -> Result<Option<String>> {
+        bail!("Looking up the authenticated user is not supported")
+    }
+    /// Returns the commit the authenticated user last reviewed the pull request at, if any
+    fn last_review_commit(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+    ) -> Result<Option<String>> {
+        bail!("Finding the last review is not supported")
+    }
+    /// Returns the numbers of the open pull requests whose head is `branch` of `head_repo`
+    ///
+    /// `head_repo` is `owner/repo` of the repo the branch lives in, either the target repo
+    /// or a fork of it.
+    fn open_prs_for_branch(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _head_repo: &str,
+        _branch: &str,
+    ) -> Result<Vec<u64>> {
+        bail!("Finding the pull request of a branch is not supported")
+    }
+    /// Returns the pull requests of a repository in `state`, most recently created first
+    fn list_prs(&self, _owner: &str, _repo: &str, _state: PrState) -> Result<Vec<PrSummary>> {
+        bail!("Listing pull requests is not supported")
+    }
+    /// Returns a single comment thread of a pull request by its id
+    ///
+    /// Only threads on a line of the diff are supported.
+    fn get_thread(
+        &self,
+        _owner: &str,
+        _repo: &str,
+        _pr_num: u64,
+        _thread_id: &str,
+    ) -> Result<Thread> {
+        bail!("Fetching a single thread is not supported")
+    }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Host {
     Github,
     Gitlab,
 }
 
 impl Host {
+    /// Parses a host name or one of its aliases, ignoring case
+    #[allow(clippy::should_implement_trait)] // Unknown hosts are not an error to callers
     pub fn from_str(s: &str) -> Option<Self> {
-        match s {
-            "github" => Some(Host::Github),
-            "gitlab" => Some(Host::Gitlab),
+        match s.to_ascii_lowercase().as_str() {
+            "github" | "gh" => Some(Host::Github),
+            "gitlab" | "gl" => Some(Host::Gitlab),
             _ => None,
         }
     }
 
+    /// Returns the canonical name of the host, as accepted by `from_str()`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Host::Github => "github",
+            Host::Gitlab => "gitlab",
+        }
+    }
+
+    /// Returns the default instance URL for the host
+    pub fn default_url(&self) -> &'static str {
+        match self {
+            Host::Github => github::GITHUB_BASE_URL,
+            Host::Gitlab => gitlab::GITLAB_BASE_URL,
+        }
+    }
+
+    /// Guesses the host from a hostname, eg. the one in an SSH remote
+    pub fn from_hostname(hostname: &str) -> Option<Self> {
+        if hostname.contains("github") {
+            Some(Host::Github)
+        } else if hostname.contains("gitlab") {
+            Some(Host::Gitlab)
+        } else {
+            None
+        }
+    }
+
     pub fn init(self, config: Config) -> Result<Box<dyn Api>> {
         match self {
             Host::Github => github::Github::new(config).map(|gh| Box::new(gh) as Box<dyn Api>),
             Host::Gitlab => gitlab::Gitlab::new(config).map(|gl| Box::new(gl) as Box<dyn Api>),
         }
     }
+
+    /// Returns the URL of the pull request in the host's web UI
+    pub fn web_url(&self, config: &Config, owner: &str, repo: &str, pr_num: u64) -> String {
+        match self {
+            Host::Github => github::web_url(config, owner, repo, pr_num),
+            Host::Gitlab => gitlab::web_url(config, owner, repo, pr_num),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_aliases() {
+        assert_eq!(Host::from_str("github"), Some(Host::Github));
+        assert_eq!(Host::from_str("gh"), Some(Host::Github));
+        assert_eq!(Host::from_str("gitlab"), Some(Host::Gitlab));
+        assert_eq!(Host::from_str("gl"), Some(Host::Gitlab));
+    }
+
+    #[test]
+    fn host_mixed_case() {
+        assert_eq!(Host::from_str("GitHub"), Some(Host::Github));
+        assert_eq!(Host::from_str("GL"), Some(Host::Gitlab));
+    }
+
+    #[test]
+    fn host_unknown() {
+        assert_eq!(Host::from_str("bitbucket"), None);
+        assert_eq!(Host::from_str(""), None);
+    }
 }
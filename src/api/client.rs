@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use crate::Config;
+
+/// Default request timeout. See `prr.timeout_secs`
+pub const DEFAULT_TIMEOUT_SECS: u64 = 30;
+
+/// Settings for the HTTP clients used to talk to the backends
+pub struct ClientSettings {
+    /// Timeout for a whole request, from connecting until the body has been read
+    pub timeout: Duration,
+    /// Proxy for all requests
+    ///
+    /// If unset, reqwest falls back to the `HTTP_PROXY`, `HTTPS_PROXY`, `ALL_PROXY` and
+    /// `NO_PROXY` environment variables.
+    pub proxy: Option<reqwest::Proxy>,
+    /// Extra CA certificate to trust, eg. for self-hosted instances with an internal CA
+    pub ca_cert: Option<reqwest::Certificate>,
+    /// Whether to accept any TLS certificate
+    pub insecure: bool,
+}
+
+/// Loads a PEM encoded CA certificate
+pub fn load_ca_cert(path: &Path) -> Result<reqwest::Certificate> {
+    let pem = fs::read(path)
+        .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+
+    reqwest::Certificate::from_pem(&pem)
+        .with_context(|| format!("CA certificate {} is not valid PEM", path.display()))
+}
+
+impl ClientSettings {
+    pub fn new(config: &Config) -> Result<Self> {
+        let proxy = match &config.prr.proxy {
+            Some(url) => Some(
+                reqwest::Proxy::all(url.as_str())
+                    .with_context(|| format!("Invalid proxy URL '{}'", url))?,
+            ),
+            None => None,
+        };
+        let ca_cert = match &config.prr.ca_cert {
+            Some(path) => Some(load_ca_cert(Path::new(path))?),
+            None => None,
+        };
+
+        Ok(Self {
+            timeout: Duration::from_secs(config.prr.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS)),
+            proxy,
+            ca_cert,
+            insecure: config.prr.insecure,
+        })
+    }
+
+    /// Builds an async client with these settings
+    pub fn client(&self) -> Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .danger_accept_invalid_certs(self.insecure);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+
+        builder.build().context("Failed to create HTTP client")
+    }
+
+    /// Builds a blocking client with these settings
+    pub fn blocking_client(&self) -> Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .danger_accept_invalid_certs(self.insecure);
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(proxy.clone());
+        }
+        if let Some(cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(cert.clone());
+        }
+
+        builder.build().context("Failed to create HTTP client")
+    }
+
+    /// Wraps a failed request so that timeouts are reported as such
+    pub fn error(&self, err: reqwest::Error) -> RequestError {
+        if err.is_timeout() {
+            RequestError::Timeout(self.timeout)
+        } else {
+            RequestError::Request(err)
+        }
+    }
+}
+
+/// Error from sending a request with one of our clients
+#[derive(Debug)]
+pub enum RequestError {
+    /// The request did not finish within the configured timeout
+    Timeout(Duration),
+    /// The request failed for any other reason, eg. the connection was refused
+    Request(reqwest::Error),
+    /// The request could not be built
+    Http(http::Error),
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Timeout(t) => write!(
+                f,
+                "Request timed out after {}s (see `prr.timeout_secs`)",
+                t.as_secs_f32()
+            ),
+            RequestError::Request(e) => write!(f, "Request failed: {}", e),
+            RequestError::Http(e) => write!(f, "Failed to build request: {}", e),
+        }
+    }
+}
+
+impl Error for RequestError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RequestError::Timeout(_) => None,
+            RequestError::Request(e) => Some(e),
+            RequestError::Http(e) => Some(e),
+        }
+    }
+}
+
+impl From<http::Error> for RequestError {
+    fn from(err: http::Error) -> Self {
+        RequestError::Http(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Instant;
+
+    #[test]
+    fn timeout() {
+        // Connections to the listener are accepted by the kernel but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let settings = ClientSettings {
+            timeout: Duration::from_millis(200),
+            proxy: None,
+            ca_cert: None,
+            insecure: false,
+        };
+        let client = settings.blocking_client().unwrap();
+
+        let start = Instant::now();
+        let err = settings.error(client.get(url).send().unwrap_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(err, RequestError::Timeout(_)));
+        assert!(err.to_string().contains("timed out"));
+    }
+
+    #[test]
+    fn proxy() {
+        let settings = |proxy: &str| {
+            let config = format!("[prr]\ntoken = \"\"\nproxy = \"{}\"", proxy);
+            ClientSettings::new(&toml::from_str(&config).unwrap())
+        };
+
+        assert!(settings("http://proxy.example.com:3128")
+            .unwrap()
+            .proxy
+            .is_some());
+        assert!(settings("proxy.example.com:3128").unwrap().proxy.is_some());
+        let err = settings("http://[proxy").err().unwrap();
+        assert!(err.to_string().contains("Invalid proxy URL"));
+    }
+
+    #[test]
+    fn ca_cert() {
+        let testdata = Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata");
+        assert!(load_ca_cert(&testdata.join("ca.pem")).is_ok());
+
+        let err = load_ca_cert(&testdata.join("does_not_exist.pem")).unwrap_err();
+        assert!(err.to_string().contains("Failed to read"));
+
+        let err = load_ca_cert(&testdata.join("single_comment")).unwrap_err();
+        assert!(err.to_string().contains("not valid PEM"));
+    }
+}
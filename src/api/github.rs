@@ -1,15 +1,28 @@
+use std::cell::OnceCell;
+use std::collections::HashMap;
+
 use lazy_static::lazy_static;
 use regex::Regex;
 
-use anyhow::{bail, Context, Result};
-use octocrab::Octocrab;
-use reqwest::StatusCode;
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use reqwest::header::{ACCEPT, CONTENT_TYPE, USER_AGENT};
+use reqwest::{Method, RequestBuilder, StatusCode, Url};
+use serde_derive::Deserialize;
 use serde_json::{json, Value};
 
 use crate::Config;
-use crate::api::Api;
-use crate::parser::{LineLocation, ReviewAction};
-use crate::review::{Extra, Review};
+use crate::api::client::ClientSettings;
+use crate::api::{
+    Api, Commit, DiffRange, GetOptions, PrState, PrSummary, SubmitFailure, SubmitOptions, Thread,
+    ThreadComment,
+};
+use crate::color;
+use crate::parser::{Emoji, InlineComment, LineLocation, Reply, ReviewAction};
+use crate::review::{
+    changed_lines, comments_outside_diff, Extra, PullState, Review, ReviewComments,
+};
+use crate::spinner::Spinner;
 
 // Use lazy static to ensure regex is only compiled once
 lazy_static! {
@@ -17,137 +30,2394 @@ lazy_static! {
     //
     //      https://github.com/danobi/prr-test-repo/pull/6
     //
-    pub static ref URL: Regex = Regex::new(r".*github\.com/(?P<org>.+)/(?P<repo>.+)/pull/(?P<pr_num>\d+)").unwrap();
+    // Anything after the PR number, eg. `/files` or a `#discussion_r1` fragment, is ignored.
+    pub static ref URL: Regex = Regex::new(r"^(https?://)?([\w\-]+\.)*github\.com/(?P<org>[^/]+?)/(?P<repo>[^/]+?)/pull/(?P<pr_num>\d+)([/?#].*)?$").unwrap();
+}
+
+pub const GITHUB_BASE_URL: &str = "https://api.github.com";
+const GITHUB_WEB_URL: &str = "https://github.com";
+
+/// Media type for regular API responses
+const MEDIA_JSON: &str = "application/vnd.github.v3+json";
+/// Media type to get the diff of a pull request or comparison
+const MEDIA_DIFF: &str = "application/vnd.github.v3.diff";
+/// Media type to get the commits of a pull request or comparison in patch format
+const MEDIA_PATCH: &str = "application/vnd.github.v3.patch";
+/// Most inline comments GH accepts in a single review
+const MAX_REVIEW_COMMENTS: usize = 200;
+/// Largest page size the list endpoints allow
+const PER_PAGE: usize = 100;
+
+/// Turns the configured `prr.url` into the root of the REST API
+///
+/// The scheme may be left out, eg. `github.example.com`, in which case https is used. A
+/// URL without a path is taken to be a GitHub Enterprise instance, which serves its API
+/// under `/api/v3`.
+fn api_base_url(url: &str) -> Result<Url> {
+    let with_scheme = if url.contains("://") {
+        url.to_string()
+    } else {
+        format!("https://{}", url)
+    };
+    let mut parsed = Url::parse(&with_scheme)
+        .with_context(|| format!("Failed to parse github base URL '{}'", url))?;
+
+    match parsed.host_str() {
+        // The web UI of github.com, not its API
+        Some("github.com") if parsed.path() == "/" => {
+            return Ok(Url::parse("https://api.github.com/")?)
+        }
+        Some("api.github.com") => (),
+        _ if parsed.path() == "/" => parsed.set_path("/api/v3/"),
+        _ => (),
+    }
+    if !parsed.path().ends_with('/') {
+        let path = format!("{}/", parsed.path());
+        parsed.set_path(&path);
+    }
+
+    Ok(parsed)
+}
+
+/// Returns the web UI URL for a pull request
+///
+/// The configured URL points at the API, so strip the API specific parts to get
+/// back to the web UI. For Enterprise instances, the API lives under `/api/v3`.
+pub fn web_url(config: &Config, owner: &str, repo: &str, pr_num: u64) -> String {
+    let base = match config.prr.url.as_deref().map(api_base_url) {
+        Some(Ok(url)) => url
+            .as_str()
+            .trim_end_matches('/')
+            .trim_end_matches("/api/v3")
+            .replacen("://api.", "://", 1),
+        // `Github::new()` reports the invalid URL
+        Some(Err(_)) | None => GITHUB_WEB_URL.to_string(),
+    };
+
+    format!("{}/{}/{}/pull/{}", base, owner, repo, pr_num)
+}
+
+/// The parts of a GH pull request we care about
+#[derive(Deserialize)]
+struct PullRequest {
+    number: u64,
+    title: String,
+    user: User,
+    html_url: String,
+    head: Head,
+    base: Base,
+    #[serde(default)]
+    draft: bool,
+    /// `open` or `closed`, merged pull requests are closed too
+    #[serde(default)]
+    state: String,
+    #[serde(default)]
+    merged_at: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Head {
+    sha: String,
+    #[serde(rename = "ref")]
+    ref_name: String,
+    /// Repo the source branch lives in. Null if it has been deleted
+    #[serde(default)]
+    repo: Option<Repo>,
+}
+
+#[derive(Deserialize)]
+struct Base {
+    /// Tip of the base branch
+    sha: String,
+    #[serde(rename = "ref")]
+    ref_name: String,
+}
+
+#[derive(Deserialize)]
+struct Repo {
+    full_name: String,
 }
 
-const GITHUB_BASE_URL: &str = "https://api.github.com";
+impl PullRequest {
+    fn state(&self) -> PullState {
+        match (self.state.as_str(), &self.merged_at) {
+            (_, Some(_)) => PullState::Merged,
+            ("closed", None) => PullState::Closed,
+            _ => PullState::Open,
+        }
+    }
+
+    /// Returns `owner/repo` of the source branch if it is in a fork of `owner/repo`
+    fn fork(&self, owner: &str, repo: &str) -> Option<String> {
+        let name = &self.head.repo.as_ref()?.full_name;
+        let fork = !name.eq_ignore_ascii_case(&format!("{}/{}", owner, repo));
+        fork.then(|| name.clone())
+    }
+}
+
+/// Returns the owner and repo to fetch the commits of a pull request from
+///
+/// Reviews always go to the target repo, but the commits of a pull request from a fork
+/// live in the fork.
+fn head_repo<'a>(fork: Option<&'a str>, owner: &'a str, repo: &'a str) -> (&'a str, &'a str) {
+    fork.and_then(|f| f.split_once('/'))
+        .unwrap_or((owner, repo))
+}
+
+/// The parts of a commit of a GH pull request we care about
+#[derive(Deserialize)]
+struct PullRequestCommit {
+    sha: String,
+    commit: GitCommit,
+}
+
+#[derive(Deserialize)]
+struct GitCommit {
+    message: String,
+    author: GitAuthor,
+}
+
+#[derive(Deserialize)]
+struct GitAuthor {
+    name: String,
+    email: String,
+}
+
+impl From<PullRequestCommit> for Commit {
+    fn from(c: PullRequestCommit) -> Self {
+        Commit {
+            sha: c.sha,
+            author: format!("{} <{}>", c.commit.author.name, c.commit.author.email),
+            message: c.commit.message,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct User {
+    login: String,
+    /// Display name. Only included when fetching a single user
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// The parts of a GH pull request review we care about
+#[derive(Deserialize)]
+struct PullRequestReview {
+    user: User,
+    state: String,
+    commit_id: String,
+}
+
+/// Fetches the review threads of a pull request, one page at a time
+const REVIEW_THREADS_QUERY: &str = r#"
+query($owner: String!, $repo: String!, $pr: Int!, $cursor: String) {
+  repository(owner: $owner, name: $repo) {
+    pullRequest(number: $pr) {
+      reviewThreads(first: 100, after: $cursor) {
+        pageInfo { hasNextPage endCursor }
+        nodes { ...thread }
+      }
+    }
+  }
+}
+"#;
+
+/// Fetches a single review thread by its node id
+const REVIEW_THREAD_QUERY: &str = r#"
+query($id: ID!) {
+  node(id: $id) {
+    ...thread
+    ... on PullRequestReviewThread { pullRequest { number } }
+  }
+}
+"#;
+
+/// Fetches the saved replies of the authenticated user. GH allows at most 100 of them
+const SAVED_REPLIES_QUERY: &str = r#"
+query {
+  viewer {
+    savedReplies(first: 100) { nodes { title body } }
+  }
+}
+"#;
+
+/// The fields of a review thread that make up a `ReviewThread`, for use in the queries above
+const REVIEW_THREAD_FRAGMENT: &str = r#"
+fragment thread on PullRequestReviewThread {
+  id
+  isResolved
+  path
+  line
+  diffSide
+  comments(first: 100) { nodes { databaseId author { login } body } }
+}
+"#;
+
+/// A page of review threads as returned by `REVIEW_THREADS_QUERY`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReviewThreads {
+    page_info: PageInfo,
+    nodes: Vec<ReviewThread>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PageInfo {
+    has_next_page: bool,
+    end_cursor: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReviewThread {
+    id: String,
+    is_resolved: bool,
+    path: String,
+    /// Unset if the thread is outdated
+    line: Option<u64>,
+    diff_side: String,
+    comments: ReviewThreadComments,
+}
+
+#[derive(Deserialize)]
+struct ReviewThreadComments {
+    nodes: Vec<ReviewThreadComment>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ReviewThreadComment {
+    database_id: u64,
+    /// Unset if the account was deleted
+    author: Option<User>,
+    body: String,
+}
+
+impl From<ReviewThread> for Thread {
+    fn from(t: ReviewThread) -> Self {
+        let (old_line, new_line) = match t.diff_side.as_str() {
+            "LEFT" => (t.line, None),
+            _ => (None, t.line),
+        };
+
+        Thread {
+            id: t.id,
+            file: t.path,
+            old_line,
+            new_line,
+            resolved: t.is_resolved,
+            comments: t
+                .comments
+                .nodes
+                .into_iter()
+                .map(|c| ThreadComment {
+                    id: c.database_id,
+                    author: c.author.map_or_else(|| "ghost".to_string(), |a| a.login),
+                    body: c.body,
+                })
+                .collect(),
+        }
+    }
+}
 
 /// Main struct that coordinates all business logic and talks to GH
 pub struct Github {
     /// User config
     config: Config,
-    /// Instantiated github client
-    crab: Octocrab,
+    /// Settings `client` was built with
+    settings: ClientSettings,
+    /// HTTP client to talk to GH with
+    client: reqwest::Client,
+    /// Base URL of the API. Always ends in a `/`
+    base_url: Url,
+    /// The authenticated user. See `user()`
+    user: OnceCell<User>,
+    /// Bodies of the saved replies of the authenticated user, by title. See `saved_replies()`
+    saved_replies: OnceCell<HashMap<String, String>>,
 }
 
 impl Github {
     pub fn new(config: Config) -> Result<Self> {
-        let octocrab = Octocrab::builder()
-            .personal_token(config.prr.token.clone())
-            .base_url(config.prr.url.as_deref().unwrap_or(GITHUB_BASE_URL))
-            .context("Failed to parse github base URL")?
-            .build()
-            .context("Failed to create GH client")?;
+        let base_url = api_base_url(config.prr.url.as_deref().unwrap_or(GITHUB_BASE_URL))?;
+        let settings = ClientSettings::new(&config)?;
+        let client = settings.client().context("Failed to create GH client")?;
 
         Ok(Self {
             config,
-            crab: octocrab,
+            settings,
+            client,
+            base_url,
+            user: OnceCell::new(),
+            saved_replies: OnceCell::new(),
         })
     }
-}
 
-impl Api for Github {
-    fn get_pr(
+    /// Returns the full URL for an API path like `/repos/danobi/prr`
+    fn url(&self, path: &str) -> Result<Url> {
+        self.base_url
+            .join(path.trim_start_matches('/'))
+            .with_context(|| format!("Invalid API path {}", path))
+    }
+
+    /// Authenticates and sends `request`, returning the status and body of the response
+    async fn send(&self, request: RequestBuilder) -> Result<(StatusCode, String)> {
+        let resp = request
+            .bearer_auth(&self.config.prr.token)
+            .header(USER_AGENT, "prr")
+            .send()
+            .await
+            .map_err(|e| self.settings.error(e))?;
+        let status = resp.status();
+        let text = resp.text().await.map_err(|e| self.settings.error(e))?;
+
+        Ok((status, text))
+    }
+
+    /// GETs `url` as `media_type` and returns the body if the request succeeded
+    async fn get(&self, url: Url, media_type: &str) -> Result<String> {
+        debug!("GET {}", url);
+        let request = self.client.get(url.clone()).header(ACCEPT, media_type);
+        let (status, text) = self.send(request).await?;
+        if !status.is_success() {
+            return Err(request_error(&format!("GET {}", url), status, &text));
+        }
+
+        Ok(text)
+    }
+
+    /// GETs a pull request
+    async fn pull_request(&self, owner: &str, repo: &str, pr_num: u64) -> Result<PullRequest> {
+        let url = self.url(&format!("/repos/{}/{}/pulls/{}", owner, repo, pr_num))?;
+        let text = self
+            .get(url, MEDIA_JSON)
+            .await
+            .context("Failed to fetch pull request")?;
+
+        serde_json::from_str(&text).context("Failed to parse pull request")
+    }
+
+    /// Returns the authenticated user, looking it up on first use
+    async fn user(&self) -> Result<&User> {
+        if let Some(user) = self.user.get() {
+            return Ok(user);
+        }
+
+        let text = self
+            .get(self.url("/user")?, MEDIA_JSON)
+            .await
+            .context("Failed to fetch authenticated user")?;
+        let user: User = serde_json::from_str(&text).context("Failed to parse user")?;
+        debug!("Authenticated as {}", user.login);
+
+        Ok(self.user.get_or_init(|| user))
+    }
+
+    /// Returns the login of the authenticated user
+    async fn login(&self) -> Result<String> {
+        Ok(self.user().await?.login.clone())
+    }
+
+    /// Returns the commits of a pull request, oldest first
+    ///
+    /// GH lists at most 250 commits of a pull request, later ones are left out.
+    async fn commits(
         &self,
         owner: &str,
         repo: &str,
         pr_num: u64,
-        force: bool,
-    ) -> Result<Review> {
-        tokio::runtime::Runtime::new()?.block_on(async {
-            let diff = self
-                .crab
-                .pulls(owner, repo)
-                .get_diff(pr_num)
+    ) -> Result<Vec<PullRequestCommit>> {
+        let mut all = Vec::new();
+        for page in 1.. {
+            let mut url = self.url(&format!(
+                "/repos/{}/{}/pulls/{}/commits",
+                owner, repo, pr_num
+            ))?;
+            url.query_pairs_mut()
+                .append_pair("per_page", &PER_PAGE.to_string())
+                .append_pair("page", &page.to_string());
+            let text = self
+                .get(url, MEDIA_JSON)
                 .await
-                .context("Failed to fetch diff")?;
+                .context("Failed to list commits")?;
+            let commits: Vec<PullRequestCommit> =
+                serde_json::from_str(&text).context("Failed to parse commits")?;
 
-            Review::new(&self.config.workdir(self.config.host_or(GITHUB_BASE_URL))?, diff, owner, repo, pr_num, Extra::default(), force)
-        })
+            let last_page = commits.len() < PER_PAGE;
+            all.extend(commits);
+            if last_page {
+                break;
+            }
+        }
+
+        Ok(all)
     }
 
-    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, debug: bool) -> Result<()> {
-        tokio::runtime::Runtime::new()?.block_on(async {
-            let review = Review::new_existing(&self.config.workdir(self.config.host_or(GITHUB_BASE_URL))?, owner, repo, pr_num);
-            let (review_action, review_comment, inline_comments) = review.comments()?;
+    /// Returns the full SHA of the commit of a pull request that `sha` abbreviates
+    ///
+    /// GH lists at most 250 commits of a pull request, later ones cannot be found.
+    async fn pr_commit(&self, owner: &str, repo: &str, pr_num: u64, sha: &str) -> Result<String> {
+        let sha = sha.to_lowercase();
+        let matches: Vec<_> = self
+            .commits(owner, repo, pr_num)
+            .await?
+            .into_iter()
+            .filter(|c| c.sha.starts_with(&sha))
+            .collect();
+
+        match matches.as_slice() {
+            [commit] => Ok(commit.sha.clone()),
+            [] => bail!("Commit {} is not part of pull request #{}", sha, pr_num),
+            _ => bail!(
+                "Commit {} is ambiguous in pull request #{}, use more of the SHA",
+                sha,
+                pr_num
+            ),
+        }
+    }
+
+    /// Returns the SHA of the commit `git_ref` (a branch, tag or SHA) points at
+    async fn resolve_ref(&self, owner: &str, repo: &str, git_ref: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct RefCommit {
+            sha: String,
+        }
+
+        let url = self.url(&format!("/repos/{}/{}/commits/{}", owner, repo, git_ref))?;
+        let text = self
+            .get(url, MEDIA_JSON)
+            .await
+            .with_context(|| format!("Base {} does not exist in {}/{}", git_ref, owner, repo))?;
+        let commit: RefCommit = serde_json::from_str(&text).context("Failed to parse commit")?;
+
+        Ok(commit.sha)
+    }
+
+    /// Returns the changes of a single commit as `media_type`
+    ///
+    /// For a merge commit, these are the changes against its first parent.
+    async fn commit_diff(
+        &self,
+        owner: &str,
+        repo: &str,
+        sha: &str,
+        media_type: &str,
+    ) -> Result<String> {
+        let parent = format!("{}^", sha);
+        self.compare(owner, repo, &parent, sha, DiffRange::ThreeDot, media_type)
+            .await
+    }
+
+    /// Returns the diff between two commits as `media_type`
+    async fn compare(
+        &self,
+        owner: &str,
+        repo: &str,
+        from: &str,
+        to: &str,
+        range: DiffRange,
+        media_type: &str,
+    ) -> Result<String> {
+        let path = format!(
+            "/repos/{}/{}/compare/{}{}{}",
+            owner,
+            repo,
+            from,
+            range.separator(),
+            to
+        );
+        let url = self.url(&path)?;
 
-            if review_comment.is_empty() && inline_comments.is_empty() {
-                bail!("No review comments");
+        self.get(url, media_type)
+            .await
+            .with_context(|| format!("Failed to compare {} against {}", from, to))
+    }
+
+    /// Returns the URL of the GraphQL API
+    ///
+    /// It lives next to the REST API, except on Enterprise where the REST API is under
+    /// `/api/v3` and the GraphQL one under `/api/graphql`.
+    fn graphql_url(&self) -> Result<Url> {
+        let path = if self.base_url.path().ends_with("/api/v3/") {
+            "../graphql"
+        } else {
+            "graphql"
+        };
+
+        self.base_url
+            .join(path)
+            .context("Failed to build GraphQL URL")
+    }
+
+    /// Runs a GraphQL query and returns its `data`
+    async fn graphql(&self, query: &str, variables: Value) -> Result<Value> {
+        let url = self.graphql_url()?;
+        debug!("POST {}", url);
+        let request = self
+            .client
+            .post(url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(json!({ "query": query, "variables": variables }).to_string());
+        let (status, text) = self.send(request).await?;
+        if !status.is_success() {
+            return Err(request_error(&format!("POST {}", url), status, &text));
+        }
+
+        let mut resp: Value =
+            serde_json::from_str(&text).context("Failed to parse GraphQL response")?;
+        if let Some(errors) = resp.get("errors").and_then(|e| e.as_array()) {
+            let messages: Vec<&str> = errors
+                .iter()
+                .filter_map(|e| e["message"].as_str())
+                .collect();
+            bail!("GraphQL query failed: {}", messages.join(", "));
+        }
+
+        Ok(resp["data"].take())
+    }
+
+    /// Returns the bodies of the authenticated user's saved replies by title, looking them
+    /// up on first use
+    async fn saved_replies(&self) -> Result<&HashMap<String, String>> {
+        #[derive(Deserialize)]
+        struct SavedReply {
+            title: String,
+            body: String,
+        }
+
+        if let Some(saved) = self.saved_replies.get() {
+            return Ok(saved);
+        }
+
+        let mut data = self
+            .graphql(SAVED_REPLIES_QUERY, json!({}))
+            .await
+            .context("Failed to fetch saved replies")?;
+        let replies: Vec<SavedReply> =
+            serde_json::from_value(data["viewer"]["savedReplies"]["nodes"].take())
+                .context("Failed to parse saved replies")?;
+        let saved = replies.into_iter().map(|r| (r.title, r.body)).collect();
+
+        Ok(self.saved_replies.get_or_init(|| saved))
+    }
+
+    /// Returns the review threads of a pull request
+    async fn threads(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Vec<Thread>> {
+        let mut threads = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let variables = json!({ "owner": owner, "repo": repo, "pr": pr_num, "cursor": cursor });
+            let mut data = self
+                .graphql(
+                    &format!("{}{}", REVIEW_THREADS_QUERY, REVIEW_THREAD_FRAGMENT),
+                    variables,
+                )
+                .await
+                .context("Failed to fetch review threads")?;
+            let page: ReviewThreads =
+                serde_json::from_value(data["repository"]["pullRequest"]["reviewThreads"].take())
+                    .context("Failed to parse review threads")?;
+
+            threads.extend(page.nodes.into_iter().map(Thread::from));
+            if !page.page_info.has_next_page {
+                break;
             }
+            cursor = page.page_info.end_cursor;
+        }
 
-            let body = json!({
-                "body": review_comment,
-                "event": match review_action {
-                    ReviewAction::Approve => "APPROVE",
-                    ReviewAction::RequestChanges => "REQUEST_CHANGES",
-                    ReviewAction::Comment => "COMMENT"
-                },
-                "comments": inline_comments
-                    .iter()
-                    .map(|c| {
-                        let (line, side) = match c.line {
-                            LineLocation::Left(line, _) => (line, "LEFT"),
-                            LineLocation::Right(_, line) | LineLocation::Both(_, line) => (line, "RIGHT"),
-                        };
-
-                        let mut json_comment = json!({
-                            "path": c.new_file,
-                            "line": line,
-                            "body": c.comment,
-                            "side": side,
-                        });
-                        if let Some(start_line) = &c.start_line {
-                            let (line, side) = match start_line {
-                                LineLocation::Left(line, _) => (line, "LEFT"),
-                                LineLocation::Right(_, line) | LineLocation::Both(_, line) => (line, "RIGHT"),
-                            };
-
-                            json_comment["start_line"] = (*line).into();
-                            json_comment["start_side"] = side.into();
-                        }
+        Ok(threads)
+    }
+
+    /// Returns a single review thread of a pull request
+    async fn thread(&self, owner: &str, repo: &str, pr_num: u64, id: &str) -> Result<Thread> {
+        #[derive(Deserialize)]
+        struct PullRequest {
+            number: u64,
+        }
+
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Node {
+            #[serde(flatten)]
+            thread: ReviewThread,
+            pull_request: PullRequest,
+        }
+
+        let mut data = self
+            .graphql(
+                &format!("{}{}", REVIEW_THREAD_QUERY, REVIEW_THREAD_FRAGMENT),
+                json!({ "id": id }),
+            )
+            .await
+            .with_context(|| format!("Failed to fetch review thread {}", id))?;
+        if data["node"].is_null() {
+            bail!("No review thread with id {}", id);
+        }
+        let node: Node = serde_json::from_value(data["node"].take())
+            .with_context(|| format!("{} is not the id of a review thread", id))?;
+        if node.pull_request.number != pr_num {
+            bail!(
+                "Review thread {} is on pull request {}, not {}/{}/{}",
+                id,
+                node.pull_request.number,
+                owner,
+                repo,
+                pr_num
+            );
+        }
+
+        Ok(node.thread.into())
+    }
+
+    /// Submits the review comment and inline comments as standalone comments
+    ///
+    /// These do not belong to a review, so there is no review action.
+    #[allow(clippy::too_many_arguments)]
+    async fn submit_individual(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        review: &Review,
+        comment: &str,
+        inline_comments: &[InlineComment],
+        debug: bool,
+    ) -> Result<()> {
+        // Comment on the commit the review file was fetched at, so lines match up
+        let metadata = review.read_metadata()?;
+        let commit_id = match metadata.commit.or(metadata.head_sha) {
+            Some(sha) => sha,
+            None => self.pull_request(owner, repo, pr_num).await?.head.sha,
+        };
 
-                        json_comment
-                    })
-                    .collect::<Vec<Value>>(),
-            });
+        // Keep going when a comment is rejected, so one bad line does not lose the rest
+        let path = format!("/repos/{}/{}/pulls/{}/comments", owner, repo, pr_num);
+        let mut failed = 0;
+        for c in inline_comments {
+            let mut body = inline_comment_body(c);
+            body["commit_id"] = commit_id.as_str().into();
+            if debug {
+                println!("{}", serde_json::to_string_pretty(&body)?);
+            }
 
+            if let Err(e) = self.post(path.clone(), &body).await {
+                let e = self
+                    .explain_rejected(owner, repo, pr_num, std::slice::from_ref(c), e)
+                    .await;
+                color::warn(format!(
+                    "Failed to submit comment on {}: {:#}",
+                    c.new_file, e
+                ));
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(SubmitFailure {
+                submitted: inline_comments.len() - failed,
+                failed,
+            }
+            .into());
+        }
+
+        if !comment.is_empty() {
+            let body = json!({ "body": comment });
             if debug {
                 println!("{}", serde_json::to_string_pretty(&body)?);
             }
 
-            let path = format!("/repos/{}/{}/pulls/{}/reviews", owner, repo, pr_num);
-            match self
-                .crab
-                ._post(self.crab.absolute_url(path)?, Some(&body))
+            let path = format!("/repos/{}/{}/issues/{}/comments", owner, repo, pr_num);
+            self.post(path, &body)
                 .await
-            {
-                Ok(resp) => {
-                    let status = resp.status();
-                    if status != StatusCode::OK {
-                        let text = resp
-                            .text()
-                            .await
-                            .context("Failed to decode failed response")?;
-                        bail!("Error during POST: Status code: {}, Body: {}", status, text);
+                .context("Failed to submit review comment")?;
+        }
+
+        Ok(())
+    }
+
+    /// Adds which inline comments are outside the pull request diff to a failed submission
+    ///
+    /// GH rejects the whole review if any comment is on a line that is not part of the
+    /// diff, without saying which one.
+    async fn explain_rejected(
+        &self,
+        owner: &str,
+        repo: &str,
+        pr_num: u64,
+        inline_comments: &[InlineComment],
+        err: anyhow::Error,
+    ) -> anyhow::Error {
+        let outside = async {
+            let url = self.url(&format!("/repos/{}/{}/pulls/{}", owner, repo, pr_num))?;
+            let diff = self.get(url, MEDIA_DIFF).await?;
+            comments_outside_diff(&diff, inline_comments)
+        };
+
+        match outside.await {
+            Ok(outside) if !outside.is_empty() => err.context(outside.join("\n")),
+            Ok(_) => err,
+            Err(e) => {
+                debug!("Failed to check comments against the diff: {:#}", e);
+                err
+            }
+        }
+    }
+
+    /// Returns the login of the author of the review comment at `path`
+    async fn comment_author(&self, path: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct ReviewComment {
+            user: User,
+        }
+
+        let text = self
+            .get(self.url(path)?, MEDIA_JSON)
+            .await
+            .context("Failed to fetch comment")?;
+        let comment: ReviewComment =
+            serde_json::from_str(&text).context("Failed to parse comment")?;
+
+        Ok(comment.user.login)
+    }
+
+    /// POSTs `body` to `path` and checks that the request went through
+    async fn post(&self, path: String, body: &Value) -> Result<()> {
+        self.send_json(Method::POST, &path, body).await
+    }
+
+    /// PATCHes `path` with `body` and checks that the request went through
+    async fn patch(&self, path: String, body: &Value) -> Result<()> {
+        self.send_json(Method::PATCH, &path, body).await
+    }
+
+    /// Sends `body` to `path` and checks that the request went through
+    async fn send_json(&self, method: Method, path: &str, body: &Value) -> Result<()> {
+        let request = self
+            .client
+            .request(method.clone(), self.url(path)?)
+            .header(ACCEPT, MEDIA_JSON)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string());
+        let (status, text) = self
+            .send(request)
+            .await
+            .with_context(|| format!("Error during {}", method))?;
+        debug!("{} returned {}", method, status);
+
+        check_submit_response(status, &text)
+    }
+}
+
+/// What the token needs to be allowed to do. Shown when GH refuses a request
+const PERMISSIONS_HINT: &str = "Make sure the token can access the repository. Fine-grained \
+    tokens need the \"Pull requests: Read and write\" and \"Contents: Read\" permissions, \
+    classic tokens need the `repo` scope";
+
+/// Builds the error for a failed request
+///
+/// GH answers requests the token lacks permissions for with a 403, or with a 404 if the token
+/// cannot see the repository at all. Both are cryptic on their own, so point out what the
+/// token needs.
+fn request_error(what: &str, status: StatusCode, body: &str) -> anyhow::Error {
+    let err = anyhow!(
+        "Error during {}: Status code: {}, Body: {}",
+        what,
+        status,
+        body
+    );
+    match status {
+        StatusCode::UNAUTHORIZED => err.context(
+            "GitHub rejected the token. Check `prr.token` and `prr.url`, the token may be \
+            mistyped, expired or revoked",
+        ),
+        StatusCode::FORBIDDEN if !body.contains("rate limit") => {
+            err.context(format!("GitHub denied access. {}", PERMISSIONS_HINT))
+        }
+        StatusCode::NOT_FOUND => err.context(format!(
+            "Not found on GitHub. If it does exist, the token may not be able to see it. {}",
+            PERMISSIONS_HINT
+        )),
+        _ => err,
+    }
+}
+
+/// Checks whether a review submission went through
+///
+/// GH is known to send unescaped control characters in JSON responses which serde will fail
+/// to parse (not that it should succeed). So a malformed body is only tolerated if the status
+/// code says the request actually succeeded.
+fn check_submit_response(status: StatusCode, body: &str) -> Result<()> {
+    if !status.is_success() {
+        return Err(request_error("POST", status, body));
+    }
+
+    if serde_json::from_str::<Value>(body).is_err() {
+        color::warn("GH response had invalid JSON");
+    }
+
+    Ok(())
+}
+
+/// Builds the review submissions for a review, along with the inline comments of each
+///
+/// GH allows at most `MAX_REVIEW_COMMENTS` inline comments per review, so larger reviews
+/// are split up. The review comment and action go on the last one, so that a pull
+/// request is not approved unless all comments made it.
+fn review_bodies<'a>(
+    action: &ReviewAction,
+    comment: &str,
+    inline_comments: &'a [InlineComment],
+) -> Vec<(Value, &'a [InlineComment])> {
+    let mut chunks: Vec<&[InlineComment]> = inline_comments.chunks(MAX_REVIEW_COMMENTS).collect();
+    if chunks.is_empty() {
+        chunks.push(&[]);
+    }
+
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let body = if idx == last {
+                review_body(action, comment, chunk)
+            } else {
+                review_body(&ReviewAction::Comment, "", chunk)
+            };
+            (body, chunk)
+        })
+        .collect()
+}
+
+/// Builds the JSON body to submit a review with
+fn review_body(action: &ReviewAction, comment: &str, inline_comments: &[InlineComment]) -> Value {
+    json!({
+        "body": comment,
+        "event": action.event(),
+        "comments": inline_comments
+            .iter()
+            .map(inline_comment_body)
+            .collect::<Vec<Value>>(),
+    })
+}
+
+/// Builds the JSON for an inline comment, as part of a review or on its own
+fn inline_comment_body(c: &InlineComment) -> Value {
+    // GH numbers the lines of a side by that side's file, so context lines are
+    // anchored to the new file with their new line number
+    let (line, side) = match c.line {
+        LineLocation::Left(line, _) => (line, "LEFT"),
+        LineLocation::Right(_, line) | LineLocation::Both(_, line) => (line, "RIGHT"),
+    };
+
+    let mut json_comment = json!({
+        "path": c.new_file,
+        "line": line,
+        "body": c.comment,
+        "side": side,
+    });
+    if let Some(start_line) = &c.start_line {
+        // A context line exists on both sides, so anchor it to the old file
+        // if the span ends on a deleted line. GH rejects spans that go from
+        // the new file back into the old one.
+        let (line, side) = match (start_line, &c.line) {
+            (LineLocation::Both(line, _), LineLocation::Left(..))
+            | (LineLocation::Left(line, _), _) => (line, "LEFT"),
+            (LineLocation::Right(_, line), _) | (LineLocation::Both(_, line), _) => (line, "RIGHT"),
+        };
+
+        json_comment["start_line"] = (*line).into();
+        json_comment["start_side"] = side.into();
+    }
+
+    json_comment
+}
+
+/// Builds the JSON for a reply to an existing review comment
+///
+/// GH takes the file and line from the comment replied to, so only the body is sent.
+fn reply_body(reply: &Reply) -> Value {
+    json!({
+        "body": reply.body,
+        "in_reply_to": reply.comment_id,
+    })
+}
+
+/// Returns the reaction content GH expects for `emoji`
+fn reaction_content(emoji: Emoji) -> &'static str {
+    match emoji {
+        Emoji::ThumbsUp => "+1",
+        Emoji::ThumbsDown => "-1",
+        Emoji::Laugh => "laugh",
+        Emoji::Confused => "confused",
+        Emoji::Heart => "heart",
+        Emoji::Hooray => "hooray",
+        Emoji::Rocket => "rocket",
+        Emoji::Eyes => "eyes",
+    }
+}
+
+impl Api for Github {
+    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &GetOptions) -> Result<Review> {
+        tokio::runtime::Runtime::new()?.block_on(async {
+            debug!(
+                "Fetching diff for {}/{}/{} from {}",
+                owner, repo, pr_num, self.base_url
+            );
+            let _spinner = opts
+                .progress
+                .then(|| Spinner::start("Fetching pull request"));
+            let pr = self.pull_request(owner, repo, pr_num).await?;
+            let media_type = if opts.patch { MEDIA_PATCH } else { MEDIA_DIFF };
+            let commit = match &opts.commit {
+                Some(sha) => Some(self.pr_commit(owner, repo, pr_num, sha).await?),
+                None => None,
+            };
+            let base = match &opts.base {
+                Some(b) => Some(self.resolve_ref(owner, repo, b).await?),
+                None => None,
+            };
+            let fork = pr.fork(owner, repo);
+            let (head_owner, head_name) = head_repo(fork.as_deref(), owner, repo);
+            let dir = self
+                .config
+                .review_dir(self.config.host_or(GITHUB_BASE_URL), owner, repo)?;
+            let cache = self.config.diff_cache(&dir);
+            // The diff of the pull request itself is three-dot, so a two-dot one is compared
+            // against the tip of the base branch
+            let base_tip = Some(&pr.base.sha).filter(|_| !opts.range.is_three_dot());
+            let since = opts.since.as_ref().or(base.as_ref()).or(base_tip);
+            let key = format!(
+                "{} {}{} {}",
+                media_type,
+                since.map_or("-", String::as_str),
+                opts.range.separator(),
+                commit.as_deref().unwrap_or("-")
+            );
+            let cached = cache.get(pr_num, &pr.head.sha, &key);
+            let diff = match cached.filter(|_| !opts.no_cache) {
+                Some(diff) => diff,
+                None => {
+                    let diff = match (since, &commit) {
+                        (_, Some(sha)) => {
+                            self.commit_diff(head_owner, head_name, sha, media_type)
+                                .await?
+                        }
+                        (Some(since), None) => {
+                            self.compare(
+                                head_owner,
+                                head_name,
+                                since,
+                                &pr.head.sha,
+                                opts.range,
+                                media_type,
+                            )
+                            .await?
+                        }
+                        (None, None) => {
+                            let path = format!("/repos/{}/{}/pulls/{}", owner, repo, pr_num);
+                            self.get(self.url(&path)?, media_type)
+                                .await
+                                .context("Failed to fetch diff")?
+                        }
+                    };
+                    // The diff is there, the next fetch just downloads it again
+                    if let Err(e) = cache.put(pr_num, &pr.head.sha, &key, &diff) {
+                        color::warn(format!("{:#}", e));
                     }
+                    diff
+                }
+            };
+
+            let mut extra = Extra::default();
+            extra
+                .base(base)
+                .state(pr.state())
+                .range(opts.range)
+                .head_sha(pr.head.sha)
+                .draft(pr.draft)
+                .commit(commit)
+                .head_repo(fork)
+                .quote_prefix(self.config.quote_prefix().map(str::to_string))
+                .paths(opts.paths.clone())
+                .hunk_ranges(opts.hunk_ranges);
+            if opts.with_comments {
+                extra.threads(self.threads(owner, repo, pr_num).await?);
+            }
+            if opts.commit_log {
+                let commits = self.commits(owner, repo, pr_num).await?;
+                extra.commits(commits.into_iter().map(Commit::from).collect());
+            }
+            if opts.stdout {
+                return Review::print(&dir, diff, pr_num, extra, &mut std::io::stdout());
+            }
+            Review::new(&dir, diff, pr_num, extra, opts.force)
+        })
+    }
+
+    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &SubmitOptions) -> Result<()> {
+        let dir = self
+            .config
+            .review_dir(self.config.host_or(GITHUB_BASE_URL), owner, repo)?;
+        let review = self
+            .config
+            .review(&dir, pr_num)
+            .deny_duplicates(opts.deny_duplicates);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let comments = review.prepare_submission(
+            opts,
+            false,
+            || runtime.block_on(self.saved_replies()).cloned(),
+            // GH only takes uploads from its web UI
+            |_, _| {
+                bail!("GitHub has no API for uploading files, `@prr attach` only works on GitLab")
+            },
+        )?;
 
-                    review
-                        .mark_submitted()
-                        .context("Failed to update review metadata")?;
+        runtime.block_on(async {
+            let ReviewComments {
+                action: review_action,
+                comment: review_comment,
+                inline: inline_comments,
+                reactions,
+                amends,
+                replies,
+                commits,
+            } = comments;
+            if opts.individual && review_action != ReviewAction::Comment {
+                bail!(
+                    "Individual comments cannot approve or request changes, submit a review instead"
+                );
+            }
 
-                    Ok(())
+            let metadata = review.read_metadata()?;
+            if review_action == ReviewAction::Approve && metadata.draft {
+                color::warn("Approving a draft pull request, it cannot be merged until it is marked ready");
+            }
+            // Set if only a single commit of the pull request is under review
+            let commit = metadata.commit;
+
+            if opts.verify_lines && !inline_comments.is_empty() {
+                let diff = match &commit {
+                    Some(sha) => {
+                        let (owner, repo) = head_repo(metadata.head_repo.as_deref(), owner, repo);
+                        self.commit_diff(owner, repo, sha, MEDIA_DIFF).await?
+                    }
+                    None => {
+                        let url = self.url(&format!("/repos/{}/{}/pulls/{}", owner, repo, pr_num))?;
+                        self.get(url, MEDIA_DIFF).await.context("Failed to fetch diff")?
+                    }
+                };
+                for msg in changed_lines(&diff, &inline_comments)? {
+                    color::warn(msg);
+                }
+            }
+
+            // Only your own comments can be edited. Check before anything is posted, so a
+            // refused amend does not leave the rest of the review half submitted
+            for amend in &amends {
+                let path = format!("/repos/{}/{}/pulls/comments/{}", owner, repo, amend.comment_id);
+                let author = self.comment_author(&path).await?;
+                let login = self.login().await?;
+                if author != login {
+                    bail!(
+                        "Comment {} was written by {}, only your own comments can be amended",
+                        amend.comment_id,
+                        author
+                    );
+                }
+            }
+
+            review.begin_submission()?;
+            if opts.individual {
+                self.submit_individual(
+                    owner,
+                    repo,
+                    pr_num,
+                    &review,
+                    &review_comment,
+                    &inline_comments,
+                    opts.debug,
+                )
+                .await?;
+            } else if !review_comment.is_empty() || !inline_comments.is_empty() {
+                let mut bodies = review_bodies(&review_action, &review_comment, &inline_comments);
+                if let Some(sha) = &commit {
+                    for (body, _) in &mut bodies {
+                        body["commit_id"] = sha.as_str().into();
+                    }
+                }
+                if bodies.len() > 1 {
+                    eprintln!(
+                        "Review has {} inline comments, more than the {} GitHub allows at once. \
+                        Submitting them as {} reviews, with the review comment and action on the last one",
+                        inline_comments.len(),
+                        MAX_REVIEW_COMMENTS,
+                        bodies.len()
+                    );
+                }
+
+                let path = format!("/repos/{}/{}/pulls/{}/reviews", owner, repo, pr_num);
+                for (idx, (body, chunk)) in bodies.iter().enumerate() {
+                    if opts.debug {
+                        println!("{}", serde_json::to_string_pretty(body)?);
+                    }
+
+                    debug!("Submitting review with {} inline comments to {}", chunk.len(), path);
+                    if let Err(e) = self.post(path.clone(), body).await {
+                        let e = self.explain_rejected(owner, repo, pr_num, chunk, e).await;
+                        if idx > 0 {
+                            let submitted = bodies[..idx].iter().map(|(_, c)| c.len()).sum();
+                            return Err(e
+                                .context(format!(
+                                    "Failed to submit review {} of {}, the earlier ones were submitted",
+                                    idx + 1,
+                                    bodies.len()
+                                ))
+                                .context(SubmitFailure {
+                                    submitted,
+                                    failed: inline_comments.len() - submitted,
+                                }));
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+
+            for reaction in &reactions {
+                let path = format!(
+                    "/repos/{}/{}/pulls/comments/{}/reactions",
+                    owner, repo, reaction.comment_id
+                );
+                let body = json!({ "content": reaction_content(reaction.emoji) });
+                if opts.debug {
+                    println!("{}", serde_json::to_string_pretty(&body)?);
+                }
+
+                self.post(path, &body)
+                    .await
+                    .with_context(|| format!("Failed to react to comment {}", reaction.comment_id))?;
+            }
+
+            for amend in &amends {
+                let path = format!("/repos/{}/{}/pulls/comments/{}", owner, repo, amend.comment_id);
+                let body = json!({ "body": amend.body });
+                if opts.debug {
+                    println!("{}", serde_json::to_string_pretty(&body)?);
+                }
+
+                self.patch(path, &body)
+                    .await
+                    .with_context(|| format!("Failed to amend comment {}", amend.comment_id))?;
+            }
+
+            for reply in &replies {
+                let path = format!("/repos/{}/{}/pulls/{}/comments", owner, repo, pr_num);
+                let body = reply_body(reply);
+                if opts.debug {
+                    println!("{}", serde_json::to_string_pretty(&body)?);
+                }
+
+                self.post(path, &body)
+                    .await
+                    .with_context(|| format!("Failed to reply to comment {}", reply.comment_id))?;
+            }
+
+            for commit in &commits {
+                let path = format!("/repos/{}/{}/commits/{}/comments", owner, repo, commit.sha);
+                let body = json!({ "body": commit.comment });
+                if opts.debug {
+                    println!("{}", serde_json::to_string_pretty(&body)?);
+                }
+
+                self.post(path, &body)
+                    .await
+                    .with_context(|| format!("Failed to comment on commit {}", commit.sha))?;
+            }
+
+            review.finish_submission(opts)
+        })
+    }
+
+    fn diff_since(&self, owner: &str, repo: &str, pr_num: u64, since: &str) -> Result<String> {
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let pr = self.pull_request(owner, repo, pr_num).await?;
+            let fork = pr.fork(owner, repo);
+            let (owner, repo) = head_repo(fork.as_deref(), owner, repo);
+            self.compare(
+                owner,
+                repo,
+                since,
+                &pr.head.sha,
+                DiffRange::ThreeDot,
+                MEDIA_DIFF,
+            )
+            .await
+        })
+    }
+
+    fn current_user(&self) -> Result<String> {
+        tokio::runtime::Runtime::new()?.block_on(self.login())
+    }
+
+    fn current_user_name(&self) -> Result<Option<String>> {
+        tokio::runtime::Runtime::new()?.block_on(async { Ok(self.user().await?.name.clone()) })
+    }
+
+    fn last_review_commit(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Option<String>> {
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let login = self.login().await?;
+
+            // Reviews are listed oldest first
+            let mut last = None;
+            for page in 1.. {
+                let mut url = self.url(&format!(
+                    "/repos/{}/{}/pulls/{}/reviews",
+                    owner, repo, pr_num
+                ))?;
+                url.query_pairs_mut()
+                    .append_pair("per_page", &PER_PAGE.to_string())
+                    .append_pair("page", &page.to_string());
+                let text = self
+                    .get(url, MEDIA_JSON)
+                    .await
+                    .context("Failed to list reviews")?;
+                let reviews: Vec<PullRequestReview> =
+                    serde_json::from_str(&text).context("Failed to parse reviews")?;
+
+                let last_page = reviews.len() < PER_PAGE;
+                last = reviews
+                    .into_iter()
+                    .rev()
+                    .find(|r| r.user.login == login && r.state != "PENDING")
+                    .map(|r| r.commit_id)
+                    .or(last);
+                if last_page {
+                    break;
                 }
-                // GH is known to send unescaped control characters in JSON responses which
-                // serde will fail to parse (not that it should succeed)
-                Err(octocrab::Error::Json {
-                    source: _,
-                    backtrace: _,
-                }) => {
-                    eprintln!("Warning: GH response had invalid JSON");
-                    Ok(())
+            }
+
+            Ok(last)
+        })
+    }
+
+    fn open_prs_for_branch(
+        &self,
+        owner: &str,
+        repo: &str,
+        head_repo: &str,
+        branch: &str,
+    ) -> Result<Vec<u64>> {
+        // GH only takes the owner of the head, a user cannot have two forks of a repo
+        let (head_owner, _) = head_repo.split_once('/').unwrap_or((owner, repo));
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let mut url = self.url(&format!("/repos/{}/{}/pulls", owner, repo))?;
+            url.query_pairs_mut()
+                .append_pair("state", "open")
+                .append_pair("head", &format!("{}:{}", head_owner, branch));
+            let text = self
+                .get(url, MEDIA_JSON)
+                .await
+                .context("Failed to list pull requests")?;
+            let prs: Vec<PullRequest> =
+                serde_json::from_str(&text).context("Failed to parse pull requests")?;
+
+            Ok(prs.iter().map(|pr| pr.number).collect())
+        })
+    }
+
+    fn list_prs(&self, owner: &str, repo: &str, state: PrState) -> Result<Vec<PrSummary>> {
+        let state = match state {
+            PrState::Open => "open",
+            PrState::Closed => "closed",
+            PrState::All => "all",
+        };
+
+        tokio::runtime::Runtime::new()?.block_on(async {
+            let mut summaries = Vec::new();
+            for page in 1.. {
+                let mut url = self.url(&format!("/repos/{}/{}/pulls", owner, repo))?;
+                url.query_pairs_mut()
+                    .append_pair("state", state)
+                    .append_pair("per_page", &PER_PAGE.to_string())
+                    .append_pair("page", &page.to_string());
+                let text = self
+                    .get(url, MEDIA_JSON)
+                    .await
+                    .context("Failed to list pull requests")?;
+                let prs: Vec<PullRequest> =
+                    serde_json::from_str(&text).context("Failed to parse pull requests")?;
+
+                let last_page = prs.len() < PER_PAGE;
+                summaries.extend(prs.into_iter().map(|pr| PrSummary {
+                    number: pr.number,
+                    title: pr.title,
+                    author: pr.user.login,
+                    head_ref: pr.head.ref_name,
+                    base_ref: pr.base.ref_name,
+                    url: pr.html_url,
+                    draft: pr.draft,
+                }));
+                if last_page {
+                    break;
                 }
-                Err(e) => bail!("Error during POST: {}", e),
             }
+
+            Ok(summaries)
         })
     }
+
+    fn get_thread(&self, owner: &str, repo: &str, pr_num: u64, thread_id: &str) -> Result<Thread> {
+        tokio::runtime::Runtime::new()?
+            .block_on(async { self.thread(owner, repo, pr_num, thread_id).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::{Comment, ReviewParser};
+    use std::ffi::{OsStr, OsString};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread::{self, JoinHandle};
+
+    /// Held by tests while they change the environment of the test process
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Sets an environment variable until dropped, then restores its previous value
+    struct EnvVar {
+        name: &'static str,
+        old: Option<OsString>,
+    }
+
+    impl EnvVar {
+        fn set(name: &'static str, value: impl AsRef<OsStr>) -> Self {
+            let old = std::env::var_os(name);
+            std::env::set_var(name, value);
+            Self { name, old }
+        }
+    }
+
+    impl Drop for EnvVar {
+        fn drop(&mut self) {
+            match &self.old {
+                Some(old) => std::env::set_var(self.name, old),
+                None => std::env::remove_var(self.name),
+            }
+        }
+    }
+
+    /// Answers one request per entry of `responses`, in order. Returns the API URL to
+    /// configure and a handle that yields the request lines that came in
+    fn serve(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<String>>) {
+        let (url, server) = serve_bodies(responses);
+        let handle = thread::spawn(move || {
+            let requests = server.join().unwrap();
+            requests.into_iter().map(|(line, _)| line).collect()
+        });
+
+        (url, handle)
+    }
+
+    /// Like `serve()`, but yields the body of each request along with its request line
+    fn serve_bodies(responses: Vec<&'static str>) -> (String, JoinHandle<Vec<(String, String)>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v3", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                reader.read_line(&mut request).unwrap();
+                let mut len = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            len = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    response.len(),
+                    response
+                )
+                .unwrap();
+                requests.push((
+                    request.trim_end().to_string(),
+                    String::from_utf8(body).unwrap(),
+                ));
+            }
+            requests
+        });
+
+        (url, handle)
+    }
+
+    #[test]
+    fn submit_response_ok() {
+        assert!(check_submit_response(StatusCode::OK, r#"{"id": 1}"#).is_ok());
+    }
+
+    #[test]
+    fn submit_response_ok_invalid_json() {
+        assert!(check_submit_response(StatusCode::OK, "{\"body\": \"\u{1}\"}").is_ok());
+    }
+
+    #[test]
+    fn submit_response_error_invalid_json() {
+        let err =
+            check_submit_response(StatusCode::INTERNAL_SERVER_ERROR, "{\"message\": ").unwrap_err();
+        assert!(err.to_string().contains("500"));
+    }
+
+    #[test]
+    fn request_headers() {
+        use std::io::{BufRead, BufReader, Write};
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v3", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            for line in BufReader::new(stream.try_clone().unwrap()).lines() {
+                let line = line.unwrap();
+                if line.is_empty() {
+                    break;
+                }
+                request.push(line.to_lowercase());
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: 4\r\nConnection: close\r\n\r\ndiff"
+            )
+            .unwrap();
+            request
+        });
+
+        let config = format!("[prr]\ntoken = \"secret\"\nurl = \"{}\"", url);
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+        let url = gh.url("/repos/danobi/prr/pulls/1").unwrap();
+        let body = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(gh.get(url, MEDIA_DIFF))
+            .unwrap();
+        assert_eq!(body, "diff");
+
+        let request = server.join().unwrap();
+        assert_eq!(request[0], "get /api/v3/repos/danobi/prr/pulls/1 http/1.1");
+        assert!(request.contains(&"authorization: bearer secret".to_string()));
+        assert!(request.contains(&format!("accept: {}", MEDIA_DIFF)));
+        assert!(request.contains(&"user-agent: prr".to_string()));
+    }
+
+    #[test]
+    fn submit_response_permissions() {
+        let body = r#"{"message": "Resource not accessible by personal access token"}"#;
+        let err = check_submit_response(StatusCode::FORBIDDEN, body).unwrap_err();
+        assert!(err.to_string().contains("Pull requests: Read and write"));
+        assert!(format!("{:#}", err).contains("Resource not accessible"));
+
+        let err = check_submit_response(StatusCode::NOT_FOUND, r#"{"message": "Not Found"}"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("Pull requests: Read and write"));
+
+        let body = r#"{"message": "API rate limit exceeded for user ID 1."}"#;
+        let err = check_submit_response(StatusCode::FORBIDDEN, body).unwrap_err();
+        assert!(!err.to_string().contains("Pull requests"));
+
+        let body = r#"{"message": "Bad credentials"}"#;
+        let err = request_error("GET /user", StatusCode::UNAUTHORIZED, body);
+        assert!(err.to_string().contains("GitHub rejected the token"));
+        assert!(format!("{:#}", err).contains("Bad credentials"));
+    }
+
+    #[test]
+    fn review_body_unicode() {
+        let comment = InlineComment {
+            old_file: "docs/café.md".to_string(),
+            new_file: "docs/café.md".to_string(),
+            line: LineLocation::Right(3, 3),
+            start_line: None,
+            comment: "Très bien 🎉👍".to_string(),
+            line_text: String::new(),
+        };
+        let body = review_body(&ReviewAction::Comment, "Merci ☕", &[comment]);
+
+        // Round trip through the serialized form to make sure nothing gets mangled
+        let body: Value = serde_json::from_str(&body.to_string()).unwrap();
+        assert_eq!(body["body"], "Merci ☕");
+        assert_eq!(body["comments"][0]["path"], "docs/café.md");
+        assert_eq!(body["comments"][0]["body"], "Très bien 🎉👍");
+    }
+
+    #[test]
+    fn review_body_span_sides() {
+        let span = |start: LineLocation, end: LineLocation| InlineComment {
+            old_file: "a.rs".to_string(),
+            new_file: "a.rs".to_string(),
+            line: end,
+            start_line: Some(start),
+            comment: "Comment".to_string(),
+            line_text: String::new(),
+        };
+        let comments = [
+            span(LineLocation::Both(1, 1), LineLocation::Both(3, 3)),
+            span(LineLocation::Left(4, 3), LineLocation::Right(4, 4)),
+            span(LineLocation::Both(2, 2), LineLocation::Left(4, 3)),
+        ];
+        let body = review_body(&ReviewAction::Comment, "", &comments);
+        let sides = |i: usize| {
+            let c = &body["comments"][i];
+            (
+                c["start_line"].clone(),
+                c["start_side"].clone(),
+                c["line"].clone(),
+                c["side"].clone(),
+            )
+        };
+
+        assert_eq!(
+            sides(0),
+            (json!(1), json!("RIGHT"), json!(3), json!("RIGHT"))
+        );
+        assert_eq!(
+            sides(1),
+            (json!(4), json!("LEFT"), json!(4), json!("RIGHT"))
+        );
+        assert_eq!(sides(2), (json!(2), json!("LEFT"), json!(4), json!("LEFT")));
+    }
+
+    #[test]
+    fn review_body_context_span() {
+        // Old and new line numbers of the context lines differ. GH wants the new ones
+        let mut parser = ReviewParser::new();
+        let mut comments = Vec::new();
+        for line in include_str!("../../testdata/span_context").lines() {
+            if let Some(Comment::Inline(c)) = parser.parse_line(line).unwrap() {
+                comments.push(c);
+            }
+        }
+        if let Some(Comment::Inline(c)) = parser.finish() {
+            comments.push(c);
+        }
+        assert_eq!(comments[0].start_line, Some(LineLocation::Both(11, 13)));
+        assert_eq!(comments[0].line, LineLocation::Both(12, 14));
+        assert_eq!(comments[1].line, LineLocation::Both(14, 16));
+
+        let body = review_body(&ReviewAction::Comment, "", &comments);
+        let span = &body["comments"][0];
+        assert_eq!(span["start_line"], 13);
+        assert_eq!(span["start_side"], "RIGHT");
+        assert_eq!(span["line"], 14);
+        assert_eq!(span["side"], "RIGHT");
+        let single = &body["comments"][1];
+        assert_eq!(single["line"], 16);
+        assert_eq!(single["side"], "RIGHT");
+        assert!(single.get("start_line").is_none());
+        assert!(single.get("start_side").is_none());
+    }
+
+    #[test]
+    fn review_bodies_chunked() {
+        let comments: Vec<InlineComment> = (1..=250)
+            .map(|line| InlineComment {
+                old_file: "a.rs".to_string(),
+                new_file: "a.rs".to_string(),
+                line: LineLocation::Right(line, line),
+                start_line: None,
+                comment: format!("Comment {}", line),
+                line_text: String::new(),
+            })
+            .collect();
+
+        let bodies = review_bodies(&ReviewAction::Approve, "LGTM", &comments);
+        assert_eq!(bodies.len(), 2);
+        let (first, first_chunk) = &bodies[0];
+        assert_eq!(first_chunk.len(), 200);
+        assert_eq!(first["comments"].as_array().unwrap().len(), 200);
+        assert_eq!(first["event"], "COMMENT");
+        assert_eq!(first["body"], "");
+        let (last, last_chunk) = &bodies[1];
+        assert_eq!(last_chunk.len(), 50);
+        assert_eq!(last["comments"][0]["body"], "Comment 201");
+        assert_eq!(last["event"], "APPROVE");
+        assert_eq!(last["body"], "LGTM");
+
+        let bodies = review_bodies(&ReviewAction::Approve, "LGTM", &comments[..200]);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].0["event"], "APPROVE");
+
+        // A review comment without inline comments still needs a submission
+        let bodies = review_bodies(&ReviewAction::Comment, "Looks good", &[]);
+        assert_eq!(bodies.len(), 1);
+        assert_eq!(bodies[0].0["body"], "Looks good");
+    }
+
+    #[test]
+    fn review_thread() {
+        let node = json!({
+            "id": "PRRT_kwDOAbc",
+            "isResolved": true,
+            "path": "src/main.rs",
+            "line": 12,
+            "diffSide": "LEFT",
+            "comments": {
+                "nodes": [
+                    { "databaseId": 101, "author": { "login": "danobi" }, "body": "Why?" },
+                    { "databaseId": 102, "author": null, "body": "Because" }
+                ]
+            }
+        });
+        let thread: Thread = serde_json::from_value::<ReviewThread>(node).unwrap().into();
+
+        assert_eq!(thread.id, "PRRT_kwDOAbc");
+        assert_eq!((thread.old_line, thread.new_line), (Some(12), None));
+        assert!(thread.resolved);
+        assert_eq!(
+            thread.comments,
+            [
+                ThreadComment {
+                    id: 101,
+                    author: "danobi".to_string(),
+                    body: "Why?".to_string(),
+                },
+                ThreadComment {
+                    id: 102,
+                    author: "ghost".to_string(),
+                    body: "Because".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn fork_pr() {
+        let pr = r#"{
+            "number": 3,
+            "title": "Fix typo",
+            "user": { "login": "contributor" },
+            "html_url": "https://github.com/org/repo/pull/3",
+            "head": { "sha": "abc", "ref": "typo", "repo": { "full_name": "contributor/repo" } },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = "diff --git a/README b/README\n\
+            index 1111111..2222222 100644\n\
+            --- a/README\n\
+            +++ b/README\n\
+            @@ -1 +1 @@\n\
+            -teh\n\
+            +the\n";
+        let (url, server) = serve(vec![pr, diff, "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let opts = GetOptions {
+            since: Some("def".to_string()),
+            ..Default::default()
+        };
+        let review = gh.get_pr("org", "repo", 3, &opts).unwrap();
+        let metadata = review.read_metadata().unwrap();
+        assert_eq!(metadata.head_repo.as_deref(), Some("contributor/repo"));
+
+        let opts = SubmitOptions {
+            message: Some("Thanks".to_string()),
+            ..Default::default()
+        };
+        gh.submit_pr("org", "repo", 3, &opts).unwrap();
+
+        // The commits are compared in the fork, but the review goes to the target repo
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GET /api/v3/repos/org/repo/pulls/3 HTTP/1.1",
+                "GET /api/v3/repos/contributor/repo/compare/def...abc HTTP/1.1",
+                "POST /api/v3/repos/org/repo/pulls/3/reviews HTTP/1.1",
+            ]
+        );
+
+        // Same repo, or a fork that has been deleted
+        let mut pr: PullRequest = serde_json::from_str(pr).unwrap();
+        assert_eq!(pr.fork("Contributor", "Repo"), None);
+        pr.head.repo = None;
+        assert_eq!(pr.fork("org", "repo"), None);
+        assert_eq!(head_repo(None, "org", "repo"), ("org", "repo"));
+    }
+
+    #[test]
+    fn xdg_workdir() {
+        let pr = r#"{
+            "number": 5,
+            "title": "Add docs",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/5",
+            "head": { "sha": "abc", "ref": "docs" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve(vec![pr, diff, "{}"]);
+        let data_home = tempfile::tempdir().unwrap();
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let _data_home = EnvVar::set("XDG_DATA_HOME", data_home.path());
+        // Without a workdir, reviews go to $XDG_DATA_HOME/prr
+        let config = format!("[prr]\ntoken = \"\"\nurl = \"{}\"", url);
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 5, &GetOptions::default())
+            .unwrap();
+        assert!(review.path().starts_with(data_home.path().join("prr")));
+
+        // Commands that only read review files locate them the same way
+        let config: Config = toml::from_str(&config).unwrap();
+        let dir = config
+            .review_dir(config.host_or(GITHUB_BASE_URL), "danobi", "prr")
+            .unwrap();
+        assert_eq!(Review::new_existing(&dir, 5).path(), review.path());
+
+        let opts = SubmitOptions {
+            message: Some("Thanks".to_string()),
+            ..Default::default()
+        };
+        gh.submit_pr("danobi", "prr", 5, &opts).unwrap();
+        assert_eq!(server.join().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn base_override() {
+        let pr = r#"{
+            "number": 8,
+            "title": "Stacked change",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/8",
+            "head": { "sha": "abc", "ref": "top" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let base = r#"{ "sha": "def" }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve(vec![pr, base, diff]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let opts = GetOptions {
+            base: Some("bottom".to_string()),
+            ..Default::default()
+        };
+        let review = gh.get_pr("danobi", "prr", 8, &opts).unwrap();
+        let metadata = review.read_metadata().unwrap();
+        assert_eq!(metadata.base.as_deref(), Some("def"));
+        assert_eq!(metadata.head_sha.as_deref(), Some("abc"));
+
+        // The ref is resolved first, so the diff is against the commit it pointed at
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GET /api/v3/repos/danobi/prr/pulls/8 HTTP/1.1",
+                "GET /api/v3/repos/danobi/prr/commits/bottom HTTP/1.1",
+                "GET /api/v3/repos/danobi/prr/compare/def...abc HTTP/1.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn two_dot_diff() {
+        let pr = r#"{
+            "number": 9,
+            "title": "Long lived branch",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/9",
+            "head": { "sha": "abc", "ref": "feature" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve(vec![pr, diff, pr, diff]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        // Without anything to compare to, a two-dot diff is against the tip of the base
+        let opts = GetOptions {
+            range: DiffRange::TwoDot,
+            ..Default::default()
+        };
+        let review = gh.get_pr("danobi", "prr", 9, &opts).unwrap();
+        assert_eq!(review.read_metadata().unwrap().range, DiffRange::TwoDot);
+
+        let opts = GetOptions {
+            since: Some("bcd".to_string()),
+            force: true,
+            ..opts
+        };
+        gh.get_pr("danobi", "prr", 9, &opts).unwrap();
+        assert_eq!(
+            server.join().unwrap(),
+            [
+                "GET /api/v3/repos/danobi/prr/pulls/9 HTTP/1.1",
+                "GET /api/v3/repos/danobi/prr/compare/fed..abc HTTP/1.1",
+                "GET /api/v3/repos/danobi/prr/pulls/9 HTTP/1.1",
+                "GET /api/v3/repos/danobi/prr/compare/bcd..abc HTTP/1.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn diff_cache() {
+        let pr = r#"{
+            "number": 9,
+            "title": "Long lived branch",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/9",
+            "head": { "sha": "abc", "ref": "feature" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let pushed = r#"{
+            "number": 9,
+            "title": "Long lived branch",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/9",
+            "head": { "sha": "abd", "ref": "feature" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let responses = vec![
+            pr, diff, pr, pr, diff, pr, diff, pr, pr, pushed, diff, pushed,
+        ];
+        let (url, server) = serve(responses);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+        let force = GetOptions {
+            force: true,
+            ..Default::default()
+        };
+        let fetch = |opts: &GetOptions| {
+            gh.get_pr("danobi", "prr", 9, opts).unwrap();
+        };
+
+        // Only the pull request is looked up again, the diff of its head is cached
+        fetch(&force);
+        fetch(&force);
+        fetch(&GetOptions {
+            no_cache: true,
+            ..force.clone()
+        });
+        // A patch is cached apart from the diff, neither evicts the other
+        let patch = GetOptions {
+            patch: true,
+            ..force.clone()
+        };
+        fetch(&patch);
+        fetch(&force);
+        fetch(&patch);
+        // A push moves the head, which needs a new diff
+        fetch(&force);
+        fetch(&force);
+        assert_eq!(server.join().unwrap().len(), 12);
+
+        // A cache that cannot be written to does not fail the fetch
+        let (url, server) = serve(vec![pr, diff, pr, diff]);
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+        let dir = gh
+            .config
+            .review_dir(gh.config.host_or(GITHUB_BASE_URL), "danobi", "prr")
+            .unwrap();
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".cache"), "").unwrap();
+        gh.get_pr("danobi", "prr", 9, &force).unwrap();
+        gh.get_pr("danobi", "prr", 9, &force).unwrap();
+        assert_eq!(server.join().unwrap().len(), 4);
+
+        // Disabled in the config, every get fetches the diff
+        let (url, server) = serve(vec![pr, diff, pr, diff]);
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"\ncache_ttl_secs = 0",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+        gh.get_pr("danobi", "prr", 9, &force).unwrap();
+        gh.get_pr("danobi", "prr", 9, &force).unwrap();
+        assert_eq!(server.join().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn reply() {
+        let pr = r#"{
+            "number": 7,
+            "title": "Fix bug",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/7",
+            "head": { "sha": "abc", "ref": "fix" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve(vec![pr, diff, "{}", "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 7, &GetOptions::default())
+            .unwrap();
+        let mut contents = "@prr reply 77\nGood catch, fixed\n".to_string();
+        let mut commented = false;
+        for line in std::fs::read_to_string(review.path()).unwrap().lines() {
+            contents += line;
+            contents += "\n";
+            if !commented && line.starts_with("> +") && !line.starts_with("> +++") {
+                contents += "New comment\n";
+                commented = true;
+            }
+        }
+        std::fs::write(review.path(), contents).unwrap();
+        gh.submit_pr("danobi", "prr", 7, &SubmitOptions::default())
+            .unwrap();
+
+        // The reply is posted on its own, next to the review with the new comment
+        assert_eq!(
+            server.join().unwrap()[2..],
+            [
+                "POST /api/v3/repos/danobi/prr/pulls/7/reviews HTTP/1.1",
+                "POST /api/v3/repos/danobi/prr/pulls/7/comments HTTP/1.1",
+            ]
+        );
+
+        let body = reply_body(&Reply {
+            comment_id: 77,
+            body: "Good catch, fixed".to_string(),
+        });
+        assert_eq!(
+            body,
+            json!({ "body": "Good catch, fixed", "in_reply_to": 77 })
+        );
+    }
+
+    #[test]
+    fn saved_replies() {
+        let pr = r#"{
+            "number": 9,
+            "title": "Fix bug",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/9",
+            "head": { "sha": "abc", "ref": "fix" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let saved = r#"{ "data": { "viewer": { "savedReplies": { "nodes": [
+            { "title": "Thanks", "body": "Thanks for the fix!" }
+        ] } } } }"#;
+        let (url, server) = serve(vec![pr, diff, saved, "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 9, &GetOptions::default())
+            .unwrap();
+        let contents = std::fs::read_to_string(review.path()).unwrap();
+        std::fs::write(review.path(), format!("@prr saved Thanks\n{}", contents)).unwrap();
+        gh.submit_pr("danobi", "prr", 9, &SubmitOptions::default())
+            .unwrap();
+
+        // Looked up once and kept for the rest of the run
+        assert_eq!(
+            server.join().unwrap()[2..],
+            [
+                "POST /api/graphql HTTP/1.1",
+                "POST /api/v3/repos/danobi/prr/pulls/9/reviews HTTP/1.1",
+            ]
+        );
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let saved = rt.block_on(gh.saved_replies()).unwrap();
+        assert_eq!(saved["Thanks"], "Thanks for the fix!");
+    }
+
+    #[test]
+    fn submit_options() {
+        let pr = r#"{
+            "number": 4,
+            "title": "Support floats",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/4",
+            "head": { "sha": "abc", "ref": "floats" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/multiple_files");
+        let (url, server) = serve_bodies(vec![pr, diff, "{}", "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 4, &GetOptions::default())
+            .unwrap();
+        let contents = format!(
+            "@prr reject\n\nNeeds work\n\n{}",
+            include_str!("../../testdata/multiple_files")
+        );
+        std::fs::write(review.path(), contents).unwrap();
+
+        // Only the comment on one file, which must not reject the whole pull request
+        let only = SubmitOptions {
+            only: vec!["libbpf-cargo/src/test.rs".to_string()],
+            ..Default::default()
+        };
+        gh.submit_pr("danobi", "prr", 4, &only).unwrap();
+        assert!(review.read_metadata().unwrap().submitted.is_none());
+
+        // The rest, with the review comment from the command line, signed
+        let opts = SubmitOptions {
+            message: Some("From the command line".to_string()),
+            signature: Some("-- Reviewed with prr".to_string()),
+            ..Default::default()
+        };
+        gh.submit_pr("danobi", "prr", 4, &opts).unwrap();
+        assert!(review.read_metadata().unwrap().submitted.is_some());
+
+        let reviews: Vec<Value> = server.join().unwrap()[2..]
+            .iter()
+            .map(|(line, body)| {
+                assert_eq!(
+                    line,
+                    "POST /api/v3/repos/danobi/prr/pulls/4/reviews HTTP/1.1"
+                );
+                serde_json::from_str(body).unwrap()
+            })
+            .collect();
+        assert_eq!(reviews[0]["event"], "COMMENT");
+        assert_eq!(reviews[0]["body"], "");
+        assert_eq!(reviews[0]["comments"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            reviews[0]["comments"][0]["path"],
+            "libbpf-cargo/src/test.rs"
+        );
+        assert_eq!(reviews[1]["event"], "REQUEST_CHANGES");
+        assert_eq!(
+            reviews[1]["body"],
+            "From the command line\n\n-- Reviewed with prr"
+        );
+        assert_eq!(reviews[1]["comments"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn refused_before_submitting() {
+        let pr = r#"{
+            "number": 12,
+            "title": "Support floats",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/12",
+            "head": { "sha": "abc", "ref": "floats" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/multiple_files");
+        let comment = r#"{ "user": { "login": "someone" } }"#;
+        let user = r#"{ "login": "danobi" }"#;
+        let (url, server) = serve(vec![pr, diff, comment, user]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 12, &GetOptions::default())
+            .unwrap();
+        let contents = include_str!("../../testdata/multiple_files");
+        std::fs::write(review.path(), format!("@prr approve\n\n{}", contents)).unwrap();
+        let individual = SubmitOptions {
+            individual: true,
+            ..Default::default()
+        };
+        let err = gh.submit_pr("danobi", "prr", 12, &individual).unwrap_err();
+        assert!(err.to_string().contains("cannot approve"), "{}", err);
+        assert_eq!(review.read_metadata().unwrap().submitting, None);
+
+        let amend = format!("@prr amend 5\nBetter wording\n\n{}", contents);
+        std::fs::write(review.path(), amend).unwrap();
+        let err = gh
+            .submit_pr("danobi", "prr", 12, &SubmitOptions::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("written by someone"), "{}", err);
+        assert_eq!(review.read_metadata().unwrap().submitting, None);
+
+        // Nothing was posted, and the next submission has no reason to warn
+        assert_eq!(
+            server.join().unwrap()[2..],
+            [
+                "GET /api/v3/repos/danobi/prr/pulls/comments/5 HTTP/1.1",
+                "GET /api/v3/user HTTP/1.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn commit_comments() {
+        let pr = r#"{
+            "number": 6,
+            "title": "Fix kind mask",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/6",
+            "head": { "sha": "abc", "ref": "mask" },
+            "base": { "sha": "fed", "ref": "main" }
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve_bodies(vec![pr, diff, "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let review = gh
+            .get_pr("danobi", "prr", 6, &GetOptions::default())
+            .unwrap();
+        let contents = std::fs::read_to_string(review.path()).unwrap();
+        let contents = format!("@prr commit 1234567\nPlease split this up\n\n{}", contents);
+        std::fs::write(review.path(), contents).unwrap();
+        gh.submit_pr("danobi", "prr", 6, &SubmitOptions::default())
+            .unwrap();
+
+        // Commented on the commit alone, without a review of the pull request
+        let requests = server.join().unwrap();
+        assert_eq!(
+            requests[2].0,
+            "POST /api/v3/repos/danobi/prr/commits/1234567/comments HTTP/1.1"
+        );
+        let body: Value = serde_json::from_str(&requests[2].1).unwrap();
+        assert_eq!(body, json!({ "body": "Please split this up" }));
+    }
+
+    #[test]
+    fn closed_and_merged() {
+        let closed = r#"{
+            "number": 10,
+            "title": "Abandoned",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/10",
+            "head": { "sha": "abc", "ref": "abandoned" },
+            "base": { "sha": "fed", "ref": "main" },
+            "state": "closed"
+        }"#;
+        let merged = r#"{
+            "number": 11,
+            "title": "Landed",
+            "user": { "login": "danobi" },
+            "html_url": "https://github.com/danobi/prr/pull/11",
+            "head": { "sha": "abc", "ref": "landed" },
+            "base": { "sha": "fed", "ref": "main" },
+            "state": "closed",
+            "merged_at": "2022-08-01T00:00:00Z"
+        }"#;
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let (url, server) = serve(vec![closed, diff, merged, diff, "{}", "{}"]);
+        let workdir = tempfile::tempdir().unwrap();
+        let config = format!(
+            "[prr]\ntoken = \"\"\nurl = \"{}\"\nworkdir = \"{}\"",
+            url,
+            workdir.path().display()
+        );
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        for (pr_num, state) in [(10, PullState::Closed), (11, PullState::Merged)] {
+            let review = gh
+                .get_pr("danobi", "prr", pr_num, &GetOptions::default())
+                .unwrap();
+            assert_eq!(review.read_metadata().unwrap().state, state);
+        }
+        for pr_num in [10, 11] {
+            let opts = SubmitOptions {
+                message: Some("Thanks".to_string()),
+                ..Default::default()
+            };
+            let err = gh.submit_pr("danobi", "prr", pr_num, &opts).unwrap_err();
+            assert!(err.to_string().contains("Submit with --force"), "{}", err);
+
+            let opts = SubmitOptions {
+                force: true,
+                ..opts
+            };
+            gh.submit_pr("danobi", "prr", pr_num, &opts).unwrap();
+        }
+
+        // Nothing was sent without --force
+        assert_eq!(
+            server.join().unwrap()[4..],
+            [
+                "POST /api/v3/repos/danobi/prr/pulls/10/reviews HTTP/1.1",
+                "POST /api/v3/repos/danobi/prr/pulls/11/reviews HTTP/1.1",
+            ]
+        );
+    }
+
+    #[test]
+    fn base_url() {
+        let url = |s: &str| api_base_url(s).unwrap().to_string();
+
+        assert_eq!(url(GITHUB_BASE_URL), "https://api.github.com/");
+        assert_eq!(url("https://api.github.com/"), "https://api.github.com/");
+        assert_eq!(url("github.com"), "https://api.github.com/");
+        assert_eq!(
+            url("https://github.example.com/api/v3"),
+            "https://github.example.com/api/v3/"
+        );
+        assert_eq!(
+            url("github.example.com/api/v3/"),
+            "https://github.example.com/api/v3/"
+        );
+        assert_eq!(
+            url("github.example.com"),
+            "https://github.example.com/api/v3/"
+        );
+        assert_eq!(
+            url("https://github.example.com/"),
+            "https://github.example.com/api/v3/"
+        );
+        assert_eq!(
+            url("http://127.0.0.1:8080/api/v3"),
+            "http://127.0.0.1:8080/api/v3/"
+        );
+        assert_eq!(
+            url("github.example.com:8443"),
+            "https://github.example.com:8443/api/v3/"
+        );
+
+        let err = api_base_url("https://[github").unwrap_err();
+        assert!(err.to_string().contains("Failed to parse github base URL"));
+    }
+
+    #[test]
+    fn web_urls() {
+        let web_url = |url: Option<&str>| {
+            let mut config: Config = toml::from_str("[prr]\ntoken = \"\"").unwrap();
+            config.prr.url = url.map(str::to_string);
+            web_url(&config, "danobi", "prr", 24)
+        };
+
+        assert_eq!(web_url(None), "https://github.com/danobi/prr/pull/24");
+        assert_eq!(
+            web_url(Some("github.example.com")),
+            "https://github.example.com/danobi/prr/pull/24"
+        );
+        assert_eq!(
+            web_url(Some("https://github.example.com/api/v3")),
+            "https://github.example.com/danobi/prr/pull/24"
+        );
+    }
+
+    #[test]
+    fn open_prs_from_fork() {
+        let prs = r#"[{
+            "number": 3,
+            "title": "Fix typo",
+            "user": { "login": "contributor" },
+            "html_url": "https://github.com/danobi/prr/pull/3",
+            "head": { "sha": "abc", "ref": "typo", "repo": { "full_name": "contributor/prr" } },
+            "base": { "sha": "fed", "ref": "main" }
+        }]"#;
+        let (url, server) = serve(vec![prs, "[]"]);
+        let config = format!("[prr]\ntoken = \"\"\nurl = \"{}\"", url);
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        let prs = gh
+            .open_prs_for_branch("danobi", "prr", "contributor/prr", "typo")
+            .unwrap();
+        assert_eq!(prs, vec![3]);
+        let prs = gh
+            .open_prs_for_branch("danobi", "prr", "danobi/prr", "typo")
+            .unwrap();
+        assert!(prs.is_empty());
+
+        // The head is looked for in the fork, not in the target repo
+        let requests = server.join().unwrap();
+        assert!(
+            requests[0].contains("head=contributor%3Atypo"),
+            "{}",
+            requests[0]
+        );
+        assert!(
+            requests[1].contains("head=danobi%3Atypo"),
+            "{}",
+            requests[1]
+        );
+    }
+
+    #[test]
+    fn last_review_commit_pages() {
+        let review = |login: &str, commit: &str| {
+            format!(
+                r#"{{ "user": {{ "login": "{}" }}, "state": "COMMENTED", "commit_id": "{}" }}"#,
+                login, commit
+            )
+        };
+        let mut first: Vec<_> = (0..PER_PAGE).map(|_| review("someone", "aaa")).collect();
+        first[10] = review("danobi", "bbb");
+        let first: &'static str = format!("[{}]", first.join(",")).leak();
+        let second = format!("[{}]", review("danobi", "ccc"));
+        let user = r#"{ "login": "danobi" }"#;
+        let (url, server) = serve(vec![user, first, second.leak(), first, "[]"]);
+        let config = format!("[prr]\ntoken = \"\"\nurl = \"{}\"", url);
+        let gh = Github::new(toml::from_str(&config).unwrap()).unwrap();
+
+        // The newest review is on the second page
+        let sha = gh.last_review_commit("danobi", "prr", 12).unwrap();
+        assert_eq!(sha.as_deref(), Some("ccc"));
+
+        // An empty page after a full one does not lose the review before it
+        let sha = gh.last_review_commit("danobi", "prr", 12).unwrap();
+        assert_eq!(sha.as_deref(), Some("bbb"));
+        let requests = server.join().unwrap();
+        assert!(requests[1].contains("/reviews?per_page=100&page=1"));
+        assert!(requests[2].contains("page=2"));
+        assert!(requests[4].contains("page=2"));
+    }
 }
@@ -1,30 +1,81 @@
-use gitlab::api::Query;
+use std::cell::OnceCell;
+
+use bytes::Bytes;
+use gitlab::api::common::NameOrId;
+use gitlab::api::endpoint_prelude::*;
+use gitlab::api::{paged, ApiError, Pagination, Query, RestClient};
 use lazy_static::lazy_static;
 use regex::Regex;
 use sha1::{Digest, Sha1};
+use url::Url;
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use gitlab::api::projects::merge_requests::approvals::MergeRequestApprovals;
 use gitlab::api::projects::merge_requests::discussions::{
-    CreateMergeRequestDiscussion, Position, TextPosition,
+    CreateMergeRequestDiscussion, MergeRequestDiscussions, Position, TextPosition,
+};
+use gitlab::api::projects::merge_requests::notes::awards::CreateMergeRequestNoteAward;
+use gitlab::api::projects::merge_requests::notes::{CreateMergeRequestNote, EditMergeRequestNote};
+use gitlab::api::projects::merge_requests::{
+    ApproveMergeRequest, MergeRequestCommits, MergeRequestState, MergeRequests,
+    UnapproveMergeRequest,
 };
-use gitlab::api::projects::merge_requests::notes::CreateMergeRequestNote;
-use gitlab::api::projects::merge_requests::ApproveMergeRequest;
+use log::debug;
+use serde_derive::Deserialize;
 
-use crate::api::Api;
-use crate::parser::{LineLocation, ReviewAction};
-use crate::review::{Extra, Review};
+use crate::api::client::{ClientSettings, RequestError};
+use crate::api::{
+    Api, Commit, DiffRange, GetOptions, PrState, PrSummary, SubmitFailure, SubmitOptions, Thread,
+    ThreadComment,
+};
+use crate::color;
+use crate::parser::{Emoji, InlineComment, LineLocation, ReviewAction};
+use crate::review::{
+    changed_lines, comments_outside_diff, Extra, PullState, Review, ReviewComments,
+};
+use crate::spinner::Spinner;
 use crate::Config;
 
 // Use lazy static to ensure regex is only compiled once
 lazy_static! {
     // Regex for url input. Url looks something like:
     //
-    //      https://github.com/danobi/prr-test-repo/pull/6
+    //      https://gitlab.com/danobi/prr-test-repo/-/merge_requests/6
     //
-    pub static ref URL: Regex = Regex::new(r".*gitlab\.com/(?P<org>.+)/(?P<repo>.+)/-/merge_requests/(?P<pr_num>\d+)").unwrap();
+    // The project may be in nested groups, so everything up to the project name is the
+    // owner. Anything after the MR number, eg. `/diffs` or a `#note_1` fragment, is ignored.
+    pub static ref URL: Regex = Regex::new(r"^(https?://)?([\w\-]+\.)*gitlab\.com/(?P<org>.+?)/(?P<repo>[^/]+?)/-/merge_requests/(?P<pr_num>\d+)([/?#].*)?$").unwrap();
+}
+
+pub const GITLAB_BASE_URL: &str = "gitlab.com";
+
+/// Returns the root URL of the GL instance at the configured `prr.url`
+///
+/// `prr.url` is a host like `gitlab.example.com`, optionally with a scheme and a trailing
+/// slash. https is used unless the URL asks for plain `http://`.
+fn instance_url(url: &str) -> Result<Url> {
+    let (scheme, host) = match url.split_once("://") {
+        Some(("http", host)) => ("http", host),
+        Some(("https", host)) => ("https", host),
+        Some((scheme, _)) => bail!("Unsupported scheme '{}' in gitlab URL '{}'", scheme, url),
+        None => ("https", url),
+    };
+
+    Url::parse(&format!("{}://{}/", scheme, host.trim_end_matches('/')))
+        .with_context(|| format!("Failed to parse gitlab URL '{}'", url))
 }
 
-const GITLAB_BASE_URL: &str = "gitlab.com";
+/// Returns the web UI URL for a merge request
+pub fn web_url(config: &Config, owner: &str, repo: &str, pr_num: u64) -> String {
+    let host = config.host_or(GITLAB_BASE_URL);
+    let base = match instance_url(host) {
+        Ok(url) => url.to_string(),
+        // `Gitlab::new()` reports the invalid URL
+        Err(_) => format!("https://{}/", host),
+    };
+
+    format!("{}{}/{}/-/merge_requests/{}", base, owner, repo, pr_num)
+}
 
 // NOTE: Used for multi-line comments (not currently implemented).
 // https://docs.gitlab.com/15.2/ee/api/discussions.html#line-code
@@ -41,109 +92,847 @@ fn line_code(filename: &str, old_line: u64, new_line: u64) -> String {
     format!("{}_{}_{}", hash_str, old_line, new_line)
 }
 
+/// Builds a git style diff out of the per-file changes GitLab returns
+///
+/// The headers are laid out like git's, so review files look the same as on GitHub.
+fn synthesize_diff(changes: &[gitlab::RepoDiff]) -> String {
+    changes
+        .iter()
+        .map(|change| {
+            let mut diff = format!(
+                "diff --git a/{} b/{}\nindex {}..{} {}\n",
+                change.old_path,
+                change.new_path,
+                "aaaaaaa",
+                "bbbbbbb",
+                change.b_mode, // TODO a_mode?
+            );
+            // Like git, leave the file names out if there are no hunks, eg. for a rename
+            if !change.diff.is_empty() {
+                let old = if change.new_file {
+                    "/dev/null".to_string()
+                } else {
+                    format!("a/{}", change.old_path)
+                };
+                let new = if change.deleted_file {
+                    "/dev/null".to_string()
+                } else {
+                    format!("b/{}", change.new_path)
+                };
+                diff += &format!("--- {}\n+++ {}\n", old, new);
+            }
+            diff += &change.diff;
+            diff
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Compares two commits of a project. Not (yet) provided by the gitlab crate.
+/// https://docs.gitlab.com/ee/api/repositories.html#compare-branches-tags-or-commits
+struct Compare<'a> {
+    project: NameOrId<'a>,
+    from: &'a str,
+    to: &'a str,
+    range: DiffRange,
+}
+
+impl<'a> Endpoint for Compare<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/repository/compare", self.project).into()
+    }
+
+    fn parameters(&self) -> QueryParams<'_> {
+        let mut params = QueryParams::default();
+        params.push("from", self.from).push("to", self.to);
+        // GL calls two-dot comparisons straight
+        if !self.range.is_three_dot() {
+            params.push("straight", true);
+        }
+        params
+    }
+}
+
+/// Gets a single note of a merge request. Not (yet) provided by the gitlab crate.
+/// https://docs.gitlab.com/ee/api/notes.html#get-single-merge-request-note
+struct MergeRequestNote<'a> {
+    project: NameOrId<'a>,
+    merge_request: u64,
+    note: u64,
+}
+
+impl<'a> Endpoint for MergeRequestNote<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/merge_requests/{}/notes/{}",
+            self.project, self.merge_request, self.note
+        )
+        .into()
+    }
+}
+
+/// Separates the parts of an `Upload` body. Unlikely enough to show up in a file
+const UPLOAD_BOUNDARY: &str = "prr-upload-3f9c2a7e1b8d4c60";
+const UPLOAD_CONTENT_TYPE: &str = "multipart/form-data; boundary=prr-upload-3f9c2a7e1b8d4c60";
+
+/// Uploads a file to a project, to embed it in a comment. Not (yet) provided by the gitlab
+/// crate.
+/// https://docs.gitlab.com/ee/api/projects.html#upload-a-file
+struct Upload<'a> {
+    project: NameOrId<'a>,
+    file_name: &'a str,
+    contents: &'a [u8],
+}
+
+impl<'a> Endpoint for Upload<'a> {
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!("projects/{}/uploads", self.project).into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        // Quotes and line breaks would end the header early
+        let file_name = self.file_name.replace(['"', '\r', '\n'], "_");
+        let mut body = format!(
+            "--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            UPLOAD_BOUNDARY, file_name
+        )
+        .into_bytes();
+        body.extend_from_slice(self.contents);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", UPLOAD_BOUNDARY).as_bytes());
+
+        Ok(Some((UPLOAD_CONTENT_TYPE, body)))
+    }
+}
+
+/// Gets a single discussion of a merge request. Not (yet) provided by the gitlab crate.
+/// https://docs.gitlab.com/ee/api/discussions.html#get-single-merge-request-discussion-item
+struct MergeRequestDiscussion<'a> {
+    project: NameOrId<'a>,
+    merge_request: u64,
+    discussion: &'a str,
+}
+
+impl<'a> Endpoint for MergeRequestDiscussion<'a> {
+    fn method(&self) -> Method {
+        Method::GET
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/merge_requests/{}/discussions/{}",
+            self.project, self.merge_request, self.discussion
+        )
+        .into()
+    }
+}
+
+/// Adds a note to a discussion of a merge request. Not (yet) provided by the gitlab crate.
+/// https://docs.gitlab.com/ee/api/discussions.html#add-note-to-existing-merge-request-thread
+struct CreateMergeRequestDiscussionNote<'a> {
+    project: NameOrId<'a>,
+    merge_request: u64,
+    discussion: &'a str,
+    body: &'a str,
+}
+
+impl<'a> Endpoint for CreateMergeRequestDiscussionNote<'a> {
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/merge_requests/{}/discussions/{}/notes",
+            self.project, self.merge_request, self.discussion
+        )
+        .into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        let mut params = FormParams::default();
+        params.push("body", self.body);
+        params.into_body()
+    }
+}
+
+/// Starts a discussion on a commit. Not (yet) provided by the gitlab crate.
+/// https://docs.gitlab.com/ee/api/discussions.html#create-new-commit-thread
+struct CreateCommitDiscussion<'a> {
+    project: NameOrId<'a>,
+    commit: &'a str,
+    body: &'a str,
+}
+
+impl<'a> Endpoint for CreateCommitDiscussion<'a> {
+    fn method(&self) -> Method {
+        Method::POST
+    }
+
+    fn endpoint(&self) -> Cow<'static, str> {
+        format!(
+            "projects/{}/repository/commits/{}/discussions",
+            self.project, self.commit
+        )
+        .into()
+    }
+
+    fn body(&self) -> Result<Option<(&'static str, Vec<u8>)>, BodyError> {
+        let mut params = FormParams::default();
+        params.push("body", self.body);
+        params.into_body()
+    }
+}
+
+/// The parts of a commit of a GL merge request we care about
+#[derive(Deserialize)]
+struct MergeRequestCommit {
+    id: String,
+    author_name: String,
+    author_email: String,
+    message: String,
+}
+
+impl From<MergeRequestCommit> for Commit {
+    fn from(c: MergeRequestCommit) -> Self {
+        Commit {
+            sha: c.id,
+            author: format!("{} <{}>", c.author_name, c.author_email),
+            message: c.message,
+        }
+    }
+}
+
+/// The parts of a GL discussion we care about
+#[derive(Deserialize)]
+struct Discussion {
+    id: String,
+    notes: Vec<DiscussionNote>,
+}
+
+#[derive(Deserialize)]
+struct DiscussionNote {
+    id: u64,
+    author: NoteAuthor,
+    body: String,
+    #[serde(default)]
+    resolved: bool,
+    position: Option<NotePosition>,
+}
+
+#[derive(Deserialize)]
+struct NoteAuthor {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct NotePosition {
+    new_path: String,
+    old_line: Option<u64>,
+    new_line: Option<u64>,
+}
+
+impl Discussion {
+    /// Converts the discussion to a `Thread`, unless it is not on a line of the diff
+    fn into_thread(mut self) -> Option<Thread> {
+        // Only discussions started on the diff have a position
+        let position = self.notes.first_mut()?.position.take()?;
+        Some(Thread {
+            id: self.id,
+            file: position.new_path,
+            old_line: position.old_line,
+            new_line: position.new_line,
+            resolved: self.notes[0].resolved,
+            comments: self
+                .notes
+                .into_iter()
+                .map(|n| ThreadComment {
+                    id: n.id,
+                    author: n.author.username,
+                    body: n.body,
+                })
+                .collect(),
+        })
+    }
+}
+
+/// The parts of the authenticated GL user we care about
+#[derive(Deserialize)]
+struct CurrentUser {
+    username: String,
+    name: String,
+}
+
+/// Returns the award emoji name GitLab expects for `emoji`
+fn award_name(emoji: Emoji) -> &'static str {
+    match emoji {
+        Emoji::ThumbsUp => "thumbsup",
+        Emoji::ThumbsDown => "thumbsdown",
+        Emoji::Laugh => "laughing",
+        Emoji::Confused => "confused",
+        Emoji::Heart => "heart",
+        Emoji::Hooray => "tada",
+        Emoji::Rocket => "rocket",
+        Emoji::Eyes => "eyes",
+    }
+}
+
+/// Returns the note that carries the review comment
+///
+/// GL has no reviews to attach it to, so it becomes a general note on the merge
+/// request, outside of any discussion on the diff.
+fn review_note<'a>(
+    project: &'a str,
+    pr_num: u64,
+    comment: &'a str,
+) -> Result<CreateMergeRequestNote<'a>> {
+    CreateMergeRequestNote::builder()
+        .project(project)
+        .merge_request(pr_num)
+        .body(comment)
+        .build()
+        .context("Failed to build review comment note")
+}
+
+/// Describes which of the `diff_refs` shas of merge request `pr_num` are missing, if any
+///
+/// Inline comments are positioned by all three, but very old merge requests and ones whose
+/// source branch was deleted may lack some.
+fn missing_shas(
+    pr_num: u64,
+    base_sha: Option<&str>,
+    head_sha: Option<&str>,
+    start_sha: Option<&str>,
+) -> Option<String> {
+    let shas = [
+        ("base_sha", base_sha),
+        ("head_sha", head_sha),
+        ("start_sha", start_sha),
+    ];
+    let missing: Vec<&str> = shas
+        .into_iter()
+        .filter(|(_, sha)| sha.is_none())
+        .map(|(name, _)| name)
+        .collect();
+    if missing.is_empty() {
+        return None;
+    }
+
+    Some(format!(
+        "!{} has no {} in its diff_refs, it may be too old or its source branch deleted. \
+         prr can't place inline comments on it",
+        pr_num,
+        missing.join(" or ")
+    ))
+}
+
+/// GitLab API client
+///
+/// Used instead of `gitlab::Gitlab` so that requests go through a reqwest client we
+/// configured ourselves.
+struct GitlabClient {
+    settings: ClientSettings,
+    client: reqwest::blocking::Client,
+    rest_url: Url,
+    token: String,
+}
+
+impl RestClient for GitlabClient {
+    type Error = RequestError;
+
+    fn rest_endpoint(&self, endpoint: &str) -> Result<Url, ApiError<Self::Error>> {
+        debug!("REST api call {}", endpoint);
+        Ok(self.rest_url.join(endpoint)?)
+    }
+}
+
+impl gitlab::api::Client for GitlabClient {
+    fn rest(
+        &self,
+        request: http::request::Builder,
+        body: Vec<u8>,
+    ) -> Result<http::Response<Bytes>, ApiError<Self::Error>> {
+        let call = || -> Result<_, RequestError> {
+            let http_request = request.header("PRIVATE-TOKEN", &self.token).body(body)?;
+            let request = http_request
+                .try_into()
+                .map_err(|e| self.settings.error(e))?;
+            let rsp = self
+                .client
+                .execute(request)
+                .map_err(|e| self.settings.error(e))?;
+
+            let mut http_rsp = http::Response::builder()
+                .status(rsp.status())
+                .version(rsp.version());
+            let headers = http_rsp.headers_mut().unwrap();
+            for (key, value) in rsp.headers() {
+                headers.insert(key, value.clone());
+            }
+            let body = rsp.bytes().map_err(|e| self.settings.error(e))?;
+            Ok(http_rsp.body(body)?)
+        };
+        call().map_err(ApiError::client)
+    }
+}
+
+/// What submitting a review does to the approval of the merge request
+#[derive(Debug, PartialEq)]
+enum Approval {
+    /// Leave the approval as it is
+    Keep,
+    /// Approve the merge request
+    Approve,
+    /// Take back our approval, which is the closest GL has to requesting changes
+    Revoke,
+}
+
+/// Returns what submitting a review with `action` does to the approval
+fn approval(action: &ReviewAction) -> Approval {
+    match action {
+        ReviewAction::Approve => Approval::Approve,
+        ReviewAction::RequestChanges => Approval::Revoke,
+        ReviewAction::Comment => Approval::Keep,
+    }
+}
+
 pub struct Gitlab {
     config: Config,
-    client: gitlab::Gitlab,
+    client: GitlabClient,
+    /// The authenticated user. See `user()`
+    user: OnceCell<CurrentUser>,
 }
 
 impl Gitlab {
     pub fn new(config: Config) -> Result<Self> {
-        let client = gitlab::Gitlab::new(
-            config.prr.url.as_deref().unwrap_or(GITLAB_BASE_URL),
-            &config.prr.token,
-        )?;
-        Ok(Self { config, client })
+        let host = config.prr.url.as_deref().unwrap_or(GITLAB_BASE_URL);
+        let rest_url = instance_url(host)?.join("api/v4/")?;
+        let settings = ClientSettings::new(&config)?;
+        let client = GitlabClient {
+            client: settings
+                .blocking_client()
+                .context("Failed to create GitLab client")?,
+            settings,
+            rest_url,
+            token: config.prr.token.clone(),
+        };
+
+        Ok(Self {
+            config,
+            client,
+            user: OnceCell::new(),
+        })
+    }
+
+    /// Takes back our approval of a merge request, if we had given one
+    ///
+    /// GL answers a 404 when unapproving a merge request we never approved, so ask first.
+    fn revoke_approval(&self, project: &str, pr_num: u64) -> Result<()> {
+        #[derive(Deserialize)]
+        struct User {
+            username: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Approver {
+            user: User,
+        }
+
+        #[derive(Deserialize)]
+        struct Approvals {
+            approved_by: Vec<Approver>,
+        }
+
+        let endpoint = MergeRequestApprovals::builder()
+            .project(project)
+            .merge_request(pr_num)
+            .build()?;
+        let approvals: Approvals = endpoint.query(&self.client)?;
+        let username = self.current_user()?;
+        if !approvals
+            .approved_by
+            .iter()
+            .any(|a| a.user.username == username)
+        {
+            debug!(
+                "{}!{} is not approved by {}, nothing to revoke",
+                project, pr_num, username
+            );
+            return Ok(());
+        }
+
+        let unapprove = UnapproveMergeRequest::builder()
+            .project(project)
+            .merge_request(pr_num)
+            .build()?;
+        gitlab::api::ignore(unapprove)
+            .query(&self.client)
+            .context("Failed to unapprove merge request")
+    }
+
+    /// Returns the authenticated user, looking it up on first use
+    fn user(&self) -> Result<&CurrentUser> {
+        if let Some(user) = self.user.get() {
+            return Ok(user);
+        }
+
+        let endpoint = gitlab::api::users::CurrentUser::builder().build()?;
+        let user: CurrentUser = endpoint.query(&self.client).map_err(|e| {
+            let hint = match &e {
+                ApiError::Gitlab { msg } if msg.starts_with("401") => Some(
+                    "GitLab rejected the token. Check `prr.token` and `prr.url`, the token may be \
+                    mistyped, expired or revoked",
+                ),
+                ApiError::GitlabObject { obj } | ApiError::GitlabUnrecognized { obj }
+                    if obj["error"] == "insufficient_scope" =>
+                {
+                    Some("The token is missing the `api` scope prr needs")
+                }
+                _ => None,
+            };
+            let err = anyhow::Error::new(e).context("Failed to fetch authenticated user");
+            match hint {
+                Some(hint) => err.context(hint),
+                None => err,
+            }
+        })?;
+        debug!("Authenticated as {}", user.username);
+
+        Ok(self.user.get_or_init(|| user))
+    }
+
+    /// Returns the username of the author of note `note_id`
+    fn note_author(&self, project: &str, pr_num: u64, note_id: u64) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Author {
+            username: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Note {
+            author: Author,
+        }
+
+        let endpoint = MergeRequestNote {
+            project: project.into(),
+            merge_request: pr_num,
+            note: note_id,
+        };
+        let note: Note = endpoint
+            .query(&self.client)
+            .with_context(|| format!("Failed to fetch note {}", note_id))?;
+
+        Ok(note.author.username)
+    }
+
+    /// Uploads a file to `project` and returns the markdown that embeds it
+    fn upload(&self, project: &str, file_name: &str, contents: &[u8]) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Uploaded {
+            markdown: String,
+        }
+
+        let endpoint = Upload {
+            project: project.into(),
+            file_name,
+            contents,
+        };
+        let uploaded: Uploaded = endpoint.query(&self.client)?;
+
+        Ok(uploaded.markdown)
+    }
+
+    /// Returns the whole diff of a merge request
+    fn diff(&self, project: &str, mr: u64) -> Result<String> {
+        let endpoint = gitlab::api::projects::merge_requests::MergeRequestChanges::builder()
+            .project(project)
+            .merge_request(mr)
+            .build()?;
+        let mr: gitlab::MergeRequestChanges = endpoint.query(&self.client)?;
+        Ok(synthesize_diff(&mr.changes))
+    }
+
+    /// Adds which inline comments are outside the merge request diff to a failed submission
+    fn explain_rejected(
+        &self,
+        project: &str,
+        mr: u64,
+        inline_comments: &[InlineComment],
+        err: anyhow::Error,
+    ) -> anyhow::Error {
+        let outside = || comments_outside_diff(&self.diff(project, mr)?, inline_comments);
+
+        match outside() {
+            Ok(outside) if !outside.is_empty() => err.context(outside.join("\n")),
+            Ok(_) => err,
+            Err(e) => {
+                debug!("Failed to check comments against the diff: {:#}", e);
+                err
+            }
+        }
+    }
+
+    /// Returns all discussions of a merge request
+    fn discussions(&self, project: &str, mr: u64) -> Result<Vec<Discussion>> {
+        let endpoint = MergeRequestDiscussions::builder()
+            .project(project)
+            .merge_request(mr)
+            .build()?;
+
+        paged(endpoint, Pagination::All)
+            .query(&self.client)
+            .context("Failed to fetch discussions")
+    }
+
+    /// Returns the discussions of a merge request that are on a line of the diff
+    fn threads(&self, project: &str, mr: u64) -> Result<Vec<Thread>> {
+        Ok(self
+            .discussions(project, mr)?
+            .into_iter()
+            .filter_map(Discussion::into_thread)
+            .collect())
+    }
+
+    /// Returns the commits of a merge request, oldest first
+    fn commits(&self, project: &str, mr: u64) -> Result<Vec<Commit>> {
+        let endpoint = MergeRequestCommits::builder()
+            .project(project)
+            .merge_request(mr)
+            .build()?;
+        let commits: Vec<MergeRequestCommit> = paged(endpoint, Pagination::All)
+            .query(&self.client)
+            .context("Failed to fetch commits")?;
+
+        // GL lists the newest first
+        Ok(commits.into_iter().rev().map(Commit::from).collect())
+    }
+
+    /// Returns the SHA of the commit `git_ref` (a branch, tag or SHA) points at
+    fn resolve_ref(&self, project: &str, git_ref: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct RefCommit {
+            id: String,
+        }
+
+        let endpoint = gitlab::api::projects::repository::commits::Commit::builder()
+            .project(project)
+            .commit(git_ref)
+            .build()?;
+        let commit: RefCommit = endpoint
+            .query(&self.client)
+            .with_context(|| format!("Base {} does not exist in {}", git_ref, project))?;
+
+        Ok(commit.id)
+    }
+
+    /// Returns the diff between two commits of `project`
+    fn compare(&self, project: &str, from: &str, to: &str, range: DiffRange) -> Result<String> {
+        #[derive(Deserialize)]
+        struct Comparison {
+            diffs: Vec<gitlab::RepoDiff>,
+        }
+
+        debug!(
+            "Comparing {}{}{} in {}",
+            from,
+            range.separator(),
+            to,
+            project
+        );
+        let compare = Compare {
+            project: project.into(),
+            from,
+            to,
+            range,
+        };
+        let comparison: Comparison = compare
+            .query(&self.client)
+            .with_context(|| format!("Failed to compare {} against {}", from, to))?;
+
+        Ok(synthesize_diff(&comparison.diffs))
     }
 }
 
 impl Api for Gitlab {
-    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, force: bool) -> Result<Review> {
+    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &GetOptions) -> Result<Review> {
+        if opts.patch {
+            bail!("Fetching merge requests in patch format is not supported on GitLab");
+        }
+        if opts.commit.is_some() {
+            bail!("Reviewing a single commit is not supported on GitLab");
+        }
+
+        debug!("Fetching changes for {}/{}!{}", owner, repo, pr_num);
+        let _spinner = opts
+            .progress
+            .then(|| Spinner::start("Fetching merge request"));
+        let project = format!("{}/{}", owner, repo);
         let endpoint = gitlab::api::projects::merge_requests::MergeRequestChanges::builder()
-            .project(format!("{}/{}", owner, repo))
+            .project(project.as_str())
             .merge_request(pr_num)
             .build()?;
         let mr: gitlab::MergeRequestChanges = endpoint.query(&self.client)?;
-        let diff = mr
-            .changes
-            .iter()
-            .map(|change| {
-                format!(
-                    "diff --git a/{} b/{}\nindex {}..{} {}\n{}",
-                    change.old_path,
-                    change.new_path,
-                    "aaaaaaa",
-                    "bbbbbbb",
-                    change.b_mode, // TODO a_mode?
-                    change.diff,
-                )
-            })
-            .collect::<Vec<_>>()
-            .join("\n");
-        let diff_refs = mr.diff_refs.ok_or_else(|| {
-            anyhow!("Missing diff_refs in merge request. Won't be able to submit review.")
-        })?;
-        let base_sha = diff_refs
-            .base_sha
-            .ok_or_else(|| anyhow!("Missing base_sha"))?
-            .value()
-            .to_string();
-        let head_sha = diff_refs
-            .head_sha
-            .ok_or_else(|| anyhow!("Missing head_sha"))?
-            .value()
-            .to_string();
-        let start_sha = diff_refs
-            .start_sha
-            .ok_or_else(|| anyhow!("Missing start_sha"))?
-            .value()
-            .to_string();
+        let sha = |sha: Option<&gitlab::ObjectId>| sha.map(|s| s.value().to_string());
+        let (base_sha, head_sha, start_sha) = match &mr.diff_refs {
+            Some(refs) => (
+                sha(refs.base_sha.as_ref()),
+                sha(refs.head_sha.as_ref()),
+                sha(refs.start_sha.as_ref()),
+            ),
+            None => (None, None, None),
+        };
+        // Without them the merge request can still get a review comment
+        if let Some(msg) = missing_shas(
+            pr_num,
+            base_sha.as_deref(),
+            head_sha.as_deref(),
+            start_sha.as_deref(),
+        ) {
+            color::warn(format!("{}, only a review comment can be submitted", msg));
+        }
+        // The diff_refs stay as they are, comments are anchored to them
+        let base = match &opts.base {
+            Some(b) => Some(self.resolve_ref(&project, b)?),
+            None => None,
+        };
+        let dir = self
+            .config
+            .review_dir(self.config.host_or(GITLAB_BASE_URL), owner, repo)?;
+        // The changes of the merge request come with it, so only comparisons are cached
+        let cache = self.config.diff_cache(&dir);
+        // The changes of the merge request are three-dot, so a two-dot diff is compared
+        // against the tip of the target branch the changes were computed with
+        let since = opts
+            .since
+            .as_ref()
+            .or(base.as_ref())
+            .or(start_sha.as_ref().filter(|_| !opts.range.is_three_dot()));
+        if since.is_none() && !opts.range.is_three_dot() {
+            bail!(
+                "!{} has no start_sha in its diff_refs to compare against",
+                pr_num
+            );
+        }
+        let diff = match since {
+            Some(since) => {
+                let head_sha = head_sha.as_deref().ok_or_else(|| {
+                    anyhow!(
+                        "!{} has no head_sha in its diff_refs to compare against",
+                        pr_num
+                    )
+                })?;
+                let key = format!("{}{}", since, opts.range.separator());
+                match cache.get(pr_num, head_sha, &key).filter(|_| !opts.no_cache) {
+                    Some(diff) => diff,
+                    None => {
+                        let diff = self.compare(&project, since, head_sha, opts.range)?;
+                        // The diff is there, the next fetch just downloads it again
+                        if let Err(e) = cache.put(pr_num, head_sha, &key, &diff) {
+                            color::warn(format!("{:#}", e));
+                        }
+                        diff
+                    }
+                }
+            }
+            None => synthesize_diff(&mr.changes),
+        };
         let mut extra = Extra::default();
         extra
             .base_sha(base_sha)
             .head_sha(head_sha)
-            .start_sha(start_sha);
-        Review::new(
-            &self.config.workdir(self.config.host_or(GITLAB_BASE_URL))?,
-            diff,
-            owner,
-            repo,
-            pr_num,
-            extra,
-            force,
-        )
+            .start_sha(start_sha)
+            .base(base)
+            .draft(mr.work_in_progress)
+            .range(opts.range)
+            .state(match mr.state {
+                gitlab::MergeRequestState::Closed => PullState::Closed,
+                gitlab::MergeRequestState::Merged => PullState::Merged,
+                _ => PullState::Open,
+            })
+            .quote_prefix(self.config.quote_prefix().map(str::to_string))
+            .paths(opts.paths.clone())
+            .hunk_ranges(opts.hunk_ranges);
+        if opts.with_comments {
+            extra.threads(self.threads(&project, pr_num)?);
+        }
+        if opts.commit_log {
+            extra.commits(self.commits(&project, pr_num)?);
+        }
+        if opts.stdout {
+            return Review::print(&dir, diff, pr_num, extra, &mut std::io::stdout());
+        }
+        Review::new(&dir, diff, pr_num, extra, opts.force)
     }
 
-    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, debug: bool) -> Result<()> {
-        let review = Review::new_existing(
-            &self.config.workdir(self.config.host_or(GITLAB_BASE_URL))?,
-            owner,
-            repo,
-            pr_num,
-        );
-        let (review_action, review_comment, inline_comments) = review.comments()?;
-        let metadata = review.read_metadata()?;
+    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &SubmitOptions) -> Result<()> {
+        let dir = self
+            .config
+            .review_dir(self.config.host_or(GITLAB_BASE_URL), owner, repo)?;
+        let review = self
+            .config
+            .review(&dir, pr_num)
+            .deny_duplicates(opts.deny_duplicates);
         let project = format!("{}/{}", owner, repo);
+        // Unlike GH, GL can approve without leaving a comment
+        let comments = review.prepare_submission(
+            opts,
+            true,
+            || {
+                bail!(
+                    "`@prr saved` only works on GitHub, use `@prr snip` with a local snippet instead"
+                )
+            },
+            |name, contents| self.upload(&project, name, &contents),
+        )?;
+        let ReviewComments {
+            action: review_action,
+            comment: review_comment,
+            inline: inline_comments,
+            reactions,
+            amends,
+            replies,
+            commits,
+        } = comments;
+        let metadata = review.read_metadata()?;
 
-        let base_sha = metadata
-            .base_sha
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing base_sha in metadata"))?;
-        let head_sha = metadata
-            .head_sha
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing head_sha in metadata"))?;
-        let start_sha = metadata
-            .start_sha
-            .as_ref()
-            .ok_or_else(|| anyhow!("Missing start_sha in metadata"))?;
+        let base_sha = metadata.base_sha.as_deref();
+        let head_sha = metadata.head_sha.as_deref();
+        let start_sha = metadata.start_sha.as_deref();
+        if let Some(msg) = missing_shas(pr_num, base_sha, head_sha, start_sha) {
+            if !inline_comments.is_empty() {
+                bail!(
+                    "{}. Remove the inline comments to submit the rest of the review",
+                    msg
+                );
+            }
+        }
 
-        if review_comment.is_empty() && inline_comments.is_empty() {
-            bail!("No review comments");
+        let approval = approval(&review_action);
+        if approval == Approval::Approve && metadata.draft {
+            color::warn(
+                "Approving a draft merge request, it cannot be merged until it is marked ready",
+            );
         }
 
-        if review_action == ReviewAction::RequestChanges {
-            // TODO: Maybe this doesn't warrant a bail.
-            bail!("GitLab doesn't support requesting changes");
+        if opts.verify_lines && !inline_comments.is_empty() {
+            let diff = self
+                .diff(&project, pr_num)
+                .context("Failed to fetch diff")?;
+            for msg in changed_lines(&diff, &inline_comments)? {
+                color::warn(msg);
+            }
         }
 
         // Make each comment a CreateMergeRequestDiscussion
@@ -151,11 +940,11 @@ impl Api for Gitlab {
             .iter()
             .map(|c| {
                 let mut position = Position::builder();
-                // These are all required by the API.
+                // These are all required by the API, and checked for above.
                 position
-                    .base_sha(base_sha)
-                    .head_sha(head_sha)
-                    .start_sha(start_sha);
+                    .base_sha(base_sha.unwrap_or_default())
+                    .head_sha(head_sha.unwrap_or_default())
+                    .start_sha(start_sha.unwrap_or_default());
 
                 let mut text_position = TextPosition::builder();
                 // Both of these are required by the API, even if they're the same.
@@ -262,27 +1051,461 @@ impl Api for Gitlab {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        for discussion in discussions {
-            gitlab::api::ignore(discussion).query(&self.client)?;
+        if opts.debug {
+            println!("{:#?}", discussions);
+        }
+
+        // Only your own notes can be edited. Check before anything is posted, so a refused
+        // amend does not leave the rest of the review half submitted
+        if !amends.is_empty() {
+            let username = self.current_user()?;
+            for amend in &amends {
+                let author = self.note_author(&project, pr_num, amend.comment_id)?;
+                if author != username {
+                    bail!(
+                        "Note {} was written by {}, only your own notes can be amended",
+                        amend.comment_id,
+                        author
+                    );
+                }
+            }
+        }
+
+        review.begin_submission()?;
+        debug!(
+            "Creating {} discussions on {}!{}",
+            discussions.len(),
+            project,
+            pr_num
+        );
+        // Keep going when a discussion is rejected, so one bad line does not lose the rest
+        let mut failed = 0;
+        for (discussion, c) in discussions.into_iter().zip(&inline_comments) {
+            if let Err(e) = gitlab::api::ignore(discussion).query(&self.client) {
+                let e = self.explain_rejected(&project, pr_num, std::slice::from_ref(c), e.into());
+                color::warn(format!(
+                    "Failed to submit comment on {}: {:#}",
+                    c.new_file, e
+                ));
+                failed += 1;
+            }
+        }
+        if failed > 0 {
+            return Err(SubmitFailure {
+                submitted: inline_comments.len() - failed,
+                failed,
+            }
+            .into());
         }
 
         if !review_comment.is_empty() {
-            let note = CreateMergeRequestNote::builder()
+            let note = review_note(&project, pr_num, &review_comment)?;
+            gitlab::api::ignore(note).query(&self.client)?;
+        }
+
+        match approval {
+            Approval::Keep => (),
+            Approval::Approve => {
+                let approve = ApproveMergeRequest::builder()
+                    .project(project.as_str())
+                    .merge_request(pr_num)
+                    .build()?;
+                gitlab::api::ignore(approve)
+                    .query(&self.client)
+                    .context("Failed to approve merge request")?;
+            }
+            Approval::Revoke => self.revoke_approval(&project, pr_num)?,
+        }
+
+        for reaction in &reactions {
+            let award = CreateMergeRequestNoteAward::builder()
                 .project(project.as_str())
                 .merge_request(pr_num)
-                .body(review_comment)
+                .note(reaction.comment_id)
+                .name(award_name(reaction.emoji))
                 .build()?;
-            gitlab::api::ignore(note).query(&self.client)?;
+            gitlab::api::ignore(award)
+                .query(&self.client)
+                .with_context(|| format!("Failed to react to note {}", reaction.comment_id))?;
         }
 
-        if review_action == ReviewAction::Approve {
-            let approve = ApproveMergeRequest::builder()
+        for amend in &amends {
+            let edit = EditMergeRequestNote::builder()
                 .project(project.as_str())
                 .merge_request(pr_num)
+                .note(amend.comment_id)
+                .body(amend.body.as_str())
                 .build()?;
-            gitlab::api::ignore(approve).query(&self.client)?;
+            if opts.debug {
+                println!("{:#?}", edit);
+            }
+            gitlab::api::ignore(edit)
+                .query(&self.client)
+                .with_context(|| format!("Failed to amend note {}", amend.comment_id))?;
+        }
+
+        if !replies.is_empty() {
+            // Notes are replied to through the discussion they are in
+            let discussions = self.discussions(&project, pr_num)?;
+            for reply in &replies {
+                let discussion = discussions
+                    .iter()
+                    .find(|d| d.notes.iter().any(|n| n.id == reply.comment_id))
+                    .ok_or_else(|| {
+                        anyhow!("Note {} is not part of !{}", reply.comment_id, pr_num)
+                    })?;
+                let note = CreateMergeRequestDiscussionNote {
+                    project: project.as_str().into(),
+                    merge_request: pr_num,
+                    discussion: &discussion.id,
+                    body: &reply.body,
+                };
+                gitlab::api::ignore(note)
+                    .query(&self.client)
+                    .with_context(|| format!("Failed to reply to note {}", reply.comment_id))?;
+            }
+        }
+
+        for commit in &commits {
+            let discussion = CreateCommitDiscussion {
+                project: project.as_str().into(),
+                commit: &commit.sha,
+                body: &commit.comment,
+            };
+            gitlab::api::ignore(discussion)
+                .query(&self.client)
+                .with_context(|| format!("Failed to comment on commit {}", commit.sha))?;
+        }
+
+        review.finish_submission(opts)
+    }
+
+    fn diff_since(&self, owner: &str, repo: &str, pr_num: u64, since: &str) -> Result<String> {
+        #[derive(Deserialize)]
+        struct MergeRequest {
+            sha: String,
+        }
+
+        let project = format!("{}/{}", owner, repo);
+        let endpoint = gitlab::api::projects::merge_requests::MergeRequest::builder()
+            .project(project.as_str())
+            .merge_request(pr_num)
+            .build()?;
+        let mr: MergeRequest = endpoint.query(&self.client)?;
+
+        self.compare(&project, since, &mr.sha, DiffRange::ThreeDot)
+    }
+
+    fn current_user(&self) -> Result<String> {
+        Ok(self.user()?.username.clone())
+    }
+
+    fn current_user_name(&self) -> Result<Option<String>> {
+        Ok(Some(self.user()?.name.clone()).filter(|n| !n.is_empty()))
+    }
+
+    fn last_review_commit(&self, _: &str, _: &str, _: u64) -> Result<Option<String>> {
+        bail!("Finding the last review is not supported on GitLab")
+    }
+
+    fn open_prs_for_branch(
+        &self,
+        owner: &str,
+        repo: &str,
+        head_repo: &str,
+        branch: &str,
+    ) -> Result<Vec<u64>> {
+        #[derive(Deserialize)]
+        struct Project {
+            id: u64,
+        }
+
+        #[derive(Deserialize)]
+        struct MergeRequest {
+            iid: u64,
+            source_project_id: u64,
+        }
+
+        // Merge requests only name the project of their source branch by id
+        let endpoint = gitlab::api::projects::Project::builder()
+            .project(head_repo)
+            .build()?;
+        let source: Project = endpoint
+            .query(&self.client)
+            .with_context(|| format!("Failed to look up {}", head_repo))?;
+        let endpoint = MergeRequests::builder()
+            .project(format!("{}/{}", owner, repo))
+            .source_branch(branch)
+            .state(MergeRequestState::Opened)
+            .build()?;
+        let mrs: Vec<MergeRequest> = endpoint.query(&self.client)?;
+
+        Ok(mrs
+            .iter()
+            .filter(|mr| mr.source_project_id == source.id)
+            .map(|mr| mr.iid)
+            .collect())
+    }
+
+    fn list_prs(&self, owner: &str, repo: &str, state: PrState) -> Result<Vec<PrSummary>> {
+        #[derive(Deserialize)]
+        struct Author {
+            username: String,
+        }
+
+        #[derive(Deserialize)]
+        struct MergeRequest {
+            iid: u64,
+            title: String,
+            author: Author,
+            source_branch: String,
+            target_branch: String,
+            web_url: String,
+            state: String,
+            /// Replaces `work_in_progress` since GL 13.2, older instances only have the latter
+            #[serde(default)]
+            draft: bool,
+            #[serde(default)]
+            work_in_progress: bool,
+        }
+
+        let mut builder = MergeRequests::builder();
+        builder.project(format!("{}/{}", owner, repo));
+        // GL keeps merged MRs apart from closed ones, so filter those locally
+        if state == PrState::Open {
+            builder.state(MergeRequestState::Opened);
+        }
+        let endpoint = builder.build()?;
+        let mrs: Vec<MergeRequest> = paged(endpoint, Pagination::All).query(&self.client)?;
+
+        Ok(mrs
+            .into_iter()
+            .filter(|mr| state != PrState::Closed || mr.state != "opened")
+            .map(|mr| PrSummary {
+                number: mr.iid,
+                title: mr.title,
+                author: mr.author.username,
+                head_ref: mr.source_branch,
+                base_ref: mr.target_branch,
+                url: mr.web_url,
+                draft: mr.draft || mr.work_in_progress,
+            })
+            .collect())
+    }
+
+    fn get_thread(&self, owner: &str, repo: &str, pr_num: u64, thread_id: &str) -> Result<Thread> {
+        let endpoint = MergeRequestDiscussion {
+            project: format!("{}/{}", owner, repo).into(),
+            merge_request: pr_num,
+            discussion: thread_id,
+        };
+        let discussion: Discussion = endpoint
+            .query(&self.client)
+            .with_context(|| format!("Failed to fetch discussion {}", thread_id))?;
+
+        discussion
+            .into_thread()
+            .ok_or_else(|| anyhow!("Discussion {} is not on a line of the diff", thread_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instance_urls() {
+        let url = |s: &str| instance_url(s).unwrap().to_string();
+
+        assert_eq!(url(GITLAB_BASE_URL), "https://gitlab.com/");
+        assert_eq!(url("gitlab.example.com/"), "https://gitlab.example.com/");
+        assert_eq!(
+            url("https://gitlab.example.com"),
+            "https://gitlab.example.com/"
+        );
+        assert_eq!(
+            url("https://gitlab.example.com/"),
+            "https://gitlab.example.com/"
+        );
+        assert_eq!(url("http://127.0.0.1:8080"), "http://127.0.0.1:8080/");
+        // Instances may live below a path
+        assert_eq!(url("example.com/gitlab/"), "https://example.com/gitlab/");
+        assert_eq!(
+            instance_url("https://example.com/gitlab")
+                .unwrap()
+                .join("api/v4/")
+                .unwrap()
+                .as_str(),
+            "https://example.com/gitlab/api/v4/"
+        );
+
+        let err = instance_url("ssh://gitlab.example.com").unwrap_err();
+        assert!(err.to_string().contains("Unsupported scheme 'ssh'"));
+    }
+
+    #[test]
+    fn synthesized_diff() {
+        let github = include_str!("../../testdata/diffs/single_file");
+        let hunks = &github[github.find("@@").unwrap()..];
+        let change = |old: &str, new: &str, diff: &str| gitlab::RepoDiff {
+            old_path: old.to_string(),
+            new_path: new.to_string(),
+            a_mode: "100644".to_string(),
+            b_mode: "100644".to_string(),
+            diff: diff.to_string(),
+            new_file: false,
+            renamed_file: false,
+            deleted_file: false,
+        };
+
+        // Same as GitHub's diff, but for the blob hashes GL does not return
+        let path = "libbpf-cargo/src/btf/btf.rs";
+        let diff = synthesize_diff(&[change(path, path, hunks)]);
+        assert_eq!(
+            diff,
+            github.replacen("a26b2a5..fffb281", "aaaaaaa..bbbbbbb", 1)
+        );
+
+        let mut added = change("new.rs", "new.rs", "@@ -0,0 +1 @@\n+fn main() {}\n");
+        added.new_file = true;
+        let mut deleted = change("old.rs", "old.rs", "@@ -1 +0,0 @@\n-fn main() {}\n");
+        deleted.deleted_file = true;
+        let mut renamed = change("a.rs", "b.rs", "");
+        renamed.renamed_file = true;
+        let diff = synthesize_diff(&[added, deleted, renamed]);
+        let headers: Vec<_> = diff
+            .lines()
+            .filter(|l| l.starts_with("---") || l.starts_with("+++"))
+            .collect();
+        assert_eq!(
+            headers,
+            [
+                "--- /dev/null",
+                "+++ b/new.rs",
+                "--- a/old.rs",
+                "+++ /dev/null"
+            ]
+        );
+    }
+
+    #[test]
+    fn upload_body() {
+        let upload = Upload {
+            project: "org/repo".into(),
+            file_name: "my \"shot\".png",
+            contents: b"\x89PNG",
+        };
+        assert_eq!(upload.endpoint(), "projects/org%2Frepo/uploads");
+
+        let (content_type, body) = upload.body().unwrap().unwrap();
+        assert_eq!(content_type, UPLOAD_CONTENT_TYPE);
+        assert!(content_type.ends_with(UPLOAD_BOUNDARY));
+        let mut expected = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"my _shot_.png\"\r\n\
+             Content-Type: application/octet-stream\r\n\r\n",
+            b = UPLOAD_BOUNDARY
+        )
+        .into_bytes();
+        expected.extend_from_slice(b"\x89PNG");
+        expected.extend_from_slice(format!("\r\n--{}--\r\n", UPLOAD_BOUNDARY).as_bytes());
+        assert_eq!(body, expected);
+    }
+
+    #[test]
+    fn web_urls() {
+        let web_url = |url: &str| {
+            let config = format!("[prr]\ntoken = \"\"\nurl = \"{}\"", url);
+            web_url(&toml::from_str(&config).unwrap(), "danobi", "prr", 3)
+        };
+
+        assert_eq!(
+            web_url("gitlab.com"),
+            "https://gitlab.com/danobi/prr/-/merge_requests/3"
+        );
+        assert_eq!(
+            web_url("http://gitlab.example.com/"),
+            "http://gitlab.example.com/danobi/prr/-/merge_requests/3"
+        );
+    }
+
+    #[test]
+    fn discussion_thread() {
+        let discussion = r#"{
+            "id": "6a9c1750b37d513a43987b574953fceb50b03ce7",
+            "notes": [
+                {
+                    "id": 1126,
+                    "author": { "username": "danobi" },
+                    "body": "Why?",
+                    "resolved": false,
+                    "position": { "new_path": "src/main.rs", "old_line": null, "new_line": 12 }
+                },
+                { "id": 1127, "author": { "username": "sornas" }, "body": "Because" }
+            ]
+        }"#;
+        let thread = serde_json::from_str::<Discussion>(discussion)
+            .unwrap()
+            .into_thread()
+            .unwrap();
+
+        assert_eq!(thread.id, "6a9c1750b37d513a43987b574953fceb50b03ce7");
+        assert_eq!(thread.file, "src/main.rs");
+        assert_eq!((thread.old_line, thread.new_line), (None, Some(12)));
+        assert!(!thread.resolved);
+        let ids: Vec<u64> = thread.comments.iter().map(|c| c.id).collect();
+        assert_eq!(ids, [1126, 1127]);
+        assert_eq!(thread.comments[1].author, "sornas");
+
+        // Discussions on the merge request as a whole are not threads
+        let general = r#"{
+            "id": "87805b7c09016a7058e91bdbe7b29d1f284a39e6",
+            "notes": [{ "id": 1128, "author": { "username": "danobi" }, "body": "LGTM" }]
+        }"#;
+        let general: Discussion = serde_json::from_str(general).unwrap();
+        assert_eq!(general.into_thread(), None);
+    }
+
+    #[test]
+    fn review_comment_note() {
+        let note = review_note("org/repo", 3, "Looks good & thanks").unwrap();
+        assert_eq!(note.method(), Method::POST);
+        assert_eq!(
+            note.endpoint(),
+            "projects/org%2Frepo/merge_requests/3/notes"
+        );
+
+        let (content_type, body) = note.body().unwrap().unwrap();
+        assert_eq!(content_type, "application/x-www-form-urlencoded");
+        assert_eq!(
+            String::from_utf8(body).unwrap(),
+            "body=Looks+good+%26+thanks"
+        );
+    }
+
+    #[test]
+    fn missing_diff_refs_shas() {
+        let sha = Some("a26b2a5");
+        assert_eq!(missing_shas(3, sha, sha, sha), None);
+
+        for (base, head, start, missing) in [
+            (None, sha, sha, "base_sha"),
+            (sha, None, sha, "head_sha"),
+            (sha, sha, None, "start_sha"),
+            (None, None, sha, "base_sha or head_sha"),
+            (None, sha, None, "base_sha or start_sha"),
+            (sha, None, None, "head_sha or start_sha"),
+            (None, None, None, "base_sha or head_sha or start_sha"),
+        ] {
+            let msg = missing_shas(3, base, head, start).unwrap();
+            let expected = format!("!3 has no {} in its diff_refs,", missing);
+            assert!(msg.starts_with(&expected), "{}", msg);
         }
+    }
 
-        Ok(())
+    #[test]
+    fn review_action_approval() {
+        assert_eq!(approval(&ReviewAction::Approve), Approval::Approve);
+        assert_eq!(approval(&ReviewAction::RequestChanges), Approval::Revoke);
+        assert_eq!(approval(&ReviewAction::Comment), Approval::Keep);
     }
 }
@@ -0,0 +1,378 @@
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::api::{Api, Commit, GetOptions, PrState, PrSummary, SubmitOptions, Thread};
+use crate::review::{Extra, PullState, Review, ReviewComments};
+use crate::Config;
+
+/// Host name review files of the mock are placed under
+const MOCK_HOST: &str = "mock";
+
+/// Everything a single `submit_pr()` would have sent
+#[derive(Debug)]
+pub struct Submission {
+    pub owner: String,
+    pub repo: String,
+    pub pr_num: u64,
+    pub comments: ReviewComments,
+    /// Names of the files attached with `@prr attach`, in upload order
+    pub uploads: Vec<String>,
+}
+
+/// An `Api` that serves canned pull requests and records submissions instead of
+/// sending them anywhere
+///
+/// Submissions are prepared with `Review::prepare_submission()` like on the real hosts, so
+/// tests can check what a review file turns into.
+pub struct MockApi {
+    config: Config,
+    /// Diffs `get_pr()` hands out, by pull request number
+    prs: HashMap<u64, String>,
+    /// Pull requests that are drafts
+    drafts: HashSet<u64>,
+    /// States of the pull requests that are not open
+    states: HashMap<u64, PullState>,
+    /// Commits of the pull requests, by pull request number
+    commits: HashMap<u64, Vec<Commit>>,
+    /// Base branches of the pull requests that are not based on `main`
+    bases: HashMap<u64, String>,
+    /// Bodies of the saved replies `@prr saved` expands to, by title
+    saved_replies: HashMap<String, String>,
+    /// Everything `submit_pr()` was asked to submit, oldest first
+    pub submissions: RefCell<Vec<Submission>>,
+}
+
+impl MockApi {
+    /// Creates a mock that places review files in `workdir`
+    ///
+    /// `config` is the rest of the config file, eg. a `[snippets]` table.
+    pub fn new(workdir: &Path, config: &str) -> Result<Self> {
+        let config = format!(
+            "[prr]\ntoken = \"\"\nworkdir = \"{}\"\n{}",
+            workdir.display(),
+            config
+        );
+
+        Ok(Self {
+            config: toml::from_str(&config).context("Failed to parse mock config")?,
+            prs: HashMap::new(),
+            drafts: HashSet::new(),
+            states: HashMap::new(),
+            commits: HashMap::new(),
+            bases: HashMap::new(),
+            saved_replies: HashMap::new(),
+            submissions: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Adds a pull request with `diff` for `get_pr()` to fetch
+    pub fn add_pr(&mut self, pr_num: u64, diff: &str) -> &mut Self {
+        self.prs.insert(pr_num, diff.to_string());
+        self
+    }
+
+    /// Marks pull request `pr_num` as a draft
+    pub fn set_draft(&mut self, pr_num: u64) -> &mut Self {
+        self.drafts.insert(pr_num);
+        self
+    }
+
+    /// Closes or merges pull request `pr_num`
+    pub fn set_state(&mut self, pr_num: u64, state: PullState) -> &mut Self {
+        self.states.insert(pr_num, state);
+        self
+    }
+
+    /// Sets the commits of pull request `pr_num`, oldest first
+    pub fn set_commits(&mut self, pr_num: u64, commits: Vec<Commit>) -> &mut Self {
+        self.commits.insert(pr_num, commits);
+        self
+    }
+
+    /// Bases pull request `pr_num` on `branch`, eg. `pr-1` to stack it on #1
+    pub fn set_base(&mut self, pr_num: u64, branch: &str) -> &mut Self {
+        self.bases.insert(pr_num, branch.to_string());
+        self
+    }
+
+    /// Adds a saved reply for `@prr saved <title>` to expand to
+    pub fn add_saved_reply(&mut self, title: &str, body: &str) -> &mut Self {
+        self.saved_replies
+            .insert(title.to_string(), body.to_string());
+        self
+    }
+
+    /// Returns the directory the review files of `owner/repo` are placed in
+    pub fn review_dir(&self, owner: &str, repo: &str) -> Result<PathBuf> {
+        self.config.review_dir(MOCK_HOST, owner, repo)
+    }
+
+    fn review(&self, owner: &str, repo: &str, pr_num: u64) -> Result<Review> {
+        let dir = self.review_dir(owner, repo)?;
+        Ok(self.config.review(&dir, pr_num))
+    }
+}
+
+impl Api for MockApi {
+    fn get_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &GetOptions) -> Result<Review> {
+        let dir = self.review_dir(owner, repo)?;
+        let diff = self
+            .prs
+            .get(&pr_num)
+            .ok_or_else(|| anyhow!("No pull request #{}", pr_num))?
+            .clone();
+
+        let mut extra = Extra::default();
+        extra
+            .head_sha("head".to_string())
+            .draft(self.drafts.contains(&pr_num))
+            .state(self.states.get(&pr_num).copied().unwrap_or_default())
+            .range(opts.range)
+            .quote_prefix(self.config.quote_prefix().map(str::to_string))
+            .paths(opts.paths.clone())
+            .hunk_ranges(opts.hunk_ranges);
+        if opts.commit_log {
+            extra.commits(self.commits.get(&pr_num).cloned().unwrap_or_default());
+        }
+        if opts.stdout {
+            return Review::print(&dir, diff, pr_num, extra, &mut std::io::stdout());
+        }
+        Review::new(&dir, diff, pr_num, extra, opts.force)
+    }
+
+    fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &SubmitOptions) -> Result<()> {
+        let review = self
+            .review(owner, repo, pr_num)?
+            .deny_duplicates(opts.deny_duplicates);
+        let mut uploads = Vec::new();
+        let comments = review.prepare_submission(
+            opts,
+            false,
+            || Ok(self.saved_replies.clone()),
+            |name, _| {
+                uploads.push(name.to_string());
+                Ok(format!("![{}](/uploads/{})", name, name))
+            },
+        )?;
+
+        review.begin_submission()?;
+        self.submissions.borrow_mut().push(Submission {
+            owner: owner.to_string(),
+            repo: repo.to_string(),
+            pr_num,
+            comments,
+            uploads,
+        });
+
+        review.finish_submission(opts)
+    }
+
+    fn diff_since(&self, _: &str, _: &str, pr_num: u64, since: &str) -> Result<String> {
+        bail!(
+            "Pull request #{} has no history to diff against {}",
+            pr_num,
+            since
+        )
+    }
+
+    fn current_user(&self) -> Result<String> {
+        Ok("mock".to_string())
+    }
+
+    fn current_user_name(&self) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn last_review_commit(&self, _: &str, _: &str, _: u64) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    fn open_prs_for_branch(&self, _: &str, _: &str, _: &str, _: &str) -> Result<Vec<u64>> {
+        Ok(Vec::new())
+    }
+
+    fn list_prs(&self, owner: &str, repo: &str, state: PrState) -> Result<Vec<PrSummary>> {
+        let open = |number: &u64| !self.states.contains_key(number);
+        let mut numbers: Vec<u64> = self
+            .prs
+            .keys()
+            .copied()
+            .filter(|n| match state {
+                PrState::Open => open(n),
+                PrState::Closed => !open(n),
+                PrState::All => true,
+            })
+            .collect();
+        numbers.sort_unstable_by(|a, b| b.cmp(a));
+
+        Ok(numbers
+            .into_iter()
+            .map(|number| PrSummary {
+                number,
+                title: format!("Pull request #{}", number),
+                author: "mock".to_string(),
+                head_ref: format!("pr-{}", number),
+                base_ref: self
+                    .bases
+                    .get(&number)
+                    .cloned()
+                    .unwrap_or_else(|| "main".to_string()),
+                url: format!("https://{}/{}/{}/pull/{}", MOCK_HOST, owner, repo, number),
+                draft: self.drafts.contains(&number),
+            })
+            .collect())
+    }
+
+    fn get_thread(&self, _: &str, _: &str, pr_num: u64, thread_id: &str) -> Result<Thread> {
+        bail!("Pull request #{} has no thread {}", pr_num, thread_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::parser::{LineLocation, ReviewAction};
+
+    /// Fetches pull request 1 with the `multiple_files` diff and writes `contents` as
+    /// its review file
+    fn setup(dir: &Path, config: &str, contents: &str) -> (MockApi, Review) {
+        let mut api = MockApi::new(dir, config).unwrap();
+        api.add_pr(1, include_str!("../../testdata/diffs/multiple_files"));
+        let review = api
+            .get_pr("danobi", "prr", 1, &GetOptions::default())
+            .unwrap();
+        fs::write(review.path(), contents).unwrap();
+
+        (api, review)
+    }
+
+    /// Returns the `multiple_files` diff as quoted in a freshly fetched review file
+    fn quoted() -> String {
+        include_str!("../../testdata/diffs/multiple_files")
+            .lines()
+            .map(|l| format!("> {}\n", l))
+            .collect()
+    }
+
+    #[test]
+    fn submit_review() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = format!(
+            "@prr approve\n\nLGTM\n\n{}",
+            include_str!("../../testdata/multiple_files")
+        );
+        let (api, review) = setup(dir.path(), "", &contents);
+
+        api.submit_pr("danobi", "prr", 1, &SubmitOptions::default())
+            .unwrap();
+
+        let submissions = api.submissions.borrow();
+        assert_eq!(submissions.len(), 1);
+        let submission = &submissions[0];
+        assert_eq!(
+            (submission.owner.as_str(), submission.pr_num),
+            ("danobi", 1)
+        );
+        assert_eq!(submission.comments.action, ReviewAction::Approve);
+        assert_eq!(submission.comments.comment, "LGTM");
+        let inline: Vec<_> = submission
+            .comments
+            .inline
+            .iter()
+            .map(|c| (c.new_file.as_str(), c.line.clone(), c.comment.as_str()))
+            .collect();
+        assert_eq!(
+            inline,
+            vec![
+                (
+                    "libbpf-cargo/src/btf/btf.rs",
+                    LineLocation::Right(734, 734),
+                    "Comment 1"
+                ),
+                (
+                    "libbpf-cargo/src/test.rs",
+                    LineLocation::Right(2147, 2159),
+                    "Comment 2"
+                ),
+            ]
+        );
+        let metadata = review.read_metadata().unwrap();
+        assert!(metadata.submitted.is_some());
+        assert_eq!(metadata.submitting, None);
+    }
+
+    #[test]
+    fn submit_summary_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let (api, review) = setup(dir.path(), "", &format!("Just a summary\n\n{}", quoted()));
+
+        api.submit_pr("danobi", "prr", 1, &SubmitOptions::default())
+            .unwrap();
+
+        let submissions = api.submissions.borrow();
+        assert_eq!(submissions[0].comments.comment, "Just a summary");
+        assert!(submissions[0].comments.inline.is_empty());
+        assert!(review.read_metadata().unwrap().submitted.is_some());
+    }
+
+    #[test]
+    fn draft() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../../testdata/diffs/single_file");
+        let mut api = MockApi::new(dir.path(), "").unwrap();
+        api.add_pr(1, diff).add_pr(2, diff).set_draft(2);
+
+        let opts = GetOptions::default();
+        let ready = api.get_pr("danobi", "prr", 1, &opts).unwrap();
+        assert!(!ready.read_metadata().unwrap().draft);
+        let draft = api.get_pr("danobi", "prr", 2, &opts).unwrap();
+        assert!(draft.read_metadata().unwrap().draft);
+
+        let prs = api.list_prs("danobi", "prr", PrState::Open).unwrap();
+        let drafts: Vec<_> = prs.iter().map(|pr| (pr.number, pr.draft)).collect();
+        assert_eq!(drafts, [(2, true), (1, false)]);
+    }
+
+    #[test]
+    fn list_closed_and_merged() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../../testdata/diffs/multiple_files");
+        let mut api = MockApi::new(dir.path(), "").unwrap();
+        api.add_pr(1, diff)
+            .add_pr(2, diff)
+            .add_pr(3, diff)
+            .set_state(1, PullState::Merged)
+            .set_state(2, PullState::Closed);
+
+        let numbers = |state| -> Vec<u64> {
+            let prs = api.list_prs("danobi", "prr", state).unwrap();
+            prs.iter().map(|pr| pr.number).collect()
+        };
+        assert_eq!(numbers(PrState::Open), [3]);
+        assert_eq!(numbers(PrState::Closed), [2, 1]);
+        assert_eq!(numbers(PrState::All), [3, 2, 1]);
+
+        let review = api
+            .get_pr("danobi", "prr", 1, &GetOptions::default())
+            .unwrap();
+        assert_eq!(review.read_metadata().unwrap().state, PullState::Merged);
+    }
+
+    #[test]
+    fn get_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut api = MockApi::new(dir.path(), "").unwrap();
+        api.add_pr(2, "");
+
+        let err = api
+            .get_pr("danobi", "prr", 2, &GetOptions::default())
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Pull request #2 has no reviewable changes");
+    }
+}
@@ -0,0 +1,144 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use log::debug;
+use sha1::{Digest, Sha1};
+
+/// Default time a cached diff is used for. See `prr.cache_ttl_secs`
+pub const DEFAULT_TTL_SECS: u64 = 300;
+
+/// Diffs fetched by `get_pr()`, kept on disk so fetching a pull request again soon after
+/// does not download them again
+///
+/// Entries are keyed by pull request, by its head commit and by whatever else the diff
+/// depends on, eg. the format. Entries of an older head are dropped once a diff of a new
+/// one is cached. Even if nothing changed, an entry is only used within its time to live,
+/// since the base branch may have moved.
+pub struct DiffCache {
+    /// Directory the entries are in, below the review directory
+    dir: PathBuf,
+    ttl: Duration,
+}
+
+impl DiffCache {
+    /// Creates a cache for the pull requests with review files in `review_dir`
+    ///
+    /// A `ttl` of zero disables the cache.
+    pub fn new(review_dir: &Path, ttl: Duration) -> Self {
+        Self {
+            dir: review_dir.join(".cache"),
+            ttl,
+        }
+    }
+
+    /// Returns the start of the names of the entries of pull request `pr_num` at `head`
+    fn prefix(pr_num: u64, head: &str) -> String {
+        format!("{}-{:x}-", pr_num, Sha1::digest(head.as_bytes()))
+    }
+
+    fn path(&self, pr_num: u64, head: &str, key: &str) -> PathBuf {
+        self.dir.join(format!(
+            "{}{:x}",
+            Self::prefix(pr_num, head),
+            Sha1::digest(key.as_bytes())
+        ))
+    }
+
+    /// Returns the cached diff of pull request `pr_num` at `head` for `key`, unless it
+    /// expired
+    pub fn get(&self, pr_num: u64, head: &str, key: &str) -> Option<String> {
+        if self.ttl.is_zero() {
+            return None;
+        }
+
+        let path = self.path(pr_num, head, key);
+        let age = fs::metadata(&path).ok()?.modified().ok()?.elapsed().ok()?;
+        if age >= self.ttl {
+            return None;
+        }
+        debug!("Using cached diff {}", path.display());
+
+        fs::read_to_string(path).ok()
+    }
+
+    /// Caches `diff` as the diff of pull request `pr_num` at `head` for `key`
+    ///
+    /// Drops the entries of the pull request at other heads, as they are outdated.
+    pub fn put(&self, pr_num: u64, head: &str, key: &str, diff: &str) -> Result<()> {
+        if self.ttl.is_zero() {
+            return Ok(());
+        }
+
+        fs::create_dir_all(&self.dir).context("Failed to create diff cache directory")?;
+        let prefix = format!("{}-", pr_num);
+        let current = Self::prefix(pr_num, head);
+        for entry in fs::read_dir(&self.dir).context("Failed to read diff cache directory")? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && !name.starts_with(&current) {
+                match fs::remove_file(entry.path()) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => {
+                        return Err(e).context("Failed to remove stale cached diff")
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        fs::write(self.path(pr_num, head, key), diff).context("Failed to cache diff")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_and_put() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiffCache::new(dir.path(), Duration::from_secs(60));
+        assert_eq!(cache.get(1, "abc", "diff"), None);
+
+        cache.put(1, "abc", "diff", "diff 1").unwrap();
+        cache.put(2, "abc", "diff", "diff 2").unwrap();
+        assert_eq!(cache.get(1, "abc", "diff").as_deref(), Some("diff 1"));
+        assert_eq!(cache.get(2, "abc", "diff").as_deref(), Some("diff 2"));
+
+        // Other keys at the same head are kept side by side
+        cache.put(1, "abc", "patch", "patch 1").unwrap();
+        assert_eq!(cache.get(1, "abc", "diff").as_deref(), Some("diff 1"));
+        assert_eq!(cache.get(1, "abc", "patch").as_deref(), Some("patch 1"));
+
+        // A new head replaces the entries of the old one
+        cache.put(1, "def", "diff", "diff 1 v2").unwrap();
+        assert_eq!(cache.get(1, "abc", "diff"), None);
+        assert_eq!(cache.get(1, "abc", "patch"), None);
+        assert_eq!(cache.get(1, "def", "diff").as_deref(), Some("diff 1 v2"));
+        assert_eq!(cache.get(2, "abc", "diff").as_deref(), Some("diff 2"));
+        // Neither do pull requests whose number starts with another's get mixed up
+        cache.put(12, "abc", "diff", "diff 12").unwrap();
+        assert_eq!(cache.get(1, "def", "diff").as_deref(), Some("diff 1 v2"));
+    }
+
+    #[test]
+    fn expiry() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DiffCache::new(dir.path(), Duration::from_millis(50));
+        cache.put(1, "abc", "diff", "diff").unwrap();
+        assert!(cache.get(1, "abc", "diff").is_some());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(cache.get(1, "abc", "diff"), None);
+
+        // Disabled, nothing is cached at all
+        let cache = DiffCache::new(dir.path(), Duration::ZERO);
+        cache.put(2, "abc", "diff", "diff").unwrap();
+        assert!(!dir.path().join(".cache").read_dir().unwrap().any(|e| e
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with("2-")));
+    }
+}
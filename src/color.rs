@@ -0,0 +1,66 @@
+use std::ffi::OsStr;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const RESET: &str = "\x1b[0m";
+
+/// Whether output may be colored. Decided once by `init()`, off until then
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Decides whether to color output for the rest of the run
+///
+/// Color is used unless `--no-color` was passed, `NO_COLOR` is set to anything but the
+/// empty string (see <https://no-color.org>) or stderr is not a terminal.
+pub fn init(no_color: bool) {
+    let enabled = should_color(
+        no_color,
+        std::env::var_os("NO_COLOR").as_deref(),
+        std::io::stderr().is_terminal(),
+    );
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+fn should_color(no_color: bool, no_color_env: Option<&OsStr>, terminal: bool) -> bool {
+    !no_color && no_color_env.is_none_or(OsStr::is_empty) && terminal
+}
+
+/// Returns whether output may be colored
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+fn paint(color: &str, text: &str) -> String {
+    if enabled() {
+        format!("{}{}{}", color, text, RESET)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Prints a warning to stderr
+pub fn warn(msg: impl Display) {
+    eprintln!("{}: {}", paint(YELLOW, "Warning"), msg);
+}
+
+/// Prints an error to stderr
+pub fn error(msg: impl Display) {
+    eprintln!("{}: {}", paint(RED, "Error"), msg);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn color_policy() {
+        assert!(should_color(false, None, true));
+        assert!(!should_color(false, None, false));
+        assert!(!should_color(true, None, true));
+        assert!(!should_color(false, Some(OsStr::new("1")), true));
+        // An empty NO_COLOR does not count as set
+        assert!(should_color(false, Some(OsStr::new("")), true));
+    }
+}
@@ -0,0 +1,131 @@
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+// Use lazy static to ensure regex is only compiled once
+lazy_static! {
+    // Regex for a git remote url. Remotes look something like:
+    //
+    //      git@github.com:danobi/prr.git
+    //      ssh://git@github.com/danobi/prr.git
+    //      https://github.com/danobi/prr
+    //
+    static ref REMOTE: Regex = Regex::new(r"^(\w+://)?([\w\-.]+@)?(?P<hostname>[\w\-.]+)(:\d+)?[:/](?P<org>[\w\-_]+(/[\w\-_]+)*)/(?P<repo>[\w\-_.]+?)(\.git)?/?$").unwrap();
+}
+
+/// Runs a git command in the current directory and returns its trimmed stdout
+fn git(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .context("Failed to run git")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    let stdout = String::from_utf8(output.stdout).context("git output is not valid UTF-8")?;
+    Ok(stdout.trim().to_string())
+}
+
+/// Returns the branch currently checked out in the local repository
+pub fn current_branch() -> Result<String> {
+    let branch = git(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch == "HEAD" {
+        bail!("Not on a branch (detached HEAD)");
+    }
+
+    Ok(branch)
+}
+
+/// Returns the remote `git push` pushes `branch` to
+///
+/// Same lookup as git: the branch's `pushRemote`, then `remote.pushDefault`, then the
+/// remote the branch tracks, then `origin`.
+pub fn push_remote(branch: &str) -> String {
+    [
+        format!("branch.{}.pushRemote", branch),
+        "remote.pushDefault".to_string(),
+        format!("branch.{}.remote", branch),
+    ]
+    .iter()
+    .find_map(|key| git(&["config", "--get", key]).ok())
+    .unwrap_or_else(|| "origin".to_string())
+}
+
+/// Returns the url of the named remote in the local repository
+pub fn remote_url(remote: &str) -> Result<String> {
+    git(&["remote", "get-url", remote])
+}
+
+/// Parses a remote url into (hostname, owner, repo)
+pub fn parse_remote_url(url: &str) -> Result<(String, String, String)> {
+    match REMOTE.captures(url) {
+        Some(captures) => Ok((
+            captures.name("hostname").unwrap().as_str().to_owned(),
+            captures.name("org").unwrap().as_str().to_owned(),
+            captures.name("repo").unwrap().as_str().to_owned(),
+        )),
+        None => bail!("Unrecognized remote url: {}", url),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test(url: &str, expected: (&str, &str, &str)) {
+        let (hostname, owner, repo) = parse_remote_url(url).unwrap();
+        assert_eq!((hostname.as_str(), owner.as_str(), repo.as_str()), expected);
+    }
+
+    #[test]
+    fn scp_style() {
+        test(
+            "git@github.com:danobi/prr.git",
+            ("github.com", "danobi", "prr"),
+        );
+    }
+
+    #[test]
+    fn ssh_url() {
+        test(
+            "ssh://git@gitlab.com:2222/danobi/prr.git",
+            ("gitlab.com", "danobi", "prr"),
+        );
+    }
+
+    #[test]
+    fn https_url() {
+        test(
+            "https://github.com/danobi/prr",
+            ("github.com", "danobi", "prr"),
+        );
+        test(
+            "https://github.com/danobi/prr.git/",
+            ("github.com", "danobi", "prr"),
+        );
+    }
+
+    #[test]
+    fn subgroups() {
+        test(
+            "git@gitlab.com:group/sub/prr.git",
+            ("gitlab.com", "group/sub", "prr"),
+        );
+        test(
+            "https://gitlab.com/a/b/c/prr",
+            ("gitlab.com", "a/b/c", "prr"),
+        );
+    }
+
+    #[test]
+    fn invalid_url() {
+        assert!(parse_remote_url("/some/local/path").is_err());
+    }
+}
@@ -0,0 +1,1152 @@
+//! Mailing list style code reviews for GitHub and GitLab
+//!
+//! This is the library behind the `prr` binary. It fetches pull requests into review
+//! files ([`api::Api::get_pr()`]), parses what the reviewer wrote in them
+//! ([`parser::ReviewParser`], [`review::Review::comments()`]) and submits the result
+//! ([`submit()`], which checks the review before handing it to [`api::Api::submit_pr()`]).
+//!
+//! ```
+//! use anyhow::Result;
+//! use prr::api::{Api, GetOptions, Host, SubmitOptions};
+//! use prr::parser::ReviewAction;
+//! use prr::review::{Extra, Review, ReviewComments};
+//! use prr::SubmitChecks;
+//! use std::cell::RefCell;
+//! use std::path::PathBuf;
+//!
+//! /// Serves a canned diff and records submissions instead of sending them anywhere
+//! struct Recorder {
+//!     dir: PathBuf,
+//!     submitted: RefCell<Vec<(String, u64, ReviewComments)>>,
+//! }
+//!
+//! impl Api for Recorder {
+//!     fn get_pr(&self, _: &str, _: &str, pr_num: u64, opts: &GetOptions) -> Result<Review> {
+//!         let diff = "diff --git a/README b/README\n--- a/README\n+++ b/README\n\
+//!                     @@ -1 +1 @@\n-teh\n+the\n";
+//!         Review::new(&self.dir, diff.to_string(), pr_num, Extra::default(), opts.force)
+//!     }
+//!
+//!     fn submit_pr(&self, owner: &str, repo: &str, pr_num: u64, opts: &SubmitOptions) -> Result<()> {
+//!         let review = Review::new_existing(&self.dir, pr_num);
+//!         let comments = review.comments()?;
+//!         let name = format!("{}/{}", owner, repo);
+//!         self.submitted.borrow_mut().push((name, pr_num, comments));
+//!         review.finish_submission(opts)
+//!     }
+//! }
+//!
+//! # fn main() -> Result<()> {
+//! let (host, owner, repo, pr_num) = prr::parse_pr_str("gitlab:danobi/prr/24", Host::Github)?;
+//! assert_eq!(host, Host::Gitlab);
+//!
+//! # let workdir = tempfile::tempdir()?;
+//! let api = Recorder {
+//!     dir: workdir.path().to_owned(),
+//!     submitted: RefCell::new(Vec::new()),
+//! };
+//! let review = api.get_pr(&owner, &repo, pr_num, &GetOptions::default())?;
+//!
+//! // The reviewer writes their comments into the review file
+//! let quoted = std::fs::read_to_string(review.path())?;
+//! std::fs::write(review.path(), format!("@prr approve\nLooks good!\n\n{}", quoted))?;
+//!
+//! let opts = SubmitOptions::default();
+//! prr::submit(&api, &review, &owner, &repo, &opts, &SubmitChecks::default())?;
+//! let (name, pr_num, comments) = &api.submitted.borrow()[0];
+//! assert_eq!((name.as_str(), *pr_num), ("danobi/prr", 24));
+//! assert_eq!(comments.action, ReviewAction::Approve);
+//! assert_eq!(comments.comment, "Looks good!");
+//!
+//! // The review is marked as submitted, so it is not submitted twice
+//! assert!(prr::submit(&api, &review, &owner, &repo, &opts, &SubmitChecks::default()).is_err());
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use lazy_static::lazy_static;
+use log::debug;
+use regex::{Captures, Regex};
+use serde::Deserialize;
+
+pub mod api;
+mod cache;
+pub mod color;
+mod git;
+pub mod parser;
+pub mod review;
+mod spinner;
+pub mod stack;
+
+use api::{Api, Host, SubmitOptions};
+use parser::ReviewAction;
+use review::{Review, ReviewComments};
+
+/// How often `submit()` checks whether the review file was saved while watching it
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+// Use lazy static to ensure regex is only compiled once
+lazy_static! {
+    // Regex for short input. The owner may be nested GitLab groups, see `parse_pr_str()`.
+    // Example:
+    //
+    //      [<host>:]danobi/prr-test-repo/6
+    //      gitlab:group/subgroup/project/6
+    //
+    static ref SHORT: Regex = Regex::new(r"^((?P<host>\w+):)?(?P<org>[\w\-_]+(/[\w\-_]+)*)/(?P<repo>[\w\-_]+)/(?P<pr_num>\d+)$").unwrap();
+    // Regex for SSH remote style input. Example:
+    //
+    //      git@github.com:danobi/prr.git#24
+    //      ssh://git@gitlab.com/danobi/prr#24
+    //
+    static ref SSH: Regex = Regex::new(r"^(ssh://)?[\w\-.]+@(?P<hostname>[\w\-.]+)[:/](?P<org>[\w\-_]+(/[\w\-_]+)*)/(?P<repo>[\w\-_.]+?)(\.git)?#(?P<pr_num>\d+)$").unwrap();
+    // Regex for repository input. Example:
+    //
+    //      [<host>:]danobi/prr-test-repo
+    //      gitlab:group/subgroup/project
+    //
+    static ref REPO: Regex = Regex::new(r"^((?P<host>\w+):)?(?P<org>[\w\-_]+(/[\w\-_]+)*)/(?P<repo>[\w\-_.]+)$").unwrap();
+}
+
+#[derive(Debug, Deserialize)]
+struct PrrConfig {
+    /// API token for the given service
+    // TODO per service
+    token: String,
+    /// Directory to place review files
+    workdir: Option<String>,
+    /// Layout of review files inside the workdir, eg. `{host}/{owner}/{repo}`
+    workdir_layout: Option<String>,
+    /// Host to use for PR refs without a host prefix. Defaults to github
+    default_host: Option<String>,
+    /// Timeout in seconds for requests to the host. Defaults to 30
+    timeout_secs: Option<u64>,
+    /// Seconds a fetched diff is reused for. Defaults to 300, 0 disables the cache
+    cache_ttl_secs: Option<u64>,
+    /// Proxy URL for requests to the host. Overrides the proxy environment variables
+    proxy: Option<String>,
+    /// Path to a PEM encoded CA certificate to trust in addition to the system ones
+    ca_cert: Option<String>,
+    /// Skip TLS certificate verification
+    #[serde(default)]
+    insecure: bool,
+    /// Prefix of the quoted diff lines in review files. Defaults to `> `
+    quote_prefix: Option<String>,
+    /// Strip trailing whitespace from each line of a comment before submitting it
+    #[serde(default)]
+    strip_trailing_whitespace: bool,
+    /// Footer for review comments, see `prr submit --append-signature`
+    signature: Option<String>,
+    /// Action of reviews without an `@prr approve`/`reject`/`comment` directive.
+    /// Defaults to `comment`
+    default_action: Option<parser::ReviewAction>,
+    /// Instance URL
+    ///
+    /// Useful for hosted instances with custom URLs
+    // TODO per service
+    url: Option<String>,
+}
+
+/// Config values given on the command line. See `Config::apply_overrides()`
+#[derive(Debug, Default)]
+pub struct Overrides {
+    /// Name of the `[profile.<name>]` table to apply
+    pub profile: Option<String>,
+    /// Directory to place review files in, overriding `prr.workdir`
+    pub workdir: Option<String>,
+    /// Skip TLS certificate verification
+    pub insecure: bool,
+}
+
+/// Values of a `[profile.<name>]` table, overlaid over `[prr]` when selected
+#[derive(Debug, Deserialize)]
+struct Profile {
+    token: Option<String>,
+    url: Option<String>,
+    workdir: Option<String>,
+}
+
+/// The contents of the config file
+#[derive(Debug, Deserialize)]
+pub struct Config {
+    prr: PrrConfig,
+    /// Named profiles, see `--profile`
+    #[serde(default)]
+    profile: HashMap<String, Profile>,
+    /// Texts to expand `@prr snip <name>` to, by name
+    #[serde(default)]
+    snippets: HashMap<String, String>,
+}
+
+impl Config {
+    /// Returns the path of the config file if none was given, ie.
+    /// `$XDG_CONFIG_HOME/prr/config.toml`
+    pub fn default_path() -> Result<PathBuf> {
+        let xdg_dirs = xdg::BaseDirectories::with_prefix("prr")?;
+        Ok(xdg_dirs.get_config_file("config.toml"))
+    }
+
+    /// Returns the path of the config file to load
+    ///
+    /// An explicit `file` wins over `config.toml` in `dir`, which wins over the default path.
+    pub fn path(file: Option<&Path>, dir: Option<&Path>) -> Result<PathBuf> {
+        match (file, dir) {
+            (Some(f), _) => Ok(f.to_path_buf()),
+            (None, Some(d)) => Ok(d.join("config.toml")),
+            (None, None) => Self::default_path(),
+        }
+    }
+
+    /// Reads and parses the config file at `path`
+    pub fn load(path: &Path) -> Result<Config> {
+        debug!("Using config file {}", path.display());
+        let contents = std::fs::read_to_string(path).context("Failed to read config")?;
+        toml::from_str(&contents).context("Failed to parse toml")
+    }
+
+    /// Returns the directory review files are placed in
+    pub fn workdir(&self) -> Result<PathBuf> {
+        match &self.prr.workdir {
+            Some(d) => expand_home(d),
+            None => {
+                let xdg_dirs = xdg::BaseDirectories::with_prefix("prr")?;
+                Ok(xdg_dirs.get_data_home())
+            }
+        }
+    }
+
+    /// Returns the directory review files for `owner/repo` on `host` are placed in
+    pub fn review_dir(&self, host: &str, owner: &str, repo: &str) -> Result<PathBuf> {
+        let layout = self
+            .prr
+            .workdir_layout
+            .as_deref()
+            .unwrap_or(review::DEFAULT_LAYOUT);
+
+        review::review_dir(&self.workdir()?, layout, host, owner, repo)
+    }
+
+    /// Returns the host to use for PR refs without a host prefix
+    pub fn default_host(&self) -> Result<Host> {
+        match &self.prr.default_host {
+            Some(h) => {
+                Host::from_str(h).with_context(|| format!("Unknown default_host '{}' in config", h))
+            }
+            None => Ok(Host::Github),
+        }
+    }
+
+    /// Applies the command line flags that override config values
+    ///
+    /// The selected profile is applied first, so that flags win over it.
+    pub fn apply_overrides(&mut self, overrides: &Overrides) -> Result<()> {
+        if let Some(name) = &overrides.profile {
+            let profile = self
+                .profile
+                .remove(name)
+                .with_context(|| format!("No profile '{}' in config", name))?;
+            if let Some(token) = profile.token {
+                self.prr.token = token;
+            }
+            if profile.url.is_some() {
+                self.prr.url = profile.url;
+            }
+            if profile.workdir.is_some() {
+                self.prr.workdir = profile.workdir;
+            }
+        }
+
+        self.prr.insecure |= overrides.insecure;
+        if let Some(workdir) = &overrides.workdir {
+            self.prr.workdir = Some(workdir.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configured instance URL, or `default` if there is none
+    pub fn host_or<'s>(&'s self, default: &'s str) -> &'s str {
+        self.prr.url.as_deref().unwrap_or(default)
+    }
+
+    /// Returns whether TLS certificate verification is disabled
+    pub fn insecure(&self) -> bool {
+        self.prr.insecure
+    }
+
+    /// Returns the configured prefix of quoted lines in review files, if any
+    pub fn quote_prefix(&self) -> Option<&str> {
+        self.prr.quote_prefix.as_deref()
+    }
+
+    /// Returns whether to strip trailing whitespace from comment lines before submitting
+    pub fn strip_trailing_whitespace(&self) -> bool {
+        self.prr.strip_trailing_whitespace
+    }
+
+    /// Returns the footer `prr submit --append-signature` adds to the review comment
+    pub fn signature(&self) -> Option<&str> {
+        self.prr.signature.as_deref()
+    }
+
+    /// Returns the action of reviews without an action directive
+    pub fn default_action(&self) -> parser::ReviewAction {
+        self.prr.default_action.unwrap_or_default()
+    }
+
+    /// Returns the review of `pr_num` in `dir`, set up to parse comments the way the
+    /// config says
+    ///
+    /// Everything that reads the comments of a review file goes through this, so that
+    /// submitting, linting and `prr status` agree on what the file holds.
+    pub fn review(&self, dir: &Path, pr_num: u64) -> review::Review {
+        review::Review::new_existing(dir, pr_num)
+            .with_snippets(self.snippets.clone())
+            .default_action(self.default_action())
+            .strip_trailing_whitespace(self.strip_trailing_whitespace())
+    }
+
+    /// Returns the cache of diffs fetched into `review_dir`
+    pub fn diff_cache(&self, review_dir: &Path) -> cache::DiffCache {
+        let ttl = self.prr.cache_ttl_secs.unwrap_or(cache::DEFAULT_TTL_SECS);
+        cache::DiffCache::new(review_dir, Duration::from_secs(ttl))
+    }
+
+    /// Returns the texts `@prr snip <name>` expands to, by name
+    pub fn snippets(&self) -> &HashMap<String, String> {
+        &self.snippets
+    }
+
+    /// Describes the configuration in effect, one `key: value` per line
+    pub fn describe(&self, path: &Path) -> Result<String> {
+        let token = if self.prr.token.is_empty() {
+            "not set".to_string()
+        } else {
+            format!("set ({} characters)", self.prr.token.chars().count())
+        };
+        let timeout = self
+            .prr
+            .timeout_secs
+            .unwrap_or(api::client::DEFAULT_TIMEOUT_SECS);
+
+        let mut out = String::new();
+        out += &format!("config file: {}\n", path.display());
+        out += &format!("token: {}\n", token);
+        out += &format!("default host: {}\n", self.default_host()?.name());
+        for host in [Host::Github, Host::Gitlab] {
+            out += &format!(
+                "{} url: {}\n",
+                host.name(),
+                self.host_or(host.default_url())
+            );
+        }
+        out += &format!("workdir: {}\n", self.workdir()?.display());
+        out += &format!(
+            "workdir layout: {}\n",
+            self.prr
+                .workdir_layout
+                .as_deref()
+                .unwrap_or(review::DEFAULT_LAYOUT)
+        );
+        out += &format!("timeout: {}s\n", timeout);
+        out += &format!(
+            "diff cache ttl: {}s\n",
+            self.prr.cache_ttl_secs.unwrap_or(cache::DEFAULT_TTL_SECS)
+        );
+        out += &format!(
+            "proxy: {}\n",
+            self.prr.proxy.as_deref().unwrap_or("from environment")
+        );
+        out += &format!(
+            "ca cert: {}\n",
+            self.prr.ca_cert.as_deref().unwrap_or("none")
+        );
+        out += &format!("insecure: {}\n", self.prr.insecure);
+        out += &format!(
+            "quote prefix: '{}'\n",
+            self.quote_prefix().unwrap_or(parser::DEFAULT_QUOTE_PREFIX)
+        );
+        out += &format!(
+            "strip trailing whitespace: {}\n",
+            self.prr.strip_trailing_whitespace
+        );
+        // Spelled like in the config
+        let default_action = match self.default_action() {
+            parser::ReviewAction::Approve => "approve",
+            parser::ReviewAction::RequestChanges => "reject",
+            parser::ReviewAction::Comment => "comment",
+        };
+        out += &format!("default action: {}\n", default_action);
+        // Quoted, since a signature may span several lines
+        let signature = match self.signature() {
+            Some(s) => format!("{:?}", s),
+            None => "none".to_string(),
+        };
+        out += &format!("signature: {}\n", signature);
+
+        Ok(out)
+    }
+}
+
+/// Expands a leading `~` in `path` to the home directory
+fn expand_home(path: &str) -> Result<PathBuf> {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) => rest,
+        None => return Ok(PathBuf::from(path)),
+    };
+    if !rest.is_empty() && !rest.starts_with('/') {
+        bail!(
+            "Only '~' for your own home directory is supported, found '{}'",
+            path
+        );
+    }
+
+    let home = std::env::var_os("HOME").context("Cannot expand '~': HOME is not set")?;
+    Ok(PathBuf::from(home).join(rest.trim_start_matches('/')))
+}
+
+/// Checks that an owner or repo name is something we can safely hand to the API and
+/// use as a path component
+///
+/// Owners may consist of multiple `/` separated segments if `allow_segments`, ie. on GitLab
+/// where they can be nested groups. Repos may not.
+fn validate_name(kind: &str, name: &str, allow_segments: bool) -> Result<()> {
+    if !allow_segments && name.contains('/') {
+        bail!("Invalid {} '{}': must not contain '/'", kind, name);
+    }
+
+    for segment in name.split('/') {
+        if segment.is_empty() {
+            bail!("Invalid {} '{}': empty path segment", kind, name);
+        }
+        if segment == "." || segment == ".." {
+            bail!("Invalid {} '{}': path traversal is not allowed", kind, name);
+        }
+        if segment.chars().any(|c| c.is_whitespace() || c == '\\') {
+            bail!(
+                "Invalid {} '{}': contains whitespace or backslash",
+                kind,
+                name
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses a PR string and returns a tuple (Host::Github, "danobi", "prr", 24) or an error if
+/// string is malformed
+///
+/// Allowed formats:
+/// - `danobi/prr/24` (uses `default_host`)
+/// - `gitlab:danobi/prr/24` (or any alias accepted by `Host::from_str`)
+/// - `git@github.com:danobi/prr.git#24`
+///
+/// Only GitLab owners may be nested groups, eg. `gitlab:group/subgroup/prr/24`.
+pub fn parse_pr_str<'a>(s: &'a str, default_host: Host) -> Result<(Host, String, String, u64)> {
+    let f = |host_override: Option<Host>,
+             captures: Captures<'a>|
+     -> Result<(Host, String, String, u64)> {
+        let host = match (host_override, captures.name("host")) {
+            (Some(host), _) => host,
+            (None, Some(h)) => Host::from_str(h.as_str())
+                .with_context(|| format!("Unknown host '{}'", h.as_str()))?,
+            (None, None) => default_host,
+        };
+        let owner = captures.name("org").unwrap().as_str().to_owned();
+        let repo = captures.name("repo").unwrap().as_str().to_owned();
+        validate_name("owner", &owner, host == Host::Gitlab)?;
+        validate_name("repo", &repo, false)?;
+        let pr_nr: u64 = captures
+            .name("pr_num")
+            .unwrap()
+            .as_str()
+            .parse()
+            .context("Failed to parse pr number")?;
+
+        Ok((host, owner, repo, pr_nr))
+    };
+
+    if let Some(captures) = SHORT.captures(s) {
+        f(None, captures)
+    } else if let Some(captures) = SSH.captures(s) {
+        let hostname = captures.name("hostname").unwrap().as_str();
+        let host = Host::from_hostname(hostname)
+            .with_context(|| format!("Unknown host in SSH ref: {}", hostname))?;
+        f(Some(host), captures)
+    } else if let Some(captures) = api::github::URL.captures(s) {
+        f(Some(Host::Github), captures)
+    } else if let Some(captures) = api::gitlab::URL.captures(s) {
+        f(Some(Host::Gitlab), captures)
+    } else {
+        bail!("Invalid PR ref format")
+    }
+}
+
+/// Parses a repository string and returns a tuple (Host::Github, "danobi", "prr")
+///
+/// Allowed formats:
+/// - `danobi/prr` (uses `default_host`)
+/// - `gitlab:danobi/prr` (or any alias accepted by `Host::from_str`)
+pub fn parse_repo_str(s: &str, default_host: Host) -> Result<(Host, String, String)> {
+    let captures = REPO.captures(s).context("Invalid repository format")?;
+    let host = match captures.name("host") {
+        Some(h) => {
+            Host::from_str(h.as_str()).with_context(|| format!("Unknown host '{}'", h.as_str()))?
+        }
+        None => default_host,
+    };
+    let owner = captures.name("org").unwrap().as_str().to_owned();
+    let repo = captures.name("repo").unwrap().as_str().to_owned();
+    validate_name("owner", &owner, host == Host::Gitlab)?;
+    validate_name("repo", &repo, false)?;
+    // Owners may be nested groups, so `danobi/prr/24` would otherwise be repo `24`
+    if repo.chars().all(|c| c.is_ascii_digit()) {
+        bail!("'{}' looks like a pull request, expected a repository", s);
+    }
+
+    Ok((host, owner, repo))
+}
+
+/// Figures out the host, owner, and repo from the `origin` remote of the local git repo
+pub fn parse_git_remote() -> Result<(Host, String, String)> {
+    let url = git::remote_url("origin").context("Failed to read origin remote")?;
+    let (hostname, owner, repo) = git::parse_remote_url(&url)?;
+    let host = Host::from_hostname(&hostname)
+        .with_context(|| format!("Unknown host in origin remote: {}", hostname))?;
+    validate_name("owner", &owner, host == Host::Gitlab)?;
+    validate_name("repo", &repo, false)?;
+
+    Ok((host, owner, repo))
+}
+
+/// Finds the single open pull request for the currently checked out branch
+///
+/// The branch is looked for in the repo it is pushed to, which is a fork when the pull
+/// request comes from one.
+pub fn pr_for_current_branch(api: &dyn Api, owner: &str, repo: &str) -> Result<u64> {
+    let branch = git::current_branch()?;
+    let remote = git::push_remote(&branch);
+    let url =
+        git::remote_url(&remote).with_context(|| format!("Failed to read {} remote", remote))?;
+    let (_, head_owner, head_name) = git::parse_remote_url(&url)?;
+    let head_repo = format!("{}/{}", head_owner, head_name);
+    let prs = api.open_prs_for_branch(owner, repo, &head_repo, &branch)?;
+    match prs.as_slice() {
+        [pr_num] => Ok(*pr_num),
+        [] => bail!("No open pull request found for branch '{}'", branch),
+        _ => bail!(
+            "Multiple open pull requests found for branch '{}': {:?}",
+            branch,
+            prs
+        ),
+    }
+}
+
+/// What `submit()` does before submitting a review
+#[derive(Debug, Default)]
+pub struct SubmitChecks {
+    /// Warn about comments that are likely mistakes. See `Review::lint()`
+    pub lint: bool,
+    /// Check the review file every time it is saved and only submit it once Enter is
+    /// pressed. Needs a terminal
+    pub watch: bool,
+}
+
+/// Submits `review` to pull request `owner/repo` like `prr submit` does
+///
+/// Refuses reviews that were already submitted and points out partial ones before
+/// handing the review to `api`. See `SubmitChecks` for the optional checks.
+pub fn submit(
+    api: &dyn Api,
+    review: &Review,
+    owner: &str,
+    repo: &str,
+    opts: &SubmitOptions,
+    checks: &SubmitChecks,
+) -> Result<()> {
+    let pr_ref = format!("{}/{}/{}", owner, repo, review.pr_num());
+    if review.has_metadata() {
+        let metadata = review.read_metadata()?;
+        if metadata.submitted.is_some() {
+            bail!(
+                "Review for {} was already submitted. Run `prr reopen {}` to submit it again, \
+                or `prr get -f {}` to start over",
+                pr_ref,
+                pr_ref,
+                pr_ref
+            );
+        }
+        if !metadata.paths.is_empty() {
+            eprintln!(
+                "Submitting a partial review, it only covers the files matching {}",
+                metadata.paths.join(", ")
+            );
+        }
+        if checks.lint {
+            for warning in review.lint()? {
+                color::warn(warning);
+            }
+        }
+    }
+
+    if checks.watch {
+        if !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+            bail!("--watch needs a terminal");
+        }
+        if !review.has_metadata() {
+            bail!("No review for {}, run `prr get {}` first", pr_ref, pr_ref);
+        }
+        if !watch(review)? {
+            bail!("Input closed, not submitting");
+        }
+    }
+
+    api.submit_pr(owner, repo, review.pr_num(), opts)
+}
+
+/// Describes what submitting `comments` would post, for `SubmitChecks::watch`
+fn describe_comments(comments: &ReviewComments) -> String {
+    let action = match comments.action {
+        ReviewAction::Approve => "approve",
+        ReviewAction::RequestChanges => "request changes",
+        ReviewAction::Comment => "comment",
+    };
+    let mut parts = Vec::new();
+    if !comments.comment.is_empty() {
+        parts.push("a review comment".to_string());
+    }
+    for (n, one, many) in [
+        (comments.inline.len(), "inline comment", "inline comments"),
+        (comments.reactions.len(), "reaction", "reactions"),
+        (comments.amends.len(), "amend", "amends"),
+        (comments.replies.len(), "reply", "replies"),
+        (comments.commits.len(), "commit comment", "commit comments"),
+    ] {
+        match n {
+            0 => (),
+            1 => parts.push(format!("1 {}", one)),
+            n => parts.push(format!("{} {}", n, many)),
+        }
+    }
+
+    if parts.is_empty() {
+        format!("Would {} without any comments", action)
+    } else {
+        format!("Would {} with {}", action, parts.join(", "))
+    }
+}
+
+/// Checks `review` every time it is saved, until Enter is pressed while it parses
+///
+/// Returns `false` if stdin was closed instead.
+fn watch(review: &Review) -> Result<bool> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for _ in std::io::stdin().lines() {
+            if tx.send(()).is_err() {
+                break;
+            }
+        }
+    });
+
+    eprintln!(
+        "Watching {}, press Enter to submit or Ctrl-C to quit",
+        review.path().display()
+    );
+    let mut modified = None;
+    let mut valid = false;
+    loop {
+        let mtime = fs::metadata(review.path()).and_then(|m| m.modified()).ok();
+        if mtime != modified {
+            modified = mtime;
+            match review.comments() {
+                Ok(comments) => {
+                    valid = true;
+                    eprintln!("{}", describe_comments(&comments));
+                }
+                Err(e) => {
+                    valid = false;
+                    color::error(format!("{:#}", e));
+                }
+            }
+        }
+
+        match rx.recv_timeout(WATCH_INTERVAL) {
+            Ok(()) if valid => return Ok(true),
+            Ok(()) => eprintln!("Fix the errors above before submitting"),
+            Err(RecvTimeoutError::Timeout) => (),
+            Err(RecvTimeoutError::Disconnected) => return Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn parse_ssh_github() {
+        let (host, owner, repo, pr_num) =
+            parse_pr_str("git@github.com:danobi/prr.git#24", Host::Github).unwrap();
+        assert_eq!(host, Host::Github);
+        assert_eq!(owner, "danobi");
+        assert_eq!(repo, "prr");
+        assert_eq!(pr_num, 24);
+    }
+
+    #[test]
+    fn parse_ssh_gitlab() {
+        let (host, owner, repo, pr_num) =
+            parse_pr_str("git@gitlab.com:danobi/prr#3", Host::Github).unwrap();
+        assert_eq!(host, Host::Gitlab);
+        assert_eq!(owner, "danobi");
+        assert_eq!(repo, "prr");
+        assert_eq!(pr_num, 3);
+
+        let (host, owner, repo, pr_num) = parse_pr_str(
+            "ssh://git@gitlab.example.com/danobi/prr.git#5",
+            Host::Github,
+        )
+        .unwrap();
+        assert_eq!(host, Host::Gitlab);
+        assert_eq!(owner, "danobi");
+        assert_eq!(repo, "prr");
+        assert_eq!(pr_num, 5);
+    }
+
+    #[test]
+    fn parse_ssh_unknown_host() {
+        assert!(parse_pr_str("git@example.com:danobi/prr.git#24", Host::Github).is_err());
+    }
+
+    #[test]
+    fn parse_short_and_url() {
+        let (host, owner, repo, pr_num) = parse_pr_str("danobi/prr/24", Host::Github).unwrap();
+        assert_eq!(host, Host::Github);
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("danobi", "prr", 24)
+        );
+
+        let (host, _, _, pr_num) = parse_pr_str(
+            "https://github.com/danobi/prr-test-repo/pull/6",
+            Host::Github,
+        )
+        .unwrap();
+        assert_eq!(host, Host::Github);
+        assert_eq!(pr_num, 6);
+    }
+
+    #[test]
+    fn parse_url_suffixes() {
+        for url in [
+            "https://github.com/danobi/prr/pull/6/files",
+            "https://github.com/danobi/prr/pull/6/files#diff-0123abcd",
+            "https://github.com/danobi/prr/pull/6/commits/0123abcd",
+            "https://github.com/danobi/prr/pull/6#discussion_r123456",
+            "https://github.com/danobi/prr/pull/6?w=1",
+        ] {
+            let (host, owner, repo, pr_num) = parse_pr_str(url, Host::Gitlab).unwrap();
+            assert_eq!(host, Host::Github, "{}", url);
+            assert_eq!(
+                (owner.as_str(), repo.as_str(), pr_num),
+                ("danobi", "prr", 6),
+                "{}",
+                url
+            );
+        }
+
+        let url = "https://gitlab.com/group/sub/prr/-/merge_requests/7/diffs#note_1";
+        let (host, owner, repo, pr_num) = parse_pr_str(url, Host::Github).unwrap();
+        assert_eq!(host, Host::Gitlab);
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("group/sub", "prr", 7)
+        );
+
+        // The number has to end at a path segment boundary
+        assert!(parse_pr_str("https://github.com/danobi/prr/pull/6abc", Host::Github).is_err());
+    }
+
+    #[test]
+    fn parse_subgroups() {
+        let (host, owner, repo, pr_num) =
+            parse_pr_str("gitlab:group/subgroup/project/6", Host::Github).unwrap();
+        assert_eq!(host, Host::Gitlab);
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("group/subgroup", "project", 6)
+        );
+
+        let (_, owner, repo, pr_num) = parse_pr_str("a/b/c/project/7", Host::Gitlab).unwrap();
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("a/b/c", "project", 7)
+        );
+
+        let (_, owner, repo, pr_num) =
+            parse_pr_str("git@gitlab.com:group/subgroup/project.git#8", Host::Github).unwrap();
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("group/subgroup", "project", 8)
+        );
+
+        let (_, owner, repo) = parse_repo_str("gitlab:a/b/c/project", Host::Github).unwrap();
+        assert_eq!((owner.as_str(), repo.as_str()), ("a/b/c", "project"));
+
+        // GitHub has no nested owners
+        assert!(parse_pr_str("a/b/c/project/7", Host::Github).is_err());
+        assert!(parse_pr_str("github:group/subgroup/project/6", Host::Gitlab).is_err());
+        assert!(parse_pr_str("git@github.com:group/subgroup/project.git#8", Host::Github).is_err());
+        assert!(parse_repo_str("a/b/c/project", Host::Github).is_err());
+
+        // Each level of the groups ends up in the review path
+        let config: Config = toml::from_str("[prr]\ntoken = \"\"\nworkdir = \"/w\"").unwrap();
+        let dir = config
+            .review_dir("gitlab.com", "group/subgroup", "project")
+            .unwrap();
+        assert_eq!(dir, PathBuf::from("/w/gitlab.com/group/subgroup/project"));
+    }
+
+    #[test]
+    fn parse_url_paths() {
+        let parse = |url: &str| parse_pr_str(url, Host::Github).unwrap();
+
+        assert_eq!(
+            parse("https://gitlab.com/group/subgroup/repo/-/merge_requests/3"),
+            (
+                Host::Gitlab,
+                "group/subgroup".to_string(),
+                "repo".to_string(),
+                3
+            )
+        );
+        assert_eq!(
+            parse("gitlab.com/a/b/c/d/-/merge_requests/4/commits"),
+            (Host::Gitlab, "a/b/c".to_string(), "d".to_string(), 4)
+        );
+        assert_eq!(
+            parse("https://www.github.com/danobi/prr/pull/5"),
+            (Host::Github, "danobi".to_string(), "prr".to_string(), 5)
+        );
+
+        // A pull request path further down must not be mistaken for the project
+        assert_eq!(
+            parse("https://github.com/danobi/prr/pull/6/files#diff-pull/7"),
+            (Host::Github, "danobi".to_string(), "prr".to_string(), 6)
+        );
+        // The host has to come first, not somewhere in the path of another site
+        assert!(parse_pr_str(
+            "https://example.com/github.com/danobi/prr/pull/6",
+            Host::Github
+        )
+        .is_err());
+        // GitHub has no nested owners
+        assert!(parse_pr_str("https://github.com/org/team/prr/pull/6", Host::Github).is_err());
+    }
+
+    #[test]
+    fn parse_host_alias() {
+        let (host, owner, repo, pr_num) =
+            parse_pr_str("GitHub:danobi/prr/1", Host::Github).unwrap();
+        assert_eq!(host, Host::Github);
+        assert_eq!(
+            (owner.as_str(), repo.as_str(), pr_num),
+            ("danobi", "prr", 1)
+        );
+
+        let (host, _, _, _) = parse_pr_str("gl:danobi/prr/1", Host::Github).unwrap();
+        assert_eq!(host, Host::Gitlab);
+    }
+
+    #[test]
+    fn parse_default_host() {
+        let (host, _, _, _) = parse_pr_str("danobi/prr/1", Host::Gitlab).unwrap();
+        assert_eq!(host, Host::Gitlab);
+
+        let (host, _, _, _) = parse_pr_str("github:danobi/prr/1", Host::Gitlab).unwrap();
+        assert_eq!(host, Host::Github);
+
+        // An unknown prefix is an error, not the default host
+        for pr in ["gitlba:danobi/prr/1", "bitbucket:danobi/prr/1"] {
+            let err = parse_pr_str(pr, Host::Gitlab).unwrap_err();
+            assert!(err.to_string().starts_with("Unknown host '"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn config_default_host() {
+        let parse = |s: &str| toml::from_str::<Config>(s).unwrap().default_host();
+
+        assert_eq!(parse("[prr]\ntoken = \"\"").unwrap(), Host::Github);
+        assert_eq!(
+            parse("[prr]\ntoken = \"\"\ndefault_host = \"GitLab\"").unwrap(),
+            Host::Gitlab
+        );
+        assert!(parse("[prr]\ntoken = \"\"\ndefault_host = \"bitbucket\"").is_err());
+    }
+
+    #[test]
+    fn config_default_action() {
+        use crate::parser::ReviewAction;
+        let parse = |s: &str| toml::from_str::<Config>(&format!("[prr]\ntoken = \"\"\n{}", s));
+
+        assert_eq!(parse("").unwrap().default_action(), ReviewAction::Comment);
+        for (value, action) in [
+            ("comment", ReviewAction::Comment),
+            ("approve", ReviewAction::Approve),
+            ("reject", ReviewAction::RequestChanges),
+        ] {
+            let config = parse(&format!("default_action = \"{}\"", value)).unwrap();
+            assert_eq!(config.default_action(), action);
+        }
+        assert!(parse("default_action = \"request-changes\"").is_err());
+        assert!(parse("default_action = \"Approve\"").is_err());
+    }
+
+    #[test]
+    fn config_review() {
+        use crate::parser::ReviewAction;
+        use crate::review::{Extra, Review};
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = "[prr]\ntoken = \"\"\ndefault_action = \"approve\"\n\
+            strip_trailing_whitespace = true\n[snippets]\nnit = \"nit:\"";
+        let config: Config = toml::from_str(config).unwrap();
+        let diff = include_str!("../testdata/diffs/single_file").to_string();
+        let review = Review::new(dir.path(), diff, 1, Extra::default(), false).unwrap();
+        let quoted = std::fs::read_to_string(review.path()).unwrap();
+        std::fs::write(
+            review.path(),
+            format!("@prr snip nit  \nRename this  \n\n{}", quoted),
+        )
+        .unwrap();
+
+        let comments = config.review(dir.path(), 1).comments().unwrap();
+        assert_eq!(comments.action, ReviewAction::Approve);
+        assert_eq!(comments.comment, "nit:\nRename this");
+    }
+
+    #[test]
+    fn parse_repo() {
+        let (host, owner, repo) = parse_repo_str("danobi/prr", Host::Github).unwrap();
+        assert_eq!(host, Host::Github);
+        assert_eq!((owner.as_str(), repo.as_str()), ("danobi", "prr"));
+
+        let (host, _, repo) = parse_repo_str("gl:danobi/prr.rs", Host::Github).unwrap();
+        assert_eq!(host, Host::Gitlab);
+        assert_eq!(repo, "prr.rs");
+
+        assert!(parse_repo_str("danobi/prr/24", Host::Github).is_err());
+        assert!(parse_repo_str("bitbucket:danobi/prr", Host::Github).is_err());
+        assert!(parse_repo_str("danobi/..", Host::Github).is_err());
+    }
+
+    #[test]
+    fn workdir_override() {
+        let mut config: Config =
+            toml::from_str("[prr]\ntoken = \"\"\nworkdir = \"/from/config\"").unwrap();
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/from/config"));
+
+        config.apply_overrides(&Overrides::default()).unwrap();
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/from/config"));
+
+        let overrides = Overrides {
+            workdir: Some("/from/flag".to_string()),
+            ..Default::default()
+        };
+        config.apply_overrides(&overrides).unwrap();
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/from/flag"));
+    }
+
+    #[test]
+    fn config_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[prr]\ntoken = \"\"\nworkdir = \"/from/dir\"").unwrap();
+
+        let resolved = Config::path(None, Some(dir.path())).unwrap();
+        assert_eq!(resolved, path);
+        let config = Config::load(&resolved).unwrap();
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/from/dir"));
+
+        // An explicit config file takes precedence
+        let file = Path::new("/explicit.toml");
+        assert_eq!(Config::path(Some(file), Some(dir.path())).unwrap(), file);
+        assert_eq!(
+            Config::path(None, None).unwrap(),
+            Config::default_path().unwrap()
+        );
+    }
+
+    #[test]
+    fn describe_config_hides_token() {
+        let config: Config = toml::from_str(
+            "[prr]\ntoken = \"ghp_secret\"\nworkdir = \"/reviews\"\ndefault_host = \"gl\"\nurl = \"https://git.example.com\"",
+        )
+        .unwrap();
+        let out = config.describe(Path::new("/prr.toml")).unwrap();
+
+        assert!(!out.contains("ghp_secret"));
+        assert!(out.contains("token: set (10 characters)\n"));
+        assert!(out.contains("config file: /prr.toml\n"));
+        assert!(out.contains("default host: gitlab\n"));
+        assert!(out.contains("gitlab url: https://git.example.com\n"));
+        assert!(out.contains("workdir: /reviews\n"));
+        assert!(out.contains("timeout: 30s\n"));
+        assert!(out.contains("diff cache ttl: 300s\n"));
+        assert!(out.contains("quote prefix: '> '\n"));
+        assert!(out.contains("signature: none\n"));
+        assert!(out.contains("default action: comment\n"));
+    }
+
+    #[test]
+    fn profiles() {
+        let config = r#"
+            [prr]
+            token = "default"
+            workdir = "/reviews"
+
+            [profile.work]
+            token = "work"
+            url = "https://github.example.com/api/v3"
+            workdir = "/work"
+
+            [profile.personal]
+            token = "personal"
+        "#;
+        let load = |profile: Option<&str>, workdir: Option<&str>| -> Result<Config> {
+            let mut config: Config = toml::from_str(config).unwrap();
+            config.apply_overrides(&Overrides {
+                profile: profile.map(str::to_string),
+                workdir: workdir.map(str::to_string),
+                insecure: false,
+            })?;
+            Ok(config)
+        };
+
+        let config = load(None, None).unwrap();
+        assert_eq!(config.prr.token, "default");
+
+        let config = load(Some("work"), None).unwrap();
+        assert_eq!(config.prr.token, "work");
+        assert_eq!(
+            config.prr.url.as_deref(),
+            Some("https://github.example.com/api/v3")
+        );
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/work"));
+
+        // Profiles only override what they set
+        let config = load(Some("personal"), None).unwrap();
+        assert_eq!(config.prr.token, "personal");
+        assert_eq!(config.prr.url, None);
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/reviews"));
+
+        // Flags win over profiles
+        let config = load(Some("work"), Some("/tmp")).unwrap();
+        assert_eq!(config.workdir().unwrap(), PathBuf::from("/tmp"));
+
+        let err = load(Some("nope"), None).unwrap_err();
+        assert!(err.to_string().contains("No profile 'nope'"));
+    }
+
+    #[test]
+    fn workdir_home() {
+        let home = PathBuf::from(std::env::var_os("HOME").unwrap());
+        assert_eq!(expand_home("~").unwrap(), home);
+        assert_eq!(expand_home("~/reviews").unwrap(), home.join("reviews"));
+        assert_eq!(expand_home("/tmp/~").unwrap(), PathBuf::from("/tmp/~"));
+        assert!(expand_home("~danobi/reviews").is_err());
+    }
+
+    #[test]
+    fn parse_malformed_names() {
+        assert!(parse_pr_str("https://github.com/../prr/pull/1", Host::Github).is_err());
+        assert!(parse_pr_str("https://github.com/danobi/../pull/1", Host::Github).is_err());
+        assert!(parse_pr_str("https://github.com/dan obi/prr/pull/1", Host::Github).is_err());
+        assert!(parse_pr_str("https://github.com//prr/pull/1", Host::Github).is_err());
+        assert!(parse_pr_str("git@github.com:danobi/..#1", Host::Github).is_err());
+        // Nothing may follow the number of a short ref
+        assert!(parse_pr_str("danobi/prr/24/files", Host::Github).is_err());
+        assert!(parse_pr_str("danobi/prr/24x", Host::Github).is_err());
+        assert!(parse_pr_str("danobi/prr/24 ", Host::Github).is_err());
+    }
+
+    #[test]
+    fn validate_names() {
+        assert!(validate_name("owner", "danobi", true).is_ok());
+        assert!(validate_name("owner", "group/subgroup", true).is_ok());
+        assert!(validate_name("repo", "prr.rs", false).is_ok());
+        assert!(validate_name("repo", "a/b", false).is_err());
+        assert!(validate_name("owner", "a//b", true).is_err());
+        assert!(validate_name("owner", "a/../b", true).is_err());
+        assert!(validate_name("repo", "", false).is_err());
+        assert!(validate_name("repo", "p\trr", false).is_err());
+    }
+
+    #[test]
+    fn submit_checks() {
+        use crate::api::mock::MockApi;
+        use crate::api::GetOptions;
+
+        let workdir = tempfile::tempdir().unwrap();
+        let mut api = MockApi::new(workdir.path(), "").unwrap();
+        api.add_pr(1, include_str!("../testdata/diffs/multiple_files"));
+        let review = api
+            .get_pr("danobi", "prr", 1, &GetOptions::default())
+            .unwrap();
+        fs::write(review.path(), include_str!("../testdata/multiple_files")).unwrap();
+
+        let opts = SubmitOptions::default();
+        let checks = SubmitChecks::default();
+        submit(&api, &review, "danobi", "prr", &opts, &checks).unwrap();
+        assert_eq!(api.submissions.borrow().len(), 1);
+
+        let err = submit(&api, &review, "danobi", "prr", &opts, &checks).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("danobi/prr/1 was already submitted"),
+            "{}",
+            err
+        );
+        assert_eq!(api.submissions.borrow().len(), 1);
+    }
+
+    #[test]
+    fn watch_summary() {
+        let mut comments = ReviewComments {
+            action: ReviewAction::Approve,
+            comment: String::new(),
+            inline: Vec::new(),
+            reactions: Vec::new(),
+            amends: Vec::new(),
+            replies: Vec::new(),
+            commits: Vec::new(),
+        };
+        assert_eq!(
+            describe_comments(&comments),
+            "Would approve without any comments"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files").to_string();
+        let review = Review::new(dir.path(), diff, 1, Default::default(), false).unwrap();
+        fs::write(review.path(), include_str!("../testdata/multiple_files")).unwrap();
+        let parsed = review.comments().unwrap();
+        comments.comment = "LGTM".to_string();
+        comments.inline = parsed.inline;
+        assert_eq!(
+            describe_comments(&comments),
+            "Would approve with a review comment, 2 inline comments"
+        );
+    }
+}
@@ -1,25 +1,25 @@
+use std::fs;
+use std::io::{IsTerminal, Write};
 use std::path::{Path, PathBuf};
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
-use lazy_static::lazy_static;
-use regex::{Captures, Regex};
-use serde::Deserialize;
+use clap::{CommandFactory, Parser, Subcommand};
 
-mod api;
-mod parser;
-mod review;
+use prr::api::client::RequestError;
+use prr::api::{self, Host, SubmitFailure};
+use prr::parser::ParseError;
+use prr::review::Review;
+use prr::{
+    color, parse_git_remote, parse_pr_str, parse_repo_str, pr_for_current_branch, stack, Config,
+    Overrides, SubmitChecks,
+};
 
-use api::Host;
-
-// Use lazy static to ensure regex is only compiled once
-lazy_static! {
-    // Regex for short input. Example:
-    //
-    //      [<host>:]danobi/prr-test-repo/6
-    //
-    static ref SHORT: Regex = Regex::new(r"^((?P<host>\w+):)?(?P<org>[\w\-_]+)/(?P<repo>[\w\-_]+)/(?P<pr_num>\d+)").unwrap();
-}
+/// Exit code when prr fails, or a submission did not get any inline comment posted
+const EXIT_FAILURE: i32 = 1;
+/// Exit code when a submission got only some of the inline comments posted
+///
+/// Not 2, as clap already exits with that on invalid arguments.
+const EXIT_PARTIAL: i32 = 3;
 
 #[derive(Subcommand, Debug)]
 enum Command {
@@ -28,8 +28,90 @@ enum Command {
         /// Ignore unsubmitted review checks
         #[clap(short, long)]
         force: bool,
+        /// Print a machine readable JSON summary instead of the review file path
+        #[clap(long)]
+        porcelain: bool,
+        /// Only fetch what changed since your last review (GitHub only)
+        ///
+        /// Falls back to the whole pull request if you have not reviewed it yet
+        #[clap(long)]
+        since_last_review: bool,
+        /// Fetch the pull request in patch format, with the metadata of each commit (GitHub only)
+        #[clap(long)]
+        patch: bool,
+        /// Show existing comment threads in the review file, below the lines they are on
+        #[clap(long, conflicts_with_all = &["since-last-review", "patch"])]
+        with_comments: bool,
+        /// Only review the files matching this glob (can be repeated)
+        ///
+        /// `*` does not match across directories but `**` does. A glob without a `/`
+        /// matches the file name in any directory, eg. `*.rs`
+        #[clap(long, value_name = "GLOB")]
+        path: Vec<String>,
+        /// Only review the changes of this commit of the pull request (GitHub only)
+        ///
+        /// Comments are placed on the commit rather than on the latest version of the pull
+        /// request. The SHA may be abbreviated
+        #[clap(long, value_name = "SHA", conflicts_with_all = &["since-last-review", "with-comments"])]
+        commit: Option<String>,
+        /// Show the log of the pull request's commits above the diff, to comment on them
+        ///
+        /// A comment below a commit's entry in the log is posted on that commit
+        #[clap(long, conflicts_with = "patch")]
+        commit_log: bool,
+        /// Review the changes against this branch, tag or SHA instead of the PR's base
+        ///
+        /// Eg. the branch of the pull request below this one in a stack
+        #[clap(
+            long,
+            value_name = "REF",
+            conflicts_with_all = &["since-last-review", "with-comments", "commit"]
+        )]
+        base: Option<String>,
+        /// Show the new lines each hunk covers below its header, ie. where comments can go
+        #[clap(long)]
+        hunk_ranges: bool,
+        /// Fetch the diff again even if it was fetched within `prr.cache_ttl_secs`
+        #[clap(long)]
+        no_cache: bool,
+        /// Diff against the merge base (three-dot, like the web UI) or the tip of the base
+        ///
+        /// A two-dot diff also shows what the base got since the pull request branched
+        /// off, as reverted. Comments can only go on lines of the three-dot diff
+        #[clap(
+            long,
+            value_enum,
+            value_name = "RANGE",
+            default_value = "three-dot",
+            conflicts_with = "commit"
+        )]
+        diff_range: api::DiffRange,
+        /// Print the review file instead of writing it to the workdir
+        ///
+        /// Nothing is saved, so a review fetched this way can't be submitted
+        #[clap(long, conflicts_with = "porcelain")]
+        stdout: bool,
+        /// Write the review file below this directory instead of the workdir
+        ///
+        /// Works like `--workdir` for this one review, so submit it with
+        /// `prr --workdir <DIR> submit`
+        #[clap(long, value_name = "DIR", conflicts_with = "stdout")]
+        output_dir: Option<String>,
+        /// Also fetch the open pull requests this one is stacked on, into one review file
+        ///
+        /// Each pull request is diffed against the one below it and gets its own section
+        /// in `<PR>.stack.prr`. Submit it with `prr submit --stack`
+        #[clap(
+            long,
+            conflicts_with_all = &[
+                "since-last-review", "with-comments", "commit", "base", "stdout", "porcelain"
+            ]
+        )]
+        stack: bool,
         /// Pull request to review (eg. `danobi/prr/24`)
-        pr: String,
+        ///
+        /// If omitted, the open pull request for the current git branch is used
+        pr: Option<String>,
     },
     /// Submit a review
     Submit {
@@ -37,7 +119,139 @@ enum Command {
         pr: String,
         #[clap(short, long)]
         debug: bool,
+        /// Only submit inline comments on this file (can be repeated)
+        ///
+        /// The review comment, action and reactions are left out unless
+        /// `--include-summary` is given
+        #[clap(long, value_name = "PATH")]
+        only: Vec<String>,
+        /// With `--only`, also submit the review comment, action and reactions
+        #[clap(long, requires = "only")]
+        include_summary: bool,
+        /// Submit inline comments one by one instead of as a single review (GitHub only)
+        ///
+        /// Each comment notifies people right away and stands on its own, instead of
+        /// arriving together with the rest of the review. The review comment is posted
+        /// as a regular comment on the pull request. Since there is no review, it is not
+        /// possible to approve or request changes this way.
+        #[clap(long)]
+        individual: bool,
+        /// Warn about inline comments whose line reads differently in the pull request now
+        ///
+        /// Comments are placed by line number, so if the pull request was force pushed
+        /// since it was fetched they may end up on other lines than intended
+        #[clap(long)]
+        verify_lines: bool,
+        /// Fail if the same comment is on the same line twice, instead of submitting it once
+        #[clap(long)]
+        deny_duplicates: bool,
+        /// Warn about comments that are likely mistakes before submitting
+        ///
+        /// Eg. comments on unchanged lines of an approving review, or comments that are
+        /// empty once their snippet is expanded
+        #[clap(long)]
+        lint: bool,
+        /// Add `prr.signature` from the config below the review comment
+        #[clap(long)]
+        append_signature: bool,
+        /// Submit this review comment instead of the one in the review file
+        #[clap(short, long, value_name = "TEXT")]
+        message: Option<String>,
+        /// Check the review file every time it is saved and submit it once you press Enter
+        ///
+        /// Errors in the review file are printed as soon as it is saved, and nothing is
+        /// submitted until it parses. Only works in a terminal
+        #[clap(long, conflicts_with = "stack")]
+        watch: bool,
+        /// Submit even if the pull request was closed or merged when it was fetched
+        #[clap(short, long)]
+        force: bool,
+        /// Submit the stack file fetched with `get --stack`, each section to its pull request
+        ///
+        /// `PR` is the topmost pull request of the stack
+        #[clap(long, conflicts_with_all = &["message", "only", "lint"])]
+        stack: bool,
+    },
+    /// Print what changed in a pull request since it was last fetched
+    ///
+    /// Does not touch the review file
+    Diff {
+        /// Pull request to check (eg. `danobi/prr/24`)
+        pr: String,
+    },
+    /// Show which files in a review have inline comments and which were skipped
+    Status {
+        /// Pull request of the review (eg. `danobi/prr/24`)
+        pr: String,
+    },
+    /// List the pull requests of a repository
+    Prs {
+        /// Repository to list pull requests of (eg. `danobi/prr`)
+        ///
+        /// If omitted, the repository of the `origin` remote of the current git repo is used
+        repo: Option<String>,
+        /// Only list pull requests in this state
+        #[clap(long, value_enum, default_value = "open")]
+        state: api::PrState,
+    },
+    /// Print who the configured token belongs to, to check that it works
+    Whoami {
+        /// Host to check the token against. Defaults to `prr.default_host`
+        host: Option<String>,
+    },
+    /// Open a pull request in the web browser
+    Browse {
+        /// Pull request to open (eg. `danobi/prr/24`)
+        pr: String,
+        /// Print the URL instead of opening it
+        #[clap(short, long)]
+        print: bool,
+    },
+    /// Print the path of an already fetched review file without fetching anything
+    #[clap(alias = "open")]
+    Path {
+        /// Pull request of the review (eg. `danobi/prr/24`)
+        pr: String,
+    },
+    /// Make a submitted review editable and submittable again
+    ///
+    /// Restores the review file if it was removed, with your earlier comments gone.
+    /// Submitting it again posts every comment in it again.
+    Reopen {
+        /// Pull request of the review (eg. `danobi/prr/24`)
+        pr: String,
     },
+    /// Bring back the review that the last `get --force` overwrote
+    ///
+    /// Reviews with unsubmitted changes are backed up before being overwritten. Each
+    /// restore goes back one more backup, up to the last five
+    Restore {
+        /// Pull request of the review (eg. `danobi/prr/24`)
+        pr: String,
+        /// Restore even if the review file has unsubmitted changes, losing them
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Print the configuration in effect, after applying flags and defaults
+    ///
+    /// The token itself is never printed
+    Config,
+    /// Generate shell completions on stdout
+    #[clap(hide = true)]
+    Completions {
+        #[clap(value_enum)]
+        shell: clap_complete::Shell,
+    },
+}
+
+/// How errors are printed before prr exits
+#[derive(Debug, Default, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum ErrorFormat {
+    /// The error and its causes as text
+    #[default]
+    Human,
+    /// A single line JSON object, for editor integrations. See `error_json()`
+    Json,
 }
 
 #[derive(Parser, Debug)]
@@ -46,119 +260,709 @@ struct Args {
     /// Path to config file
     #[clap(long, parse(from_os_str))]
     config: Option<PathBuf>,
+    /// Directory to look for `config.toml` in instead of `$XDG_CONFIG_HOME/prr`
+    ///
+    /// `--config` takes precedence
+    #[clap(long, parse(from_os_str), value_name = "PATH")]
+    config_dir: Option<PathBuf>,
+    /// Config profile to use, ie. a `[profile.<name>]` table overriding `[prr]`
+    #[clap(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+    /// Directory to place review files in, overriding `prr.workdir`
+    #[clap(long, global = true, value_name = "PATH")]
+    workdir: Option<String>,
+    /// Increase logging verbosity (can be repeated). `RUST_LOG` takes precedence
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    verbose: u64,
+    /// Skip TLS certificate verification. Only meant for testing against local instances
+    #[clap(long, global = true)]
+    insecure: bool,
+    /// Never color output. Setting `NO_COLOR` does the same
+    #[clap(long, global = true)]
+    no_color: bool,
+    /// Only print what was asked for, eg. no review file path after `get`
+    ///
+    /// Errors and warnings are still printed to stderr
+    #[clap(short, long, global = true)]
+    quiet: bool,
+    /// How to print errors
+    #[clap(
+        long,
+        global = true,
+        value_enum,
+        default_value = "human",
+        value_name = "FORMAT"
+    )]
+    error_format: ErrorFormat,
     #[clap(subcommand)]
     command: Command,
 }
 
-#[derive(Debug, Deserialize)]
-struct PrrConfig {
-    /// API token for the given service
-    // TODO per service
-    token: String,
-    /// Directory to place review files
-    workdir: Option<String>,
-    /// Instance URL
-    ///
-    /// Useful for hosted instances with custom URLs
-    // TODO per service
-    url: Option<String>,
+/// Opens `url` in the platform's default browser
+fn open_in_browser(url: &str) -> Result<()> {
+    let mut cmd = if cfg!(target_os = "macos") {
+        std::process::Command::new("open")
+    } else if cfg!(target_os = "windows") {
+        let mut c = std::process::Command::new("cmd");
+        c.args(["/C", "start", ""]);
+        c
+    } else {
+        std::process::Command::new("xdg-open")
+    };
+
+    let status = cmd.arg(url).status().context("Failed to launch browser")?;
+    if !status.success() {
+        bail!("Browser exited with {}", status);
+    }
+
+    Ok(())
 }
 
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    prr: PrrConfig,
+/// Describes the authenticated user for `prr whoami`
+fn describe_user(host: Host, url: &str, login: &str, name: Option<&str>) -> String {
+    let mut out = format!("login: {}\n", login);
+    if let Some(name) = name {
+        out += &format!("name: {}\n", name);
+    }
+    out += &format!("host: {} ({})\n", host.name(), url);
+
+    out
 }
 
-impl Config {
-    fn workdir(&self, host: impl AsRef<Path>) -> Result<PathBuf> {
-        match &self.prr.workdir {
-            Some(d) => {
-                if d.starts_with('~') {
-                    bail!("Workdir may not use '~' to denote home directory");
-                }
+/// Writes the completion script for `shell` to `out`
+fn generate_completions(shell: clap_complete::Shell, out: &mut dyn std::io::Write) {
+    let mut cmd = Args::command();
+    let name = cmd.get_name().to_string();
+    clap_complete::generate(shell, &mut cmd, name, out);
+}
 
-                Ok(PathBuf::from(d))
-            }
-            None => {
-                let xdg_dirs = xdg::BaseDirectories::with_prefix("prr")?;
-                Ok(xdg_dirs.get_data_home())
-            }
-        }
-        .map(|p| p.join(host))
-    }
+/// Sets up the global logger according to the requested verbosity
+fn init_logging(verbose: u64) {
+    let level = match verbose {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
 
-    fn host_or<'s>(&'s self, default: &'s str) -> &'s str {
-        self.prr.url.as_deref().unwrap_or(default)
+    env_logger::Builder::new()
+        .filter_level(level)
+        .write_style(if color::enabled() {
+            env_logger::WriteStyle::Always
+        } else {
+            env_logger::WriteStyle::Never
+        })
+        .parse_default_env()
+        .init();
+}
+
+/// Returns the config values overridden by the command line flags
+fn overrides(args: &Args) -> Overrides {
+    let workdir = match &args.command {
+        Command::Get {
+            output_dir: Some(dir),
+            ..
+        } => Some(dir.clone()),
+        _ => args.workdir.clone(),
+    };
+
+    Overrides {
+        profile: args.profile.clone(),
+        workdir,
+        insecure: args.insecure,
     }
 }
 
-/// Parses a PR string and returns a tuple (Host::Github, "danobi", "prr", 24) or an error if
-/// string is malformed
+/// Checks that files can be created in `dir`, creating it if needed
+fn check_writable(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let probe = dir.join(".prr-write-test");
+    fs::write(&probe, "")
+        .and_then(|_| fs::remove_file(&probe))
+        .with_context(|| format!("{} is not writable", dir.display()))
+}
+
+/// Returns the machine readable summary `get --porcelain` prints for `review`
+fn porcelain_summary(
+    host: Host,
+    owner: &str,
+    repo: &str,
+    pr_num: u64,
+    review: &Review,
+) -> Result<serde_json::Value> {
+    Ok(serde_json::json!({
+        "host": host.name(),
+        "owner": owner,
+        "repo": repo,
+        "pr_num": pr_num,
+        "path": review.path(),
+        "files": review.num_files()?,
+        "draft": review.read_metadata()?.draft,
+    }))
+}
+
+/// Writes what `get` reports on stdout about the fetched `review`
 ///
-/// Allowed formats:
-/// - `danobi/prr/24` (defaults to github)
-/// - `gitlab:danobi/prr/24`
-fn parse_pr_str<'a>(s: &'a str) -> Result<(Host, String, String, u64)> {
-    let f = |host_override: Option<Host>, captures: Captures<'a>|
-        -> Result<(Host, String, String, u64)>
-    {
-        let host = host_override.unwrap_or_else(
-            || captures
-                .name("host")
-                .and_then(|capture| Host::from_str(capture.as_str()))
-                .unwrap_or(Host::Github)
-        );
-        let owner = captures.name("org").unwrap().as_str().to_owned();
-        let repo = captures.name("repo").unwrap().as_str().to_owned();
-        let pr_nr: u64 = captures
-            .name("pr_num")
-            .unwrap()
-            .as_str()
-            .parse()
-            .context("Failed to parse pr number")?;
-
-        Ok((host, owner, repo, pr_nr))
-    };
+/// With `--porcelain` that is its `summary`, else the path of the review file unless
+/// `--quiet` was given.
+fn report_review(
+    out: &mut impl Write,
+    review: &Review,
+    summary: Option<serde_json::Value>,
+    quiet: bool,
+) -> Result<()> {
+    match summary {
+        Some(summary) => writeln!(out, "{}", summary)?,
+        None if !quiet => writeln!(out, "{}", review.path().display())?,
+        None => (),
+    }
 
-    if let Some(captures) = SHORT.captures(s) {
-        f(None, captures)
-    } else if let Some(captures) = api::github::URL.captures(s) {
-        f(Some(Host::Github), captures)
-    } else if let Some(captures) = api::gitlab::URL.captures(s) {
-        f(Some(Host::Gitlab), captures)
-    } else {
-        bail!("Invalid PR ref format")
+    Ok(())
+}
+
+/// Returns the code to exit with after `err`
+fn exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<SubmitFailure>() {
+        Some(failure) if failure.submitted > 0 => EXIT_PARTIAL,
+        _ => EXIT_FAILURE,
+    }
+}
+
+/// Returns the JSON object `--error-format json` prints for `err`
+///
+/// `kind` tells what went wrong, `message` is the error with its causes. Errors in a
+/// review file add the `line` they are on and partially submitted reviews how many inline
+/// comments were `submitted` and how many `failed`.
+fn error_json(err: &anyhow::Error) -> serde_json::Value {
+    let mut json = serde_json::json!({
+        "kind": "other",
+        "message": format!("{:#}", err),
+    });
+    if let Some(parse) = err.downcast_ref::<ParseError>() {
+        json["kind"] = "parse".into();
+        json["line"] = parse.line.into();
+    } else if let Some(failure) = err.downcast_ref::<SubmitFailure>() {
+        json["kind"] = "submit".into();
+        json["submitted"] = failure.submitted.into();
+        json["failed"] = failure.failed.into();
+    } else if let Some(request) = err.downcast_ref::<RequestError>() {
+        json["kind"] = match request {
+            RequestError::Timeout(_) => "timeout",
+            _ => "request",
+        }
+        .into();
     }
+
+    json
 }
 
-fn main() -> Result<()> {
+fn main() {
     let args = Args::parse();
+    let error_format = args.error_format;
+    if let Err(e) = run(args) {
+        match error_format {
+            ErrorFormat::Human => color::error(format!("{:?}", e)),
+            ErrorFormat::Json => eprintln!("{}", error_json(&e)),
+        }
+        std::process::exit(exit_code(&e));
+    }
+}
+
+fn run(args: Args) -> Result<()> {
+    color::init(args.no_color);
+    init_logging(args.verbose);
+
+    // Completions do not need a config file, so handle them before loading one
+    if let Command::Completions { shell } = args.command {
+        generate_completions(shell, &mut std::io::stdout());
+        return Ok(());
+    }
 
     // Figure out where config file is
-    let config_path = match args.config {
-        Some(c) => c,
-        None => {
-            let xdg_dirs = xdg::BaseDirectories::with_prefix("prr")?;
-            xdg_dirs.get_config_file("config.toml")
-        }
-    };
+    let config_path = Config::path(args.config.as_deref(), args.config_dir.as_deref())?;
 
-    let config_contents = std::fs::read_to_string(config_path).context("Failed to read config")?;
-    let config: Config = toml::from_str(&config_contents).context("Failed to parse toml")?;
+    let mut config = Config::load(&config_path)?;
+    let default_host = config.default_host()?;
+    config.apply_overrides(&overrides(&args))?;
+    if config.insecure() {
+        color::warn("TLS certificate verification is disabled. Do not use this in production!");
+    }
 
     match args.command {
-        Command::Get { pr, force } => {
-            let (host, owner, repo, pr_num) = parse_pr_str(&pr)?;
+        Command::Get {
+            pr,
+            force,
+            porcelain,
+            since_last_review,
+            patch,
+            with_comments,
+            path,
+            commit,
+            commit_log,
+            base,
+            hunk_ranges,
+            no_cache,
+            diff_range,
+            stdout,
+            output_dir,
+            stack,
+        } => {
+            if output_dir.is_some() {
+                if args.workdir.is_some() {
+                    bail!("--output-dir and --workdir cannot be used together");
+                }
+                check_writable(&config.workdir()?)?;
+            }
+            let (host, owner, repo, dir, api, pr_num) = match pr {
+                Some(pr) => {
+                    let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+                    let dir =
+                        config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+                    (host, owner, repo, dir, host.init(config)?, pr_num)
+                }
+                None => {
+                    let (host, owner, repo) = parse_git_remote()?;
+                    let dir =
+                        config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+                    let api = host.init(config)?;
+                    let pr_num = pr_for_current_branch(&*api, &owner, &repo)?;
+                    (host, owner, repo, dir, api, pr_num)
+                }
+            };
+            let mut opts = api::GetOptions {
+                force,
+                patch,
+                with_comments,
+                progress: !porcelain && !args.quiet && std::io::stderr().is_terminal(),
+                paths: path,
+                commit,
+                commit_log,
+                base,
+                hunk_ranges,
+                no_cache,
+                range: diff_range,
+                stdout,
+                ..Default::default()
+            };
+            if since_last_review {
+                opts.since = api.last_review_commit(&owner, &repo, pr_num)?;
+                if opts.since.is_none() && !args.quiet {
+                    eprintln!("No previous review found, fetching the whole pull request");
+                }
+            }
+            if stack {
+                let prs = stack::find(&*api, &owner, &repo, pr_num)?;
+                let path = stack::get(&*api, &dir, &owner, &repo, &prs, &opts)?;
+                if !args.quiet {
+                    let numbers: Vec<String> =
+                        prs.iter().map(|pr| format!("#{}", pr.number)).collect();
+                    eprintln!("Stack: {}", numbers.join(" <- "));
+                    println!("{}", path.display());
+                }
+                return Ok(());
+            }
+            let review = api.get_pr(&owner, &repo, pr_num, &opts)?;
+            if stdout {
+                // The review file was already printed
+            } else if porcelain {
+                let summary = porcelain_summary(host, &owner, &repo, pr_num, &review)?;
+                report_review(&mut std::io::stdout(), &review, Some(summary), args.quiet)?;
+            } else {
+                let metadata = review.read_metadata()?;
+                if metadata.draft && !args.quiet {
+                    eprintln!("Pull request {} is a draft", pr_num);
+                }
+                if !metadata.state.is_open() {
+                    color::warn(format!(
+                        "Pull request {} is {}, submitting a review will likely be rejected",
+                        pr_num,
+                        metadata.state.name()
+                    ));
+                }
+                if let Some(dir) = output_dir.as_ref().filter(|_| !args.quiet) {
+                    eprintln!(
+                        "Submit it with `prr --workdir {} submit {}:{}/{}/{}`",
+                        dir,
+                        host.name(),
+                        owner,
+                        repo,
+                        pr_num
+                    );
+                }
+                report_review(&mut std::io::stdout(), &review, None, args.quiet)?;
+            }
+        }
+        Command::Submit {
+            pr,
+            debug,
+            only,
+            include_summary,
+            individual,
+            verify_lines,
+            deny_duplicates,
+            lint,
+            append_signature,
+            message,
+            watch,
+            force,
+            stack,
+        } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = config.review(&dir, pr_num).deny_duplicates(deny_duplicates);
+            let signature = match (append_signature, config.signature()) {
+                (false, _) => None,
+                (true, Some(s)) => Some(s.to_string()),
+                (true, None) => bail!("--append-signature needs `prr.signature` in the config"),
+            };
+
             let api = host.init(config)?;
-            let review = api.get_pr(&owner, &repo, pr_num, force)?;
-            println!("{}", review.path().display());
+            let opts = api::SubmitOptions {
+                debug,
+                only,
+                include_summary,
+                individual,
+                verify_lines,
+                deny_duplicates,
+                message,
+                signature,
+                force,
+            };
+            // The sections of a stack are checked one by one when submitting
+            if stack {
+                let submitted = stack::submit(&*api, &dir, &owner, &repo, pr_num, &opts)?;
+                if submitted.is_empty() {
+                    bail!("Nothing to submit in the stack of {}", pr);
+                }
+                if !args.quiet {
+                    let numbers: Vec<String> =
+                        submitted.iter().map(|n| format!("#{}", n)).collect();
+                    eprintln!("Submitted reviews of {}", numbers.join(", "));
+                }
+            } else {
+                let checks = SubmitChecks { lint, watch };
+                prr::submit(&*api, &review, &owner, &repo, &opts, &checks)?;
+            }
         }
-        Command::Submit { pr, debug } => {
-            let (host, owner, repo, pr_num) = parse_pr_str(&pr)?;
+        Command::Diff { pr } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = Review::new_existing(&dir, pr_num);
+            if !review.has_metadata() {
+                bail!("No prior review metadata, run `prr get {}` first", pr);
+            }
+            let head_sha = match review.read_metadata()?.head_sha {
+                Some(sha) => sha,
+                None => bail!(
+                    "Review metadata has no head commit, re-fetch with `prr get -f {}`",
+                    pr
+                ),
+            };
+
             let api = host.init(config)?;
-            api.submit_pr(&owner, &repo, pr_num, debug)?;
+            print!("{}", api.diff_since(&owner, &repo, pr_num, &head_sha)?);
+        }
+        Command::Status { pr } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = config.review(&dir, pr_num);
+            if !review.has_metadata() {
+                bail!("No review for {}, run `prr get {}` first", pr, pr);
+            }
+
+            let metadata = review.read_metadata()?;
+            if metadata.draft {
+                println!("Draft: the pull request was a draft when fetched");
+            }
+            if let Some(base) = &metadata.base {
+                println!(
+                    "Base: the diff is against {} instead of the pull request's base",
+                    base
+                );
+            }
+            if !metadata.range.is_three_dot() {
+                println!("Two-dot: the diff is against the tip of the base, not the merge base");
+            }
+            if !metadata.state.is_open() {
+                println!(
+                    "Read-only: the pull request was {} when fetched",
+                    metadata.state.name()
+                );
+            }
+            if metadata.submitting.is_some() {
+                println!(
+                    "Interrupted: the last submission did not finish, some comments may already \
+                    be on the pull request"
+                );
+            }
+            if !metadata.paths.is_empty() {
+                println!("Only files matching: {}", metadata.paths.join(", "));
+            }
+
+            let (commented, skipped): (Vec<_>, Vec<_>) = review
+                .file_coverage()?
+                .into_iter()
+                .partition(|f| f.comments > 0);
+            println!("Commented ({}):", commented.len());
+            for f in &commented {
+                println!("    {} ({})", f.file, f.comments);
+            }
+            println!("Not commented ({}):", skipped.len());
+            for f in &skipped {
+                println!("    {}", f.file);
+            }
+        }
+        Command::Prs { repo, state } => {
+            let (host, owner, repo) = match repo {
+                Some(r) => parse_repo_str(&r, default_host)?,
+                None => parse_git_remote()?,
+            };
+            let api = host.init(config)?;
+            for pr in api.list_prs(&owner, &repo, state)? {
+                let draft = if pr.draft { "[draft] " } else { "" };
+                println!(
+                    "{}\t{}\t{}\t{}{}\t{}",
+                    pr.number, pr.author, pr.head_ref, draft, pr.title, pr.url
+                );
+            }
+        }
+        Command::Whoami { host } => {
+            let host = match host {
+                Some(h) => Host::from_str(&h).with_context(|| format!("Unknown host '{}'", h))?,
+                None => default_host,
+            };
+            let url = config.host_or(host.default_url()).to_string();
+            let api = host.init(config)?;
+            let login = api.current_user()?;
+            print!(
+                "{}",
+                describe_user(host, &url, &login, api.current_user_name()?.as_deref())
+            );
+        }
+        Command::Browse { pr, print } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let url = host.web_url(&config, &owner, &repo, pr_num);
+            if print {
+                println!("{}", url);
+            } else {
+                open_in_browser(&url)?;
+            }
+        }
+        Command::Path { pr } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = Review::new_existing(&dir, pr_num);
+            let path = review.path();
+            if !path.exists() {
+                bail!(
+                    "No review file at {}, run `prr get {}` first",
+                    path.display(),
+                    pr
+                );
+            }
+
+            println!("{}", path.display());
+        }
+        Command::Reopen { pr } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = Review::new_existing(&dir, pr_num);
+            if !review.has_metadata() {
+                bail!("No review for {}, run `prr get {}` first", pr, pr);
+            }
+
+            if review.reopen()? {
+                eprintln!("Review file was removed, restored it without any comments");
+            }
+            println!("{}", review.path().display());
         }
+        Command::Restore { pr, force } => {
+            let (host, owner, repo, pr_num) = parse_pr_str(&pr, default_host)?;
+            let dir = config.review_dir(config.host_or(host.default_url()), &owner, &repo)?;
+            let review = Review::new_existing(&dir, pr_num);
+            review.restore(force)?;
+            println!("{}", review.path().display());
+        }
+        Command::Config => print!("{}", config.describe(&config_path)?),
+        Command::Completions { .. } => unreachable!("Completions are handled before config load"),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prr::parser::ParseErrorKind;
+
+    use clap::ValueEnum;
+    #[test]
+    fn whoami() {
+        assert_eq!(
+            describe_user(
+                Host::Github,
+                "https://api.github.com",
+                "danobi",
+                Some("Daniel Xu")
+            ),
+            "login: danobi\nname: Daniel Xu\nhost: github (https://api.github.com)\n"
+        );
+        assert_eq!(
+            describe_user(Host::Gitlab, "gitlab.example.com", "danobi", None),
+            "login: danobi\nhost: gitlab (gitlab.example.com)\n"
+        );
+    }
+
+    #[test]
+    fn flag_overrides() {
+        let flags = overrides(&Args::parse_from(["prr", "whoami"]));
+        assert_eq!(flags.profile, None);
+        assert_eq!(flags.workdir, None);
+        assert!(!flags.insecure);
+
+        // The flags are global, so they may also come after the subcommand
+        let args = [
+            "prr",
+            "whoami",
+            "--profile",
+            "work",
+            "--workdir",
+            "/tmp",
+            "--insecure",
+        ];
+        let flags = overrides(&Args::parse_from(args));
+        assert_eq!(flags.profile.as_deref(), Some("work"));
+        assert_eq!(flags.workdir.as_deref(), Some("/tmp"));
+        assert!(flags.insecure);
+    }
+
+    #[test]
+    fn quiet() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/single_file").to_string();
+        let review = Review::new(dir.path(), diff, 24, Default::default(), false).unwrap();
+        let report = |porcelain: bool, quiet: bool| {
+            let summary = porcelain
+                .then(|| porcelain_summary(Host::Github, "danobi", "prr", 24, &review).unwrap());
+            let mut out = Vec::new();
+            report_review(&mut out, &review, summary, quiet).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(
+            report(false, false),
+            format!("{}\n", review.path().display())
+        );
+        assert_eq!(report(false, true), "");
+        // Asking for the summary is asking for output
+        let summary: serde_json::Value = serde_json::from_str(&report(true, true)).unwrap();
+        assert_eq!(summary["pr_num"], 24);
+        assert_eq!(summary["files"], 1);
+
+        assert!(Args::parse_from(["prr", "-q", "get", "danobi/prr/24"]).quiet);
+        assert!(Args::parse_from(["prr", "get", "--quiet", "danobi/prr/24"]).quiet);
+    }
+
+    #[test]
+    fn output_dir() {
+        let args = [
+            "prr",
+            "get",
+            "--output-dir",
+            "/tmp/one-off",
+            "danobi/prr/24",
+        ];
+        let flags = overrides(&Args::parse_from(args));
+        assert_eq!(flags.workdir.as_deref(), Some("/tmp/one-off"));
+
+        let dir = tempfile::tempdir().unwrap();
+        check_writable(&dir.path().join("new/dir")).unwrap();
+        assert!(dir.path().join("new/dir").is_dir());
+        assert_eq!(fs::read_dir(dir.path().join("new/dir")).unwrap().count(), 0);
+
+        // Root may write anywhere, so block the directory with a file instead
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        let err = check_writable(&file.join("dir")).unwrap_err();
+        assert!(err.to_string().starts_with("Failed to create"), "{}", err);
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(
+            exit_code(&anyhow::anyhow!("No review comments")),
+            EXIT_FAILURE
+        );
+
+        let partial = SubmitFailure {
+            submitted: 2,
+            failed: 1,
+        };
+        assert_eq!(
+            partial.to_string(),
+            "Submitted 2 of 3 inline comments, 1 failed"
+        );
+        assert_eq!(exit_code(&partial.into()), EXIT_PARTIAL);
+
+        // Also found behind context, as when a split review fails half way
+        let err = anyhow::anyhow!("422 Unprocessable Entity")
+            .context("Failed to submit review 2 of 2")
+            .context(SubmitFailure {
+                submitted: 200,
+                failed: 10,
+            });
+        assert_eq!(exit_code(&err), EXIT_PARTIAL);
+
+        let none = SubmitFailure {
+            submitted: 0,
+            failed: 3,
+        };
+        assert_eq!(exit_code(&none.into()), EXIT_FAILURE);
+    }
+
+    #[test]
+    fn error_format() {
+        let args = Args::parse_from(["prr", "whoami", "--error-format", "json"]);
+        assert_eq!(args.error_format, ErrorFormat::Json);
+        assert_eq!(
+            Args::parse_from(["prr", "whoami"]).error_format,
+            ErrorFormat::Human
+        );
+
+        let err = anyhow::Error::new(ParseError {
+            line: 3,
+            kind: ParseErrorKind::UnknownDirective("approv".to_string()),
+        })
+        .context("Failed to parse review on line 3");
+        assert_eq!(
+            error_json(&err),
+            serde_json::json!({
+                "kind": "parse",
+                "message": "Failed to parse review on line 3: Unknown @prr directive: approv",
+                "line": 3,
+            })
+        );
+
+        let err = anyhow::Error::new(SubmitFailure {
+            submitted: 2,
+            failed: 1,
+        });
+        assert_eq!(error_json(&err)["kind"], "submit");
+        assert_eq!(error_json(&err)["failed"], 1);
+
+        let err = anyhow::anyhow!("No review comments");
+        assert_eq!(
+            error_json(&err),
+            serde_json::json!({ "kind": "other", "message": "No review comments" })
+        );
+    }
+
+    #[test]
+    fn completions() {
+        for shell in clap_complete::Shell::value_variants() {
+            let mut out = Vec::new();
+            generate_completions(*shell, &mut out);
+            assert!(!out.is_empty(), "No completions generated for {}", shell);
+        }
+    }
+}
@@ -1,6 +1,10 @@
+use std::error::Error;
+use std::fmt;
+
 use anyhow::{anyhow, bail, Context, Result};
 use lazy_static::lazy_static;
 use regex::Regex;
+use serde::Deserialize;
 
 // Use lazy static to ensure regex is only compiled once
 lazy_static! {
@@ -8,12 +12,29 @@ lazy_static! {
     //
     //      `@@ -731,7 +731,7 @@[...]`
     //
-    static ref HUNK_START: Regex = Regex::new(r"^@@ -(?P<lstart>\d+),\d+ \+(?P<rstart>\d+),\d+ @@").unwrap();
+    // The line counts are left out when they are 1, eg. `@@ -0,0 +1 @@`.
+    //
+    static ref HUNK_START: Regex = Regex::new(r"^@@ -(?P<lstart>\d+)(,(?P<llen>\d+))? \+(?P<rstart>\d+)(,(?P<rlen>\d+))? @@").unwrap();
     // Regex for start of a file diff. The start of a file diff should look like:
     //
     //      `diff --git a/ch1.txt b/ch1.txt`
     //
-    static ref DIFF_START: Regex = Regex::new(r"^diff --git a/(?P<old>.+) b/(?P<new>.+)$").unwrap();
+    // or, if git had to quote the paths (eg. for non-ASCII characters):
+    //
+    //      `diff --git "a/caf\303\251.txt" "b/caf\303\251.txt"`
+    //
+    static ref DIFF_START: Regex = Regex::new(r#"^diff --git (?P<old>"a/.+"|a/.+) (?P<new>"b/.+"|b/.+)$"#).unwrap();
+    // Regex for the start of a commit in patch format. The start of a commit should look like:
+    //
+    //      `From 2c5e0f8ea0d3ee1b1ec1a7ccd21e2a4d5b8d3d2c Mon Sep 17 00:00:00 2001`
+    //
+    static ref PATCH_START: Regex = Regex::new(r"^From [0-9a-f]{40} ").unwrap();
+    // Regex for the start of a commit in the commit log of `prr get --commit-log`. The start
+    // of a commit should look like:
+    //
+    //      `commit 2c5e0f8ea0d3ee1b1ec1a7ccd21e2a4d5b8d3d2c`
+    //
+    static ref COMMIT_START: Regex = Regex::new(r"^commit (?P<sha>[0-9a-f]{40})$").unwrap();
 }
 
 /// The location of a line
@@ -46,15 +67,117 @@ pub struct InlineComment {
     pub start_line: Option<LineLocation>,
     /// The user-supplied review comment
     pub comment: String,
+    /// The diff line at `line`, including its `+`/`-`/` ` prefix
+    ///
+    /// Lets a submission check the line did not change since the review was fetched.
+    pub line_text: String,
 }
 
-#[derive(Debug, PartialEq)]
+/// The overall verdict of a review
+///
+/// Reviews without an `@prr approve`/`reject`/`comment` directive default to `Comment`,
+/// unless `prr.default_action` says otherwise. There it is spelled like the directive
+#[derive(Debug, Default, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ReviewAction {
     Approve,
+    #[serde(rename = "reject")]
     RequestChanges,
+    #[default]
     Comment,
 }
 
+impl ReviewAction {
+    /// The GitHub review events, as taken by `@prr event <EVENT>`
+    pub const EVENTS: [&'static str; 3] = ["APPROVE", "REQUEST_CHANGES", "COMMENT"];
+
+    /// Returns the GitHub review event submitting this action
+    pub fn event(&self) -> &'static str {
+        match self {
+            ReviewAction::Approve => "APPROVE",
+            ReviewAction::RequestChanges => "REQUEST_CHANGES",
+            ReviewAction::Comment => "COMMENT",
+        }
+    }
+
+    /// Returns the action submitted as GitHub review event `event`, ignoring case
+    pub fn from_event(event: &str) -> Option<ReviewAction> {
+        [
+            ReviewAction::Approve,
+            ReviewAction::RequestChanges,
+            ReviewAction::Comment,
+        ]
+        .into_iter()
+        .find(|a| a.event().eq_ignore_ascii_case(event))
+    }
+}
+
+/// An emoji that can be used to react to a comment
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Emoji {
+    ThumbsUp,
+    ThumbsDown,
+    Laugh,
+    Confused,
+    Heart,
+    Hooray,
+    Rocket,
+    Eyes,
+}
+
+impl Emoji {
+    /// Parses an emoji shortcode, eg. `:+1:`
+    pub fn from_shortcode(s: &str) -> Option<Self> {
+        let name = s.strip_prefix(':')?.strip_suffix(':')?;
+        match name {
+            "+1" | "thumbsup" => Some(Emoji::ThumbsUp),
+            "-1" | "thumbsdown" => Some(Emoji::ThumbsDown),
+            "laugh" | "smile" => Some(Emoji::Laugh),
+            "confused" => Some(Emoji::Confused),
+            "heart" => Some(Emoji::Heart),
+            "hooray" | "tada" => Some(Emoji::Hooray),
+            "rocket" => Some(Emoji::Rocket),
+            "eyes" => Some(Emoji::Eyes),
+            _ => None,
+        }
+    }
+}
+
+/// Represents a reaction to an existing comment on the PR
+#[derive(Debug, PartialEq)]
+pub struct Reaction {
+    /// ID of the comment to react to
+    pub comment_id: u64,
+    pub emoji: Emoji,
+}
+
+/// Represents a new body for an existing comment on the PR
+#[derive(Debug, PartialEq)]
+pub struct Amend {
+    /// ID of the comment to edit
+    pub comment_id: u64,
+    /// The user-supplied replacement body
+    pub body: String,
+}
+
+/// Represents a reply to an existing comment on the PR, in the same thread
+#[derive(Debug, PartialEq)]
+pub struct Reply {
+    /// ID of the comment to reply to
+    pub comment_id: u64,
+    /// The user-supplied reply
+    pub body: String,
+}
+
+/// Represents a comment on a commit of the PR, eg. on its message
+#[derive(Debug, PartialEq)]
+pub struct CommitComment {
+    /// SHA of the commit to comment on. May be abbreviated
+    pub sha: String,
+    /// The user-supplied comment
+    pub comment: String,
+}
+
 /// Represents a comment of some sort on a review
 #[derive(Debug, PartialEq)]
 pub enum Comment {
@@ -64,14 +187,166 @@ pub enum Comment {
     Inline(InlineComment),
     /// Overall approve, reject, or comment on review
     ReviewAction(ReviewAction),
+    /// Emoji reaction to an existing comment
+    Reaction(Reaction),
+    /// Edit of an existing comment
+    Amend(Amend),
+    /// Reply to an existing comment
+    Reply(Reply),
+    /// Comment on a commit rather than on a line of the diff
+    Commit(CommitComment),
+}
+
+/// Error from `ReviewParser::parse_line()`
+#[derive(Debug)]
+pub struct ParseError {
+    /// Number of the line the error is on, counting from 1
+    pub line: usize,
+    pub kind: ParseErrorKind,
+}
+
+/// What was wrong with a line of a review file
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    /// A span that was not ended with a comment before the next file or commit
+    UnterminatedSpan { old_file: String, new_file: String },
+    /// A span that runs from one hunk into the next
+    CrossHunkSpan { old_file: String, new_file: String },
+    /// Text where comments are not supported, eg. above the first hunk of a file
+    UnexpectedComment { comment: String, message: String },
+    /// A `@prr` directive prr does not know
+    UnknownDirective(String),
+    /// A known `@prr` directive with missing or invalid arguments
+    InvalidDirective { directive: String, message: String },
+    /// A `diff --git` line whose file names cannot be parsed
+    BadDiffHeader { header: String, message: String },
+    /// A quoted line that does not fit the diff around it, eg. because it was edited
+    CorruptedDiff { line: String, message: String },
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseErrorKind::UnterminatedSpan { old_file, new_file } => write!(
+                f,
+                "Detected span that was not terminated with a comment, file: a/{} b/{}",
+                old_file, new_file
+            ),
+            ParseErrorKind::CrossHunkSpan { old_file, new_file } => write!(
+                f,
+                "Detected cross chunk span, file: a/{} b/{}",
+                old_file, new_file
+            ),
+            ParseErrorKind::UnknownDirective(d) => write!(f, "Unknown @prr directive: {}", d),
+            ParseErrorKind::UnexpectedComment { message, .. }
+            | ParseErrorKind::InvalidDirective { message, .. }
+            | ParseErrorKind::BadDiffHeader { message, .. }
+            | ParseErrorKind::CorruptedDiff { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.kind.fmt(f)
+    }
+}
+
+impl Error for ParseError {}
+
+impl ParseErrorKind {
+    fn unexpected_comment(comment: &str, message: String) -> Self {
+        ParseErrorKind::UnexpectedComment {
+            comment: comment.to_string(),
+            message,
+        }
+    }
+
+    fn invalid_directive(directive: &str, message: String) -> Self {
+        ParseErrorKind::InvalidDirective {
+            directive: directive.to_string(),
+            message,
+        }
+    }
+
+    fn corrupted_diff(line: &str, message: String) -> Self {
+        ParseErrorKind::CorruptedDiff {
+            line: line.to_string(),
+            message,
+        }
+    }
 }
 
+/// State for the top of the review file, before the first diff header
+///
+/// `@prr` directives are emitted as soon as they're seen. Every other line, starting
+/// at the first non-blank one, becomes part of the review comment, which is emitted
+/// once the first diff header is reached. This means directives and the review
+/// comment can be freely mixed.
+///
+/// The exceptions are `@prr amend`, `@prr reply` and `@prr commit`: every line after
+/// them, up to the next such directive or the first diff header, is the body of the
+/// amended comment, the reply or the commit comment.
 #[derive(Default)]
 struct StartState {
     /// Each line of review-level comment is stored as an entry
     comment: Vec<String>,
+    /// The `@prr amend`/`reply`/`commit` block we are in and its body lines, if any
+    block: Option<(Block, Vec<String>)>,
+}
+
+/// A directive that takes the lines after it as its body
+enum Block {
+    /// `@prr amend <comment_id>`
+    Amend(u64),
+    /// `@prr reply <comment_id>`
+    Reply(u64),
+    /// `@prr commit <sha>`
+    Commit(String),
+}
+
+impl StartState {
+    /// Ends the current `@prr amend`/`reply`/`commit` block, if any
+    fn finish_block(&mut self) -> Result<Option<Comment>, ParseErrorKind> {
+        let (block, body) = match self.block.take() {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        let body = body.join("\n").trim().to_string();
+        let (directive, missing) = match &block {
+            Block::Amend(comment_id) => (format!("amend {}", comment_id), "the new comment body"),
+            Block::Reply(comment_id) => (format!("reply {}", comment_id), "the reply"),
+            Block::Commit(sha) => (format!("commit {}", sha), "the comment body"),
+        };
+        if body.is_empty() {
+            return Err(ParseErrorKind::invalid_directive(
+                &directive,
+                format!("`@prr {}` is missing {}", directive, missing),
+            ));
+        }
+
+        Ok(Some(match block {
+            Block::Amend(comment_id) => Comment::Amend(Amend { comment_id, body }),
+            Block::Reply(comment_id) => Comment::Reply(Reply { comment_id, body }),
+            Block::Commit(sha) => Comment::Commit(CommitComment { sha, comment: body }),
+        }))
+    }
+}
+
+/// State for the commit log at the top of the review file, see `prr get --commit-log`
+///
+/// Comments below any line of a commit's log entry are on that commit.
+struct CommitLogState {
+    /// SHA of the commit whose log entry we are in
+    sha: String,
+    /// Each line of the comment on the commit is stored as an entry
+    comment: Vec<String>,
 }
 
+/// State for the lines between a `diff --git` header and the first hunk of the file
+///
+/// Every header starts over, even for a file that already had one. Comments are placed
+/// by the hunk headers of the section they are written in.
 struct FilePreambleState {
     /// Relative path of the file under diff, before rename
     old_file: String,
@@ -91,9 +366,120 @@ struct FileDiffState {
     right_line: u64,
     /// Current line position
     line: LineLocation,
+    /// Diff line at the current line position
+    line_text: String,
     /// First line of the span. See `LineLocation` for docs on
     /// semantics of `line`
     span_start_line: Option<LineLocation>,
+    /// Lines of the current hunk not seen yet on the left side, per the hunk header
+    left_remaining: u64,
+    /// Lines of the current hunk not seen yet on the right side, per the hunk header
+    right_remaining: u64,
+    /// Whether the current line comes after the end of the hunk, eg. the signature at the
+    /// end of a patch
+    past_hunk: bool,
+}
+
+impl FileDiffState {
+    fn new(old_file: String, new_file: String) -> Self {
+        FileDiffState {
+            old_file,
+            new_file,
+            left_line: 0,
+            right_line: 0,
+            line: LineLocation::Both(0, 0),
+            line_text: String::new(),
+            span_start_line: None,
+            left_remaining: 0,
+            right_remaining: 0,
+            past_hunk: false,
+        }
+    }
+
+    /// Moves on to the next line of the diff, which is either a hunk header or a line of
+    /// the current hunk
+    ///
+    /// The hunk header says how many lines the hunk has on each side. A line the hunk has
+    /// no room for, eg. a context line in a hunk that only adds lines, means the diff was
+    /// corrupted and we would get the line numbers wrong.
+    fn advance(&mut self, line: &str) -> Result<(), ParseErrorKind> {
+        if let Some((left_start, right_start)) = hunk_start(line)? {
+            let (left_len, right_len) = parse_hunk_lengths(line)
+                .map_err(|e| ParseErrorKind::corrupted_diff(line, format!("{:#}", e)))?;
+            // Subtract 1 b/c this line is before the actual diff hunk
+            self.left_line = left_start.saturating_sub(1);
+            self.right_line = right_start.saturating_sub(1);
+            self.line = LineLocation::Both(self.left_line, self.right_line);
+            self.line_text = line.to_owned();
+            self.left_remaining = left_len;
+            self.right_remaining = right_len;
+            self.past_hunk = false;
+            return Ok(());
+        }
+
+        // `\ No newline at end of file` belongs to the line before it
+        if line.starts_with('\\') {
+            return Ok(());
+        }
+        if self.left_remaining == 0 && self.right_remaining == 0 {
+            self.line_text = line.to_owned();
+            self.past_hunk = true;
+            return Ok(());
+        }
+
+        let (left, right) = if is_left_line(line) {
+            (true, false)
+        } else if is_right_line(line) {
+            (false, true)
+        } else if line.is_empty() || line.starts_with(' ') {
+            // Some editors strip the trailing space of empty context lines
+            (true, true)
+        } else {
+            return Err(ParseErrorKind::corrupted_diff(
+                line,
+                format!("Unexpected line '{}' in a hunk of {}", line, self.new_file),
+            ));
+        };
+        if (left && self.left_remaining == 0) || (right && self.right_remaining == 0) {
+            return Err(ParseErrorKind::corrupted_diff(
+                line,
+                format!(
+                    "Line '{}' does not fit in its hunk of {}, the hunk header says there are \
+                    fewer lines. The review file may be corrupted",
+                    line, self.new_file
+                ),
+            ));
+        }
+
+        let (next_left, next_right) = get_next_lines(line, self.left_line, self.right_line);
+        self.left_line = next_left;
+        self.right_line = next_right;
+        self.line = match (left, right) {
+            (true, false) => LineLocation::Left(next_left, next_right),
+            (false, true) => LineLocation::Right(next_left, next_right),
+            _ => LineLocation::Both(next_left, next_right),
+        };
+        self.line_text = line.to_owned();
+        self.left_remaining -= left as u64;
+        self.right_remaining -= right as u64;
+
+        Ok(())
+    }
+
+    /// Checks that the current line can be commented on
+    fn check_commentable(&self, comment: &str) -> Result<(), ParseErrorKind> {
+        if self.past_hunk {
+            return Err(ParseErrorKind::unexpected_comment(
+                comment,
+                format!(
+                    "Comment below '{}' is outside of the hunks of {}",
+                    self.line_text, self.new_file
+                ),
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 struct SpanStartOrCommentState {
@@ -112,6 +498,7 @@ struct CommentState {
 ///
 /// Only the following state transitions are valid:
 ///
+/// ```text
 ///                                  +---------------+
 ///                                  |               |
 ///                                  v               |
@@ -121,10 +508,20 @@ struct CommentState {
 ///                 |    +--------+--+-+----------------------------+---+
 ///                 |             |  |                              |
 ///                 +-------------+  +------------------------------+
+/// ```
 ///
+/// Additionally, for diffs in patch format, Start, FileDiff and Comment can move to
+/// PatchHeader when a new commit starts, and PatchHeader moves on to FilePreamble. A
+/// review file that starts with a commit log moves from Start to CommitLog, which moves
+/// on to FilePreamble or PatchHeader.
 enum State {
     /// Starting state
     Start(StartState),
+    /// The commit log above the diff, if the review file has one
+    CommitLog(CommitLogState),
+    /// The metadata of a commit in patch format (`From <sha> ...`, `Subject: ...`, the
+    /// diffstat), up to the first diff header of the commit
+    PatchHeader,
     /// The `diff --git a/...` preamble as well as the lines before the first hunk
     FilePreamble(FilePreambleState),
     /// We are inside the diff of a file
@@ -138,15 +535,48 @@ enum State {
     Comment(CommentState),
 }
 
+/// Prefix of the quoted lines of a review file, unless configured otherwise
+pub const DEFAULT_QUOTE_PREFIX: &str = "> ";
+
 /// Simple state machine to parse a review file
 pub struct ReviewParser {
     state: State,
+    /// Prefix that marks a line as quoted. See `with_quote_prefix()`
+    quote_prefix: String,
+    /// Number of lines parsed so far
+    line: usize,
+    /// Comment completed by a line that already completed another one
+    ///
+    /// Only happens at the first diff or commit header, which ends both the review comment
+    /// and the last `@prr amend`/`reply`/`commit` block. The lines right after such a header
+    /// never complete a comment, so the queued one is handed out with the next line.
+    queued: Option<Comment>,
 }
 
-fn is_diff_header(s: &str) -> bool {
+pub fn is_diff_header(s: &str) -> bool {
     s.starts_with("diff --git ")
 }
 
+pub fn is_patch_header(s: &str) -> bool {
+    PATCH_START.is_match(s)
+}
+
+/// Returns the SHA of a `commit <sha>` line of a commit log
+fn commit_header(s: &str) -> Option<&str> {
+    COMMIT_START
+        .captures(s)
+        .map(|c| c.name("sha").unwrap().as_str())
+}
+
+/// Returns whether a quoted line shows an existing comment thread or the range of a hunk
+///
+/// These are interleaved into the diff by `prr get --with-comments` and `--hunk-ranges`
+/// and are purely informational. No diff line starts with `|`, so they cannot be mistaken
+/// for one.
+pub fn is_thread_line(s: &str) -> bool {
+    s.starts_with('|')
+}
+
 /// Parses lines in the form of `@prr DIRECTIVE`
 ///
 /// Returns Some(directive) if found, else None
@@ -159,20 +589,204 @@ fn is_prr_directive(s: &str) -> Option<&str> {
     }
 }
 
-/// Parses the new filename out of a diff header
-fn parse_diff_header(line: &str) -> Result<(String, String)> {
+/// Returns the path of a `@prr attach <path>` line
+///
+/// Unlike other directives, attachments are part of the comment they are in. They are
+/// replaced with a link to the uploaded file when the review is submitted.
+pub fn attachment_path(s: &str) -> Option<&str> {
+    let path = is_prr_directive(s)?.trim().strip_prefix("attach ")?.trim();
+    if path.is_empty() {
+        None
+    } else {
+        Some(path)
+    }
+}
+
+/// Returns the name of a `@prr snip <name>` line
+///
+/// Like attachments, snippets are part of the comment they start. `Review::comments()`
+/// replaces them with the configured text.
+pub fn snippet_name(s: &str) -> Option<&str> {
+    let name = is_prr_directive(s)?.trim().strip_prefix("snip ")?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Returns the name of a `@prr saved <name>` line
+///
+/// Like attachments, saved replies are part of the comment they are in. They are replaced
+/// with the GitHub saved reply titled `name` when the review is submitted.
+pub fn saved_reply_name(s: &str) -> Option<&str> {
+    let name = is_prr_directive(s)?.trim().strip_prefix("saved ")?.trim();
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Parses the directive part of a `@prr DIRECTIVE` line
+fn parse_directive(d: &str) -> Result<Comment, ParseErrorKind> {
+    let invalid = |message: String| ParseErrorKind::invalid_directive(d, message);
+    let mut words = d.split_whitespace();
+    match words.next() {
+        Some("approve") => Ok(Comment::ReviewAction(ReviewAction::Approve)),
+        Some("reject") => Ok(Comment::ReviewAction(ReviewAction::RequestChanges)),
+        Some("comment") => Ok(Comment::ReviewAction(ReviewAction::Comment)),
+        Some("event") => {
+            let event = match (words.next(), words.next()) {
+                (Some(event), None) => event,
+                _ => {
+                    return Err(invalid(format!(
+                        "Expected `@prr event <EVENT>`, found '{}'",
+                        d
+                    )))
+                }
+            };
+            let action = ReviewAction::from_event(event).ok_or_else(|| {
+                invalid(format!(
+                    "Unknown review event '{}', expected one of {}",
+                    event,
+                    ReviewAction::EVENTS.join(", ")
+                ))
+            })?;
+
+            Ok(Comment::ReviewAction(action))
+        }
+        Some(name @ ("amend" | "reply")) => {
+            let id = match (words.next(), words.next()) {
+                (Some(id), None) => id,
+                _ => {
+                    return Err(invalid(format!(
+                        "Expected `@prr {} <comment_id>`, found '{}'",
+                        name, d
+                    )))
+                }
+            };
+            let comment_id = id
+                .parse()
+                .map_err(|_| invalid(format!("Invalid comment id '{}'", id)))?;
+
+            let body = String::new();
+            Ok(match name {
+                "amend" => Comment::Amend(Amend { comment_id, body }),
+                _ => Comment::Reply(Reply { comment_id, body }),
+            })
+        }
+        Some("react") => {
+            let (id, shortcode) = match (words.next(), words.next(), words.next()) {
+                (Some(id), Some(shortcode), None) => (id, shortcode),
+                _ => {
+                    return Err(invalid(format!(
+                        "Expected `@prr react <comment_id> <:emoji:>`, found '{}'",
+                        d
+                    )))
+                }
+            };
+            let comment_id = id
+                .parse()
+                .map_err(|_| invalid(format!("Invalid comment id '{}'", id)))?;
+            let emoji = Emoji::from_shortcode(shortcode)
+                .ok_or_else(|| invalid(format!("Unknown reaction emoji: {}", shortcode)))?;
+
+            Ok(Comment::Reaction(Reaction { comment_id, emoji }))
+        }
+        Some("commit") => {
+            let sha = match (words.next(), words.next()) {
+                (Some(sha), None) => sha,
+                _ => {
+                    return Err(invalid(format!(
+                        "Expected `@prr commit <sha>`, found '{}'",
+                        d
+                    )))
+                }
+            };
+            if sha.len() < 7 || sha.len() > 40 || !sha.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid(format!("Invalid commit SHA '{}'", sha)));
+            }
+
+            Ok(Comment::Commit(CommitComment {
+                sha: sha.to_string(),
+                comment: String::new(),
+            }))
+        }
+        // Only reached without a path, see `attachment_path()`
+        Some("attach") => Err(invalid(format!(
+            "Expected `@prr attach <path>`, found '{}'",
+            d
+        ))),
+        // Only reached without a name, see `snippet_name()`
+        Some("snip") => Err(invalid(format!(
+            "Expected `@prr snip <name>`, found '{}'",
+            d
+        ))),
+        // Only reached without a name, see `saved_reply_name()`
+        Some("saved") => Err(invalid(format!(
+            "Expected `@prr saved <name>`, found '{}'",
+            d
+        ))),
+        _ => Err(ParseErrorKind::UnknownDirective(d.to_string())),
+    }
+}
+
+/// Undoes git's C-style quoting of a path, eg. `"caf\303\251.txt"` -> `café.txt`
+///
+/// Paths that are not surrounded by quotes are returned as is.
+fn unquote_path(path: &str) -> Result<String> {
+    let inner = match path.strip_prefix('"').and_then(|p| p.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => return Ok(path.to_string()),
+    };
+
+    let mut bytes = Vec::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+
+        match chars.next() {
+            Some('a') => bytes.push(0x07),
+            Some('b') => bytes.push(0x08),
+            Some('t') => bytes.push(b'\t'),
+            Some('n') => bytes.push(b'\n'),
+            Some('v') => bytes.push(0x0b),
+            Some('f') => bytes.push(0x0c),
+            Some('r') => bytes.push(b'\r'),
+            Some(c @ ('"' | '\\')) => bytes.push(c as u8),
+            Some(c @ '0'..='3') => {
+                let octal: String = std::iter::once(c).chain(chars.by_ref().take(2)).collect();
+                let byte = u8::from_str_radix(&octal, 8)
+                    .with_context(|| format!("Invalid octal escape in path {}", path))?;
+                bytes.push(byte);
+            }
+            _ => bail!("Invalid escape in path {}", path),
+        }
+    }
+
+    String::from_utf8(bytes).with_context(|| format!("Path {} is not valid UTF-8", path))
+}
+
+/// Parses the old and new filenames out of a diff header
+pub fn parse_diff_header(line: &str) -> Result<(String, String)> {
     if let Some(captures) = DIFF_START.captures(line) {
-        let old = captures.name("old").unwrap().as_str().trim().to_string();
-        let new = captures.name("new").unwrap().as_str().trim().to_string();
+        let old = unquote_path(captures.name("old").unwrap().as_str().trim())?;
+        let new = unquote_path(captures.name("new").unwrap().as_str().trim())?;
 
-        Ok((old, new))
+        // The regex guarantees the prefixes
+        Ok((old[2..].to_string(), new[2..].to_string()))
     } else {
         Err(anyhow!("Invalid diff header: could not parse"))
     }
 }
 
 /// Parses the starting left & right lines out of the hunk start
-fn parse_hunk_start(line: &str) -> Result<Option<(u64, u64)>> {
+pub fn parse_hunk_start(line: &str) -> Result<Option<(u64, u64)>> {
     if let Some(captures) = HUNK_START.captures(line) {
         let hunk_start_line_left: u64 = captures
             .name("lstart")
@@ -204,6 +818,35 @@ fn parse_hunk_start(line: &str) -> Result<Option<(u64, u64)>> {
     Ok(None)
 }
 
+/// Parses the number of left & right lines out of the hunk start
+fn parse_hunk_lengths(line: &str) -> Result<(u64, u64)> {
+    let captures = HUNK_START
+        .captures(line)
+        .ok_or_else(|| anyhow!("Invalid hunk start: {}", line))?;
+    let len = |name| match captures.name(name) {
+        Some(len) => len
+            .as_str()
+            .parse()
+            .with_context(|| format!("Failed to parse hunk length in {}", line)),
+        None => Ok(1),
+    };
+
+    Ok((len("llen")?, len("rlen")?))
+}
+
+/// `parse_diff_header()` for the parser
+fn diff_header(line: &str) -> Result<(String, String), ParseErrorKind> {
+    parse_diff_header(line).map_err(|e| ParseErrorKind::BadDiffHeader {
+        header: line.to_string(),
+        message: format!("{:#}", e),
+    })
+}
+
+/// `parse_hunk_start()` for the parser
+fn hunk_start(line: &str) -> Result<Option<(u64, u64)>, ParseErrorKind> {
+    parse_hunk_start(line).map_err(|e| ParseErrorKind::corrupted_diff(line, format!("{:#}", e)))
+}
+
 fn is_left_line(line: &str) -> bool {
     line.starts_with('-')
 }
@@ -223,24 +866,58 @@ fn get_next_lines(line: &str, left: u64, right: u64) -> (u64, u64) {
     }
 }
 
+impl Default for ReviewParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ReviewParser {
     pub fn new() -> ReviewParser {
+        ReviewParser::with_quote_prefix(DEFAULT_QUOTE_PREFIX)
+    }
+
+    /// Creates a parser for review files whose quoted lines start with `prefix`
+    pub fn with_quote_prefix(prefix: &str) -> ReviewParser {
         ReviewParser {
             state: State::Start(StartState::default()),
+            quote_prefix: prefix.to_string(),
+            line: 0,
+            queued: None,
+        }
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<Comment>, ParseError> {
+        self.line += 1;
+        match self.parse(line) {
+            Ok(Some(c)) => Ok(Some(c)),
+            Ok(None) => Ok(self.queued.take()),
+            Err(kind) => Err(ParseError {
+                line: self.line,
+                kind,
+            }),
         }
     }
 
-    pub fn parse_line(&mut self, mut line: &str) -> Result<Option<Comment>> {
-        let is_quoted = line.starts_with("> ");
-        if is_quoted {
-            line = &line[2..];
+    fn parse(&mut self, mut line: &str) -> Result<Option<Comment>, ParseErrorKind> {
+        let quoted = line.strip_prefix(self.quote_prefix.as_str());
+        let is_quoted = quoted.is_some();
+        if let Some(quoted) = quoted {
+            line = quoted;
+            if is_thread_line(line) {
+                return Ok(None);
+            }
         }
 
         match &mut self.state {
             State::Start(state) => {
                 if is_quoted {
-                    if !is_diff_header(line) {
-                        bail!("Expected diff header from start state, found '{}'", line);
+                    let commit = commit_header(line);
+                    if !is_diff_header(line) && !is_patch_header(line) && commit.is_none() {
+                        return Err(ParseErrorKind::corrupted_diff(
+                            line,
+                            format!("Expected diff header from start state, found '{}'", line),
+                        ));
                     }
 
                     let mut review_comment = None;
@@ -248,21 +925,45 @@ impl ReviewParser {
                         review_comment =
                             Some(Comment::Review(state.comment.join("\n").trim().to_string()));
                     }
+                    let block = state.finish_block()?;
+                    if review_comment.is_none() {
+                        review_comment = block;
+                    } else {
+                        self.queued = block;
+                    }
 
-                    let (old_file, new_file) = parse_diff_header(line)?;
-                    self.state = State::FilePreamble(FilePreambleState {
-                        old_file,
-                        new_file,
-                    });
+                    if let Some(sha) = commit {
+                        self.state = State::CommitLog(CommitLogState {
+                            sha: sha.to_string(),
+                            comment: Vec::new(),
+                        });
+                    } else if is_patch_header(line) {
+                        self.state = State::PatchHeader;
+                    } else {
+                        let (old_file, new_file) = diff_header(line)?;
+                        self.state = State::FilePreamble(FilePreambleState {
+                            old_file,
+                            new_file,
+                        });
+                    }
 
                     return Ok(review_comment);
-                } else if let Some(d) = is_prr_directive(line) {
-                    return match d {
-                        "approve" => Ok(Some(Comment::ReviewAction(ReviewAction::Approve))),
-                        "reject" => Ok(Some(Comment::ReviewAction(ReviewAction::RequestChanges))),
-                        "comment" => Ok(Some(Comment::ReviewAction(ReviewAction::Comment))),
-                        _ => bail!("Unknown @prr directive: {}", d),
+                } else if let Some(d) = is_prr_directive(line).filter(|_| {
+                    attachment_path(line).is_none()
+                        && snippet_name(line).is_none()
+                        && saved_reply_name(line).is_none()
+                }) {
+                    let block = match parse_directive(d)? {
+                        Comment::Amend(Amend { comment_id, .. }) => Block::Amend(comment_id),
+                        Comment::Reply(Reply { comment_id, .. }) => Block::Reply(comment_id),
+                        Comment::Commit(CommitComment { sha, .. }) => Block::Commit(sha),
+                        c => return Ok(Some(c)),
                     };
+                    let prev = state.finish_block()?;
+                    state.block = Some((block, Vec::new()));
+                    return Ok(prev);
+                } else if let Some((_, body)) = &mut state.block {
+                    body.push(line.to_owned());
                 } else if !state.comment.is_empty() || !line.trim().is_empty() {
                     // Only blindly add lines if lines have already been added
                     state.comment.push(line.to_owned());
@@ -270,89 +971,116 @@ impl ReviewParser {
 
                 Ok(None)
             }
-            State::FilePreamble(state) => {
+            State::CommitLog(state) => {
                 if !is_quoted {
-                    bail!(
-                        "Unexpected comment in file preamble state, file: a/{} b/{}",
-                        state.old_file,
-                        state.new_file,
-                    );
+                    // Only blindly add lines if lines have already been added
+                    if !state.comment.is_empty() || !line.trim().is_empty() {
+                        state.comment.push(line.to_owned());
+                    }
+                    return Ok(None);
                 }
 
-                if let Some((mut left_start, mut right_start)) = parse_hunk_start(line)? {
-                    // Subtract 1 b/c this line is before the actual diff hunk
-                    left_start = left_start.saturating_sub(1);
-                    right_start = right_start.saturating_sub(1);
-
-                    self.state = State::FileDiff(FileDiffState {
-                        old_file: state.old_file.to_owned(),
-                        new_file: state.new_file.to_owned(),
-                        left_line: left_start,
-                        right_line: right_start,
-                        line: if is_left_line(line) {
-                            LineLocation::Left(left_start, right_start)
-                        } else if is_right_line(line) {
-                            LineLocation::Right(left_start, right_start)
-                        } else {
-                            LineLocation::Both(left_start, right_start)
-                        },
-                        span_start_line: None,
+                let mut comment = None;
+                if !state.comment.is_empty() {
+                    comment = Some(Comment::Commit(CommitComment {
+                        sha: state.sha.clone(),
+                        comment: state.comment.join("\n").trim_end().to_string(),
+                    }));
+                }
+                state.comment.clear();
+                if let Some(sha) = commit_header(line) {
+                    state.sha = sha.to_string();
+                } else if is_patch_header(line) {
+                    self.state = State::PatchHeader;
+                } else if is_diff_header(line) {
+                    let (old_file, new_file) = diff_header(line)?;
+                    self.state = State::FilePreamble(FilePreambleState {
+                        old_file,
+                        new_file,
                     });
                 }
 
+                Ok(comment)
+            }
+            State::PatchHeader => {
+                if !is_quoted {
+                    if !line.trim().is_empty() {
+                        return Err(ParseErrorKind::unexpected_comment(
+                            line,
+                            format!(
+                                "Comments on commit metadata are not supported, found '{}'",
+                                line
+                            ),
+                        ));
+                    }
+                } else if is_diff_header(line) {
+                    let (old_file, new_file) = diff_header(line)?;
+                    self.state = State::FilePreamble(FilePreambleState {
+                        old_file,
+                        new_file,
+                    });
+                }
+
+                Ok(None)
+            }
+            State::FilePreamble(state) => {
+                if !is_quoted {
+                    let message = if line.trim().is_empty() {
+                        format!("Span starts before the first hunk of {}", state.new_file)
+                    } else {
+                        format!(
+                            "Comment '{}' comes before the first hunk of {}. Move it below a \
+                            line of the diff it applies to; comments on a whole file are not \
+                            supported yet",
+                            line.trim(),
+                            state.new_file,
+                        )
+                    };
+                    return Err(ParseErrorKind::unexpected_comment(line, message));
+                }
+
+                if hunk_start(line)?.is_some() {
+                    let mut file_diff_state =
+                        FileDiffState::new(state.old_file.to_owned(), state.new_file.to_owned());
+                    file_diff_state.advance(line)?;
+                    self.state = State::FileDiff(file_diff_state);
+                }
+
                 Ok(None)
             }
             State::FileDiff(state) => {
                 if is_quoted {
                     if is_diff_header(line) {
                         if state.span_start_line.is_some() {
-                            bail!(
-                                "Detected span that was not terminated with a comment, file: a/{} b/{}",
-                                state.old_file,
-                                state.new_file,
-                            );
+                            return Err(ParseErrorKind::UnterminatedSpan {
+                                old_file: state.old_file.clone(),
+                                new_file: state.new_file.clone(),
+                            });
                         }
 
-                        let (old_file, new_file) = parse_diff_header(line)?;
+                        let (old_file, new_file) = diff_header(line)?;
                         self.state = State::FilePreamble(FilePreambleState {
                             old_file,
                             new_file,
                         });
-                    } else if let Some((mut left_start, mut right_start)) = parse_hunk_start(line)?
-                    {
+                    } else if is_patch_header(line) {
                         if state.span_start_line.is_some() {
-                            bail!(
-                                "Detected cross chunk span, file: a/{} b/{}",
-                                state.old_file,
-                                state.new_file,
-                            );
+                            return Err(ParseErrorKind::UnterminatedSpan {
+                                old_file: state.old_file.clone(),
+                                new_file: state.new_file.clone(),
+                            });
                         }
 
-                        // Subtract 1 b/c this line is before the actual diff hunk
-                        left_start = left_start.saturating_sub(1);
-                        right_start = right_start.saturating_sub(1);
-
-                        state.left_line = left_start;
-                        state.right_line = right_start;
-                        if is_left_line(line) {
-                            state.line = LineLocation::Left(left_start, right_start);
-                        } else if is_right_line(line) {
-                            state.line = LineLocation::Right(left_start, right_start);
-                        } else {
-                            state.line = LineLocation::Both(left_start, right_start);
-                        }
+                        self.state = State::PatchHeader;
                     } else {
-                        let (next_left, next_right) =
-                            get_next_lines(line, state.left_line, state.right_line);
-                        state.left_line = next_left;
-                        state.right_line = next_right;
-                        if is_left_line(line) {
-                            state.line = LineLocation::Left(next_left, next_right);
-                        } else if is_right_line(line) {
-                            state.line = LineLocation::Right(next_left, next_right);
-                        } else {
-                            state.line = LineLocation::Both(next_left, next_right);
+                        if state.span_start_line.is_some() && hunk_start(line)?.is_some() {
+                            return Err(ParseErrorKind::CrossHunkSpan {
+                                old_file: state.old_file.clone(),
+                                new_file: state.new_file.clone(),
+                            });
                         }
+
+                        state.advance(line)?;
                     }
 
                     return Ok(None);
@@ -366,6 +1094,7 @@ impl ReviewParser {
                         file_diff_state: state.clone(),
                     })
                 } else {
+                    state.check_commentable(line)?;
                     self.state = State::Comment(CommentState {
                         file_diff_state: state.clone(),
                         comment: vec![line.to_owned()],
@@ -377,34 +1106,17 @@ impl ReviewParser {
             State::SpanStartOrComment(state) => {
                 if is_quoted {
                     if state.file_diff_state.span_start_line.is_some() {
-                        bail!(
-                            "Detected span that was not terminated with a comment, file: a/{} b/{}",
-                            state.file_diff_state.old_file,
-                            state.file_diff_state.new_file,
-                        );
+                        return Err(ParseErrorKind::UnterminatedSpan {
+                            old_file: state.file_diff_state.old_file.clone(),
+                            new_file: state.file_diff_state.new_file.clone(),
+                        });
                     }
 
                     // Back to the original file diff
-                    let (next_left, next_right) = get_next_lines(
-                        line,
-                        state.file_diff_state.left_line,
-                        state.file_diff_state.right_line,
-                    );
-                    let line = if is_left_line(line) {
-                        LineLocation::Left(next_left, next_right)
-                    } else if is_right_line(line) {
-                        LineLocation::Right(next_left, next_right)
-                    } else {
-                        LineLocation::Both(next_left, next_right)
-                    };
-                    self.state = State::FileDiff(FileDiffState {
-                        old_file: state.file_diff_state.old_file.to_owned(),
-                        new_file: state.file_diff_state.new_file.to_owned(),
-                        left_line: next_left,
-                        right_line: next_right,
-                        line: line.clone(),
-                        span_start_line: Some(line),
-                    });
+                    let mut file_diff_state = state.file_diff_state.clone();
+                    file_diff_state.advance(line)?;
+                    file_diff_state.span_start_line = Some(file_diff_state.line.clone());
+                    self.state = State::FileDiff(file_diff_state);
 
                     Ok(None)
                 } else if line.trim().is_empty() {
@@ -412,6 +1124,7 @@ impl ReviewParser {
                     Ok(None)
                 } else {
                     // In a comment now
+                    state.file_diff_state.check_commentable(line)?;
                     self.state = State::Comment(CommentState {
                         file_diff_state: state.file_diff_state.clone(),
                         comment: vec![line.to_owned()],
@@ -428,34 +1141,22 @@ impl ReviewParser {
                         line: state.file_diff_state.line.clone(),
                         start_line: state.file_diff_state.span_start_line.clone(),
                         comment: state.comment.join("\n").trim_end().to_string(),
+                        line_text: state.file_diff_state.line_text.clone(),
                     });
 
                     if is_diff_header(line) {
-                        let (old_file, new_file) = parse_diff_header(line)?;
+                        let (old_file, new_file) = diff_header(line)?;
                         self.state = State::FilePreamble(FilePreambleState {
                             old_file,
                             new_file,
                         });
+                    } else if is_patch_header(line) {
+                        self.state = State::PatchHeader;
                     } else {
-                        let (next_left, next_right) = get_next_lines(
-                            line,
-                            state.file_diff_state.left_line,
-                            state.file_diff_state.right_line,
-                        );
-                        self.state = State::FileDiff(FileDiffState {
-                            old_file: state.file_diff_state.old_file.to_owned(),
-                            new_file: state.file_diff_state.new_file.to_owned(),
-                            left_line: next_left,
-                            right_line: next_right,
-                            line: if is_left_line(line) {
-                                LineLocation::Left(next_left, next_right)
-                            } else if is_right_line(line) {
-                                LineLocation::Right(next_left, next_right)
-                            } else {
-                                LineLocation::Both(next_left, next_right)
-                            },
-                            span_start_line: None,
-                        });
+                        let mut file_diff_state = state.file_diff_state.clone();
+                        file_diff_state.span_start_line = None;
+                        file_diff_state.advance(line)?;
+                        self.state = State::FileDiff(file_diff_state);
                     }
 
                     return Ok(Some(comment));
@@ -468,13 +1169,24 @@ impl ReviewParser {
     }
 
     pub fn finish(self) -> Option<Comment> {
+        if self.queued.is_some() {
+            return self.queued;
+        }
+
         match self.state {
+            State::CommitLog(state) if !state.comment.is_empty() => {
+                Some(Comment::Commit(CommitComment {
+                    sha: state.sha,
+                    comment: state.comment.join("\n").trim_end().to_string(),
+                }))
+            }
             State::Comment(state) => Some(Comment::Inline(InlineComment {
                 old_file: state.file_diff_state.old_file,
                 new_file: state.file_diff_state.new_file,
                 line: state.file_diff_state.line,
                 start_line: state.file_diff_state.span_start_line,
                 comment: state.comment.join("\n").trim_end().to_string(),
+                line_text: state.file_diff_state.line_text,
             })),
             _ => None,
         }
@@ -489,7 +1201,7 @@ mod tests {
         let mut parser = ReviewParser::new();
 
         for line in input.lines() {
-            if let Err(_) = parser.parse_line(line) {
+            if parser.parse_line(line).is_err() {
                 return;
             }
         }
@@ -497,6 +1209,15 @@ mod tests {
         panic!("Parser succeeded when it should have failed");
     }
 
+    /// Returns the error parsing `input` stops at
+    fn parse_error(input: &str) -> ParseError {
+        let mut parser = ReviewParser::new();
+        input
+            .lines()
+            .find_map(|line| parser.parse_line(line).err())
+            .expect("Parser succeeded when it should have failed")
+    }
+
     fn test(input: &str, expected: &[Comment]) {
         let mut parser = ReviewParser::new();
         let mut comments = Vec::new();
@@ -523,10 +1244,12 @@ mod tests {
     fn single_comment() {
         let input = include_str!("../testdata/single_comment");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-            line: LineLocation::Right(734),
-            start_line: Some(LineLocation::Right(731)),
+            old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            line: LineLocation::Right(734, 734),
+            start_line: Some(LineLocation::Both(731, 731)),
             comment: "Comment 1".to_string(),
+            line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
         })];
 
         test(input, &expected);
@@ -538,26 +1261,60 @@ mod tests {
         let expected = vec![
             Comment::ReviewAction(ReviewAction::Approve),
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(734),
-                start_line: Some(LineLocation::Right(731)),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: Some(LineLocation::Both(731, 731)),
                 comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
             }),
         ];
 
         test(input, &expected);
     }
 
+    #[test]
+    fn custom_quote_prefix() {
+        let input: String = include_str!("../testdata/approve_review")
+            .lines()
+            .map(|l| match l.strip_prefix("> ") {
+                Some(quoted) => format!("~ {}\n", quoted),
+                None => format!("{}\n", l),
+            })
+            .collect();
+
+        let mut parser = ReviewParser::with_quote_prefix("~ ");
+        let mut comments = Vec::new();
+        for line in input.lines() {
+            comments.extend(parser.parse_line(line).unwrap());
+        }
+        comments.extend(parser.finish());
+        assert_eq!(comments.len(), 2);
+        assert_eq!(comments[0], Comment::ReviewAction(ReviewAction::Approve));
+        match &comments[1] {
+            Comment::Inline(c) => {
+                assert_eq!(c.line, LineLocation::Right(734, 734));
+                assert_eq!(c.comment, "Comment 1");
+            }
+            c => panic!("Expected an inline comment, got {:?}", c),
+        }
+
+        // With the default prefix, the diff reads as part of the review comment
+        test_fail(&input.replace("Comment 1", "> Comment 1"));
+    }
+
     #[test]
     fn reject_review() {
         let input = include_str!("../testdata/reject_review");
         let expected = vec![
             Comment::ReviewAction(ReviewAction::RequestChanges),
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(734),
-                start_line: Some(LineLocation::Right(731)),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: Some(LineLocation::Both(731, 731)),
                 comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
             }),
         ];
 
@@ -570,10 +1327,12 @@ mod tests {
         let expected = vec![
             Comment::Review("Review comment".to_string()),
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(734),
-                start_line: Some(LineLocation::Right(731)),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: Some(LineLocation::Both(731, 731)),
                 comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
             }),
         ];
 
@@ -591,14 +1350,38 @@ mod tests {
         test(input, &expected);
     }
 
+    #[test]
+    fn directive_and_summary() {
+        let input = include_str!("../testdata/directive_and_summary");
+        let expected = vec![
+            Comment::ReviewAction(ReviewAction::Approve),
+            Comment::Review(
+                "Looks good overall.\n\nA couple of nits inline, feel\nfree to ignore them."
+                    .to_string(),
+            ),
+            Comment::Inline(InlineComment {
+                old_file: "ch5.txt".to_string(),
+                new_file: "ch5.txt".to_string(),
+                line: LineLocation::Right(0, 3),
+                start_line: None,
+                comment: "Nit: trailing whitespace".to_string(),
+                line_text: "+1. Sun Tzu said: The control of a large force is the same principle as the control of a few men: it is merely a question of dividing up their numbers. ".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
     #[test]
     fn multiline_comment() {
         let input = include_str!("../testdata/multiline_comment");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-            line: LineLocation::Right(736),
+            old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            line: LineLocation::Both(736, 736),
             start_line: None,
             comment: "Comment line 1\nComment line 2\n\nComment line 4".to_string(),
+            line_text: "         match BtfKind::try_from(kind)? {".to_string(),
         })];
 
         test(input, &expected);
@@ -609,16 +1392,20 @@ mod tests {
         let input = include_str!("../testdata/back_to_back_span");
         let expected = vec![
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(734),
-                start_line: Some(LineLocation::Right(731)),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: Some(LineLocation::Both(731, 731)),
                 comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
             }),
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(737),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Both(737, 737),
                 start_line: None,
                 comment: "Comment 2".to_string(),
+                line_text: "             BtfKind::Void => {".to_string(),
             }),
         ];
 
@@ -630,16 +1417,72 @@ mod tests {
         let input = include_str!("../testdata/multiple_files");
         let expected = vec![
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/btf/btf.rs".to_string(),
-                line: LineLocation::Right(734),
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
                 start_line: None,
                 comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
             }),
             Comment::Inline(InlineComment {
-                file: "libbpf-cargo/src/test.rs".to_string(),
-                line: LineLocation::Right(2159),
+                old_file: "libbpf-cargo/src/test.rs".to_string(),
+                new_file: "libbpf-cargo/src/test.rs".to_string(),
+                line: LineLocation::Right(2147, 2159),
                 start_line: None,
                 comment: "Comment 2".to_string(),
+                line_text: "+    let d = find_type_in_btf!(btf, Var, \"d\");".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn no_index_lines() {
+        // Some tools strip the index line and the `---`/`+++` lines from their diffs
+        let input = include_str!("../testdata/no_index_lines");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: None,
+                comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/test.rs".to_string(),
+                new_file: "libbpf-cargo/src/test.rs".to_string(),
+                line: LineLocation::Right(2147, 2159),
+                start_line: None,
+                comment: "Comment 2".to_string(),
+                line_text: "+    let d = find_type_in_btf!(btf, Var, \"d\");".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn same_file_twice() {
+        // Each stanza of a file that shows up twice counts lines from its own hunks
+        let input = include_str!("../testdata/same_file_twice");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                old_file: "src/lib.rs".to_string(),
+                new_file: "src/lib.rs".to_string(),
+                line: LineLocation::Right(11, 11),
+                start_line: None,
+                comment: "First stanza".to_string(),
+                line_text: "+    let b = 3;".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                old_file: "src/lib.rs".to_string(),
+                new_file: "src/lib.rs".to_string(),
+                line: LineLocation::Right(40, 41),
+                start_line: None,
+                comment: "Second stanza".to_string(),
+                line_text: "+    let y = 2;".to_string(),
             }),
         ];
 
@@ -650,10 +1493,12 @@ mod tests {
     fn hunk_start_no_trailing_whitespace() {
         let input = include_str!("../testdata/hunk_start_no_trailing_whitespace");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "ch5.txt".to_string(),
-            line: LineLocation::Right(7),
+            old_file: "ch5.txt".to_string(),
+            new_file: "ch5.txt".to_string(),
+            line: LineLocation::Right(0, 7),
             start_line: None,
             comment: "Great passage".to_string(),
+            line_text: "+3. To ensure that your whole host may withstand the brunt of the enemy's attack and remain unshaken---this is effected by maneuvers direct and indirect. ".to_string(),
         })];
 
         test(input, &expected);
@@ -663,10 +1508,12 @@ mod tests {
     fn deleted_file() {
         let input = include_str!("../testdata/deleted_file");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "ch1.txt".to_string(),
-            line: LineLocation::Left(58),
-            start_line: Some(LineLocation::Left(1)),
+            old_file: "ch1.txt".to_string(),
+            new_file: "ch1.txt".to_string(),
+            line: LineLocation::Left(58, 0),
+            start_line: Some(LineLocation::Left(1, 0)),
             comment: "Comment 1".to_string(),
+            line_text: "-".to_string(),
         })];
 
         test(input, &expected);
@@ -676,10 +1523,12 @@ mod tests {
     fn trailing_comment() {
         let input = include_str!("../testdata/trailing_comment");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "ch1.txt".to_string(),
-            line: LineLocation::Left(59),
-            start_line: Some(LineLocation::Left(1)),
+            old_file: "ch1.txt".to_string(),
+            new_file: "ch1.txt".to_string(),
+            line: LineLocation::Left(59, 0),
+            start_line: Some(LineLocation::Left(1, 0)),
             comment: "Comment 1".to_string(),
+            line_text: "-26. Now the general who wins a battle makes many calculations in his temple ere the battle is fought. The general who loses a battle makes but few calculations beforehand. Thus do many calculations lead to victory, and few calculations to defeat: how much more no calculation at all! It is by attention to this point that I can foresee who is likely to win or lose. ".to_string(),
         })];
 
         test(input, &expected);
@@ -690,10 +1539,12 @@ mod tests {
     fn spaces_in_filename() {
         let input = include_str!("../testdata/spaces_in_filename");
         let expected = vec![Comment::Inline(InlineComment {
-            file: "build/scripts/grafana/provisioning/dashboards/Docker Prometheus Monitoring-1571332751387.json".to_string(),
-            line: LineLocation::Right(2),
+            old_file: "build/scripts/grafana/provisioning/dashboards/Docker Prometheus Monitoring-1571332751387.json".to_string(),
+            new_file: "build/scripts/grafana/provisioning/dashboards/Docker Prometheus Monitoring-1571332751387.json".to_string(),
+            line: LineLocation::Right(0, 2),
             start_line: None,
             comment: "foo".to_string(),
+            line_text: "+    \"annotations\": {".to_string(),
         })];
 
         test(input, &expected);
@@ -705,6 +1556,25 @@ mod tests {
         test_fail(input);
     }
 
+    #[test]
+    fn comment_in_preamble() {
+        let input = include_str!("../testdata/comment_in_preamble");
+        let mut parser = ReviewParser::new();
+        let err = input
+            .lines()
+            .find_map(|line| parser.parse_line(line).err())
+            .expect("Parser succeeded when it should have failed");
+
+        let msg = err.to_string();
+        assert!(msg.contains("'Comment on the whole file'"), "{}", msg);
+        assert!(
+            msg.contains("before the first hunk of libbpf-cargo/src/btf/btf.rs"),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("Move it below"), "{}", msg);
+    }
+
     #[test]
     fn cross_file_span_ignored() {
         let input = include_str!("../testdata/cross_file_span_ignored");
@@ -717,15 +1587,558 @@ mod tests {
         test_fail(input);
     }
 
+    #[test]
+    fn error_kinds() {
+        let btf = "libbpf-cargo/src/btf/btf.rs".to_string();
+
+        let err = parse_error(include_str!("../testdata/unknown_directive"));
+        assert_eq!(err.line, 1);
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnknownDirective("asdf".to_string())
+        );
+        assert_eq!(err.to_string(), "Unknown @prr directive: asdf");
+
+        let err = parse_error(include_str!("../testdata/cross_hunk_span"));
+        assert_eq!(err.line, 20);
+        assert!(
+            matches!(err.kind, ParseErrorKind::CrossHunkSpan { .. }),
+            "{:?}",
+            err
+        );
+
+        let err = parse_error(include_str!("../testdata/comment_in_preamble"));
+        assert_eq!(err.line, 4);
+        assert!(
+            matches!(&err.kind, ParseErrorKind::UnexpectedComment { comment, .. }
+                if comment == "Comment on the whole file"),
+            "{:?}",
+            err
+        );
+
+        let err = parse_error(include_str!("../testdata/react_unknown_emoji"));
+        assert!(
+            matches!(&err.kind, ParseErrorKind::InvalidDirective { message, .. }
+                if message.starts_with("Unknown reaction emoji")),
+            "{:?}",
+            err
+        );
+
+        let input = "> diff --git a/libbpf-cargo/src/btf/btf.rs b/libbpf-cargo/src/btf/btf.rs";
+        let err = parse_error(&format!(
+            "@prr approve\n{}\n> @@ -1 +1 @@\n\n> +a\n{}",
+            input, input
+        ));
+        assert_eq!(err.line, 6);
+        assert_eq!(
+            err.kind,
+            ParseErrorKind::UnterminatedSpan {
+                old_file: btf.clone(),
+                new_file: btf,
+            }
+        );
+
+        let err = parse_error("> diff --git a/x.rs");
+        assert!(
+            matches!(err.kind, ParseErrorKind::BadDiffHeader { .. }),
+            "{:?}",
+            err
+        );
+        let err = parse_error("> diff --git a/x.rs b/x.rs\n> @@ -1 +1 @@\n> *x");
+        assert_eq!(err.line, 3);
+        assert!(
+            matches!(err.kind, ParseErrorKind::CorruptedDiff { .. }),
+            "{:?}",
+            err
+        );
+    }
+
     #[test]
     fn cross_hunk_span() {
         let input = include_str!("../testdata/cross_hunk_span");
         test_fail(input);
     }
 
+    #[test]
+    fn span_same_side() {
+        let input = include_str!("../testdata/span_same_side");
+        let expected = vec![Comment::Inline(InlineComment {
+            old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            line: LineLocation::Both(733, 733),
+            start_line: Some(LineLocation::Both(731, 731)),
+            comment: "Same side span".to_string(),
+            line_text: "         let extra = &data[size_of::<btf_type>()..];".to_string(),
+        })];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn span_mixed_side() {
+        // Starts on a deleted line and ends on an added line. Backends that anchor
+        // comments to a side get the start and end sides separately.
+        let input = include_str!("../testdata/span_mixed_side");
+        let expected = vec![Comment::Inline(InlineComment {
+            old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            line: LineLocation::Right(734, 734),
+            start_line: Some(LineLocation::Left(734, 733)),
+            comment: "Mixed side span".to_string(),
+            line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
+        })];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn thread_lines() {
+        let input = include_str!("../testdata/threads_comments");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Left(734, 733),
+                start_line: None,
+                comment: "Comment on deleted line".to_string(),
+                line_text: "-        let kind = (t.info >> 24) & 0xf;".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: None,
+                comment: "Comment after thread".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn react_directive() {
+        let input = include_str!("../testdata/react_directive");
+        let expected = vec![
+            Comment::Reaction(Reaction {
+                comment_id: 123,
+                emoji: Emoji::ThumbsUp,
+            }),
+            Comment::Reaction(Reaction {
+                comment_id: 456,
+                emoji: Emoji::Rocket,
+            }),
+            Comment::Review("Thanks!".to_string()),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn amend_directive() {
+        let input = include_str!("../testdata/amend_directive");
+        let expected = vec![
+            // Directives inside an amend block are still directives
+            Comment::ReviewAction(ReviewAction::Approve),
+            Comment::Amend(Amend {
+                comment_id: 123,
+                body: "Fixed typo.\n\nSecond paragraph.".to_string(),
+            }),
+            Comment::Review("Review comment".to_string()),
+            Comment::Amend(Amend {
+                comment_id: 456,
+                body: "Other body".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn reply_directive() {
+        let input = include_str!("../testdata/reply_directive");
+        let expected = vec![
+            Comment::Reply(Reply {
+                comment_id: 123,
+                body: "Good point, fixed.".to_string(),
+            }),
+            Comment::Review("Review comment".to_string()),
+            Comment::Amend(Amend {
+                comment_id: 456,
+                body: "Other body".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+
+        let mut parser = ReviewParser::new();
+        assert!(parser.parse_line("@prr reply abc").is_err());
+        assert!(parser.parse_line("@prr reply").is_err());
+        let err = parse_error("@prr reply 1\n\n> diff --git a/a.rs b/a.rs\n");
+        assert_eq!(err.to_string(), "`@prr reply 1` is missing the reply");
+    }
+
+    #[test]
+    fn commit_log() {
+        let input = include_str!("../testdata/commit_log");
+        let expected = vec![
+            Comment::Review("Review comment".to_string()),
+            Comment::Commit(CommitComment {
+                sha: "1111111".to_string(),
+                comment: "Good split.".to_string(),
+            }),
+            Comment::Commit(CommitComment {
+                sha: "1".repeat(40),
+                comment: "Typo in the subject".to_string(),
+            }),
+            Comment::Commit(CommitComment {
+                sha: "2".repeat(40),
+                comment: "Please squash this\ninto the first commit".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn commit_invalid_sha() {
+        let mut parser = ReviewParser::new();
+        assert!(parser.parse_line("@prr commit").is_err());
+        assert!(parser.parse_line("@prr commit abc").is_err());
+        assert!(parser.parse_line("@prr commit 1234567g").is_err());
+        assert!(parser.parse_line("@prr commit 1234567 89abcde").is_err());
+
+        let err = parse_error("@prr commit 1234567\n\n> diff --git a/a.rs b/a.rs\n");
+        assert_eq!(
+            err.to_string(),
+            "`@prr commit 1234567` is missing the comment body"
+        );
+    }
+
+    #[test]
+    fn amend_empty() {
+        let input = include_str!("../testdata/amend_empty");
+        test_fail(input);
+    }
+
+    #[test]
+    fn amend_invalid_id() {
+        let mut parser = ReviewParser::new();
+        assert!(parser.parse_line("@prr amend abc").is_err());
+        assert!(parser.parse_line("@prr amend").is_err());
+        assert!(parser.parse_line("@prr amend 1 2").is_err());
+    }
+
+    #[test]
+    fn attach_directive() {
+        let input = include_str!("../testdata/attach_directive");
+        let expected = vec![
+            Comment::Review(
+                "Looks good, but the page breaks on narrow screens:\n\n@prr attach ./screenshots/narrow.png"
+                    .to_string(),
+            ),
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: None,
+                comment: "Same here:\n@prr attach diagram.svg".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+
+        assert_eq!(attachment_path("@prr attach  a b.png "), Some("a b.png"));
+        assert_eq!(attachment_path("@prr approve"), None);
+        assert!(ReviewParser::new().parse_line("@prr attach").is_err());
+    }
+
+    #[test]
+    fn indented_directives() {
+        // Eg. after a markdown list
+        let input = include_str!("../testdata/approve_review").replacen("@prr", "  @prr", 1);
+        let mut parser = ReviewParser::new();
+        let first = input.lines().next().unwrap();
+        assert_eq!(first, "  @prr approve");
+        assert_eq!(
+            parser.parse_line(first).unwrap(),
+            Some(Comment::ReviewAction(ReviewAction::Approve))
+        );
+
+        let mut parser = ReviewParser::new();
+        assert_eq!(
+            parser.parse_line("\t@prr react 123 :+1:").unwrap(),
+            Some(Comment::Reaction(Reaction {
+                comment_id: 123,
+                emoji: Emoji::ThumbsUp,
+            }))
+        );
+        assert_eq!(parser.parse_line("    @prr amend 456").unwrap(), None);
+        assert_eq!(parser.parse_line("New body").unwrap(), None);
+        assert_eq!(
+            parser.parse_line("  @prr reject").unwrap(),
+            Some(Comment::ReviewAction(ReviewAction::RequestChanges))
+        );
+        assert_eq!(
+            parser.parse_line("> diff --git a/a.rs b/a.rs").unwrap(),
+            Some(Comment::Amend(Amend {
+                comment_id: 456,
+                body: "New body".to_string(),
+            }))
+        );
+
+        // Not directives, but handled like them
+        assert_eq!(attachment_path("  @prr attach a.png"), Some("a.png"));
+        assert_eq!(snippet_name("  @prr snip nit"), Some("nit"));
+    }
+
+    #[test]
+    fn snip_directive() {
+        let mut parser = ReviewParser::new();
+        // Kept in the review comment for `Review::comments()` to expand
+        assert_eq!(parser.parse_line("@prr snip nit").unwrap(), None);
+        assert_eq!(
+            parser.parse_line("> diff --git a/a.rs b/a.rs").unwrap(),
+            Some(Comment::Review("@prr snip nit".to_string()))
+        );
+
+        assert_eq!(snippet_name(" @prr snip test "), Some("test"));
+        assert_eq!(snippet_name("@prr attach test"), None);
+        assert!(ReviewParser::new().parse_line("@prr snip").is_err());
+    }
+
+    #[test]
+    fn saved_directive() {
+        let mut parser = ReviewParser::new();
+        // Kept in the review comment for the backend to expand
+        assert_eq!(parser.parse_line("Thanks!").unwrap(), None);
+        assert_eq!(parser.parse_line("@prr saved Merge policy").unwrap(), None);
+        assert_eq!(
+            parser.parse_line("> diff --git a/a.rs b/a.rs").unwrap(),
+            Some(Comment::Review(
+                "Thanks!\n@prr saved Merge policy".to_string()
+            ))
+        );
+
+        assert_eq!(
+            saved_reply_name(" @prr saved  Merge policy "),
+            Some("Merge policy")
+        );
+        assert_eq!(saved_reply_name("@prr snip test"), None);
+        assert!(ReviewParser::new().parse_line("@prr saved").is_err());
+    }
+
+    #[test]
+    fn event_directive() {
+        let action = |line: &str| ReviewParser::new().parse_line(line);
+        assert_eq!(
+            action("@prr event REQUEST_CHANGES").unwrap(),
+            Some(Comment::ReviewAction(ReviewAction::RequestChanges))
+        );
+        assert_eq!(
+            action("@prr event approve").unwrap(),
+            Some(Comment::ReviewAction(ReviewAction::Approve))
+        );
+        for event in ReviewAction::EVENTS {
+            assert_eq!(ReviewAction::from_event(event).unwrap().event(), event);
+        }
+
+        let err = action("@prr event DISMISS").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown review event 'DISMISS', expected one of APPROVE, REQUEST_CHANGES, COMMENT"
+        );
+        assert!(action("@prr event").is_err());
+        assert!(action("@prr event COMMENT APPROVE").is_err());
+    }
+
+    #[test]
+    fn react_unknown_emoji() {
+        let input = include_str!("../testdata/react_unknown_emoji");
+        test_fail(input);
+    }
+
     #[test]
     fn unknown_directive() {
         let input = include_str!("../testdata/unknown_directive");
         test_fail(input);
     }
+
+    #[test]
+    fn patch_format() {
+        let input = include_str!("../testdata/patch_format");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                new_file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                line: LineLocation::Right(734, 734),
+                start_line: None,
+                comment: "Comment 1".to_string(),
+                line_text: "+        let kind = (t.info >> 24) & 0x1f;".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                old_file: "libbpf-cargo/src/test.rs".to_string(),
+                new_file: "libbpf-cargo/src/test.rs".to_string(),
+                line: LineLocation::Right(2147, 2148),
+                start_line: None,
+                comment: "Comment 2".to_string(),
+                line_text: "+".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn patch_format_commit_comment() {
+        let input = include_str!("../testdata/patch_format").replacen(
+            "> Kind is 5 bits wide.\n",
+            "> Kind is 5 bits wide.\nWhy?\n",
+            1,
+        );
+        test_fail(&input);
+    }
+
+    #[test]
+    fn unicode() {
+        let input = include_str!("../testdata/unicode");
+        let expected = vec![
+            Comment::Inline(InlineComment {
+                old_file: "docs/café.md".to_string(),
+                new_file: "docs/café.md".to_string(),
+                line: LineLocation::Right(3, 3),
+                start_line: None,
+                comment: "Très bien 🎉👍 — ça marche!".to_string(),
+                line_text: "+Bienvenue à tous 👋".to_string(),
+            }),
+            Comment::Inline(InlineComment {
+                old_file: "日本.txt".to_string(),
+                new_file: "日本.txt".to_string(),
+                line: LineLocation::Right(1, 1),
+                start_line: None,
+                comment: "🌙".to_string(),
+                line_text: "+こんばんは".to_string(),
+            }),
+        ];
+
+        test(input, &expected);
+    }
+
+    #[test]
+    fn hunk_lengths() {
+        let input = "> diff --git a/new.txt b/new.txt\n\
+                     > new file mode 100644\n\
+                     > --- /dev/null\n\
+                     > +++ b/new.txt\n\
+                     > @@ -0,0 +1 @@\n\
+                     > +Only line\n\
+                     Comment";
+        let expected = vec![Comment::Inline(InlineComment {
+            old_file: "new.txt".to_string(),
+            new_file: "new.txt".to_string(),
+            line: LineLocation::Right(0, 1),
+            start_line: None,
+            comment: "Comment".to_string(),
+            line_text: "+Only line".to_string(),
+        })];
+        test(input, &expected);
+
+        // A hunk that only adds lines has no context lines
+        let context = input.replace("> +Only line", ">  Only line");
+        test_fail(&context);
+        // Nor more lines than its header says
+        let extra = input.replace("> +Only line", "> +Only line\n> +Another line");
+        test_fail(&extra);
+        // Nor anything that is not a diff line
+        let garbage = input.replace("> +Only line", "> *Only line");
+        test_fail(&garbage);
+
+        let missing_newline = input.replace("Comment", "> \\ No newline at end of file\nComment");
+        test(&missing_newline, &expected);
+
+        // Nothing to comment on after the last hunk of a patch
+        let patch =
+            include_str!("../testdata/patch_format").replace("> 2.35.1", "> 2.35.1\nComment");
+        test_fail(&patch);
+    }
+
+    /// Parses reviews with each diff line corrupted in a few ways, to check the parser
+    /// never places a comment on a line of the wrong kind
+    #[test]
+    fn corrupted_hunks() {
+        fn parse(input: &str) -> Result<Vec<Comment>> {
+            let mut parser = ReviewParser::new();
+            let mut comments = Vec::new();
+            for line in input.lines() {
+                comments.extend(parser.parse_line(line)?);
+            }
+            comments.extend(parser.finish());
+            Ok(comments)
+        }
+
+        fn consistent(c: &InlineComment) -> bool {
+            match (c.line_text.chars().next(), &c.line) {
+                (Some('-'), LineLocation::Left(left, _)) => *left > 0,
+                (Some('+'), LineLocation::Right(_, right)) => *right > 0,
+                (Some(' ') | None, LineLocation::Both(left, right)) => *left > 0 && *right > 0,
+                (Some('@'), LineLocation::Both(..)) => true,
+                _ => false,
+            }
+        }
+
+        for input in [
+            include_str!("../testdata/multiple_files"),
+            include_str!("../testdata/span_mixed_side"),
+            include_str!("../testdata/deleted_file"),
+        ] {
+            let lines: Vec<&str> = input.lines().collect();
+            let mut failed = 0;
+            for (idx, line) in lines.iter().enumerate() {
+                let diff_line = match line.strip_prefix("> ") {
+                    Some(l) if !l.starts_with("+++") && !l.starts_with("---") => l,
+                    _ => continue,
+                };
+                if !diff_line.is_empty() && !diff_line.starts_with(['+', '-', ' ']) {
+                    continue;
+                }
+
+                let mut corrupted = Vec::new();
+                for prefix in ["+", "-", " "] {
+                    corrupted.push(format!("> {}{}", prefix, diff_line.get(1..).unwrap_or("")));
+                }
+                corrupted.push(format!("{}\n{}", line, line));
+                corrupted.push(String::new());
+
+                for replacement in corrupted {
+                    let mut mutated = lines.clone();
+                    mutated[idx] = &replacement;
+                    match parse(&mutated.join("\n")) {
+                        Ok(comments) => {
+                            for c in comments {
+                                if let Comment::Inline(c) = c {
+                                    assert!(consistent(&c), "{:?}", c);
+                                }
+                            }
+                        }
+                        Err(_) => failed += 1,
+                    }
+                }
+            }
+
+            // Not every corruption can be told apart from a valid diff, but most can
+            assert!(failed > 0);
+        }
+    }
+
+    #[test]
+    fn quoted_paths() {
+        let header = r#"diff --git "a/tab\there" "b/quote\"and\\backslash""#;
+        let (old, new) = parse_diff_header(header).unwrap();
+        assert_eq!(old, "tab\there");
+        assert_eq!(new, "quote\"and\\backslash");
+
+        assert!(parse_diff_header(r#"diff --git "a/bad\q" "b/bad\q""#).is_err());
+        assert!(parse_diff_header(r#"diff --git "a/\377" "b/\377""#).is_err());
+    }
 }
@@ -1,43 +1,599 @@
+use std::collections::HashMap;
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::{ErrorKind, Write};
 use std::os::unix::fs::MetadataExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::time::SystemTime;
 
 use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
 use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
 
-use crate::parser::{Comment, InlineComment, ReviewAction, ReviewParser};
+use crate::api::{Commit, DiffRange, SubmitOptions, Thread};
+use crate::color;
+use crate::parser::{
+    attachment_path, is_diff_header, is_patch_header, is_thread_line, parse_diff_header,
+    parse_hunk_start, saved_reply_name, snippet_name, Amend, Comment, CommitComment, InlineComment,
+    LineLocation, ParseErrorKind, Reaction, Reply, ReviewAction, ReviewParser,
+    DEFAULT_QUOTE_PREFIX,
+};
 
 /// Represents the state of a single review
 pub struct Review {
-    /// Path to workdir
-    workdir: PathBuf,
-    /// Name of the owner of the repository
-    owner: String,
-    /// Name of the repository
-    repo: String,
+    /// Directory the review file lives in. See `review_dir()`
+    dir: PathBuf,
     /// Issue # of the pull request
     pr_num: u64,
+    /// Texts that `@prr snip <name>` expands to, by name. See `with_snippets()`
+    snippets: HashMap<String, String>,
+    /// Whether duplicate inline comments are an error. See `deny_duplicates()`
+    deny_duplicates: bool,
+    /// Whether to strip trailing whitespace from comment lines. See
+    /// `strip_trailing_whitespace()`
+    strip_trailing_whitespace: bool,
+    /// Action of a review without an action directive. See `default_action()`
+    default_action: ReviewAction,
+}
+
+/// Everything the user wrote in a review file
+#[derive(Debug)]
+pub struct ReviewComments {
+    /// Overall review action
+    pub action: ReviewAction,
+    /// Overall review comment
+    pub comment: String,
+    /// Comments attached to lines of the diff, in the order they appear in the review file
+    pub inline: Vec<InlineComment>,
+    /// Reactions to existing comments
+    pub reactions: Vec<Reaction>,
+    /// New bodies for existing comments
+    pub amends: Vec<Amend>,
+    /// Replies to existing comments
+    pub replies: Vec<Reply>,
+    /// Comments on commits of the pull request
+    pub commits: Vec<CommitComment>,
+}
+
+impl ReviewComments {
+    /// Returns whether there is nothing to submit besides the action
+    pub fn is_empty(&self) -> bool {
+        self.comment.is_empty()
+            && self.inline.is_empty()
+            && self.reactions.is_empty()
+            && self.amends.is_empty()
+            && self.replies.is_empty()
+            && self.commits.is_empty()
+    }
+
+    /// Drops inline comments on files other than `files`
+    ///
+    /// Unless `keep_summary` is set, the overall review comment, reactions, amends, replies
+    /// and commit comments are dropped as well and the action becomes a plain comment, so
+    /// that a partial submission does not approve or reject the whole pull request.
+    pub fn retain_files(&mut self, files: &[String], keep_summary: bool) {
+        self.inline
+            .retain(|c| files.iter().any(|f| *f == c.new_file || *f == c.old_file));
+        if !keep_summary {
+            self.action = ReviewAction::Comment;
+            self.comment.clear();
+            self.reactions.clear();
+            self.amends.clear();
+            self.replies.clear();
+            self.commits.clear();
+        }
+    }
+
+    /// Replaces the review comment with `message`, eg. from `prr submit --message`
+    ///
+    /// A review comment in the review file loses, with a warning.
+    pub fn set_comment(&mut self, message: &str) {
+        if !self.comment.is_empty() {
+            color::warn("--message replaces the review comment in the review file");
+        }
+        self.comment = message.trim().to_string();
+    }
+
+    /// Appends `signature` to the review comment, separated by a blank line
+    ///
+    /// Inline comments are left alone. A review comment that already ends with the
+    /// signature keeps it as is, and a review with neither a review comment nor inline
+    /// comments is not given one.
+    pub fn append_signature(&mut self, signature: &str) {
+        let signature = signature.trim();
+        if signature.is_empty() || self.comment.ends_with(signature) {
+            return;
+        }
+        if self.comment.is_empty() {
+            if !self.inline.is_empty() {
+                self.comment = signature.to_string();
+            }
+            return;
+        }
+
+        self.comment += "\n\n";
+        self.comment += signature;
+    }
+
+    /// Replaces each `@prr attach <path>` line with what `upload` returns for the file
+    ///
+    /// `upload` gets the file name and contents and returns the markdown to embed the
+    /// uploaded file with. Paths are relative to the working directory. A file attached
+    /// to several comments is only uploaded once.
+    pub fn upload_attachments(
+        &mut self,
+        mut upload: impl FnMut(&str, Vec<u8>) -> Result<String>,
+    ) -> Result<()> {
+        let mut uploaded = HashMap::new();
+        for body in self.bodies_mut() {
+            if !body.lines().any(|l| attachment_path(l).is_some()) {
+                continue;
+            }
+
+            let mut lines = Vec::new();
+            for line in body.lines() {
+                let path = match attachment_path(line) {
+                    Some(path) => path,
+                    None => {
+                        lines.push(line.to_string());
+                        continue;
+                    }
+                };
+                if !uploaded.contains_key(path) {
+                    let contents = fs::read(path)
+                        .with_context(|| format!("Failed to read attachment {}", path))?;
+                    let name = Path::new(path)
+                        .file_name()
+                        .map(|n| n.to_string_lossy())
+                        .unwrap_or_default();
+                    let markdown = upload(&name, contents)
+                        .with_context(|| format!("Failed to upload attachment {}", path))?;
+                    uploaded.insert(path.to_string(), markdown);
+                }
+                lines.push(uploaded[path].clone());
+            }
+            *body = lines.join("\n");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the names of the saved replies used with `@prr saved <name>`
+    pub fn saved_replies(&self) -> Vec<&str> {
+        std::iter::once(&self.comment)
+            .chain(self.inline.iter().map(|c| &c.comment))
+            .chain(self.amends.iter().map(|a| &a.body))
+            .chain(self.replies.iter().map(|r| &r.body))
+            .chain(self.commits.iter().map(|c| &c.comment))
+            .flat_map(|body| body.lines().filter_map(saved_reply_name))
+            .collect()
+    }
+
+    /// Replaces each `@prr saved <name>` line with the body of the saved reply titled
+    /// `name` in `saved`
+    pub fn expand_saved_replies(&mut self, saved: &HashMap<String, String>) -> Result<()> {
+        for body in self.bodies_mut() {
+            if !body.lines().any(|l| saved_reply_name(l).is_some()) {
+                continue;
+            }
+
+            let mut lines = Vec::new();
+            for line in body.lines() {
+                match saved_reply_name(line) {
+                    Some(name) => match saved.get(name) {
+                        Some(reply) => lines.push(reply.trim_end().to_string()),
+                        None => {
+                            let mut titles: Vec<&str> = saved.keys().map(String::as_str).collect();
+                            titles.sort_unstable();
+                            bail!(
+                                "Unknown saved reply '{}', your saved replies are: {}",
+                                name,
+                                titles.join(", ")
+                            );
+                        }
+                    },
+                    None => lines.push(line.to_string()),
+                }
+            }
+            *body = lines.join("\n");
+        }
+
+        Ok(())
+    }
+
+    /// Returns the bodies of every comment, eg. to replace directives in them
+    fn bodies_mut(&mut self) -> impl Iterator<Item = &mut String> {
+        std::iter::once(&mut self.comment)
+            .chain(self.inline.iter_mut().map(|c| &mut c.comment))
+            .chain(self.amends.iter_mut().map(|a| &mut a.body))
+            .chain(self.replies.iter_mut().map(|r| &mut r.body))
+            .chain(self.commits.iter_mut().map(|c| &mut c.comment))
+    }
+}
+
+/// How many inline comments a file in the diff received
+#[derive(Debug, PartialEq)]
+pub struct FileCoverage {
+    /// File name, after an eventual rename
+    pub file: String,
+    /// Number of inline comments on the file
+    pub comments: usize,
+}
+
+/// Whether a pull request is still open for review
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PullState {
+    #[default]
+    Open,
+    /// Closed without being merged
+    Closed,
+    Merged,
+}
+
+impl PullState {
+    pub fn is_open(&self) -> bool {
+        *self == PullState::Open
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            PullState::Open => "open",
+            PullState::Closed => "closed",
+            PullState::Merged => "merged",
+        }
+    }
 }
 
 /// Metadata for a single review. Stored as dotfile next to user-facing review file
+///
+/// Metadata outlives the prr version that wrote it, so every field other than
+/// `original` must be optional with a default. Unknown fields written by newer
+/// versions are ignored.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ReviewMetadata {
     /// Original .diff file contents. Used to detect corrupted review files
     original: String,
     /// Time (seconds since epoch) the review file was last submitted
-    submitted: Option<u64>,
-
-    /* Required by GitLab */
+    #[serde(default)]
+    pub submitted: Option<u64>,
 
-    /// The HEAD commit of the source branch
+    /// The HEAD commit of the source branch. Used to find what changed since the last fetch
+    #[serde(default)]
     pub head_sha: Option<String>,
+    /// The single commit under review, if the review does not cover the whole pull request.
+    /// Comments are anchored to this commit instead of the HEAD
+    #[serde(default)]
+    pub commit: Option<String>,
+    /// `owner/repo` the source branch lives in, if it is a fork of the target repo
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub head_repo: Option<String>,
+
+    /* Required by GitLab */
     /// The merge-base commit SHA between the source branch and the target branches
+    #[serde(default)]
     pub base_sha: Option<String>,
     /// The HEAD commit SHA of the target branch when this version of the diff was created
+    #[serde(default)]
     pub start_sha: Option<String>,
+
+    /// Globs the diff was filtered with on fetch. If set, the review only covers part of
+    /// the pull request
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paths: Vec<String>,
+    /// Prefix of the quoted lines of the review file, if not the default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quote_prefix: Option<String>,
+    /// Whether the pull request was a draft when it was fetched
+    #[serde(default)]
+    pub draft: bool,
+    /// Whether the pull request was closed or merged when it was fetched. See
+    /// `check_state()`
+    #[serde(default, skip_serializing_if = "PullState::is_open")]
+    pub state: PullState,
+    /// Whether the diff was against the merge base or the tip of the base
+    #[serde(default, skip_serializing_if = "DiffRange::is_three_dot")]
+    pub range: DiffRange,
+    /// The commit the diff was taken against, if fetched with `--base`. Unlike `base_sha`,
+    /// comments are not anchored to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base: Option<String>,
+    /// Set while the review is being submitted, to the SHA-1 of the review file contents.
+    /// See `Review::begin_submission()`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitting: Option<String>,
+}
+
+impl ReviewMetadata {
+    /// Returns the prefix of the quoted lines of the review file
+    pub fn quote_prefix(&self) -> &str {
+        self.quote_prefix.as_deref().unwrap_or(DEFAULT_QUOTE_PREFIX)
+    }
+
+    /// Fails if the pull request was closed or merged when it was fetched, unless `force`
+    ///
+    /// Hosts reject most of a review on such pull requests, so it is only read.
+    pub fn check_state(&self, force: bool) -> Result<()> {
+        if !self.state.is_open() && !force {
+            bail!(
+                "The pull request was {} when it was fetched, so the review is likely to be \
+                 rejected. Submit with --force to try anyway",
+                self.state.name()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Thread {
+    /// Returns whether the thread is on the diff line at `old`/`new`
+    fn is_on(&self, old: Option<u64>, new: Option<u64>) -> bool {
+        match self.new_line {
+            Some(_) => self.new_line == new,
+            None => self.old_line.is_some() && self.old_line == old,
+        }
+    }
+
+    /// Renders the thread as lines quoted with `prefix` for the review file
+    fn render(&self, prefix: &str) -> String {
+        let mut out = String::new();
+        for (idx, c) in self.comments.iter().enumerate() {
+            let status = match (idx, self.resolved) {
+                (0, true) => " (resolved)",
+                (0, false) => " (unresolved)",
+                _ => "",
+            };
+            out += &format!("{}| {}{}:\n", prefix, c.author, status);
+            for line in c.body.lines() {
+                if line.is_empty() {
+                    out += &format!("{}|\n", prefix);
+                } else {
+                    out += &format!("{}|   {}\n", prefix, line);
+                }
+            }
+        }
+
+        out
+    }
+}
+
+/// Calls `f` for every line of `diff` with the file and old/new line numbers of the line
+///
+/// Lines outside of hunks (headers, commit metadata) get no file and no line numbers.
+fn walk_diff<'a>(
+    diff: &'a str,
+    mut f: impl FnMut(&'a str, Option<&str>, Option<u64>, Option<u64>),
+) -> Result<()> {
+    let mut file = None;
+    let mut in_hunk = false;
+    let (mut old, mut new) = (0, 0);
+    for line in diff.lines() {
+        if is_diff_header(line) {
+            file = Some(parse_diff_header(line)?.1);
+            in_hunk = false;
+        } else if is_patch_header(line) {
+            file = None;
+            in_hunk = false;
+        } else if let Some((l, r)) = parse_hunk_start(line)? {
+            (old, new) = (l, r);
+            in_hunk = true;
+        } else if in_hunk {
+            let (old_line, new_line) = match line.chars().next() {
+                Some('-') => {
+                    old += 1;
+                    (Some(old - 1), None)
+                }
+                Some('+') => {
+                    new += 1;
+                    (None, Some(new - 1))
+                }
+                Some(' ') | None => {
+                    old += 1;
+                    new += 1;
+                    (Some(old - 1), Some(new - 1))
+                }
+                _ => (None, None),
+            };
+            f(line, file.as_deref(), old_line, new_line);
+            continue;
+        }
+
+        f(line, None, None, None);
+    }
+
+    Ok(())
+}
+
+/// Returns whether `line` of a diff starts a hunk, given it is not inside one
+fn is_hunk_start(line: &str, file: Option<&str>) -> bool {
+    file.is_none() && matches!(parse_hunk_start(line), Ok(Some(_)))
+}
+
+/// Returns the first and last new line of each hunk of `diff`, in the order of the hunks
+///
+/// Hunks that only remove lines have none.
+fn hunk_ranges(diff: &str) -> Result<Vec<Option<(u64, u64)>>> {
+    let mut ranges: Vec<Option<(u64, u64)>> = Vec::new();
+    walk_diff(diff, |line, file, _, new_line| {
+        if is_hunk_start(line, file) {
+            ranges.push(None);
+        } else if let (Some(range), Some(new)) = (ranges.last_mut(), new_line) {
+            *range = Some(range.map_or((new, new), |(first, _)| (first, new)));
+        }
+    })?;
+
+    Ok(ranges)
+}
+
+/// Renders the new lines a hunk covers as a line quoted with `prefix`
+///
+/// These are the lines of the new file that comments in the hunk can be placed on.
+fn render_hunk_range(range: Option<(u64, u64)>, prefix: &str) -> String {
+    let lines = match range {
+        Some((first, last)) if first == last => format!("new line {}", first),
+        Some((first, last)) => format!("new lines {}-{}", first, last),
+        None => "only removed lines".to_string(),
+    };
+
+    format!("{}| Commentable: {}\n", prefix, lines)
+}
+
+/// Interleaves `threads` into `diff` right below the lines they are on
+///
+/// Returns the contents of the review file, quoted with `prefix`. Threads on lines that
+/// are not part of the diff (eg. outdated ones) are left out. With `show_ranges`, the new
+/// lines each hunk covers are shown below its header as well.
+fn annotate_diff(
+    diff: &str,
+    threads: &[Thread],
+    show_ranges: bool,
+    prefix: &str,
+) -> Result<String> {
+    let mut ranges = if show_ranges {
+        hunk_ranges(diff)?
+    } else {
+        Vec::new()
+    }
+    .into_iter();
+    let mut out = String::with_capacity(diff.len());
+    let mut shown = vec![false; threads.len()];
+    walk_diff(diff, |line, file, old_line, new_line| {
+        out += prefix;
+        out += line;
+        out += "\n";
+
+        if is_hunk_start(line, file) {
+            if let Some(range) = ranges.next() {
+                out += &render_hunk_range(range, prefix);
+            }
+        }
+        if file.is_none() {
+            return;
+        }
+        for (thread, shown) in threads.iter().zip(shown.iter_mut()) {
+            if !*shown && Some(thread.file.as_str()) == file && thread.is_on(old_line, new_line) {
+                out += &thread.render(prefix);
+                *shown = true;
+            }
+        }
+    })?;
+
+    Ok(out)
+}
+
+/// Finds inline comments on lines that are not part of `diff`
+///
+/// Comments can only go on lines of the whole pull request diff. A review fetched with
+/// only the changes since some commit may show lines that are not, and the backends
+/// reject comments on them. Returns a message for each such comment that points out the
+/// nearest line that can be commented on.
+pub fn comments_outside_diff(diff: &str, comments: &[InlineComment]) -> Result<Vec<String>> {
+    // Lines that can be commented on, per file and side
+    let mut old_lines: HashMap<String, Vec<u64>> = HashMap::new();
+    let mut new_lines: HashMap<String, Vec<u64>> = HashMap::new();
+    walk_diff(diff, |_, file, old_line, new_line| {
+        let file = match file {
+            Some(f) => f,
+            None => return,
+        };
+        for (lines, line) in [(&mut old_lines, old_line), (&mut new_lines, new_line)] {
+            if let Some(line) = line {
+                match lines.get_mut(file) {
+                    Some(l) => l.push(line),
+                    None => {
+                        lines.insert(file.to_string(), vec![line]);
+                    }
+                }
+            }
+        }
+    })?;
+
+    let mut outside = Vec::new();
+    for c in comments {
+        let (line, side, valid) = match c.line {
+            LineLocation::Left(line, _) => (line, "old", old_lines.get(&c.new_file)),
+            LineLocation::Right(_, line) | LineLocation::Both(_, line) => {
+                (line, "new", new_lines.get(&c.new_file))
+            }
+        };
+        let valid = valid.map(Vec::as_slice).unwrap_or_default();
+        if valid.contains(&line) {
+            continue;
+        }
+
+        let msg = match valid.iter().min_by_key(|l| l.abs_diff(line)) {
+            Some(nearest) => format!(
+                "{}: line {} of the {} file is not part of the pull request diff, \
+                the nearest line that can be commented on is {}",
+                c.new_file, line, side, nearest
+            ),
+            None => format!(
+                "{}: no line of the {} file is part of the pull request diff",
+                c.new_file, side
+            ),
+        };
+        outside.push(msg);
+    }
+
+    Ok(outside)
+}
+
+/// Finds inline comments whose line reads differently in `diff` than when it was commented on
+///
+/// Line numbers alone do not notice when a force push shuffles lines around, eg. after a
+/// rebase with many similar looking lines, so compare the text of each line as well. Returns
+/// a message for each comment whose line changed or is gone.
+pub fn changed_lines(diff: &str, comments: &[InlineComment]) -> Result<Vec<String>> {
+    // Text of each line, per file, side and line number
+    let mut lines: HashMap<(String, &str, u64), &str> = HashMap::new();
+    walk_diff(diff, |line, file, old_line, new_line| {
+        let file = match file {
+            Some(f) => f,
+            None => return,
+        };
+        let key = match (old_line, new_line) {
+            (_, Some(new)) => ("new", new),
+            (Some(old), None) => ("old", old),
+            (None, None) => return,
+        };
+        lines.insert((file.to_string(), key.0, key.1), line);
+    })?;
+
+    // The `+`/`-`/` ` prefix may change, eg. when a line got merged upstream, without
+    // moving the comment
+    let text = |line: &str| line.get(1..).unwrap_or_default().to_string();
+
+    let mut changed = Vec::new();
+    for c in comments {
+        // Comments on a hunk header are anchored to the line before it, which is not in the diff
+        if parse_hunk_start(&c.line_text)?.is_some() {
+            continue;
+        }
+
+        let (side, line) = match c.line {
+            LineLocation::Left(line, _) => ("old", line),
+            LineLocation::Right(_, line) | LineLocation::Both(_, line) => ("new", line),
+        };
+        match lines.get(&(c.new_file.clone(), side, line)) {
+            Some(current) if text(current) == text(&c.line_text) => (),
+            Some(current) => changed.push(format!(
+                "{}: line {} of the {} file was '{}' when you commented on it, but is now '{}'",
+                c.new_file,
+                line,
+                side,
+                text(&c.line_text).trim(),
+                text(current).trim()
+            )),
+            None => changed.push(format!(
+                "{}: line {} of the {} file you commented on is no longer part of the pull request diff",
+                c.new_file, line, side
+            )),
+        }
+    }
+
+    Ok(changed)
 }
 
 #[derive(Default)]
@@ -45,6 +601,27 @@ pub struct Extra {
     base_sha: Option<String>,
     head_sha: Option<String>,
     start_sha: Option<String>,
+    commit: Option<String>,
+    /// `owner/repo` of the source branch, if it differs from the target repo
+    head_repo: Option<String>,
+    /// Existing threads to show in the review file. Not stored in the metadata
+    threads: Vec<Thread>,
+    /// Only keep the files matching one of these globs in the review
+    paths: Vec<String>,
+    /// Prefix to quote the diff with instead of `DEFAULT_QUOTE_PREFIX`
+    quote_prefix: Option<String>,
+    /// Whether the pull request is a draft
+    draft: bool,
+    /// Whether the pull request is closed or merged
+    state: PullState,
+    /// Whether the diff is against the merge base or the tip of the base
+    range: DiffRange,
+    /// Commit the diff is against instead of the pull request's base
+    base: Option<String>,
+    /// Commits to show a log of above the diff, oldest first
+    commits: Vec<Commit>,
+    /// Whether to show the new lines each hunk covers below its header
+    hunk_ranges: bool,
 }
 
 macro_rules! impl_builder {
@@ -63,7 +640,274 @@ impl Extra {
         base_sha: String,
         head_sha: String,
         start_sha: String,
+        commit: String,
+        head_repo: String,
+        quote_prefix: String,
+        base: String,
     );
+
+    pub fn threads(&mut self, threads: Vec<Thread>) -> &mut Self {
+        self.threads = threads;
+        self
+    }
+
+    pub fn paths(&mut self, paths: Vec<String>) -> &mut Self {
+        self.paths = paths;
+        self
+    }
+
+    pub fn draft(&mut self, draft: bool) -> &mut Self {
+        self.draft = draft;
+        self
+    }
+
+    pub fn state(&mut self, state: PullState) -> &mut Self {
+        self.state = state;
+        self
+    }
+
+    pub fn range(&mut self, range: DiffRange) -> &mut Self {
+        self.range = range;
+        self
+    }
+
+    pub fn commits(&mut self, commits: Vec<Commit>) -> &mut Self {
+        self.commits = commits;
+        self
+    }
+
+    pub fn hunk_ranges(&mut self, hunk_ranges: bool) -> &mut Self {
+        self.hunk_ranges = hunk_ranges;
+        self
+    }
+}
+
+/// Formats `commits` like `git log` does, to show them above the diff
+///
+/// Comments below a commit's entry in a review file are on that commit.
+fn commit_log(commits: &[Commit]) -> String {
+    let mut log = String::new();
+    for commit in commits {
+        log += &format!("commit {}\nAuthor: {}\n\n", commit.sha, commit.author);
+        for line in commit.message.trim_end().lines() {
+            // No trailing whitespace on blank lines, editors like to strip it
+            log += format!("    {}", line).trim_end();
+            log += "\n";
+        }
+        log += "\n";
+    }
+
+    log
+}
+
+/// Replaces a leading `@prr snip <name>` line of a comment with the snippet called `name`
+fn expand_snippet(body: &mut String, snippets: &HashMap<String, String>) -> Result<()> {
+    if body.lines().skip(1).any(|l| snippet_name(l).is_some()) {
+        bail!("`@prr snip` must be on the first line of a comment");
+    }
+    let name = match body.lines().next().and_then(snippet_name) {
+        Some(name) => name,
+        None => return Ok(()),
+    };
+    let snippet = match snippets.get(name) {
+        Some(s) => s.trim_end(),
+        None => bail!(
+            "Unknown snippet '{}', add it to the [snippets] config table",
+            name
+        ),
+    };
+
+    let rest = body
+        .split_once('\n')
+        .map(|(_, rest)| rest)
+        .unwrap_or_default();
+    *body = format!("{}\n{}", snippet, rest).trim().to_string();
+
+    Ok(())
+}
+
+/// Drops blank lines at the start and end of a comment, and with `strip_trailing_whitespace`
+/// the trailing whitespace of each line
+fn normalize_comment(body: &str, strip_trailing_whitespace: bool) -> String {
+    let lines: Vec<&str> = body
+        .lines()
+        .map(|l| {
+            if strip_trailing_whitespace {
+                l.trim_end()
+            } else {
+                l
+            }
+        })
+        .skip_while(|l| l.trim().is_empty())
+        .collect();
+    let end = lines
+        .iter()
+        .rposition(|l| !l.trim().is_empty())
+        .map_or(0, |i| i + 1);
+
+    lines[..end].join("\n").trim_end().to_string()
+}
+
+/// Checks that `name` cannot make a review path escape the workdir
+///
+/// `name` may contain multiple normal path components (eg. GitLab subgroups) but not
+/// anything that is absolute or refers to the current or a parent directory.
+fn check_path_component(kind: &str, name: &str) -> Result<()> {
+    let path = Path::new(name);
+    if name.is_empty()
+        || path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        bail!(
+            "Refusing to use suspicious {} '{}' in review path",
+            kind,
+            name
+        );
+    }
+
+    Ok(())
+}
+
+/// Default layout of review files inside the workdir. See `review_dir()`
+pub const DEFAULT_LAYOUT: &str = "{host}/{owner}/{repo}";
+
+/// Number of backups of overwritten reviews kept per pull request. See `Review::backup()`
+pub const MAX_BACKUPS: usize = 5;
+
+/// Returns the directory review files for a repository are placed in
+///
+/// `layout` is a path relative to `workdir` where `{host}`, `{owner}`, and `{repo}`
+/// are substituted. The result is guaranteed to be inside `workdir`.
+pub fn review_dir(
+    workdir: &Path,
+    layout: &str,
+    host: &str,
+    owner: &str,
+    repo: &str,
+) -> Result<PathBuf> {
+    check_path_component("owner", owner)?;
+    check_path_component("repo", repo)?;
+
+    let rendered = layout
+        .replace("{host}", host)
+        .replace("{owner}", owner)
+        .replace("{repo}", repo);
+    if rendered.contains('{') || rendered.contains('}') {
+        bail!("Unknown placeholder in workdir layout '{}'", layout);
+    }
+    check_path_component("workdir layout", &rendered)?;
+
+    Ok(workdir.join(rendered))
+}
+
+/// Returns whether `path` matches the glob `pattern`
+///
+/// `*` and `?` match within a path component and `**` across components. Like in
+/// `.gitignore`, a pattern without a `/` is matched against the file name alone.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    fn matches(p: &[char], s: &[char]) -> bool {
+        match p {
+            [] => s.is_empty(),
+            ['*', '*', '/', rest @ ..] => {
+                // `**/` may also match no directory at all
+                matches(rest, s) || (0..s.len()).any(|i| s[i] == '/' && matches(rest, &s[i + 1..]))
+            }
+            ['*', '*', rest @ ..] => (0..=s.len()).any(|i| matches(rest, &s[i..])),
+            ['*', rest @ ..] => (0..=s.len())
+                .take_while(|&i| i == 0 || s[i - 1] != '/')
+                .any(|i| matches(rest, &s[i..])),
+            ['?', rest @ ..] => s.first().is_some_and(|&c| c != '/') && matches(rest, &s[1..]),
+            [c, rest @ ..] => s.first() == Some(c) && matches(rest, &s[1..]),
+        }
+    }
+
+    let path = if pattern.contains('/') {
+        path
+    } else {
+        path.rsplit('/').next().unwrap_or(path)
+    };
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&pattern, &path)
+}
+
+/// Drops the files from `diff` that match none of the glob `patterns`
+///
+/// Renamed files are kept if either name matches. Lines outside of any file, like
+/// commit metadata in patch format, are always kept.
+fn filter_diff(diff: &str, patterns: &[String]) -> Result<String> {
+    let mut out = String::with_capacity(diff.len());
+    let mut keep = true;
+    for line in diff.lines() {
+        if is_diff_header(line) {
+            let (old, new) = parse_diff_header(line)?;
+            keep = patterns
+                .iter()
+                .any(|p| glob_match(p, &old) || glob_match(p, &new));
+        } else if is_patch_header(line) {
+            keep = true;
+        }
+
+        if keep {
+            out += line;
+            out += "\n";
+        }
+    }
+
+    if !out.lines().any(is_diff_header) {
+        bail!(
+            "No file in the pull request matches {}",
+            patterns.join(", ")
+        );
+    }
+
+    Ok(out)
+}
+
+/// Returns the diff left after filtering it by `extra.paths` and the review file made
+/// from it
+fn render(diff: String, pr_num: u64, extra: &Extra) -> Result<(String, String)> {
+    // Eg. a PR without commits. There's nothing to comment on and a review file with
+    // nothing in it would only confuse submit
+    if diff.trim().is_empty() {
+        bail!("Pull request #{} has no reviewable changes", pr_num);
+    }
+    let prefix = extra
+        .quote_prefix
+        .as_deref()
+        .unwrap_or(DEFAULT_QUOTE_PREFIX);
+    // Blank lines separate comments from the diff, so they must never count as quoted
+    if prefix.trim().is_empty() {
+        bail!(
+            "Invalid quote_prefix '{}': must not be empty or only whitespace",
+            prefix
+        );
+    }
+
+    let diff = if extra.paths.is_empty() {
+        diff
+    } else {
+        filter_diff(&diff, &extra.paths)?
+    };
+    let diff = commit_log(&extra.commits) + &diff;
+    let review_contents = if extra.threads.is_empty() && !extra.hunk_ranges {
+        prefix_lines(&diff, prefix)
+    } else {
+        annotate_diff(&diff, &extra.threads, extra.hunk_ranges, prefix)?
+    };
+
+    Ok((diff, review_contents))
+}
+
+/// Renames `from` to `to`, doing nothing if `from` does not exist
+fn rename_if_exists(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() != ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("Failed to rename {}", from.display()))
+        }
+        _ => Ok(()),
+    }
 }
 
 fn prefix_lines(s: &str, prefix: &str) -> String {
@@ -72,47 +916,152 @@ fn prefix_lines(s: &str, prefix: &str) -> String {
         .collect()
 }
 
+/// Describes a line location for error messages
+fn describe_line(loc: &LineLocation) -> String {
+    match loc {
+        LineLocation::Left(old, _) => format!("old line {}", old),
+        LineLocation::Right(_, new) => format!("new line {}", new),
+        LineLocation::Both(_, new) => format!("line {}", new),
+    }
+}
+
+/// Finds comments that are likely mistakes, though they can be submitted
+///
+/// Reports inline comments with an empty body and, if the review approves, comments on
+/// context lines, which the author may not expect feedback on. `default_action` is the
+/// action of a review without an action directive, see `Review::default_action()`.
+pub fn lint_comments(comments: &[Comment], default_action: ReviewAction) -> Vec<String> {
+    let action = comments
+        .iter()
+        .rev()
+        .find_map(|c| match c {
+            Comment::ReviewAction(a) => Some(*a),
+            _ => None,
+        })
+        .unwrap_or(default_action);
+    let approve = action == ReviewAction::Approve;
+
+    let mut warnings = Vec::new();
+    for c in comments {
+        let c = match c {
+            Comment::Inline(c) => c,
+            _ => continue,
+        };
+        if c.comment.trim().is_empty() {
+            warnings.push(format!(
+                "Empty comment on {} {}",
+                c.new_file,
+                describe_line(&c.line)
+            ));
+        } else if approve && matches!(c.line, LineLocation::Both(..)) {
+            warnings.push(format!(
+                "Comment on unchanged {} of {} in an approving review",
+                describe_line(&c.line),
+                c.new_file
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Rejects spans the backends cannot anchor
+///
+/// Spans may mix sides as long as they read top to bottom in the diff, eg. from a
+/// deleted line to the added line that replaces it. A span that starts on an added
+/// line and ends on a deleted line would have to run backwards from the new file
+/// into the old one, which neither GH nor GL accept.
+fn validate_span(c: &InlineComment) -> Result<()> {
+    let start = match &c.start_line {
+        Some(start) => start,
+        None => return Ok(()),
+    };
+
+    if let (LineLocation::Right(..), LineLocation::Left(..)) = (start, &c.line) {
+        bail!(
+            "Span in {} starts on an added line and ends on a deleted line, which is not supported",
+            c.new_file
+        );
+    }
+
+    // Both positions only ever grow going down the diff
+    let position = |loc: &LineLocation| match *loc {
+        LineLocation::Left(old, new)
+        | LineLocation::Right(old, new)
+        | LineLocation::Both(old, new) => (old, new),
+    };
+    let (start_old, start_new) = position(start);
+    let (end_old, end_new) = position(&c.line);
+    if start_old > end_old || start_new > end_new {
+        bail!(
+            "Span in {} starts at {} which is after where it ends at {}",
+            c.new_file,
+            describe_line(start),
+            describe_line(&c.line)
+        );
+    }
+
+    Ok(())
+}
+
+/// Checks that the inline comments of a review of a two-dot diff are all on added lines
+///
+/// The old side of a two-dot diff is the tip of the base instead of the merge base the
+/// hosts diff against, so deleted and context lines need not be where the host expects
+/// them, or be in its diff at all. Added lines are the same in both.
+fn check_two_dot(comments: &[InlineComment]) -> Result<()> {
+    for c in comments {
+        let loc = std::iter::once(&c.line)
+            .chain(&c.start_line)
+            .find(|loc| !matches!(loc, LineLocation::Right(..)));
+        if let Some(loc) = loc {
+            bail!(
+                "Comment on {} of {} is not on an added line, which is all a two-dot diff can be \
+                commented on. Move it, or re-fetch with `--diff-range three-dot`",
+                describe_line(loc),
+                c.new_file
+            );
+        }
+    }
+
+    Ok(())
+}
+
 impl Review {
     /// Creates a new `Review`
     ///
-    /// `review_file` is the path where the user-facing review file should
+    /// `dir` is the directory where the user-facing review file should
     /// be created. Additional metadata files (dotfiles) may be created in the same
     /// directory.
-    pub fn new(
-        workdir: &Path,
-        diff: String,
-        owner: &str,
-        repo: &str,
-        pr_num: u64,
-        extra: Extra,
-        force: bool,
-    ) -> Result<Review> {
-        let review = Review {
-            workdir: workdir.to_owned(),
-            owner: owner.to_owned(),
-            repo: repo.to_owned(),
-            pr_num,
-        };
+    pub fn new(dir: &Path, diff: String, pr_num: u64, extra: Extra, force: bool) -> Result<Review> {
+        let (diff, review_contents) = render(diff, pr_num, &extra)?;
+        let review = Review::new_existing(dir, pr_num);
 
         // First create directories leading up to review file if necessary
         let review_path = review.path();
         let review_dir = review_path
             .parent()
             .ok_or_else(|| anyhow!("Review path has no parent!"))?;
-        fs::create_dir_all(&review_dir).context("Failed to create workdir directories")?;
+        fs::create_dir_all(review_dir).context("Failed to create workdir directories")?;
 
         // Check if there are unsubmitted changes
-        if !force
-            && review
-                .unsubmitted()
-                .context("Failed to check for unsubmitted review")?
-        {
+        let unsubmitted = review
+            .unsubmitted()
+            .context("Failed to check for unsubmitted review")?;
+        if unsubmitted && !force {
             bail!(
                 "You have unsubmitted changes to the requested review. \
                 Either submit the existing changes, delete the existing review file, \
                 or re-run this command with --force."
             );
         }
+        if unsubmitted {
+            let backup = review.backup()?;
+            color::warn(format!(
+                "Backed up the unsubmitted review to {}, `prr restore` brings it back",
+                backup.display()
+            ));
+        }
 
         // Now create review file
         let mut review_file = OpenOptions::new()
@@ -121,7 +1070,6 @@ impl Review {
             .truncate(true)
             .open(&review_path)
             .context("Failed to create review file")?;
-        let review_contents = prefix_lines(&diff, "> ");
         review_file
             .write_all(review_contents.as_bytes())
             .context("Failed to write review file")?;
@@ -131,8 +1079,17 @@ impl Review {
             original: diff,
             submitted: None,
             head_sha: extra.head_sha,
+            commit: extra.commit,
+            head_repo: extra.head_repo,
             base_sha: extra.base_sha,
             start_sha: extra.start_sha,
+            paths: extra.paths,
+            quote_prefix: extra.quote_prefix,
+            draft: extra.draft,
+            state: extra.state,
+            range: extra.range,
+            base: extra.base,
+            submitting: None,
         };
         let json = serde_json::to_string(&metadata)?;
         let metadata_path = review.metadata_path();
@@ -149,32 +1106,85 @@ impl Review {
         Ok(review)
     }
 
+    /// Writes the review file `new()` would create to `out` instead
+    ///
+    /// Nothing is written to `dir`, not even metadata, so the returned review can't be
+    /// submitted.
+    pub fn print(
+        dir: &Path,
+        diff: String,
+        pr_num: u64,
+        extra: Extra,
+        out: &mut impl Write,
+    ) -> Result<Review> {
+        let (_, review_contents) = render(diff, pr_num, &extra)?;
+        out.write_all(review_contents.as_bytes())
+            .context("Failed to print review file")?;
+
+        Ok(Review::new_existing(dir, pr_num))
+    }
+
     /// Creates a `Review` that already exists on disk
     ///
     /// Note we do not check that anything actually exists on disk because that is
     /// inherently racy. We'll handle ENOENT errors when we actually use any files.
-    pub fn new_existing(workdir: &Path, owner: &str, repo: &str, pr_num: u64) -> Review {
+    pub fn new_existing(dir: &Path, pr_num: u64) -> Review {
         Review {
-            workdir: workdir.to_owned(),
-            owner: owner.to_owned(),
-            repo: repo.to_owned(),
+            dir: dir.to_owned(),
             pr_num,
+            snippets: HashMap::new(),
+            deny_duplicates: false,
+            strip_trailing_whitespace: false,
+            default_action: ReviewAction::default(),
         }
     }
 
-    /// Parse the user-supplied comments on a review
+    /// Sets the snippets `comments()` expands, usually the `[snippets]` config table
+    pub fn with_snippets(mut self, snippets: HashMap<String, String>) -> Review {
+        self.snippets = snippets;
+        self
+    }
+
+    /// Makes `comments()` fail on duplicate inline comments instead of dropping them
     ///
-    /// Returns (overall review action, overall review comment, inline comments)
-    pub fn comments(&self) -> Result<(ReviewAction, String, Vec<InlineComment>)> {
-        let contents = fs::read_to_string(self.path()).context("Failed to read review file")?;
-        self.validate_review_file(&contents)?;
+    /// A duplicate is a comment with the same text on the same line as an earlier one,
+    /// eg. on a line that shows up in several commits of a `--patch` review.
+    pub fn deny_duplicates(mut self, deny: bool) -> Review {
+        self.deny_duplicates = deny;
+        self
+    }
 
-        let mut parser = ReviewParser::new();
-        let mut review_action = ReviewAction::Comment;
-        let mut review_comment = String::new();
-        let mut inline_comments = Vec::new();
-        for (idx, line) in contents.lines().enumerate() {
-            let res = parser
+    /// Makes `comments()` strip trailing whitespace from every line of a comment
+    ///
+    /// Off by default since two trailing spaces are a line break in markdown.
+    pub fn strip_trailing_whitespace(mut self, strip: bool) -> Review {
+        self.strip_trailing_whitespace = strip;
+        self
+    }
+
+    /// Sets the action of a review without an `@prr approve`/`reject`/`comment` directive,
+    /// usually `prr.default_action` from the config
+    pub fn default_action(mut self, action: ReviewAction) -> Review {
+        self.default_action = action;
+        self
+    }
+
+    /// Parse the user-supplied comments on a review
+    pub fn comments(&self) -> Result<ReviewComments> {
+        let contents = fs::read_to_string(self.path()).context("Failed to read review file")?;
+        let metadata = self.read_metadata()?;
+        self.validate_review_file(&contents, &metadata)?;
+
+        let mut parser = ReviewParser::with_quote_prefix(metadata.quote_prefix());
+        let mut review_action = self.default_action;
+        let mut review_comment = String::new();
+        let mut inline_comments = Vec::new();
+        let mut reactions = Vec::new();
+        let mut amends = Vec::new();
+        let mut replies = Vec::new();
+        let mut commits = Vec::new();
+        for (idx, line) in contents.lines().enumerate() {
+            let res = parser
                 .parse_line(line)
                 .with_context(|| format!("Failed to parse review on line {}", idx + 1))?;
 
@@ -188,6 +1198,10 @@ impl Review {
                 }
                 Some(Comment::Inline(c)) => inline_comments.push(c),
                 Some(Comment::ReviewAction(a)) => review_action = a,
+                Some(Comment::Reaction(r)) => reactions.push(r),
+                Some(Comment::Amend(a)) => amends.push(a),
+                Some(Comment::Reply(r)) => replies.push(r),
+                Some(Comment::Commit(c)) => commits.push(c),
                 None => {}
             }
         }
@@ -197,30 +1211,338 @@ impl Review {
             // Original diff must have been short to begin with
             Some(Comment::Review(_)) => bail!("Unexpected review comment at parser finish"),
             Some(Comment::ReviewAction(_)) => bail!("Unexpected review action at parser finish"),
+            Some(Comment::Reaction(_)) => bail!("Unexpected reaction at parser finish"),
+            Some(Comment::Amend(a)) => amends.push(a),
+            Some(Comment::Reply(r)) => replies.push(r),
+            Some(Comment::Commit(c)) => commits.push(c),
             None => {}
         };
 
-        Ok((review_action, review_comment, inline_comments))
+        let bodies = std::iter::once(&mut review_comment)
+            .chain(inline_comments.iter_mut().map(|c| &mut c.comment))
+            .chain(amends.iter_mut().map(|a| &mut a.body))
+            .chain(replies.iter_mut().map(|r| &mut r.body))
+            .chain(commits.iter_mut().map(|c| &mut c.comment));
+        for body in bodies {
+            expand_snippet(body, &self.snippets)?;
+            *body = normalize_comment(body, self.strip_trailing_whitespace);
+        }
+        inline_comments.retain(|c| {
+            if c.comment.is_empty() {
+                color::warn(format!(
+                    "Empty comment on {} {}, skipping it",
+                    c.new_file,
+                    describe_line(&c.line)
+                ));
+            }
+            !c.comment.is_empty()
+        });
+        commits.retain(|c| {
+            if c.comment.is_empty() {
+                color::warn(format!("Empty comment on commit {}, skipping it", c.sha));
+            }
+            !c.comment.is_empty()
+        });
+
+        for c in &mut inline_comments {
+            validate_span(c)?;
+            // A span over a single line is just a comment on that line. GH refuses spans
+            // that start and end on the same line.
+            if c.start_line.as_ref() == Some(&c.line) {
+                c.start_line = None;
+            }
+        }
+
+        // Submitting the same comment twice only starts two identical threads
+        let mut unique: Vec<InlineComment> = Vec::with_capacity(inline_comments.len());
+        for c in inline_comments {
+            let duplicate = unique
+                .iter()
+                .any(|u| u.new_file == c.new_file && u.line == c.line && u.comment == c.comment);
+            if !duplicate {
+                unique.push(c);
+                continue;
+            }
+
+            let msg = format!(
+                "Duplicate comment on {} {}",
+                c.new_file,
+                describe_line(&c.line)
+            );
+            if self.deny_duplicates {
+                bail!("{}", msg);
+            }
+            color::warn(format!("{}, submitting it only once", msg));
+        }
+        let inline_comments = unique;
+
+        debug!(
+            "Parsed review file {}: action {:?}, {} inline comments, {} reactions, {} amends, \
+            {} replies, {} commit comments",
+            self.path().display(),
+            review_action,
+            inline_comments.len(),
+            reactions.len(),
+            amends.len(),
+            replies.len(),
+            commits.len(),
+        );
+
+        Ok(ReviewComments {
+            action: review_action,
+            comment: review_comment,
+            inline: inline_comments,
+            reactions,
+            amends,
+            replies,
+            commits,
+        })
+    }
+
+    /// Checks the review file for comments that are likely mistakes
+    ///
+    /// Unlike `comments()` this does not fail on a span that crosses into the next hunk,
+    /// but reports it along with the findings of `lint_comments()`. Other errors are left
+    /// for `comments()` to report.
+    pub fn lint(&self) -> Result<Vec<String>> {
+        let contents = fs::read_to_string(self.path()).context("Failed to read review file")?;
+        let metadata = self.read_metadata()?;
+
+        let mut parser = ReviewParser::with_quote_prefix(metadata.quote_prefix());
+        let mut comments = Vec::new();
+        let mut error = None;
+        for line in contents.lines() {
+            match parser.parse_line(line) {
+                Ok(c) => comments.extend(c),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            }
+        }
+
+        let mut warnings = match error {
+            None => {
+                comments.extend(parser.finish());
+                Vec::new()
+            }
+            Some(e) => match e.kind {
+                ParseErrorKind::CrossHunkSpan { new_file, .. } => vec![format!(
+                    "Span in {} crosses into the next hunk on line {} of the review file, \
+                    submitting will fail",
+                    new_file, e.line
+                )],
+                _ => Vec::new(),
+            },
+        };
+        // A snippet may expand to nothing. Unknown snippets are left for `comments()`
+        for c in &mut comments {
+            if let Comment::Inline(c) = c {
+                let _ = expand_snippet(&mut c.comment, &self.snippets);
+            }
+        }
+        warnings.extend(lint_comments(&comments, self.default_action));
+        Ok(warnings)
+    }
+
+    /// Returns the comments to submit with `opts`, after the steps every host takes
+    ///
+    /// Refuses reviews of closed or merged pull requests unless forced, and comments a
+    /// two-dot diff cannot take, see `check_two_dot()`. Applies `--only`, `--message` and
+    /// `--append-signature`, expands `@prr saved` with what `saved_replies` returns and
+    /// replaces `@prr attach` with what `upload` returns, see
+    /// `ReviewComments::upload_attachments()`. `saved_replies` is only called if the
+    /// review uses a saved reply.
+    ///
+    /// A review without anything to submit is an error, unless `bare_action` is set and it
+    /// approves or rejects, for hosts that take an approval without a comment.
+    pub fn prepare_submission(
+        &self,
+        opts: &SubmitOptions,
+        bare_action: bool,
+        saved_replies: impl FnOnce() -> Result<HashMap<String, String>>,
+        upload: impl FnMut(&str, Vec<u8>) -> Result<String>,
+    ) -> Result<ReviewComments> {
+        let metadata = self.read_metadata()?;
+        metadata.check_state(opts.force)?;
+        let mut comments = self.comments()?;
+        if !metadata.range.is_three_dot() {
+            check_two_dot(&comments.inline)?;
+        }
+        if !opts.only.is_empty() {
+            comments.retain_files(&opts.only, opts.include_summary);
+        }
+        if let Some(message) = &opts.message {
+            comments.set_comment(message);
+        }
+        if let Some(signature) = &opts.signature {
+            comments.append_signature(signature);
+        }
+        if !comments.saved_replies().is_empty() {
+            comments.expand_saved_replies(&saved_replies()?)?;
+        }
+        // Upload before posting anything, so a failed upload leaves the pull request as is
+        comments.upload_attachments(upload)?;
+
+        if comments.is_empty() && !(bare_action && comments.action != ReviewAction::Comment) {
+            bail!("No review comments");
+        }
+
+        Ok(comments)
+    }
+
+    /// Ends the submission started with `begin_submission()` once everything
+    /// `prepare_submission()` returned was submitted
+    ///
+    /// Marks the review submitted, unless `opts` left part of it to submit later.
+    pub fn finish_submission(&self, opts: &SubmitOptions) -> Result<()> {
+        // The rest of the review still needs to be submitted
+        if opts.only.is_empty() {
+            self.mark_submitted()
+        } else {
+            self.end_submission()
+        }
+        .context("Failed to update review metadata")
     }
 
     /// Update the review file's submission time
+    ///
+    /// Also ends the submission started with `begin_submission()`.
     pub fn mark_submitted(&self) -> Result<()> {
-        let metadata_path = self.metadata_path();
-        let data = fs::read_to_string(&metadata_path).context("Failed to read metadata file")?;
-        let mut metadata: ReviewMetadata =
-            serde_json::from_str(&data).context("Failed to parse metadata json")?;
-
+        let mut metadata = self.read_metadata()?;
         let submission_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .expect("Time went backwards");
         metadata.submitted = Some(submission_time.as_secs());
+        metadata.submitting = None;
 
-        let json = serde_json::to_string(&metadata)?;
+        self.write_metadata(&metadata)
+    }
+
+    /// Records that the review file is about to be submitted
+    ///
+    /// If prr crashes or the connection drops after the host got a request, there is no
+    /// telling which comments made it. The marker stays behind in that case, and the next
+    /// submission warns that comments may already be on the pull request. It holds a hash
+    /// of the review file, so the warning can tell whether the file changed since.
+    pub fn begin_submission(&self) -> Result<()> {
+        let contents = fs::read(self.path()).context("Failed to read review file")?;
+        let key = format!("{:x}", Sha1::digest(contents));
+        let mut metadata = self.read_metadata()?;
+        if let Some(previous) = &metadata.submitting {
+            let what = if *previous == key {
+                "this review"
+            } else {
+                "an earlier version of this review"
+            };
+            color::warn(format!(
+                "A submission of {} did not finish, some of its comments may already be on \
+                the pull request",
+                what
+            ));
+        }
+        debug!("Submitting review file with hash {}", key);
+        metadata.submitting = Some(key);
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Ends the submission started with `begin_submission()` without marking the review
+    /// submitted, eg. after submitting only part of it
+    pub fn end_submission(&self) -> Result<()> {
+        let mut metadata = self.read_metadata()?;
+        metadata.submitting = None;
+
+        self.write_metadata(&metadata)
+    }
+
+    /// Makes a submitted review editable and submittable again
+    ///
+    /// Clears the submission time, and restores the review file from the metadata if it
+    /// was removed. Returns whether the review file was restored.
+    pub fn reopen(&self) -> Result<bool> {
+        let mut metadata = self.read_metadata()?;
+        metadata.submitted = None;
+        self.write_metadata(&metadata)?;
+
+        let path = self.path();
+        if path.exists() {
+            return Ok(false);
+        }
+        fs::write(
+            &path,
+            prefix_lines(&metadata.original, metadata.quote_prefix()),
+        )
+        .context("Failed to restore review file")?;
+
+        Ok(true)
+    }
+
+    /// Returns the paths of backup `n` of the review file and of its metadata, 1 being the
+    /// most recent
+    fn backup_paths(&self, n: usize) -> (PathBuf, PathBuf) {
+        let mut review = self.path().into_os_string();
+        review.push(format!(".bak.{}", n));
+        let mut metadata = self.metadata_path().into_os_string();
+        metadata.push(format!(".bak.{}", n));
+
+        (review.into(), metadata.into())
+    }
+
+    /// Backs up the review file and its metadata before they are overwritten
+    ///
+    /// Only the last `MAX_BACKUPS` backups are kept. Returns the path of the backup of the
+    /// review file.
+    pub fn backup(&self) -> Result<PathBuf> {
+        for n in (1..MAX_BACKUPS).rev() {
+            let (review, metadata) = self.backup_paths(n);
+            let (older_review, older_metadata) = self.backup_paths(n + 1);
+            rename_if_exists(&review, &older_review)?;
+            rename_if_exists(&metadata, &older_metadata)?;
+        }
+
+        let (review, metadata) = self.backup_paths(1);
+        fs::copy(self.path(), &review).context("Failed to back up review file")?;
+        fs::copy(self.metadata_path(), metadata).context("Failed to back up metadata file")?;
+
+        Ok(review)
+    }
+
+    /// Puts the most recent backup made by `backup()` back in place of the review
+    ///
+    /// Unless `force` is set, fails if the review has unsubmitted changes, as those would
+    /// be lost.
+    pub fn restore(&self, force: bool) -> Result<()> {
+        let (review, metadata) = self.backup_paths(1);
+        if !review.exists() {
+            bail!("No backup of the review for #{}", self.pr_num);
+        }
+        if !force && self.unsubmitted()? {
+            bail!(
+                "You have unsubmitted changes to the review. Either submit them, delete the \
+                review file, or re-run this command with --force."
+            );
+        }
+
+        fs::rename(&review, self.path()).context("Failed to restore review file")?;
+        rename_if_exists(&metadata, &self.metadata_path())?;
+        for n in 2..=MAX_BACKUPS {
+            let (review, metadata) = self.backup_paths(n);
+            let (newer_review, newer_metadata) = self.backup_paths(n - 1);
+            rename_if_exists(&review, &newer_review)?;
+            rename_if_exists(&metadata, &newer_metadata)?;
+        }
+
+        Ok(())
+    }
+
+    fn write_metadata(&self, metadata: &ReviewMetadata) -> Result<()> {
+        let json = serde_json::to_string(metadata)?;
         let mut metadata_file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&metadata_path)
+            .open(self.metadata_path())
             .context("Failed to create metadata file")?;
         metadata_file
             .write_all(json.as_bytes())
@@ -229,40 +1551,74 @@ impl Review {
         Ok(())
     }
 
+    /// Returns whether this review has been fetched before
+    pub fn has_metadata(&self) -> bool {
+        self.metadata_path().exists()
+    }
+
     pub fn read_metadata(&self) -> Result<ReviewMetadata> {
         let metadata_path = self.metadata_path();
         let data = fs::read_to_string(metadata_path).context("Failed to read metadata file")?;
         serde_json::from_str(&data).context("Failed to parse metadata json")
     }
 
+    /// Returns the number of files in the diff under review
+    pub fn num_files(&self) -> Result<usize> {
+        let metadata = self.read_metadata()?;
+        Ok(metadata
+            .original
+            .lines()
+            .filter(|l| l.starts_with("diff --git "))
+            .count())
+    }
+
+    /// Returns every file in the diff, in diff order, with the number of inline comments on it
+    pub fn file_coverage(&self) -> Result<Vec<FileCoverage>> {
+        let metadata = self.read_metadata()?;
+        let inline = self.comments()?.inline;
+
+        metadata
+            .original
+            .lines()
+            .filter(|l| l.starts_with("diff --git "))
+            .map(|l| {
+                let (_, file) = parse_diff_header(l)?;
+                let comments = inline.iter().filter(|c| c.new_file == file).count();
+                Ok(FileCoverage { file, comments })
+            })
+            .collect()
+    }
+
     /// Validates whether the user corrupted the quoted contents
-    fn validate_review_file(&self, contents: &str) -> Result<()> {
+    fn validate_review_file(&self, contents: &str, metadata: &ReviewMetadata) -> Result<()> {
+        let prefix = metadata.quote_prefix();
+        // Existing threads are informational, so leave them out on both sides
         let mut reconstructed = String::with_capacity(contents.len());
         for line in contents.lines() {
-            if let Some(stripped) = line.strip_prefix("> ") {
+            if let Some(stripped) = line.strip_prefix(prefix).filter(|l| !is_thread_line(l)) {
                 reconstructed += stripped;
                 reconstructed += "\n";
             }
         }
 
-        let metadata = self.read_metadata()?;
+        let mut original = String::with_capacity(metadata.original.len());
+        for line in metadata.original.lines().filter(|l| !is_thread_line(l)) {
+            original += line;
+            original += "\n";
+        }
 
-        if reconstructed != metadata.original {
+        if reconstructed != original {
             // Be helpful and provide exact line number of mismatch.
             //
             // This loop on zip() will work as long as there isn't any truncation or trailing junk
             // in the original text. To handle this case, there's the final bail!()
-            for (idx, (l, r)) in reconstructed
-                .lines()
-                .zip(metadata.original.lines())
-                .enumerate()
-            {
+            for (idx, (l, r)) in reconstructed.lines().zip(original.lines()).enumerate() {
                 if l != r {
                     // Get number of user generated lines up until the mismatch
                     let user_lines = contents
                         .lines()
                         .take(idx)
-                        .filter(|l| !l.starts_with("> "))
+                        .filter(|l| l.strip_prefix(prefix).is_none_or(is_thread_line))
                         .count();
                     let err = format!("Line {}, found '{l}' expected '{r}'", idx + 1 + user_lines);
                     bail!("Detected corruption in quoted part of review file: {err}");
@@ -276,7 +1632,7 @@ impl Review {
     }
 
     /// Returns whether or not there exist unsubmitted changes on disk
-    fn unsubmitted(&self) -> Result<bool> {
+    pub fn unsubmitted(&self) -> Result<bool> {
         let data = match fs::read_to_string(self.metadata_path()) {
             Ok(d) => d,
             Err(e) => match e.kind() {
@@ -302,18 +1658,45 @@ impl Review {
             .context("mtime is negative")?;
 
         match metadata.submitted {
-            // If modified time is more recent than last submission, then unsubmitted
-            Some(t) => Ok(mtime > t),
-            // If no last submission time, then default to unsubmitted
-            None => Ok(true),
+            // If not modified since last submission, then nothing is unsubmitted
+            Some(t) if mtime <= t => Ok(false),
+            // Otherwise only unsubmitted if the user actually wrote something. This keeps
+            // a freshly fetched (or merely opened and saved) review from blocking a re-fetch.
+            _ => self.has_user_content(metadata.quote_prefix()),
+        }
+    }
+
+    /// Returns whether the review file contains anything written by the user
+    ///
+    /// A review file that fails to parse is assumed to contain user edits.
+    fn has_user_content(&self, quote_prefix: &str) -> Result<bool> {
+        let contents = match fs::read_to_string(self.path()) {
+            Ok(c) => c,
+            Err(e) => match e.kind() {
+                ErrorKind::NotFound => return Ok(false),
+                _ => bail!("Failed to read review file: {}", e),
+            },
+        };
+
+        let mut parser = ReviewParser::with_quote_prefix(quote_prefix);
+        for line in contents.lines() {
+            match parser.parse_line(line) {
+                Ok(None) => {}
+                Ok(Some(_)) | Err(_) => return Ok(true),
+            }
         }
+
+        Ok(parser.finish().is_some())
+    }
+
+    /// Returns the number of the pull request under review
+    pub fn pr_num(&self) -> u64 {
+        self.pr_num
     }
 
     /// Returns path to user-facing review file
     pub fn path(&self) -> PathBuf {
-        let mut p = self.workdir.clone();
-        p.push(&self.owner);
-        p.push(&self.repo);
+        let mut p = self.dir.clone();
         p.push(format!("{}.prr", self.pr_num));
 
         p
@@ -326,3 +1709,1269 @@ impl Review {
         metadata_path
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::ThreadComment;
+    use crate::parser::Emoji;
+
+    #[test]
+    fn path_traversal() {
+        let root = tempfile::tempdir().unwrap();
+        let workdir = root.path().join("workdir");
+
+        for owner in ["../../etc", "/etc", "./danobi", "danobi/../.."] {
+            let res = review_dir(&workdir, DEFAULT_LAYOUT, "github.com", owner, "prr")
+                .and_then(|dir| Review::new(&dir, String::new(), 1, Extra::default(), false));
+            assert!(res.is_err(), "owner '{}' was accepted", owner);
+        }
+        assert!(review_dir(&workdir, "../{owner}", "github.com", "danobi", "prr").is_err());
+
+        // Nothing may have been written, not even the workdir itself
+        assert_eq!(fs::read_dir(root.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn nested_owner() {
+        let workdir = Path::new("/workdir");
+        let dir = review_dir(
+            workdir,
+            DEFAULT_LAYOUT,
+            "gitlab.com",
+            "group/subgroup",
+            "prr",
+        );
+        assert_eq!(
+            dir.unwrap(),
+            Path::new("/workdir/gitlab.com/group/subgroup/prr")
+        );
+    }
+
+    #[test]
+    fn layouts() {
+        let workdir = Path::new("/workdir");
+        let dir = |layout| review_dir(workdir, layout, "github.com", "danobi", "prr").unwrap();
+
+        assert_eq!(
+            dir(DEFAULT_LAYOUT),
+            Path::new("/workdir/github.com/danobi/prr")
+        );
+        assert_eq!(dir("{owner}/{repo}"), Path::new("/workdir/danobi/prr"));
+        assert_eq!(dir("{owner}-{repo}"), Path::new("/workdir/danobi-prr"));
+        assert_eq!(
+            dir("reviews/{host}"),
+            Path::new("/workdir/reviews/github.com")
+        );
+    }
+
+    const DIFF: &str = include_str!("../testdata/diffs/single_file");
+
+    #[test]
+    fn refetch_pristine() {
+        let dir = tempfile::tempdir().unwrap();
+        Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false).unwrap();
+
+        // Nothing was written by the user yet, so re-fetching is fine
+        Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false).unwrap();
+    }
+
+    #[test]
+    fn refetch_clobber() {
+        let dir = tempfile::tempdir().unwrap();
+        let review = Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false).unwrap();
+
+        let mut contents = fs::read_to_string(review.path()).unwrap();
+        contents.push_str("\nLooks wrong\n");
+        fs::write(review.path(), contents).unwrap();
+
+        let err = Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false)
+            .err()
+            .expect("Unsubmitted review was clobbered");
+        assert!(err.to_string().contains("--force"));
+        assert!(fs::read_to_string(review.path())
+            .unwrap()
+            .contains("Looks wrong"));
+
+        Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), true).unwrap();
+        assert!(!fs::read_to_string(review.path())
+            .unwrap()
+            .contains("Looks wrong"));
+    }
+
+    #[test]
+    fn empty_diff() {
+        let dir = tempfile::tempdir().unwrap();
+        for diff in ["", "\n"] {
+            let err = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false)
+                .err()
+                .unwrap();
+            assert_eq!(err.to_string(), "Pull request #1 has no reviewable changes");
+        }
+        assert!(!Review::new_existing(dir.path(), 1).path().exists());
+    }
+
+    #[test]
+    fn print() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut out = Vec::new();
+        let review =
+            Review::print(dir.path(), DIFF.to_string(), 1, Extra::default(), &mut out).unwrap();
+
+        assert_eq!(String::from_utf8(out).unwrap(), prefix_lines(DIFF, "> "));
+        assert!(!review.path().exists());
+        assert!(!review.has_metadata());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn custom_quote_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut extra = Extra::default();
+        extra.quote_prefix("| ".to_string());
+        let review = Review::new(dir.path(), DIFF.to_string(), 1, extra, false).unwrap();
+
+        let contents = fs::read_to_string(review.path()).unwrap();
+        assert_eq!(contents, prefix_lines(DIFF, "| "));
+        assert!(!review.unsubmitted().unwrap());
+        let commented = contents.replacen("0x1f;\n", "0x1f;\nWhy?\n", 1);
+        fs::write(review.path(), commented).unwrap();
+        assert!(review.unsubmitted().unwrap());
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.inline.len(), 1);
+        assert_eq!(comments.inline[0].comment, "Why?");
+
+        // Removed review files come back quoted the same way
+        review.mark_submitted().unwrap();
+        fs::remove_file(review.path()).unwrap();
+        review.reopen().unwrap();
+        assert_eq!(fs::read_to_string(review.path()).unwrap(), contents);
+
+        for prefix in ["", "  "] {
+            let mut extra = Extra::default();
+            extra.quote_prefix(prefix.to_string());
+            let err = Review::new(dir.path(), DIFF.to_string(), 2, extra, false)
+                .err()
+                .unwrap();
+            assert!(err.to_string().contains("Invalid quote_prefix"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn duplicate_comments() {
+        // The same line shows up in both commits of a patch
+        let patch = |n: u64| {
+            format!(
+                "From {:040x} Mon Sep 17 00:00:00 2001\nSubject: [PATCH {}/2] Fix\n---\n\n{}",
+                n, n, DIFF
+            )
+        };
+        let diff = patch(1) + &patch(2);
+        let dir = tempfile::tempdir().unwrap();
+        let review = Review::new(dir.path(), diff, 1, Extra::default(), false).unwrap();
+        let contents = fs::read_to_string(review.path())
+            .unwrap()
+            .replace("0x1f;\n", "0x1f;\nTypo\n");
+        fs::write(review.path(), &contents).unwrap();
+
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.inline.len(), 1);
+        assert_eq!(comments.inline[0].comment, "Typo");
+
+        let err = Review::new_existing(dir.path(), 1)
+            .deny_duplicates(true)
+            .comments()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Duplicate comment on libbpf-cargo/src/btf/btf.rs new line 734"
+        );
+
+        // Different text on the same line is fine
+        let contents = contents.replacen("Typo\n", "Typo here\n", 1);
+        fs::write(review.path(), contents).unwrap();
+        let review = Review::new_existing(dir.path(), 1).deny_duplicates(true);
+        assert_eq!(review.comments().unwrap().inline.len(), 2);
+    }
+
+    #[test]
+    fn submission_marker() {
+        let dir = tempfile::tempdir().unwrap();
+        let review = Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false).unwrap();
+        let submitting = || review.read_metadata().unwrap().submitting;
+        assert_eq!(submitting(), None);
+
+        review.begin_submission().unwrap();
+        let key = submitting().unwrap();
+        assert_eq!(key.len(), 40);
+        // A crashed run leaves the marker behind, the next one replaces it
+        review.begin_submission().unwrap();
+        assert_eq!(submitting(), Some(key.clone()));
+        review.end_submission().unwrap();
+        assert_eq!(submitting(), None);
+
+        let contents = fs::read_to_string(review.path()).unwrap();
+        fs::write(review.path(), format!("LGTM\n\n{}", contents)).unwrap();
+        review.begin_submission().unwrap();
+        assert_ne!(submitting(), Some(key));
+        review.mark_submitted().unwrap();
+        assert_eq!(submitting(), None);
+        assert!(review.read_metadata().unwrap().submitted.is_some());
+    }
+
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let review = Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), false).unwrap();
+        let mut contents = fs::read_to_string(review.path()).unwrap();
+        contents.push_str("\nLooks wrong\n");
+        fs::write(review.path(), &contents).unwrap();
+        review.mark_submitted().unwrap();
+        assert!(review.read_metadata().unwrap().submitted.is_some());
+
+        // Comments are kept, but the review counts as unsubmitted again
+        assert!(!review.reopen().unwrap());
+        assert_eq!(review.read_metadata().unwrap().submitted, None);
+        assert_eq!(fs::read_to_string(review.path()).unwrap(), contents);
+        assert!(review.unsubmitted().unwrap());
+
+        // A removed review file comes back without comments
+        review.mark_submitted().unwrap();
+        fs::remove_file(review.path()).unwrap();
+        assert!(review.reopen().unwrap());
+        assert_eq!(
+            fs::read_to_string(review.path()).unwrap(),
+            prefix_lines(DIFF, "> ")
+        );
+        review.comments().unwrap();
+    }
+
+    #[test]
+    fn backup_and_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let get = || Review::new(dir.path(), DIFF.to_string(), 1, Extra::default(), true).unwrap();
+        let review = get();
+        let fetched = fs::read_to_string(review.path()).unwrap();
+        let err = review.restore(false).err().unwrap();
+        assert_eq!(err.to_string(), "No backup of the review for #1");
+
+        // Only reviews with unsubmitted changes are backed up
+        get();
+        assert!(!review.backup_paths(1).0.exists());
+
+        for i in 0..MAX_BACKUPS + 2 {
+            fs::write(review.path(), format!("Comment {}\n{}", i, fetched)).unwrap();
+            get();
+        }
+        assert!(review.backup_paths(MAX_BACKUPS).0.exists());
+        assert!(review.backup_paths(MAX_BACKUPS).1.exists());
+        assert!(!review.backup_paths(MAX_BACKUPS + 1).0.exists());
+
+        // Restoring does not overwrite unsubmitted changes unless forced
+        fs::write(review.path(), format!("Newer comment\n{}", fetched)).unwrap();
+        let err = review.restore(false).err().unwrap();
+        assert!(err.to_string().starts_with("You have unsubmitted changes"));
+        review.restore(true).unwrap();
+        let last = format!("Comment {}\n{}", MAX_BACKUPS + 1, fetched);
+        assert_eq!(fs::read_to_string(review.path()).unwrap(), last);
+        assert!(review.read_metadata().is_ok());
+        review.comments().unwrap();
+
+        // The backups move up, so the next restore gets the one before
+        review.restore(true).unwrap();
+        let before = format!("Comment {}\n{}", MAX_BACKUPS, fetched);
+        assert_eq!(fs::read_to_string(review.path()).unwrap(), before);
+        assert!(!review.backup_paths(MAX_BACKUPS - 1).0.exists());
+    }
+
+    #[test]
+    fn file_coverage() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false).unwrap();
+
+        let contents = include_str!("../testdata/multiple_files").replace("Comment 2\n", "");
+        fs::write(review.path(), contents).unwrap();
+
+        let coverage = review.file_coverage().unwrap();
+        assert_eq!(
+            coverage,
+            vec![
+                FileCoverage {
+                    file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+                    comments: 1,
+                },
+                FileCoverage {
+                    file: "libbpf-cargo/src/test.rs".to_string(),
+                    comments: 0,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn snippets() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false)
+            .unwrap()
+            .with_snippets(HashMap::from([
+                ("nit".to_string(), "nit: ".to_string()),
+                (
+                    "test".to_string(),
+                    "Can you add a test for this?\n".to_string(),
+                ),
+            ]));
+
+        let contents = format!(
+            "@prr snip test\n\n{}",
+            include_str!("../testdata/multiple_files")
+                .replace("Comment 1\n", "@prr snip nit\nRename this\n")
+        );
+        fs::write(review.path(), contents).unwrap();
+
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.comment, "Can you add a test for this?");
+        assert_eq!(comments.inline[0].comment, "nit:\nRename this");
+        assert_eq!(comments.inline[1].comment, "Comment 2");
+
+        let contents =
+            include_str!("../testdata/multiple_files").replace("Comment 1\n", "@prr snip typo\n");
+        fs::write(review.path(), contents).unwrap();
+        let err = review.comments().unwrap_err();
+        assert!(err.to_string().contains("Unknown snippet 'typo'"));
+
+        let contents = include_str!("../testdata/multiple_files")
+            .replace("Comment 1\n", "Comment 1\n@prr snip nit\n");
+        fs::write(review.path(), contents).unwrap();
+        assert!(review.comments().is_err());
+    }
+
+    #[test]
+    fn whitespace_comments() {
+        assert_eq!(normalize_comment("  \n\t\n", false), "");
+        assert_eq!(
+            normalize_comment("\n\nFix this  \n    code\n\n  \n", false),
+            "Fix this  \n    code"
+        );
+        assert_eq!(
+            normalize_comment("Fix this  \n    code \t", true),
+            "Fix this\n    code"
+        );
+
+        // A snippet that is only whitespace leaves nothing to submit
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false)
+            .unwrap()
+            .with_snippets(HashMap::from([(
+                "blank".to_string(),
+                " \n\t\n".to_string(),
+            )]))
+            .strip_trailing_whitespace(true);
+        let contents = include_str!("../testdata/multiple_files")
+            .replace("Comment 1\n", "@prr snip blank\n  \n")
+            .replace("Comment 2\n", "Comment 2   \nwith trailing spaces  \n");
+        fs::write(review.path(), contents).unwrap();
+
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.inline.len(), 1);
+        assert_eq!(
+            comments.inline[0].comment,
+            "Comment 2\nwith trailing spaces"
+        );
+    }
+
+    #[test]
+    fn layout_unknown_placeholder() {
+        let workdir = Path::new("/workdir");
+        assert!(review_dir(workdir, "{org}/{repo}", "github.com", "danobi", "prr").is_err());
+    }
+
+    #[test]
+    fn no_index_lines() {
+        let strip = |s: &str| -> String {
+            s.lines()
+                .filter(|l| {
+                    let l = l.strip_prefix("> ").unwrap_or(l);
+                    !l.starts_with("index ") && !l.starts_with("--- ") && !l.starts_with("+++ ")
+                })
+                .map(|l| format!("{}\n", l))
+                .collect()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let diff = strip(include_str!("../testdata/diffs/multiple_files"));
+        let review = Review::new(dir.path(), diff.clone(), 1, Extra::default(), false).unwrap();
+        fs::write(
+            review.path(),
+            strip(include_str!("../testdata/multiple_files")),
+        )
+        .unwrap();
+
+        let inline = review.comments().unwrap().inline;
+        assert_eq!(inline.len(), 2);
+        assert_eq!(inline[1].new_file, "libbpf-cargo/src/test.rs");
+        assert_eq!(inline[1].line, LineLocation::Right(2147, 2159));
+        assert!(comments_outside_diff(&diff, &inline).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lint() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false)
+            .unwrap()
+            .with_snippets(HashMap::from([("blank".to_string(), " \n".to_string())]));
+        let contents = include_str!("../testdata/multiple_files")
+            .replace("Comment 2\n", "@prr snip blank\n")
+            .replace(
+                ">          match BtfKind::try_from(kind)? {\n",
+                ">          match BtfKind::try_from(kind)? {\nOn context\n",
+            );
+        fs::write(review.path(), &contents).unwrap();
+        assert_eq!(
+            review.lint().unwrap(),
+            vec!["Empty comment on libbpf-cargo/src/test.rs new line 2159"]
+        );
+
+        fs::write(review.path(), format!("@prr approve\n{}", contents)).unwrap();
+        let warnings = review.lint().unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(
+            warnings[0],
+            "Comment on unchanged line 736 of libbpf-cargo/src/btf/btf.rs in an approving review"
+        );
+
+        fs::write(review.path(), include_str!("../testdata/cross_hunk_span")).unwrap();
+        let warnings = review.lint().unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("crosses into the next hunk on line 20"));
+        assert!(review.comments().is_err());
+    }
+
+    #[test]
+    fn comment_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false).unwrap();
+        fs::write(review.path(), include_str!("../testdata/multiple_files")).unwrap();
+
+        let inline = review.comments().unwrap().inline;
+        let order: Vec<_> = inline
+            .iter()
+            .map(|c| (c.new_file.as_str(), c.comment.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("libbpf-cargo/src/btf/btf.rs", "Comment 1"),
+                ("libbpf-cargo/src/test.rs", "Comment 2"),
+            ]
+        );
+    }
+
+    #[test]
+    fn default_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false).unwrap();
+        fs::write(review.path(), include_str!("../testdata/multiple_files")).unwrap();
+
+        // Only inline comments and no directive, so this is a plain comment
+        let comments = review.comments().unwrap();
+        assert_eq!(comments.action, ReviewAction::Comment);
+        assert_eq!(comments.inline.len(), 2);
+
+        // Unless `prr.default_action` says otherwise
+        for action in [
+            ReviewAction::Comment,
+            ReviewAction::Approve,
+            ReviewAction::RequestChanges,
+        ] {
+            let review = Review::new_existing(dir.path(), 1).default_action(action);
+            assert_eq!(review.comments().unwrap().action, action);
+        }
+
+        // A directive wins over the configured default, also when linting
+        let contents = include_str!("../testdata/multiple_files");
+        fs::write(review.path(), format!("@prr comment\n{}", contents)).unwrap();
+        let review = Review::new_existing(dir.path(), 1).default_action(ReviewAction::Approve);
+        assert_eq!(review.comments().unwrap().action, ReviewAction::Comment);
+        let contents = contents.replace(
+            ">          match BtfKind::try_from(kind)? {\n",
+            ">          match BtfKind::try_from(kind)? {\nOn context\n",
+        );
+        fs::write(review.path(), &contents).unwrap();
+        assert_eq!(review.lint().unwrap().len(), 1);
+        fs::write(review.path(), format!("@prr reject\n{}", contents)).unwrap();
+        assert!(review.lint().unwrap().is_empty());
+    }
+
+    #[test]
+    fn single_line_span() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/single_file");
+        let review = Review::new(dir.path(), diff.to_string(), 1, Extra::default(), false).unwrap();
+        fs::write(review.path(), include_str!("../testdata/span_single_line")).unwrap();
+
+        let inline = review.comments().unwrap().inline;
+        assert_eq!(inline.len(), 1);
+        assert_eq!(inline[0].line, LineLocation::Right(734, 734));
+        assert_eq!(inline[0].start_line, None);
+    }
+
+    #[test]
+    fn retain_files() {
+        let inline = |old: &str, new: &str| InlineComment {
+            old_file: old.to_string(),
+            new_file: new.to_string(),
+            line: LineLocation::Right(1, 1),
+            start_line: None,
+            comment: "Comment".to_string(),
+            line_text: String::new(),
+        };
+        let comments = || ReviewComments {
+            action: ReviewAction::Approve,
+            comment: "LGTM".to_string(),
+            inline: vec![
+                inline("a.rs", "a.rs"),
+                inline("old.rs", "new.rs"),
+                inline("c.rs", "c.rs"),
+            ],
+            reactions: vec![Reaction {
+                comment_id: 1,
+                emoji: Emoji::Rocket,
+            }],
+            amends: vec![Amend {
+                comment_id: 2,
+                body: "Edited".to_string(),
+            }],
+            replies: vec![Reply {
+                comment_id: 3,
+                body: "Done".to_string(),
+            }],
+            commits: vec![CommitComment {
+                sha: "abcdef0".to_string(),
+                comment: "Typo in the subject".to_string(),
+            }],
+        };
+
+        let mut filtered = comments();
+        filtered.retain_files(&["a.rs".to_string(), "old.rs".to_string()], false);
+        assert_eq!(
+            filtered.inline,
+            vec![inline("a.rs", "a.rs"), inline("old.rs", "new.rs")]
+        );
+        assert_eq!(filtered.action, ReviewAction::Comment);
+        assert!(filtered.comment.is_empty());
+        assert!(filtered.reactions.is_empty());
+        assert!(filtered.amends.is_empty());
+        assert!(filtered.replies.is_empty());
+        assert!(filtered.commits.is_empty());
+
+        let mut filtered = comments();
+        filtered.retain_files(&["new.rs".to_string()], true);
+        assert_eq!(filtered.inline, vec![inline("old.rs", "new.rs")]);
+        assert_eq!(filtered.action, ReviewAction::Approve);
+        assert_eq!(filtered.comment, "LGTM");
+        assert_eq!(filtered.reactions.len(), 1);
+        assert_eq!(filtered.amends.len(), 1);
+        assert_eq!(filtered.commits.len(), 1);
+    }
+
+    #[test]
+    fn append_signature() {
+        let mut comments = ReviewComments {
+            action: ReviewAction::Comment,
+            comment: "LGTM".to_string(),
+            inline: Vec::new(),
+            reactions: Vec::new(),
+            amends: Vec::new(),
+            replies: Vec::new(),
+            commits: Vec::new(),
+        };
+        comments.append_signature("-- Reviewed with prr\n");
+        comments.append_signature("-- Reviewed with prr");
+        assert_eq!(comments.comment, "LGTM\n\n-- Reviewed with prr");
+
+        // Nothing to sign
+        comments.comment.clear();
+        comments.append_signature("-- Reviewed with prr");
+        assert_eq!(comments.comment, "");
+    }
+
+    #[test]
+    fn expand_saved_replies() {
+        let mut comments = ReviewComments {
+            action: ReviewAction::Comment,
+            comment: "Thanks!\n@prr saved Merge policy".to_string(),
+            inline: vec![InlineComment {
+                old_file: "a.rs".to_string(),
+                new_file: "a.rs".to_string(),
+                line: LineLocation::Right(1, 1),
+                start_line: None,
+                comment: "Nit".to_string(),
+                line_text: String::new(),
+            }],
+            reactions: Vec::new(),
+            amends: Vec::new(),
+            replies: Vec::new(),
+            commits: Vec::new(),
+        };
+        assert_eq!(comments.saved_replies(), ["Merge policy"]);
+
+        let mut saved = HashMap::new();
+        saved.insert("Merge policy".to_string(), "Please squash.\n".to_string());
+        saved.insert("Docs".to_string(), "Please add docs.".to_string());
+        comments.expand_saved_replies(&saved).unwrap();
+        assert_eq!(comments.comment, "Thanks!\nPlease squash.");
+        assert_eq!(comments.inline[0].comment, "Nit");
+        assert!(comments.saved_replies().is_empty());
+
+        comments.inline[0].comment = "@prr saved Tests".to_string();
+        let err = comments.expand_saved_replies(&saved).err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "Unknown saved reply 'Tests', your saved replies are: Docs, Merge policy"
+        );
+    }
+
+    #[test]
+    fn upload_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("narrow.png");
+        fs::write(&image, b"png").unwrap();
+        let attach = format!("@prr attach {}", image.display());
+
+        let mut comments = ReviewComments {
+            action: ReviewAction::Comment,
+            comment: format!("Breaks on narrow screens:\n{}", attach),
+            inline: vec![InlineComment {
+                old_file: "a.rs".to_string(),
+                new_file: "a.rs".to_string(),
+                line: LineLocation::Right(1, 1),
+                start_line: None,
+                comment: format!("{}\nHere too", attach),
+                line_text: String::new(),
+            }],
+            reactions: Vec::new(),
+            amends: Vec::new(),
+            replies: Vec::new(),
+            commits: Vec::new(),
+        };
+        let mut uploads = Vec::new();
+        comments
+            .upload_attachments(|name, contents| {
+                uploads.push((name.to_string(), contents));
+                Ok("![narrow](/uploads/narrow.png)".to_string())
+            })
+            .unwrap();
+        assert_eq!(uploads, vec![("narrow.png".to_string(), b"png".to_vec())]);
+        assert_eq!(
+            comments.comment,
+            "Breaks on narrow screens:\n![narrow](/uploads/narrow.png)"
+        );
+        assert_eq!(
+            comments.inline[0].comment,
+            "![narrow](/uploads/narrow.png)\nHere too"
+        );
+
+        let mut missing = ReviewComments {
+            comment: format!("@prr attach {}", dir.path().join("missing.png").display()),
+            ..comments
+        };
+        let err = missing
+            .upload_attachments(|_, _| unreachable!())
+            .unwrap_err();
+        assert!(err.to_string().contains("Failed to read attachment"));
+
+        let mut failing = ReviewComments {
+            comment: attach,
+            ..missing
+        };
+        let err = failing
+            .upload_attachments(|_, _| bail!("413 Payload Too Large"))
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Failed to upload attachment"));
+        assert!(format!("{:#}", err).contains("413"));
+    }
+
+    #[test]
+    fn span_sides() {
+        let span = |start: LineLocation, end: LineLocation| InlineComment {
+            old_file: "a.rs".to_string(),
+            new_file: "a.rs".to_string(),
+            line: end,
+            start_line: Some(start),
+            comment: "Comment".to_string(),
+            line_text: String::new(),
+        };
+
+        // Same side
+        assert!(validate_span(&span(LineLocation::Both(1, 1), LineLocation::Both(3, 3))).is_ok());
+        assert!(validate_span(&span(LineLocation::Left(2, 1), LineLocation::Left(3, 1))).is_ok());
+        // Deleted line to the added line replacing it
+        assert!(validate_span(&span(LineLocation::Left(4, 3), LineLocation::Right(4, 4))).is_ok());
+        // Added line to a later deleted line
+        let err = validate_span(&span(LineLocation::Right(4, 4), LineLocation::Left(6, 5)));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("starts on an added line"));
+        // Inverted
+        let err = validate_span(&span(LineLocation::Both(5, 5), LineLocation::Both(3, 3)));
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "Span in a.rs starts at line 5 which is after where it ends at line 3"
+        );
+        let err = validate_span(&span(LineLocation::Left(7, 5), LineLocation::Right(6, 6)));
+        assert!(err
+            .unwrap_err()
+            .to_string()
+            .contains("starts at old line 7"));
+    }
+
+    #[test]
+    fn annotate_threads() {
+        let thread = |old_line, new_line, resolved, body: &str| Thread {
+            id: "1".to_string(),
+            file: "libbpf-cargo/src/btf/btf.rs".to_string(),
+            old_line,
+            new_line,
+            resolved,
+            comments: vec![ThreadComment {
+                id: 1,
+                author: "danobi".to_string(),
+                body: body.to_string(),
+            }],
+        };
+        let mut other_file = thread(None, Some(734), false, "Not in this diff");
+        other_file.file = "src/main.rs".to_string();
+        let mut replied = thread(None, Some(734), true, "Why 0x1f?\n\nSee the spec");
+        replied.comments.push(ThreadComment {
+            id: 2,
+            author: "ghost".to_string(),
+            body: "Because".to_string(),
+        });
+        let threads = [
+            replied,
+            thread(Some(734), None, false, "Deleted line"),
+            thread(Some(732), Some(732), false, "Context line"),
+            thread(None, Some(1000), false, "Outdated"),
+            other_file,
+        ];
+
+        let diff = include_str!("../testdata/diffs/single_file");
+        let annotated = annotate_diff(diff, &threads, false, DEFAULT_QUOTE_PREFIX).unwrap();
+        assert_eq!(annotated, include_str!("../testdata/threads"));
+    }
+
+    #[test]
+    fn hunk_range_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/multiple_files");
+        let mut extra = Extra::default();
+        extra.hunk_ranges(true);
+        let review = Review::new(dir.path(), diff.to_string(), 1, extra, false).unwrap();
+        let contents = fs::read_to_string(review.path()).unwrap();
+        assert!(contents.contains("{\n> | Commentable: new lines 731-737\n"));
+        assert!(contents.contains("> | Commentable: new lines 2145-2171\n"));
+
+        // The annotations are not comments, and do not get in the way of real ones
+        assert!(!review.unsubmitted().unwrap());
+        assert!(review.comments().unwrap().inline.is_empty());
+        let contents = contents.replacen(
+            "> +        let kind = (t.info >> 24) & 0x1f;\n",
+            "> +        let kind = (t.info >> 24) & 0x1f;\n\nWhy?\n\n",
+            1,
+        );
+        fs::write(review.path(), contents).unwrap();
+        let inline = review.comments().unwrap().inline;
+        assert_eq!(inline.len(), 1);
+        assert_eq!(inline[0].line, LineLocation::Right(734, 734));
+
+        // Hunks that only remove lines have nothing to comment on in the new file
+        let removal = "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -1,2 +0,0 @@\n-a\n-b\n";
+        assert_eq!(hunk_ranges(removal).unwrap(), [None]);
+        assert_eq!(
+            render_hunk_range(None, "> "),
+            "> | Commentable: only removed lines\n"
+        );
+        assert_eq!(
+            render_hunk_range(Some((3, 3)), "> "),
+            "> | Commentable: new line 3\n"
+        );
+    }
+
+    #[test]
+    fn metadata_compat() {
+        // Written by an old version, before any of the optional fields existed
+        let old: ReviewMetadata = serde_json::from_str(r#"{"original": "diff"}"#).unwrap();
+        assert_eq!(old.original, "diff");
+        assert_eq!(old.submitted, None);
+        assert_eq!(old.head_sha, None);
+        assert_eq!(old.commit, None);
+        assert!(!old.draft);
+        assert_eq!(old.state, PullState::Open);
+        assert_eq!(old.range, DiffRange::ThreeDot);
+
+        // Written by a newer version with fields we do not know about
+        let future = r#"{
+            "original": "diff",
+            "submitted": 1700000000,
+            "head_sha": "abc",
+            "diff_hash": "0123",
+            "title": {"text": "Fix it"}
+        }"#;
+        let future: ReviewMetadata = serde_json::from_str(future).unwrap();
+        assert_eq!(future.submitted, Some(1700000000));
+        assert_eq!(future.head_sha.as_deref(), Some("abc"));
+        assert_eq!(future.base_sha, None);
+
+        assert!(serde_json::from_str::<ReviewMetadata>(r#"{"submitted": 1}"#).is_err());
+    }
+
+    #[test]
+    fn pull_state() {
+        let metadata = |state: &str| -> ReviewMetadata {
+            serde_json::from_str(&format!(r#"{{"original": "diff"{}}}"#, state)).unwrap()
+        };
+
+        let open = metadata("");
+        assert!(open.check_state(false).is_ok());
+        // Open is the default, so it is not written
+        assert!(!serde_json::to_string(&open).unwrap().contains("state"));
+
+        for state in ["closed", "merged"] {
+            let closed = metadata(&format!(r#", "state": "{}""#, state));
+            assert_eq!(closed.state.name(), state);
+            let err = closed.check_state(false).unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with(&format!("The pull request was {}", state)));
+            assert!(closed.check_state(true).is_ok());
+        }
+    }
+
+    /// Writes `contents` as the review file of a review of the `multiple_files` diff,
+    /// replacing any earlier one
+    fn submittable(dir: &Path, extra: Extra, contents: &str) -> Review {
+        let diff = include_str!("../testdata/diffs/multiple_files").to_string();
+        let review = Review::new(dir, diff, 1, extra, true).unwrap();
+        fs::write(review.path(), contents).unwrap();
+        review
+    }
+
+    /// Prepares `review` for a host without saved replies or uploads, that needs a comment
+    fn prepare(review: &Review, opts: &SubmitOptions) -> Result<ReviewComments> {
+        review.prepare_submission(
+            opts,
+            false,
+            || bail!("No saved replies"),
+            |_, _| bail!("No uploads"),
+        )
+    }
+
+    #[test]
+    fn prepare_submission_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = format!(
+            "@prr reject\n\nNeeds work\n\n{}",
+            include_str!("../testdata/multiple_files")
+        );
+        let review = submittable(dir.path(), Extra::default(), &contents);
+
+        let comments = prepare(&review, &SubmitOptions::default()).unwrap();
+        assert_eq!(comments.action, ReviewAction::RequestChanges);
+        assert_eq!(comments.comment, "Needs work");
+        assert_eq!(comments.inline.len(), 2);
+
+        // Only the inline comments on the file, without approving or rejecting
+        let only = SubmitOptions {
+            only: vec!["libbpf-cargo/src/test.rs".to_string()],
+            ..Default::default()
+        };
+        let comments = prepare(&review, &only).unwrap();
+        assert_eq!(comments.action, ReviewAction::Comment);
+        assert!(comments.comment.is_empty());
+        assert_eq!(comments.inline.len(), 1);
+        assert_eq!(comments.inline[0].comment, "Comment 2");
+
+        // The message wins over the review comment in the file, and is signed
+        let message = SubmitOptions {
+            message: Some("From the command line\n".to_string()),
+            signature: Some("-- Reviewed with prr\n".to_string()),
+            ..Default::default()
+        };
+        let comments = prepare(&review, &message).unwrap();
+        assert_eq!(
+            comments.comment,
+            "From the command line\n\n-- Reviewed with prr"
+        );
+        assert_eq!(comments.action, ReviewAction::RequestChanges);
+        assert!(comments
+            .inline
+            .iter()
+            .all(|c| !c.comment.contains("Reviewed")));
+
+        // Nothing is submitted yet
+        let metadata = review.read_metadata().unwrap();
+        assert_eq!(metadata.submitted, None);
+        assert_eq!(metadata.submitting, None);
+    }
+
+    #[test]
+    fn prepare_submission_expansions() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("narrow.png");
+        fs::write(&image, b"png").unwrap();
+        let contents = include_str!("../testdata/multiple_files")
+            .replace("Comment 1\n", "@prr snip nit\n@prr saved Naming\n")
+            .replace("Comment 2\n", &format!("@prr attach {}\n", image.display()));
+        let review = submittable(dir.path(), Extra::default(), &contents).with_snippets(
+            [("nit".to_string(), "nit:".to_string())]
+                .into_iter()
+                .collect(),
+        );
+
+        let mut uploads = Vec::new();
+        let comments = review
+            .prepare_submission(
+                &SubmitOptions::default(),
+                false,
+                || Ok([("Naming".to_string(), "Rename this".to_string())].into()),
+                |name, contents| {
+                    uploads.push((name.to_string(), contents));
+                    Ok(format!("![{}](/uploads/{})", name, name))
+                },
+            )
+            .unwrap();
+        assert_eq!(comments.inline[0].comment, "nit:\nRename this");
+        assert_eq!(
+            comments.inline[1].comment,
+            "![narrow.png](/uploads/narrow.png)"
+        );
+        assert_eq!(uploads, [("narrow.png".to_string(), b"png".to_vec())]);
+
+        // Saved replies are only looked up if the review uses one
+        let contents = include_str!("../testdata/multiple_files");
+        let review = submittable(dir.path(), Extra::default(), contents);
+        let called = std::cell::Cell::new(false);
+        review
+            .prepare_submission(
+                &SubmitOptions::default(),
+                false,
+                || {
+                    called.set(true);
+                    Ok(HashMap::new())
+                },
+                |_, _| bail!("No uploads"),
+            )
+            .unwrap();
+        assert!(!called.get());
+    }
+
+    #[test]
+    fn prepare_submission_refusals() {
+        let dir = tempfile::tempdir().unwrap();
+        let quoted = prefix_lines(include_str!("../testdata/diffs/multiple_files"), "> ");
+
+        // Nothing in the review file
+        let review = submittable(dir.path(), Extra::default(), &quoted);
+        let err = prepare(&review, &SubmitOptions::default()).unwrap_err();
+        assert_eq!(err.to_string(), "No review comments");
+
+        // Only an approval, which some hosts take on its own
+        let review = submittable(
+            dir.path(),
+            Extra::default(),
+            &format!("@prr approve\n\n{}", quoted),
+        );
+        assert!(prepare(&review, &SubmitOptions::default()).is_err());
+        let comments = review
+            .prepare_submission(
+                &SubmitOptions::default(),
+                true,
+                || bail!("No saved replies"),
+                |_, _| bail!("No uploads"),
+            )
+            .unwrap();
+        assert_eq!(comments.action, ReviewAction::Approve);
+        assert!(comments.is_empty());
+
+        // Closed and merged pull requests need --force
+        for state in [PullState::Closed, PullState::Merged] {
+            let mut extra = Extra::default();
+            extra.state(state);
+            let contents = include_str!("../testdata/multiple_files");
+            let review = submittable(dir.path(), extra, contents);
+            let err = prepare(&review, &SubmitOptions::default()).unwrap_err();
+            assert!(err.to_string().contains("Submit with --force"), "{}", err);
+
+            let force = SubmitOptions {
+                force: true,
+                ..Default::default()
+            };
+            assert_eq!(prepare(&review, &force).unwrap().inline.len(), 2);
+        }
+    }
+
+    #[test]
+    fn two_dot_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let two_dot = || {
+            let mut extra = Extra::default();
+            extra.range(DiffRange::TwoDot);
+            extra
+        };
+        let contents = include_str!("../testdata/multiple_files");
+
+        // Comments on added lines are where the host expects them
+        let review = submittable(dir.path(), two_dot(), contents);
+        assert_eq!(
+            prepare(&review, &SubmitOptions::default())
+                .unwrap()
+                .inline
+                .len(),
+            2
+        );
+
+        let removed = "> -        let kind = (t.info >> 24) & 0xf;\n";
+        let context = ">          match BtfKind::try_from(kind)? {\n";
+        for (line, expected) in [(removed, "old line 734"), (context, "line 736")] {
+            let commented = contents.replacen(line, &format!("{}Not here\n", line), 1);
+            let review = submittable(dir.path(), two_dot(), &commented);
+            let err = prepare(&review, &SubmitOptions::default()).unwrap_err();
+            assert!(
+                err.to_string()
+                    .starts_with(&format!("Comment on {} of", expected)),
+                "{}",
+                err
+            );
+
+            // Three-dot diffs have no such restriction
+            let review = submittable(dir.path(), Extra::default(), &commented);
+            assert!(prepare(&review, &SubmitOptions::default()).is_ok());
+        }
+    }
+
+    #[test]
+    fn finish_submission() {
+        let dir = tempfile::tempdir().unwrap();
+        let contents = include_str!("../testdata/multiple_files");
+        let review = submittable(dir.path(), Extra::default(), contents);
+
+        // Part of the review is left to submit
+        review.begin_submission().unwrap();
+        let only = SubmitOptions {
+            only: vec!["libbpf-cargo/src/test.rs".to_string()],
+            ..Default::default()
+        };
+        review.finish_submission(&only).unwrap();
+        let metadata = review.read_metadata().unwrap();
+        assert_eq!(metadata.submitted, None);
+        assert_eq!(metadata.submitting, None);
+
+        review.begin_submission().unwrap();
+        review.finish_submission(&SubmitOptions::default()).unwrap();
+        let metadata = review.read_metadata().unwrap();
+        assert!(metadata.submitted.is_some());
+        assert_eq!(metadata.submitting, None);
+    }
+
+    #[test]
+    fn commit_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let commit = |n: u64, message: &str| Commit {
+            sha: format!("{:040x}", n),
+            author: "Daniel Xu <dxu@dxuuu.xyz>".to_string(),
+            message: message.to_string(),
+        };
+        let mut extra = Extra::default();
+        extra.commits(vec![
+            commit(1, "Fix kind mask\n\nIt is 5 bits"),
+            commit(2, "Wip"),
+        ]);
+        let review = Review::new(dir.path(), DIFF.to_string(), 1, extra, false).unwrap();
+        let contents = fs::read_to_string(review.path()).unwrap();
+        assert!(contents.starts_with(&format!(
+            "> commit {:040x}\n> Author: Daniel Xu <dxu@dxuuu.xyz>\n> \n>     Fix kind mask\n> \n\
+            >     It is 5 bits\n> \n> commit {:040x}\n",
+            1, 2
+        )));
+
+        let contents = format!(
+            "@prr commit {:07x}\nNice message\n\n{}",
+            1,
+            contents.replace(">     Wip\n", ">     Wip\nPlease squash this\n")
+        );
+        fs::write(review.path(), contents).unwrap();
+
+        let comments = prepare(&review, &SubmitOptions::default()).unwrap();
+        let commits: Vec<_> = comments
+            .commits
+            .iter()
+            .map(|c| (c.sha.clone(), c.comment.as_str()))
+            .collect();
+        assert_eq!(
+            commits,
+            [
+                ("0000001".to_string(), "Nice message"),
+                (format!("{:040x}", 2), "Please squash this"),
+            ]
+        );
+        assert!(comments.comment.is_empty());
+    }
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*.rs", "src/main.rs"));
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(!glob_match("*.rs", "src/main.rs.orig"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/api/github.rs"));
+        assert!(glob_match("src/**/*.rs", "src/main.rs"));
+        assert!(glob_match("src/**/*.rs", "src/api/github.rs"));
+        assert!(glob_match("src/**", "src/api/github.rs"));
+        assert!(!glob_match("src/**", "docs/src.md"));
+        assert!(glob_match("docs/guide.m?", "docs/guide.md"));
+        assert!(!glob_match("docs?guide.md", "docs/guide.md"));
+        assert!(glob_match("README.md", "README.md"));
+        assert!(!glob_match("README.md", "docs/README.md.in"));
+    }
+
+    #[test]
+    fn filter_paths() {
+        let diff = include_str!("../testdata/diffs/many_files");
+        let files = |patterns: &[&str]| -> Result<Vec<String>> {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            filter_diff(diff, &patterns)?
+                .lines()
+                .filter(|l| is_diff_header(l))
+                .map(|l| Ok(parse_diff_header(l)?.1))
+                .collect()
+        };
+
+        assert_eq!(
+            files(&["*.rs"]).unwrap(),
+            ["src/main.rs", "src/api/github.rs"]
+        );
+        assert_eq!(files(&["src/*"]).unwrap(), ["src/main.rs"]);
+        assert_eq!(
+            files(&["*.md", "src/api/**"]).unwrap(),
+            ["README.md", "docs/guide.md", "src/api/github.rs"]
+        );
+        assert_eq!(
+            files(&["docs/**", "*.md"]).unwrap(),
+            ["README.md", "docs/guide.md"]
+        );
+        let err = files(&["*.toml"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("No file in the pull request matches *.toml"));
+
+        // Whole files are kept, hunks included
+        let filtered = filter_diff(diff, &["src/main.rs".to_string()]).unwrap();
+        assert!(filtered.starts_with("diff --git a/src/main.rs b/src/main.rs\n"));
+        assert!(filtered.ends_with("     run(args);\n"));
+    }
+
+    #[test]
+    fn partial_review() {
+        let dir = tempfile::tempdir().unwrap();
+        let diff = include_str!("../testdata/diffs/many_files");
+        let mut extra = Extra::default();
+        extra.paths(vec!["*.md".to_string()]);
+        let review = Review::new(dir.path(), diff.to_string(), 1, extra, false).unwrap();
+
+        assert_eq!(review.num_files().unwrap(), 2);
+        assert_eq!(review.read_metadata().unwrap().paths, ["*.md"]);
+        let contents = fs::read_to_string(review.path()).unwrap();
+        assert!(!contents.contains("src/main.rs"));
+    }
+
+    #[test]
+    fn outside_diff() {
+        let comment = |file: &str, line: LineLocation| InlineComment {
+            old_file: file.to_string(),
+            new_file: file.to_string(),
+            line,
+            start_line: None,
+            comment: "Comment".to_string(),
+            line_text: String::new(),
+        };
+        let file = "libbpf-cargo/src/btf/btf.rs";
+        let comments = [
+            comment(file, LineLocation::Right(734, 734)),
+            comment(file, LineLocation::Left(733, 733)),
+            comment(file, LineLocation::Both(740, 740)),
+            comment(file, LineLocation::Left(700, 700)),
+            comment("src/main.rs", LineLocation::Right(1, 1)),
+        ];
+
+        let diff = include_str!("../testdata/diffs/single_file");
+        let outside = comments_outside_diff(diff, &comments).unwrap();
+        assert_eq!(outside.len(), 3);
+        assert!(
+            outside[0].contains("line 740 of the new file"),
+            "{}",
+            outside[0]
+        );
+        assert!(outside[0].ends_with("the nearest line that can be commented on is 737"));
+        assert!(
+            outside[1].contains("line 700 of the old file"),
+            "{}",
+            outside[1]
+        );
+        assert!(outside[1].ends_with("is 731"), "{}", outside[1]);
+        assert!(
+            outside[2].starts_with("src/main.rs: no line"),
+            "{}",
+            outside[2]
+        );
+    }
+
+    #[test]
+    fn changed_lines_since_fetch() {
+        let diff = include_str!("../testdata/diffs/single_file");
+        let mut parser = ReviewParser::new();
+        let mut comments = Vec::new();
+        for line in include_str!("../testdata/single_comment").lines() {
+            if let Some(Comment::Inline(c)) = parser.parse_line(line).unwrap() {
+                comments.push(c);
+            }
+        }
+        assert_eq!(comments.len(), 1);
+
+        assert!(changed_lines(diff, &comments).unwrap().is_empty());
+
+        let edited = diff.replace("0x1f;", "0x3f;");
+        let changed = changed_lines(&edited, &comments).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(
+            changed[0].contains("line 734 of the new file"),
+            "{}",
+            changed[0]
+        );
+        assert!(
+            changed[0].ends_with("was 'let kind = (t.info >> 24) & 0x1f;' when you commented on it, but is now 'let kind = (t.info >> 24) & 0x3f;'"),
+            "{}",
+            changed[0]
+        );
+
+        // Same number of lines, but everything moved up by one
+        let shifted = diff.replace("@@ -731,7 +731,7 @@", "@@ -730,7 +730,7 @@");
+        let changed = changed_lines(&shifted, &comments).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(changed[0].ends_with("but is now ''"), "{}", changed[0]);
+
+        let other = include_str!("../testdata/diffs/many_files");
+        let changed = changed_lines(other, &comments).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert!(
+            changed[0].ends_with("is no longer part of the pull request diff"),
+            "{}",
+            changed[0]
+        );
+    }
+}
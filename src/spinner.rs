@@ -0,0 +1,69 @@
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Frames of the spinner animation
+const FRAMES: &[char] = &['|', '/', '-', '\\'];
+/// Time between two frames
+const INTERVAL: Duration = Duration::from_millis(100);
+/// Fast requests finish before the spinner shows up, so it does not flicker
+const DELAY: Duration = Duration::from_millis(300);
+
+/// A spinner on stderr to show that a slow operation is in progress
+///
+/// The spinner runs on its own thread until it is dropped, which also clears it.
+pub struct Spinner {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Spinner {
+    /// Starts a spinner with `message` next to it
+    pub fn start(message: &str) -> Spinner {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = stop.clone();
+            let message = message.to_string();
+            thread::spawn(move || spin(&stop, &message))
+        };
+
+        Spinner {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+fn spin(stop: &AtomicBool, message: &str) {
+    let start = Instant::now();
+    let mut shown = false;
+    for frame in FRAMES.iter().cycle() {
+        if stop.load(Ordering::Relaxed) {
+            break;
+        }
+
+        if start.elapsed() >= DELAY {
+            eprint!("\r{} {}", frame, message);
+            let _ = std::io::stderr().flush();
+            shown = true;
+        }
+        thread::sleep(INTERVAL);
+    }
+
+    if shown {
+        // Return to the start of the line and clear it
+        eprint!("\r\x1b[K");
+        let _ = std::io::stderr().flush();
+    }
+}
+
+impl Drop for Spinner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Reviewing stacked pull requests, ie. chains of pull requests that each build on the
+//! branch of the one below
+//!
+//! Every pull request of a stack gets its own review file as usual, diffed against the
+//! one below it so it only holds its own changes. The stack file puts those review files
+//! one after another, each below a delimiter line with its number. Before anything else
+//! reads the review files, `split()` copies the sections back into them, so comments end
+//! up on the pull request of the section they are in.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+
+use crate::api::{Api, GetOptions, PrState, PrSummary, SubmitOptions};
+use crate::review::Review;
+
+lazy_static! {
+    // Line starting the section of a pull request in a stack file. Example:
+    //
+    //      ==== prr stack #24: Add stack support ====
+    //
+    static ref DELIMITER: Regex = Regex::new(r"^==== prr stack #(?P<pr_num>\d+)\b").unwrap();
+}
+
+/// Returns the path of the stack file with `top` as its topmost pull request
+pub fn path(dir: &Path, top: u64) -> PathBuf {
+    dir.join(format!("{}.stack.prr", top))
+}
+
+/// Returns the open pull requests `top` is stacked on, bottom first and ending with `top`
+///
+/// A pull request is stacked on the one whose branch is its base.
+pub fn find(api: &dyn Api, owner: &str, repo: &str, top: u64) -> Result<Vec<PrSummary>> {
+    let mut open = api.list_prs(owner, repo, PrState::Open)?;
+    let pos = open
+        .iter()
+        .position(|pr| pr.number == top)
+        .ok_or_else(|| anyhow!("#{} is not an open pull request", top))?;
+    let mut stack = vec![open.swap_remove(pos)];
+    // Taking pull requests out of `open` as they are stacked keeps cycles from looping
+    while let Some(pos) = open
+        .iter()
+        .position(|pr| Some(&pr.head_ref) == stack.last().map(|below| &below.base_ref))
+    {
+        stack.push(open.swap_remove(pos));
+    }
+    if stack.len() == 1 {
+        bail!(
+            "#{} is not based on another open pull request, fetch it without --stack",
+            top
+        );
+    }
+    stack.reverse();
+
+    Ok(stack)
+}
+
+/// Fetches the pull requests of `stack` as returned by `find()` and writes their stack file
+///
+/// Returns the path of the stack file.
+pub fn get(
+    api: &dyn Api,
+    dir: &Path,
+    owner: &str,
+    repo: &str,
+    stack: &[PrSummary],
+    opts: &GetOptions,
+) -> Result<PathBuf> {
+    let top = stack.last().ok_or_else(|| anyhow!("Empty stack"))?.number;
+    // Carry edits to an earlier stack file over so the unsubmitted changes check sees them
+    if !opts.force && path(dir, top).exists() {
+        split(dir, top)?;
+    }
+
+    let mut contents = String::new();
+    let mut below: Option<&PrSummary> = None;
+    for pr in stack {
+        let opts = GetOptions {
+            base: below.map(|below| below.head_ref.clone()),
+            ..opts.clone()
+        };
+        let review = api.get_pr(owner, repo, pr.number, &opts)?;
+        let section = fs::read_to_string(review.path()).context("Failed to read review file")?;
+
+        if !contents.is_empty() {
+            contents.push('\n');
+        }
+        contents += &format!("==== prr stack #{}: {} ====\n", pr.number, pr.title);
+        contents += &section;
+        if !section.ends_with('\n') {
+            contents.push('\n');
+        }
+        below = Some(pr);
+    }
+
+    let path = path(dir, top);
+    fs::write(&path, contents).context("Failed to write stack file")?;
+
+    Ok(path)
+}
+
+/// Copies the sections of the stack file of `top` back into the review files
+///
+/// Only review files whose section changed are written. Returns the pull requests of the
+/// stack, bottom first.
+pub fn split(dir: &Path, top: u64) -> Result<Vec<u64>> {
+    let path = path(dir, top);
+    let contents = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read stack file {}", path.display()))?;
+
+    let mut sections: Vec<(u64, String)> = Vec::new();
+    for (idx, line) in contents.lines().enumerate() {
+        if let Some(captures) = DELIMITER.captures(line) {
+            let pr_num = captures["pr_num"].parse()?;
+            sections.push((pr_num, String::new()));
+        } else if let Some((_, section)) = sections.last_mut() {
+            section.push_str(line);
+            section.push('\n');
+        } else if !line.trim().is_empty() {
+            bail!(
+                "Line {} of {} is above the first pull request of the stack",
+                idx + 1,
+                path.display()
+            );
+        }
+    }
+    if sections.is_empty() {
+        bail!("{} has no pull requests in it", path.display());
+    }
+
+    let mut numbers = Vec::new();
+    for (pr_num, mut section) in sections {
+        // Drop the blank line `get()` puts between sections
+        if section.ends_with("\n\n") {
+            section.pop();
+        }
+
+        let review = Review::new_existing(dir, pr_num);
+        if fs::read_to_string(review.path()).ok().as_ref() != Some(&section) {
+            fs::write(review.path(), section)
+                .with_context(|| format!("Failed to write review file of #{}", pr_num))?;
+        }
+        numbers.push(pr_num);
+    }
+
+    Ok(numbers)
+}
+
+/// Submits the reviews in the stack file of `top`, each to the pull request of its section
+///
+/// Pull requests without unsubmitted comments are skipped. Returns the ones submitted.
+pub fn submit(
+    api: &dyn Api,
+    dir: &Path,
+    owner: &str,
+    repo: &str,
+    top: u64,
+    opts: &SubmitOptions,
+) -> Result<Vec<u64>> {
+    let mut submitted = Vec::new();
+    for pr_num in split(dir, top)? {
+        if !Review::new_existing(dir, pr_num).unsubmitted()? {
+            continue;
+        }
+        api.submit_pr(owner, repo, pr_num, opts)
+            .with_context(|| format!("Failed to submit review of #{}", pr_num))?;
+        submitted.push(pr_num);
+    }
+
+    Ok(submitted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::mock::MockApi;
+    use crate::parser::{LineLocation, ReviewAction};
+
+    /// Mock with #1 on `main`, #2 on #1, #3 on #2 and #4 on `main`
+    fn stacked(workdir: &Path) -> MockApi {
+        let mut api = MockApi::new(workdir, "").unwrap();
+        api.add_pr(1, include_str!("../testdata/diffs/single_file"))
+            .add_pr(2, include_str!("../testdata/diffs/multiple_files"))
+            .add_pr(3, include_str!("../testdata/diffs/single_file"))
+            .add_pr(4, include_str!("../testdata/diffs/single_file"))
+            .set_base(2, "pr-1")
+            .set_base(3, "pr-2");
+
+        api
+    }
+
+    #[test]
+    fn find_stack() {
+        let workdir = tempfile::tempdir().unwrap();
+        let api = stacked(workdir.path());
+
+        let numbers = |top| -> Vec<u64> {
+            find(&api, "danobi", "prr", top)
+                .unwrap()
+                .iter()
+                .map(|pr| pr.number)
+                .collect()
+        };
+        assert_eq!(numbers(3), [1, 2, 3]);
+        assert_eq!(numbers(2), [1, 2]);
+
+        let err = find(&api, "danobi", "prr", 4).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("not based on another open pull request"));
+        let err = find(&api, "danobi", "prr", 5).err().unwrap();
+        assert_eq!(err.to_string(), "#5 is not an open pull request");
+    }
+
+    #[test]
+    fn get_and_submit() {
+        let workdir = tempfile::tempdir().unwrap();
+        let api = stacked(workdir.path());
+        let dir = api.review_dir("danobi", "prr").unwrap();
+
+        let stack = find(&api, "danobi", "prr", 3).unwrap();
+        let stack_path = get(&api, &dir, "danobi", "prr", &stack, &GetOptions::default()).unwrap();
+        assert_eq!(stack_path, dir.join("3.stack.prr"));
+        let contents = fs::read_to_string(&stack_path).unwrap();
+        let delimiters: Vec<&str> = contents.lines().filter(|l| l.starts_with("====")).collect();
+        assert_eq!(
+            delimiters,
+            [
+                "==== prr stack #1: Pull request #1 ====",
+                "==== prr stack #2: Pull request #2 ====",
+                "==== prr stack #3: Pull request #3 ====",
+            ]
+        );
+
+        // Splitting an untouched stack file leaves the review files as fetched
+        let fetched = fs::read_to_string(Review::new_existing(&dir, 2).path()).unwrap();
+        assert_eq!(split(&dir, 3).unwrap(), [1, 2, 3]);
+        assert_eq!(
+            fs::read_to_string(Review::new_existing(&dir, 2).path()).unwrap(),
+            fetched
+        );
+
+        // Comment on #2 and on the changed line of its diff, which #1 and #3 change too,
+        // and approve #3
+        let (below_1, from_2) = contents
+            .split_once("==== prr stack #2: Pull request #2 ====\n")
+            .unwrap();
+        let (section_2, from_3) = from_2.split_once("==== prr stack #3").unwrap();
+        let changed = "> +        let kind = (t.info >> 24) & 0x1f;\n";
+        let section_2 = section_2.replacen(changed, &format!("{}\nWhy 0x1f?\n\n", changed), 1);
+        let contents = format!(
+            "{}==== prr stack #2: Pull request #2 ====\nWhy?\n\n{}==== prr stack #3{}",
+            below_1, section_2, from_3
+        )
+        .replacen(
+            "==== prr stack #3: Pull request #3 ====\n",
+            "==== prr stack #3: Pull request #3 ====\n@prr approve\nLGTM\n\n",
+            1,
+        );
+        fs::write(&stack_path, contents).unwrap();
+
+        let submitted = submit(&api, &dir, "danobi", "prr", 3, &SubmitOptions::default()).unwrap();
+        assert_eq!(submitted, [2, 3]);
+        let submissions = api.submissions.borrow();
+        assert_eq!(submissions[0].pr_num, 2);
+        assert_eq!(submissions[0].comments.comment, "Why?");
+        let inline = &submissions[0].comments.inline;
+        assert_eq!(inline.len(), 1);
+        assert_eq!(inline[0].new_file, "libbpf-cargo/src/btf/btf.rs");
+        assert_eq!(inline[0].line, LineLocation::Right(734, 734));
+        assert_eq!(inline[0].comment, "Why 0x1f?");
+        assert_eq!(submissions[1].comments.inline.len(), 0);
+        assert_eq!(submissions[1].pr_num, 3);
+        assert_eq!(submissions[1].comments.action, ReviewAction::Approve);
+        drop(submissions);
+
+        // Nothing is left to submit the second time around
+        let submitted = submit(&api, &dir, "danobi", "prr", 3, &SubmitOptions::default()).unwrap();
+        assert!(submitted.is_empty());
+    }
+
+    #[test]
+    fn text_above_first_section() {
+        let workdir = tempfile::tempdir().unwrap();
+        let dir = workdir.path();
+        fs::write(
+            path(dir, 3),
+            "\nStray comment\n==== prr stack #1: Bottom ====\n",
+        )
+        .unwrap();
+
+        let err = split(dir, 3).err().unwrap();
+        assert!(err.to_string().starts_with("Line 2 of"));
+    }
+}